@@ -0,0 +1,21 @@
+use quantum_simulator::qasm::definitions::GateDefinitions;
+use quantum_simulator::qasm::parser::Parser;
+use quantum_simulator::qasm::simulator::{Options, Simulator};
+use quantum_simulator::quantum::sampling::{sample_counts, Rng};
+use std::io;
+
+/// Prepares a Bell state from OpenQASM source and samples measurements of it.
+fn main() -> io::Result<()> {
+    let source = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\nh q[0];\ncx q[0], q[1];";
+    let simulator = Simulator::new(GateDefinitions::new(), Options::default());
+    let result = simulator.run(Parser::new(source.as_bytes()))?;
+    println!("Final state: {}", result.final_state);
+
+    let probabilities = result.final_state.marginal_probabilities(&[0, 1]);
+    let counts = sample_counts(&probabilities, 1000, &mut Rng::new(0));
+    println!("Counts of 1000 shots:");
+    for (outcome, count) in counts {
+        println!("  {outcome:02b}  {count}");
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use quantum_simulator::gates::gate::Gate;
+use quantum_simulator::qasm::definitions::GateDefinitions;
+use quantum_simulator::qasm::simulator::{Options, Simulator};
+use quantum_simulator::quantum::register::QubitRef;
+use std::f64::consts::PI;
+use std::io;
+
+/// The number of qubits searched over, so that there are 16 items.
+const SEARCH_QUBITS: usize = 4;
+/// The item the oracle marks.
+const MARKED: usize = 0b1011;
+
+/// Searches 16 items for the marked one with Grover's algorithm, building the circuit
+/// from gates rather than OpenQASM source.
+fn main() -> io::Result<()> {
+    let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+    // One more qubit than is searched over, for the multi-controlled Z.
+    simulator.append_qasm(&format!["OPENQASM 2.0;\nqreg q[{}];", SEARCH_QUBITS + 1])?;
+
+    apply_all(&mut simulator, |target| Gate::H { target })?;
+    let iterations = (PI / 4.0 * ((1 << SEARCH_QUBITS) as f64).sqrt()) as usize;
+    for _ in 0..iterations {
+        // The oracle flips the phase of the marked item.
+        let zeros: Vec<QubitRef> = (0..SEARCH_QUBITS)
+            .filter(|qubit| MARKED & (1 << qubit) == 0)
+            .map(QubitRef)
+            .collect();
+        for target in &zeros {
+            simulator.apply(Gate::X { target: *target })?;
+        }
+        apply_controlled_z(&mut simulator)?;
+        for target in &zeros {
+            simulator.apply(Gate::X { target: *target })?;
+        }
+
+        // The diffusion operator reflects about the uniform superposition.
+        apply_all(&mut simulator, |target| Gate::H { target })?;
+        apply_all(&mut simulator, |target| Gate::X { target })?;
+        apply_controlled_z(&mut simulator)?;
+        apply_all(&mut simulator, |target| Gate::X { target })?;
+        apply_all(&mut simulator, |target| Gate::H { target })?;
+    }
+
+    let result = simulator.finish()?;
+    let qubits: Vec<usize> = (0..SEARCH_QUBITS).collect();
+    let probabilities = result.final_state.marginal_probabilities(&qubits);
+    println!("After {iterations} iterations:");
+    for (item, probability) in &probabilities {
+        println!("  {item:04b}  {probability:.4}");
+    }
+    let (found, _) = probabilities
+        .iter()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .unwrap();
+    println!("Found {found:04b}, marked {MARKED:04b}");
+    Ok(())
+}
+
+/// Applies a gate to each of the searched qubits.
+fn apply_all(simulator: &mut Simulator, gate: impl Fn(QubitRef) -> Gate) -> io::Result<()> {
+    (0..SEARCH_QUBITS)
+        .map(QubitRef)
+        .try_for_each(|qubit| simulator.apply(gate(qubit)))
+}
+
+/// Flips the phase of the state with every searched qubit set, computing the AND of the
+/// first two qubits into the spare qubit and uncomputing it afterwards.
+fn apply_controlled_z(simulator: &mut Simulator) -> io::Result<()> {
+    let [q0, q1, q2, q3] = [0, 1, 2, 3].map(QubitRef);
+    let spare = QubitRef(SEARCH_QUBITS);
+    let mut gates = Gate::expand_builtin("ccx", &[], &[q0, q1, spare]).unwrap();
+    gates.push(Gate::H { target: q3 });
+    gates.extend(Gate::expand_builtin("ccx", &[], &[spare, q2, q3]).unwrap());
+    gates.push(Gate::H { target: q3 });
+    gates.extend(Gate::expand_builtin("ccx", &[], &[q0, q1, spare]).unwrap());
+    gates.into_iter().try_for_each(|gate| simulator.apply(gate))
+}
@@ -0,0 +1,62 @@
+use quantum_simulator::gates::gate::Gate;
+use quantum_simulator::qasm::definitions::GateDefinitions;
+use quantum_simulator::qasm::simulator::{Options, Simulator};
+use quantum_simulator::quantum::distribution::{compare_distributions, Distribution};
+use quantum_simulator::quantum::register::QubitRef;
+use quantum_simulator::quantum::sampling::Rng;
+use quantum_simulator::quantum::xeb::sample_bitstrings;
+use std::io;
+
+const NUM_QUBITS: usize = 3;
+const SHOTS: usize = 2000;
+/// The probability of a bit flip on each qubit a gate acts on, after the gate.
+const FLIP_PROBABILITY: f64 = 0.02;
+
+/// Samples a GHZ state under bit flip noise by simulating one noisy trajectory per
+/// shot, and compares the measured distribution with the noiseless one.
+fn main() -> io::Result<()> {
+    let mut gates = vec![Gate::H {
+        target: QubitRef(0),
+    }];
+    for target in 1..NUM_QUBITS {
+        gates.push(Gate::CX {
+            control: QubitRef(target - 1),
+            target: QubitRef(target),
+        });
+    }
+
+    let mut rng = Rng::new(7);
+    let mut noisy = Distribution::new();
+    for _ in 0..SHOTS {
+        let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        simulator.append_qasm(&format!["OPENQASM 2.0;\nqreg q[{NUM_QUBITS}];"])?;
+        for gate in &gates {
+            simulator.apply(gate.clone())?;
+            for target in gate.qubits() {
+                if rng.next_f64() < FLIP_PROBABILITY {
+                    simulator.apply(Gate::X {
+                        target: QubitRef(target),
+                    })?;
+                }
+            }
+        }
+        let state = simulator.finish()?.final_state;
+        let sample = &sample_bitstrings(&state, 1, &mut rng)[0];
+        let outcome: String = sample
+            .iter()
+            .rev()
+            .map(|bit| if *bit { '1' } else { '0' })
+            .collect();
+        *noisy.entry(outcome).or_default() += 1.0 / SHOTS as f64;
+    }
+
+    let ideal = Distribution::from([("000".to_string(), 0.5), ("111".to_string(), 0.5)]);
+    println!("Measured distribution of {SHOTS} noisy shots:");
+    for (outcome, probability) in &noisy {
+        println!("  {outcome}  {probability:.4}");
+    }
+    let distance = compare_distributions(&noisy, &ideal)?;
+    println!("Total variation:    {:.4}", distance.total_variation);
+    println!("Hellinger fidelity: {:.4}", distance.hellinger_fidelity);
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+use std::io;
+
+/// A value in a configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(value) => write!(f, "{value}"),
+            Value::Integer(value) => write!(f, "{value}"),
+            Value::Float(value) => write!(f, "{value}"),
+            Value::Boolean(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// A single `key = value` setting along with the line it is on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/// Parses the settings in a configuration file, in the order they appear.
+///
+/// Configuration files use the subset of TOML needed for flat settings: `key = value`
+/// pairs with string, integer, float and boolean values, and `#` comments. Tables and
+/// arrays are not supported.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::config::{parse_config, Value};
+///
+/// let source = "# Shared settings\nbackend = \"dense\"\nthreads = 4 # per job\n";
+/// let settings = parse_config(source).unwrap();
+/// assert_eq!(settings[0].key, "backend");
+/// assert_eq!(settings[0].value, Value::String("dense".to_string()));
+/// assert_eq!(settings[1].value, Value::Integer(4));
+/// ```
+pub fn parse_config(source: &str) -> io::Result<Vec<Setting>> {
+    let mut settings: Vec<Setting> = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["{message} on line {line_number}"],
+            )
+        };
+
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(invalid("Tables are not supported"));
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(invalid("Expected 'key = value'"));
+        };
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(invalid(&format!["Invalid key '{key}'"]));
+        }
+        if settings.iter().any(|setting| setting.key == key) {
+            return Err(invalid(&format!["Duplicate key '{key}'"]));
+        }
+        let value = parse_value(value.trim())
+            .ok_or_else(|| invalid(&format!["Invalid value for '{key}'"]))?;
+        settings.push(Setting {
+            key: key.to_string(),
+            value,
+            line: line_number,
+        });
+    }
+    Ok(settings)
+}
+
+/// Removes a `#` comment from the end of a line, ignoring any inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        return parse_string(quoted.strip_suffix('"')?).map(Value::String);
+    }
+    match value {
+        "true" => return Some(Value::Boolean(true)),
+        "false" => return Some(Value::Boolean(false)),
+        _ => {}
+    }
+    // TOML allows underscores between digits.
+    let number = value.replace('_', "");
+    if let Ok(integer) = number.parse() {
+        return Some(Value::Integer(integer));
+    }
+    if !number.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+') {
+        return None;
+    }
+    number.parse().ok().map(Value::Float)
+}
+
+/// Parses the contents of a basic string, resolving its escape sequences.
+fn parse_string(contents: &str) -> Option<String> {
+    let mut string = String::new();
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return None,
+            '\\' => string.push(match chars.next()? {
+                '"' => '"',
+                '\\' => '\\',
+                'n' => '\n',
+                't' => '\t',
+                _ => return None,
+            }),
+            c => string.push(c),
+        }
+    }
+    Some(string)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Tests parsing each kind of value.
+    #[test]
+    fn test_parse_values() {
+        let source = r#"
+            dir = "C:\\tmp # not a comment"
+            chunk-size = 16_384
+            tolerance = 1e-9
+            fuse = false
+        "#;
+        let values: Vec<Value> = parse_config(source)
+            .unwrap()
+            .into_iter()
+            .map(|setting| setting.value)
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                Value::String("C:\\tmp # not a comment".to_string()),
+                Value::Integer(16384),
+                Value::Float(1e-9),
+                Value::Boolean(false),
+            ]
+        );
+    }
+
+    /// Tests that unsupported syntax is reported with its line.
+    #[test]
+    fn test_parse_errors() {
+        for (source, message) in [
+            ("[simulator]", "Tables are not supported on line 1"),
+            ("\nbackend", "Expected 'key = value' on line 2"),
+            ("a b = 1", "Invalid key 'a b' on line 1"),
+            (
+                "threads = 1\nthreads = 2",
+                "Duplicate key 'threads' on line 2",
+            ),
+            ("backend = dense", "Invalid value for 'backend' on line 1"),
+            ("paths = [\"a\"]", "Invalid value for 'paths' on line 1"),
+            ("name = \"a\"b\"", "Invalid value for 'name' on line 1"),
+        ] {
+            assert_eq!(parse_config(source).unwrap_err().to_string(), message);
+        }
+    }
+}
@@ -1 +1,13 @@
+pub mod analysis;
+pub mod clifford;
+pub mod diff;
+pub mod exact;
+pub mod fusion;
 pub mod gate;
+pub mod generators;
+pub mod kernels;
+pub mod lightcone;
+pub mod origin;
+pub mod parallel;
+pub mod peephole;
+pub mod phase_polynomial;
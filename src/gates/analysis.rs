@@ -0,0 +1,310 @@
+use crate::gates::gate::Gate;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How a single qubit is used by a circuit, measured in layers of the circuit where
+/// each gate is placed in the first layer after the previous gates on its qubits.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QubitUsage {
+    /// The number of gates acting on the qubit.
+    pub gates: usize,
+    /// The layer of the first gate on the qubit, if there is one.
+    pub first_use: Option<usize>,
+    /// The layer of the last gate on the qubit, if there is one.
+    pub last_use: Option<usize>,
+    /// The largest number of layers between two consecutive gates on the qubit.
+    pub longest_idle: usize,
+}
+
+/// Counts of a circuit's gates by how hard they are to simulate and how they use each
+/// qubit, built up one gate at a time.
+///
+/// The T-count is the number of T-like gates (see [`Gate::is_t_like`]) and the T-depth
+/// is the largest number of them on any path through the circuit, with multi-qubit
+/// gates joining the paths of their qubits.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::analysis::CircuitAnalysis;
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let mut analysis = CircuitAnalysis::new(2);
+/// analysis.push(&Gate::H { target: QubitRef(0) });
+/// analysis.push(&Gate::T { target: QubitRef(0) });
+/// analysis.push(&Gate::T { target: QubitRef(1) });
+/// analysis.push(&Gate::CX { control: QubitRef(0), target: QubitRef(1) });
+/// analysis.push(&Gate::T { target: QubitRef(1) });
+///
+/// assert_eq!(analysis.t_count, 3);
+/// assert_eq!(analysis.t_depth(), 2);
+/// assert!(!analysis.is_stabilizer());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitAnalysis {
+    /// The total number of gates.
+    pub gates: usize,
+    /// The number of Clifford gates of each kind.
+    pub clifford: BTreeMap<String, usize>,
+    /// The number of non-Clifford gates of each kind.
+    pub non_clifford: BTreeMap<String, usize>,
+    /// The number of T-like gates.
+    pub t_count: usize,
+    /// How each qubit is used.
+    pub qubits: Vec<QubitUsage>,
+    /// The T-depth of the circuit up to each qubit.
+    t_depths: Vec<usize>,
+}
+
+impl CircuitAnalysis {
+    /// Creates an empty analysis for a register of `num_qubits` qubits.
+    pub fn new(num_qubits: usize) -> Self {
+        Self {
+            gates: 0,
+            clifford: BTreeMap::new(),
+            non_clifford: BTreeMap::new(),
+            t_count: 0,
+            qubits: vec![QubitUsage::default(); num_qubits],
+            t_depths: vec![0; num_qubits],
+        }
+    }
+
+    /// Adds the next gate of the circuit.
+    pub fn push(&mut self, gate: &Gate) {
+        self.gates += 1;
+        let counts = match gate.is_clifford() {
+            true => &mut self.clifford,
+            false => &mut self.non_clifford,
+        };
+        *counts.entry(gate.name().to_string()).or_default() += 1;
+
+        let qubits = gate.qubits();
+        let depth = qubits
+            .iter()
+            .map(|qubit| self.t_depths[*qubit])
+            .max()
+            .unwrap_or(0)
+            + gate.is_t_like() as usize;
+        self.t_count += gate.is_t_like() as usize;
+        for qubit in &qubits {
+            self.t_depths[*qubit] = depth;
+        }
+
+        // Place the gate in the layer after the last gate on any of its qubits.
+        let next_layer = |usage: &QubitUsage| usage.last_use.map_or(0, |layer| layer + 1);
+        let layer = qubits
+            .iter()
+            .map(|qubit| next_layer(&self.qubits[*qubit]))
+            .max()
+            .unwrap_or(0);
+        for qubit in qubits {
+            let usage = &mut self.qubits[qubit];
+            if usage.last_use.is_some() {
+                usage.longest_idle = usage.longest_idle.max(layer - next_layer(usage));
+            }
+            usage.gates += 1;
+            usage.first_use.get_or_insert(layer);
+            usage.last_use = Some(layer);
+        }
+    }
+
+    /// Returns the number of layers in the circuit so far.
+    pub fn depth(&self) -> usize {
+        self.qubits
+            .iter()
+            .filter_map(|usage| usage.last_use)
+            .max()
+            .map_or(0, |layer| layer + 1)
+    }
+
+    /// Returns the qubits that no gate acts on.
+    pub fn unused_qubits(&self) -> Vec<usize> {
+        (0..self.qubits.len())
+            .filter(|qubit| self.qubits[*qubit].gates == 0)
+            .collect()
+    }
+
+    /// Returns the T-depth of the circuit so far.
+    pub fn t_depth(&self) -> usize {
+        self.t_depths.iter().copied().max().unwrap_or(0)
+    }
+
+    /// Returns the number of Clifford gates.
+    pub fn clifford_count(&self) -> usize {
+        self.clifford.values().sum()
+    }
+
+    /// Returns whether every gate is a Clifford gate, so that the circuit could be
+    /// simulated efficiently with a stabilizer simulator.
+    pub fn is_stabilizer(&self) -> bool {
+        self.non_clifford.is_empty()
+    }
+}
+
+impl fmt::Display for CircuitAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let by_name = |counts: &BTreeMap<String, usize>| {
+            let counts: Vec<String> = counts
+                .iter()
+                .map(|(name, count)| format!["{name} {count}"])
+                .collect();
+            counts.join(", ")
+        };
+        writeln!(f, "Gates:            {}", self.gates)?;
+        writeln!(
+            f,
+            "Clifford gates:   {} ({})",
+            self.clifford_count(),
+            by_name(&self.clifford)
+        )?;
+        writeln!(
+            f,
+            "Non-Clifford:     {} ({})",
+            self.gates - self.clifford_count(),
+            by_name(&self.non_clifford)
+        )?;
+        writeln!(f, "T-count:          {}", self.t_count)?;
+        writeln!(f, "T-depth:          {}", self.t_depth())?;
+        writeln!(
+            f,
+            "Stabilizer:       {}",
+            if self.is_stabilizer() { "yes" } else { "no" }
+        )?;
+        writeln!(f, "Depth:            {}", self.depth())?;
+
+        writeln!(f, "\nQubit  Gates  First  Last  Longest idle")?;
+        let layer = |layer: Option<usize>| layer.map_or("-".to_string(), |layer| layer.to_string());
+        for (qubit, usage) in self.qubits.iter().enumerate() {
+            writeln!(
+                f,
+                "{qubit:>5}  {:>5}  {:>5}  {:>4}  {:>12}",
+                usage.gates,
+                layer(usage.first_use),
+                layer(usage.last_use),
+                usage.longest_idle
+            )?;
+        }
+        let unused = self.unused_qubits();
+        if unused.is_empty() {
+            write!(f, "All qubits are used")
+        } else {
+            write!(f, "Unused qubits: {unused:?}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::register::QubitRef;
+    use std::f64::consts::PI;
+
+    /// Tests the classification of each kind of gate.
+    #[test]
+    fn test_gate_classification() {
+        let mut analysis = CircuitAnalysis::new(2);
+        for gate in [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::X {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::RZ {
+                target: QubitRef(0),
+                theta: -PI,
+            },
+            Gate::RZ {
+                target: QubitRef(1),
+                theta: 0.3,
+            },
+            Gate::TDgr {
+                target: QubitRef(1),
+            },
+        ] {
+            analysis.push(&gate);
+        }
+
+        assert_eq!(analysis.gates, 6);
+        assert_eq!(analysis.clifford_count(), 4);
+        assert_eq!(analysis.non_clifford.get("rz"), Some(&1));
+        assert_eq!(analysis.non_clifford.get("tdg"), Some(&1));
+        assert_eq!(analysis.t_count, 1);
+        assert!(!analysis.is_stabilizer());
+    }
+
+    /// Tests the per-qubit usage, including idle stretches and unused qubits.
+    #[test]
+    fn test_qubit_usage() {
+        let mut analysis = CircuitAnalysis::new(4);
+        for gate in [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::X {
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(3),
+            },
+        ] {
+            analysis.push(&gate);
+        }
+
+        assert_eq!(analysis.depth(), 4);
+        assert_eq!(
+            analysis.qubits[0],
+            QubitUsage {
+                gates: 2,
+                first_use: Some(0),
+                last_use: Some(3),
+                longest_idle: 2,
+            }
+        );
+        assert_eq!(analysis.qubits[1].longest_idle, 0);
+        assert_eq!(analysis.qubits[2], QubitUsage::default());
+        assert_eq!(analysis.qubits[3].first_use, Some(0));
+        assert_eq!(analysis.unused_qubits(), vec![2]);
+    }
+
+    /// Tests that T gates on separate qubits share a layer until a two qubit gate joins
+    /// them.
+    #[test]
+    fn test_t_depth() {
+        let mut analysis = CircuitAnalysis::new(3);
+        for target in 0..3 {
+            analysis.push(&Gate::T {
+                target: QubitRef(target),
+            });
+        }
+        assert_eq!(analysis.t_depth(), 1);
+
+        analysis.push(&Gate::T {
+            target: QubitRef(2),
+        });
+        analysis.push(&Gate::CX {
+            control: QubitRef(2),
+            target: QubitRef(0),
+        });
+        analysis.push(&Gate::T {
+            target: QubitRef(0),
+        });
+        assert_eq!(analysis.t_count, 5);
+        assert_eq!(analysis.t_depth(), 3);
+    }
+}
@@ -0,0 +1,366 @@
+use crate::gates::gate::Gate;
+use crate::gates::kernels::{adjoint, multiply, Matrix2};
+use crate::quantum::observable::Pauli;
+use crate::quantum::tolerance::Tolerance;
+use num::Complex;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+/// How close an angle or overlap must be to that of a Clifford gate to be treated as one.
+const CLIFFORD_TOLERANCE: Tolerance = Tolerance::absolute(1e-9);
+
+/// The images of X, Y and Z under conjugation `U† P U` by a single qubit Clifford gate,
+/// each a Pauli operator with a sign.
+pub type PauliTable = [(Pauli, f64); 3];
+
+/// Conjugation by H, which swaps X and Z.
+pub const H_TABLE: PauliTable = [(Pauli::Z, 1.0), (Pauli::Y, -1.0), (Pauli::X, 1.0)];
+
+/// Conjugation by X, which flips the signs of Y and Z.
+pub const X_TABLE: PauliTable = [(Pauli::X, 1.0), (Pauli::Y, -1.0), (Pauli::Z, -1.0)];
+
+/// Conjugation by the identity, such as `RZ(0)`.
+pub const IDENTITY_TABLE: PauliTable = [(Pauli::X, 1.0), (Pauli::Y, 1.0), (Pauli::Z, 1.0)];
+
+/// Conjugation by S, which is `RZ(pi / 2)` up to a global phase.
+pub const S_TABLE: PauliTable = [(Pauli::Y, -1.0), (Pauli::X, 1.0), (Pauli::Z, 1.0)];
+
+/// Conjugation by Z, which is `RZ(pi)` up to a global phase.
+pub const Z_TABLE: PauliTable = [(Pauli::X, -1.0), (Pauli::Y, -1.0), (Pauli::Z, 1.0)];
+
+/// Conjugation by S†, which is `RZ(-pi / 2)` up to a global phase.
+pub const S_DAGGER_TABLE: PauliTable = [(Pauli::Y, 1.0), (Pauli::X, -1.0), (Pauli::Z, 1.0)];
+
+/// The image of the Paulis on the control and target of a two qubit gate, with `None`
+/// for the identity, and its sign.
+pub type TwoQubitImage = (Option<Pauli>, Option<Pauli>, f64);
+
+/// Conjugation by CX, indexed by the Paulis on the control and the target in the order
+/// I, X, Y, Z. X spreads from the control to the target and Z from the target to the
+/// control.
+pub const CX_TABLE: [[TwoQubitImage; 4]; 4] = {
+    const I: Option<Pauli> = None;
+    const X: Option<Pauli> = Some(Pauli::X);
+    const Y: Option<Pauli> = Some(Pauli::Y);
+    const Z: Option<Pauli> = Some(Pauli::Z);
+    [
+        [(I, I, 1.0), (I, X, 1.0), (Z, Y, 1.0), (Z, Z, 1.0)],
+        [(X, X, 1.0), (X, I, 1.0), (Y, Z, 1.0), (Y, Y, -1.0)],
+        [(Y, X, 1.0), (Y, I, 1.0), (X, Z, -1.0), (X, Y, 1.0)],
+        [(Z, I, 1.0), (Z, X, 1.0), (I, Y, 1.0), (I, Z, 1.0)],
+    ]
+};
+
+/// How a Clifford gate maps Pauli strings onto Pauli strings under conjugation, shared by
+/// everything that tracks Pauli operators through a circuit.
+///
+/// The conjugation is `G† P G`, which is how an observable is propagated backwards in
+/// the Heisenberg picture. `G P G†`, as needed to update stabilizers as a state evolves,
+/// is conjugation by [`Gate::inverse`].
+///
+/// # Examples
+/// ```
+/// use std::collections::BTreeMap;
+/// use quantum_simulator::gates::clifford::Conjugation;
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::observable::Pauli;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let cx = Gate::CX { control: QubitRef(0), target: QubitRef(1) };
+/// let conjugation = Conjugation::of(&cx).unwrap();
+/// let mut paulis = BTreeMap::from([(0, Pauli::Y), (1, Pauli::Y)]);
+/// assert_eq!(conjugation.apply(&mut paulis), -1.0);
+/// assert_eq!(paulis, BTreeMap::from([(0, Pauli::X), (1, Pauli::Z)]));
+///
+/// assert!(Conjugation::of(&Gate::T { target: QubitRef(0) }).is_none());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conjugation {
+    SingleQubit { target: usize, table: PauliTable },
+    CX { control: usize, target: usize },
+}
+
+impl Conjugation {
+    /// Returns the conjugation by a gate, or `None` if it is not a Clifford gate.
+    pub fn of(gate: &Gate) -> Option<Conjugation> {
+        let table = match gate {
+            Gate::CX { control, target } => {
+                return Some(Conjugation::CX {
+                    control: control.index(),
+                    target: target.index(),
+                })
+            }
+            Gate::H { .. } => H_TABLE,
+            Gate::X { .. } => X_TABLE,
+            Gate::T { .. } | Gate::TDgr { .. } => return None,
+            Gate::RZ { theta, .. } => {
+                let quarter_turns = theta / (PI / 2.0);
+                if !CLIFFORD_TOLERANCE.approx_eq(quarter_turns, quarter_turns.round()) {
+                    return None;
+                }
+                [IDENTITY_TABLE, S_TABLE, Z_TABLE, S_DAGGER_TABLE]
+                    [(quarter_turns.round() as i64).rem_euclid(4) as usize]
+            }
+            Gate::Unitary { matrix, .. } => matrix_table(matrix)?,
+        };
+        Some(Conjugation::SingleQubit {
+            target: gate.qubits()[0],
+            table,
+        })
+    }
+
+    /// Replaces a Pauli string with its image under the conjugation, returning the sign
+    /// of the image.
+    pub fn apply(&self, paulis: &mut BTreeMap<usize, Pauli>) -> f64 {
+        match self {
+            Conjugation::SingleQubit { target, table } => match paulis.get_mut(target) {
+                Some(pauli) => {
+                    let (image, sign) = table[pauli_index(*pauli)];
+                    *pauli = image;
+                    sign
+                }
+                None => 1.0,
+            },
+            Conjugation::CX { control, target } => {
+                let index =
+                    |pauli: Option<&Pauli>| pauli.map_or(0, |pauli| pauli_index(*pauli) + 1);
+                let (control_image, target_image, sign) =
+                    CX_TABLE[index(paulis.get(control))][index(paulis.get(target))];
+                for (qubit, image) in [(*control, control_image), (*target, target_image)] {
+                    match image {
+                        Some(image) => paulis.insert(qubit, image),
+                        None => paulis.remove(&qubit),
+                    };
+                }
+                sign
+            }
+        }
+    }
+}
+
+/// Returns the matrix of a Pauli operator.
+pub(crate) fn pauli_matrix(pauli: Pauli) -> Matrix2 {
+    let zero = Complex::new(0.0, 0.0);
+    let one = Complex::new(1.0, 0.0);
+    let i = Complex::new(0.0, 1.0);
+    match pauli {
+        Pauli::X => [[zero, one], [one, zero]],
+        Pauli::Y => [[zero, -i], [i, zero]],
+        Pauli::Z => [[one, zero], [zero, -one]],
+    }
+}
+
+/// Returns the position of a Pauli operator in the order X, Y, Z.
+pub(crate) fn pauli_index(pauli: Pauli) -> usize {
+    match pauli {
+        Pauli::X => 0,
+        Pauli::Y => 1,
+        Pauli::Z => 2,
+    }
+}
+
+/// Returns the conjugation table of a single qubit gate from its matrix, or `None` if it
+/// does not map each Pauli operator onto another one.
+fn matrix_table(matrix: &Matrix2) -> Option<PauliTable> {
+    let paulis = [Pauli::X, Pauli::Y, Pauli::Z];
+    let mut table = IDENTITY_TABLE;
+    for (entry, pauli) in table.iter_mut().zip(paulis) {
+        let conjugated = multiply(&multiply(&adjoint(matrix), &pauli_matrix(pauli)), matrix);
+        // The image is the Pauli operator whose overlap `Tr(Q U† P U) / 2` is ±1.
+        *entry = paulis.iter().find_map(|other| {
+            let product = multiply(&pauli_matrix(*other), &conjugated);
+            let overlap = (product[0][0] + product[1][1]).re / 2.0;
+            CLIFFORD_TOLERANCE
+                .approx_eq(overlap.abs(), 1.0)
+                .then_some((*other, overlap.signum()))
+        })?;
+    }
+    Some(table)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::kernels::Matrix4;
+    use crate::quantum::register::QubitRef;
+
+    /// Returns the matrix of a Pauli string on two qubits, indexed by `low + 2 * high`.
+    fn pauli_string_matrix(paulis: &BTreeMap<usize, Pauli>) -> Matrix4 {
+        let identity = [
+            [Complex::new(1.0, 0.0), Complex::new(0.0, 0.0)],
+            [Complex::new(0.0, 0.0), Complex::new(1.0, 0.0)],
+        ];
+        let factor = |qubit| {
+            paulis
+                .get(&qubit)
+                .map_or(identity, |pauli| pauli_matrix(*pauli))
+        };
+        kron(&factor(1), &factor(0))
+    }
+
+    /// Returns `high ⊗ low` as a matrix indexed by `low + 2 * high`.
+    fn kron(high: &Matrix2, low: &Matrix2) -> Matrix4 {
+        let mut product = [[Complex::new(0.0, 0.0); 4]; 4];
+        for (row, values) in product.iter_mut().enumerate() {
+            for (column, value) in values.iter_mut().enumerate() {
+                *value = high[row / 2][column / 2] * low[row % 2][column % 2];
+            }
+        }
+        product
+    }
+
+    fn multiply4(lhs: &Matrix4, rhs: &Matrix4) -> Matrix4 {
+        let mut product = [[Complex::new(0.0, 0.0); 4]; 4];
+        for (row, values) in product.iter_mut().enumerate() {
+            for (column, value) in values.iter_mut().enumerate() {
+                *value = (0..4).map(|k| lhs[row][k] * rhs[k][column]).sum();
+            }
+        }
+        product
+    }
+
+    fn adjoint4(matrix: &Matrix4) -> Matrix4 {
+        let mut adjoint = [[Complex::new(0.0, 0.0); 4]; 4];
+        for (row, values) in adjoint.iter_mut().enumerate() {
+            for (column, value) in values.iter_mut().enumerate() {
+                *value = matrix[column][row].conj();
+            }
+        }
+        adjoint
+    }
+
+    /// Returns the matrix of a gate on two qubits.
+    fn gate_matrix(gate: &Gate) -> Matrix4 {
+        let identity = [
+            [Complex::new(1.0, 0.0), Complex::new(0.0, 0.0)],
+            [Complex::new(0.0, 0.0), Complex::new(1.0, 0.0)],
+        ];
+        match gate {
+            Gate::CX { control, target } => {
+                let mut matrix = [[Complex::new(0.0, 0.0); 4]; 4];
+                // CX permutes the basis states and is its own inverse, so each row has a
+                // one in the column of its own image.
+                for (row, values) in matrix.iter_mut().enumerate() {
+                    let column = match row >> control.index() & 1 {
+                        1 => row ^ (1 << target.index()),
+                        _ => row,
+                    };
+                    values[column] = Complex::new(1.0, 0.0);
+                }
+                matrix
+            }
+            _ => {
+                let single = gate.single_qubit_matrix().unwrap();
+                match gate.qubits()[0] {
+                    0 => kron(&identity, &single),
+                    _ => kron(&single, &identity),
+                }
+            }
+        }
+    }
+
+    /// Tests every Clifford conjugation against conjugating the matrices, for every Pauli
+    /// string on two qubits.
+    #[test]
+    fn test_against_matrices() {
+        let fused = multiply(
+            &Gate::H {
+                target: QubitRef(0),
+            }
+            .single_qubit_matrix()
+            .unwrap(),
+            &Gate::RZ {
+                target: QubitRef(0),
+                theta: PI / 2.0,
+            }
+            .single_qubit_matrix()
+            .unwrap(),
+        );
+        let mut gates = vec![
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::X {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(0),
+            },
+            Gate::Unitary {
+                target: QubitRef(1),
+                matrix: fused,
+            },
+        ];
+        for quarter_turns in -4..=4 {
+            gates.push(Gate::RZ {
+                target: QubitRef(0),
+                theta: quarter_turns as f64 * PI / 2.0,
+            });
+        }
+
+        let paulis = [None, Some(Pauli::X), Some(Pauli::Y), Some(Pauli::Z)];
+        for gate in &gates {
+            let conjugation = Conjugation::of(gate).unwrap();
+            let matrix = gate_matrix(gate);
+            for (low, high) in paulis
+                .iter()
+                .flat_map(|low| paulis.iter().map(move |high| (low, high)))
+            {
+                let mut string = BTreeMap::new();
+                for (qubit, pauli) in [(0, low), (1, high)] {
+                    if let Some(pauli) = pauli {
+                        string.insert(qubit, *pauli);
+                    }
+                }
+                let expected = multiply4(
+                    &multiply4(&adjoint4(&matrix), &pauli_string_matrix(&string)),
+                    &matrix,
+                );
+                let original = string.clone();
+                let sign = conjugation.apply(&mut string);
+                let image = pauli_string_matrix(&string);
+                for (expected_row, row) in expected.iter().zip(&image) {
+                    for (expected, entry) in expected_row.iter().zip(row) {
+                        assert!(
+                            (expected - entry * sign).norm() < 1e-12,
+                            "{gate:?} maps {original:?} to {string:?} with sign {sign}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tests that non-Clifford gates have no conjugation table.
+    #[test]
+    fn test_non_clifford() {
+        for gate in [
+            Gate::T {
+                target: QubitRef(0),
+            },
+            Gate::TDgr {
+                target: QubitRef(0),
+            },
+            Gate::RZ {
+                target: QubitRef(0),
+                theta: 0.1,
+            },
+            Gate::Unitary {
+                target: QubitRef(0),
+                matrix: Gate::T {
+                    target: QubitRef(0),
+                }
+                .single_qubit_matrix()
+                .unwrap(),
+            },
+        ] {
+            assert!(Conjugation::of(&gate).is_none(), "{gate:?}");
+        }
+    }
+}
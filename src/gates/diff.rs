@@ -0,0 +1,348 @@
+use crate::gates::gate::Gate;
+use std::io;
+
+/// The most cells the table aligning the differing middles of two circuits can have.
+pub const MAX_DIFF_CELLS: usize = 1 << 26;
+
+/// A difference between two circuits, with the layer of each gate in its own circuit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GateDiff {
+    /// A gate of the first circuit that is not in the second.
+    Removed { layer: usize, gate: Gate },
+    /// A gate of the second circuit that is not in the first.
+    Inserted { layer: usize, gate: Gate },
+    /// A gate of the first circuit replaced by a different gate on the same qubits.
+    Changed {
+        from_layer: usize,
+        from: Gate,
+        to_layer: usize,
+        to: Gate,
+    },
+}
+
+impl GateDiff {
+    /// Returns the qubits of the gates of this difference.
+    pub fn qubits(&self) -> Vec<usize> {
+        match self {
+            GateDiff::Removed { gate, .. } | GateDiff::Inserted { gate, .. } => gate.qubits(),
+            GateDiff::Changed { from, .. } => from.qubits(),
+        }
+    }
+
+    /// Returns whether this and another difference are a removed and an inserted gate on
+    /// the same qubits.
+    fn pairs_with(&self, other: &GateDiff) -> bool {
+        matches!(
+            (self, other),
+            (GateDiff::Removed { .. }, GateDiff::Inserted { .. })
+                | (GateDiff::Inserted { .. }, GateDiff::Removed { .. })
+        ) && self.qubits() == other.qubits()
+    }
+
+    /// Returns the change from the removed gate of this and another difference to the
+    /// inserted one.
+    fn paired(self, other: GateDiff) -> GateDiff {
+        match (self, other) {
+            (
+                GateDiff::Removed { layer, gate },
+                GateDiff::Inserted {
+                    layer: to_layer,
+                    gate: to,
+                },
+            )
+            | (
+                GateDiff::Inserted {
+                    layer: to_layer,
+                    gate: to,
+                },
+                GateDiff::Removed { layer, gate },
+            ) => GateDiff::Changed {
+                from_layer: layer,
+                from: gate,
+                to_layer,
+                to,
+            },
+            _ => unreachable!("Only a removed and an inserted gate can be paired"),
+        }
+    }
+}
+
+/// Returns the gates of a circuit in layers, where each gate is placed in the first
+/// layer after the previous gates on its qubits, ordered by layer and then by qubit.
+///
+/// Gates on different qubits can be written in either order, and this order is the same
+/// for both, so that circuits that only differ in how their gates are interleaved have
+/// the same layered gates.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::diff::layered;
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let gates = [
+///     Gate::T { target: QubitRef(1) },
+///     Gate::H { target: QubitRef(0) },
+///     Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+/// ];
+/// let layers: Vec<usize> = layered(&gates).iter().map(|(layer, _)| *layer).collect();
+/// assert_eq!(layers, vec![0, 0, 1]);
+/// assert_eq!(layered(&gates)[0].1, Gate::H { target: QubitRef(0) });
+/// ```
+pub fn layered(gates: &[Gate]) -> Vec<(usize, Gate)> {
+    let mut next_layers: Vec<usize> = Vec::new();
+    let mut layered: Vec<(usize, Gate)> = gates
+        .iter()
+        .map(|gate| {
+            let qubits = gate.qubits();
+            if let Some(max) = qubits.iter().max() {
+                next_layers.resize(next_layers.len().max(max + 1), 0);
+            }
+            let layer = qubits
+                .iter()
+                .map(|qubit| next_layers[*qubit])
+                .max()
+                .unwrap_or(0);
+            qubits
+                .iter()
+                .for_each(|qubit| next_layers[*qubit] = layer + 1);
+            (layer, gate.clone())
+        })
+        .collect();
+    // The sort is stable, and gates in one layer act on different qubits.
+    layered.sort_by_key(|(layer, gate)| (*layer, gate.qubits().into_iter().min()));
+    layered
+}
+
+/// Aligns the layered gates (see [`layered`]) of two circuits with a longest common
+/// subsequence and returns the gates that differ, in the order of the alignment.
+///
+/// A removed and an inserted gate on the same qubits, with no gate that is in both
+/// circuits between them on those qubits, are reported as a change. Gates are only the
+/// same if they are equal, including their angles. Returns an error if the parts of the circuits left after their common start
+/// and end are too large to align, with more than [`MAX_DIFF_CELLS`] pairs of gates.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::diff::{diff_circuits, GateDiff};
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let [q0, q1] = [0, 1].map(QubitRef);
+/// let first = [Gate::H { target: q0 }, Gate::T { target: q0 }, Gate::X { target: q1 }];
+/// let second = [Gate::X { target: q1 }, Gate::H { target: q0 }, Gate::TDgr { target: q0 }];
+/// let diff = diff_circuits(&first, &second).unwrap();
+/// assert_eq!(
+///     diff,
+///     vec![GateDiff::Changed {
+///         from_layer: 1,
+///         from: Gate::T { target: QubitRef(0) },
+///         to_layer: 1,
+///         to: Gate::TDgr { target: QubitRef(0) },
+///     }]
+/// );
+/// ```
+pub fn diff_circuits(first: &[Gate], second: &[Gate]) -> io::Result<Vec<GateDiff>> {
+    let (first, second) = (layered(first), layered(second));
+    let same = |a: &(usize, Gate), b: &(usize, Gate)| a.1 == b.1;
+    let prefix = first
+        .iter()
+        .zip(&second)
+        .take_while(|(a, b)| same(a, b))
+        .count();
+    let suffix = first[prefix..]
+        .iter()
+        .rev()
+        .zip(second[prefix..].iter().rev())
+        .take_while(|(a, b)| same(a, b))
+        .count();
+    let first = &first[prefix..first.len() - suffix];
+    let second = &second[prefix..second.len() - suffix];
+    if first.len().saturating_mul(second.len()) > MAX_DIFF_CELLS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format![
+                "The circuits differ in {} and {} gates, which is too many to align",
+                first.len(),
+                second.len()
+            ],
+        ));
+    }
+
+    // lengths[i][j] is the length of the longest common subsequence of first[i..] and
+    // second[j..].
+    let width = second.len() + 1;
+    let mut lengths = vec![0u32; (first.len() + 1) * width];
+    for i in (0..first.len()).rev() {
+        for j in (0..second.len()).rev() {
+            lengths[i * width + j] = match same(&first[i], &second[j]) {
+                true => lengths[(i + 1) * width + j + 1] + 1,
+                false => lengths[(i + 1) * width + j].max(lengths[i * width + j + 1]),
+            };
+        }
+    }
+
+    let mut diff: Vec<GateDiff> = Vec::new();
+    // The removed and inserted gates that can still be paired as a change, by their
+    // index in the diff. Gates that are the same in both circuits close them on their
+    // qubits, since a change only pairs gates at the same point of each qubit's timeline.
+    let mut open: Vec<usize> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < first.len() || j < second.len() {
+        if i < first.len() && j < second.len() && same(&first[i], &second[j]) {
+            let qubits = first[i].1.qubits();
+            open.retain(|index| {
+                !diff[*index]
+                    .qubits()
+                    .iter()
+                    .any(|qubit| qubits.contains(qubit))
+            });
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let change = if j == second.len()
+            || (i < first.len() && lengths[(i + 1) * width + j] >= lengths[i * width + j + 1])
+        {
+            i += 1;
+            GateDiff::Removed {
+                layer: first[i - 1].0,
+                gate: first[i - 1].1.clone(),
+            }
+        } else {
+            j += 1;
+            GateDiff::Inserted {
+                layer: second[j - 1].0,
+                gate: second[j - 1].1.clone(),
+            }
+        };
+        let pair = open
+            .iter()
+            .position(|index| diff[*index].pairs_with(&change));
+        match pair {
+            Some(position) => {
+                let index = open.remove(position);
+                diff[index] = diff[index].clone().paired(change);
+            }
+            None => {
+                open.push(diff.len());
+                diff.push(change);
+            }
+        }
+    }
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::register::QubitRef;
+
+    /// Tests that circuits which only interleave their gates differently have no diff.
+    #[test]
+    fn test_reordered_circuits_are_same() {
+        let first = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(2),
+            },
+        ];
+        let second = [
+            Gate::T {
+                target: QubitRef(2),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+        ];
+        assert_eq!(diff_circuits(&first, &second).unwrap(), vec![]);
+    }
+
+    /// Tests removed, inserted and changed gates in the middle of otherwise equal
+    /// circuits.
+    #[test]
+    fn test_diff() {
+        let cx = Gate::CX {
+            control: QubitRef(0),
+            target: QubitRef(1),
+        };
+        let first = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            cx.clone(),
+            cx.clone(),
+            Gate::RZ {
+                target: QubitRef(1),
+                theta: 0.5,
+            },
+            Gate::X {
+                target: QubitRef(0),
+            },
+        ];
+        // The CX pair cancels, the angle changes and a T gate is added after the X.
+        let second = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::RZ {
+                target: QubitRef(1),
+                theta: 0.25,
+            },
+            Gate::X {
+                target: QubitRef(0),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+        ];
+        assert_eq!(
+            diff_circuits(&first, &second).unwrap(),
+            vec![
+                GateDiff::Removed {
+                    layer: 1,
+                    gate: cx.clone(),
+                },
+                GateDiff::Removed {
+                    layer: 2,
+                    gate: cx.clone(),
+                },
+                GateDiff::Changed {
+                    from_layer: 3,
+                    from: Gate::RZ {
+                        target: QubitRef(1),
+                        theta: 0.5
+                    },
+                    to_layer: 0,
+                    to: Gate::RZ {
+                        target: QubitRef(1),
+                        theta: 0.25
+                    },
+                },
+                GateDiff::Inserted {
+                    layer: 2,
+                    gate: Gate::T {
+                        target: QubitRef(0)
+                    },
+                },
+            ]
+        );
+    }
+}
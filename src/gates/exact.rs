@@ -0,0 +1,490 @@
+use crate::gates::gate::Gate;
+use num::Complex;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::io;
+
+/// The most qubits a circuit can have to be verified exactly, since each of its basis
+/// states is simulated in turn.
+pub const MAX_EXACT_QUBITS: usize = 12;
+
+/// A number `(a + bω + cω² + dω³) / √2^k` in the ring `Z[1/√2, i]`, where `ω = e^{iπ/4}`,
+/// which holds every amplitude of a circuit of H, T, CX and phase gates exactly.
+///
+/// The exponent `k` is kept as small as possible, so each number has a single
+/// representation and two numbers are equal exactly when they compare equal. The
+/// arithmetic is checked, and returns `None` if a coefficient would overflow.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::exact::ExactAmplitude;
+///
+/// // ω + ω⁷ = √2, and dividing by √2 twice gives 1 / √2.
+/// let omega = ExactAmplitude::ONE.times_omega(1);
+/// let sqrt2 = omega.checked_add(&omega.times_omega(6)).unwrap();
+/// let inverse = sqrt2.divided_by_sqrt2().unwrap().divided_by_sqrt2().unwrap();
+/// assert!((inverse.to_complex().re - 1.0 / 2.0_f64.sqrt()).abs() < 1e-15);
+/// assert_eq!(inverse.checked_add(&inverse).unwrap(), sqrt2);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExactAmplitude {
+    /// The coefficients of 1, ω, ω² and ω³.
+    coefficients: [i128; 4],
+    /// The power of √2 dividing the coefficients.
+    exponent: u32,
+}
+
+impl ExactAmplitude {
+    pub const ZERO: ExactAmplitude = ExactAmplitude {
+        coefficients: [0; 4],
+        exponent: 0,
+    };
+
+    pub const ONE: ExactAmplitude = ExactAmplitude {
+        coefficients: [1, 0, 0, 0],
+        exponent: 0,
+    };
+
+    /// Returns whether this number is zero.
+    pub fn is_zero(&self) -> bool {
+        self.coefficients == [0; 4]
+    }
+
+    /// Returns the negation of this number.
+    pub fn negated(&self) -> ExactAmplitude {
+        self.times_omega(4)
+    }
+
+    /// Returns this number multiplied by `ω^power`.
+    pub fn times_omega(&self, power: u32) -> ExactAmplitude {
+        let mut coefficients = self.coefficients;
+        // ω⁴ = -1, so each power moves the coefficients up one and negates the last.
+        for _ in 0..power % 8 {
+            let [a, b, c, d] = coefficients;
+            coefficients = [-d, a, b, c];
+        }
+        ExactAmplitude {
+            coefficients,
+            exponent: self.exponent,
+        }
+    }
+
+    /// Returns the sum of two numbers.
+    pub fn checked_add(&self, other: &ExactAmplitude) -> Option<ExactAmplitude> {
+        let exponent = self.exponent.max(other.exponent);
+        let (first, second) = (self.scaled_to(exponent)?, other.scaled_to(exponent)?);
+        let mut coefficients = [0; 4];
+        for (sum, (a, b)) in coefficients.iter_mut().zip(first.iter().zip(&second)) {
+            *sum = a.checked_add(*b)?;
+        }
+        ExactAmplitude {
+            coefficients,
+            exponent,
+        }
+        .reduced()
+    }
+
+    /// Returns this number divided by √2.
+    pub fn divided_by_sqrt2(&self) -> Option<ExactAmplitude> {
+        ExactAmplitude {
+            coefficients: self.coefficients,
+            exponent: self.exponent.checked_add(1)?,
+        }
+        .reduced()
+    }
+
+    /// Returns the value of this number as a complex number, which is only approximate.
+    pub fn to_complex(&self) -> Complex<f64> {
+        let scale = 2.0_f64.sqrt().powi(self.exponent as i32);
+        self.coefficients
+            .iter()
+            .enumerate()
+            .map(|(power, coefficient)| {
+                Complex::from_polar(*coefficient as f64, power as f64 * PI / 4.0)
+            })
+            .sum::<Complex<f64>>()
+            / scale
+    }
+
+    /// Returns the coefficients of this number as a fraction with `√2^exponent` below,
+    /// which must be at least the current exponent.
+    fn scaled_to(&self, exponent: u32) -> Option<[i128; 4]> {
+        let mut coefficients = self.coefficients;
+        for _ in self.exponent..exponent {
+            coefficients = times_sqrt2(coefficients)?;
+        }
+        Some(coefficients)
+    }
+
+    /// Returns the same number with the smallest exponent.
+    fn reduced(mut self) -> Option<ExactAmplitude> {
+        if self.is_zero() {
+            return Some(ExactAmplitude::ZERO);
+        }
+        // x / √2 = x √2 / 2, which stays in the ring when every coefficient of x √2 is even.
+        while self.exponent > 0 {
+            let doubled = times_sqrt2(self.coefficients)?;
+            if doubled.iter().any(|coefficient| coefficient % 2 != 0) {
+                break;
+            }
+            self.coefficients = doubled.map(|coefficient| coefficient / 2);
+            self.exponent -= 1;
+        }
+        Some(self)
+    }
+}
+
+/// Multiplies the coefficients of a number by `√2 = ω - ω³`.
+fn times_sqrt2([a, b, c, d]: [i128; 4]) -> Option<[i128; 4]> {
+    Some([
+        b.checked_sub(d)?,
+        a.checked_add(c)?,
+        b.checked_add(d)?,
+        c.checked_sub(a)?,
+    ])
+}
+
+/// The outcome of verifying that two circuits are equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// The circuits are equal up to a global phase.
+    Equal,
+    /// The circuits differ, which was found when both were applied to this basis state.
+    Differs { basis_state: usize },
+}
+
+/// A gate as it acts on exact amplitudes.
+#[derive(Debug, Clone, Copy)]
+enum ExactGate {
+    H {
+        target: usize,
+    },
+    X {
+        target: usize,
+    },
+    /// Multiplies the amplitudes of basis states with the target set by `ω^eighths`.
+    Phase {
+        target: usize,
+        eighths: u32,
+    },
+    CX {
+        control: usize,
+        target: usize,
+    },
+}
+
+impl ExactGate {
+    /// Returns the exact form of a gate, up to a global phase, or `None` if it is not a
+    /// Clifford+T gate.
+    fn of(gate: &Gate) -> Option<ExactGate> {
+        Some(match *gate {
+            Gate::H { target } => ExactGate::H {
+                target: target.index(),
+            },
+            Gate::X { target } => ExactGate::X {
+                target: target.index(),
+            },
+            Gate::CX { control, target } => ExactGate::CX {
+                control: control.index(),
+                target: target.index(),
+            },
+            // RZ(θ) is diag(1, e^{iθ}) up to the global phase e^{-iθ/2}.
+            Gate::T { target } | Gate::TDgr { target } | Gate::RZ { target, .. } => {
+                ExactGate::Phase {
+                    target: target.index(),
+                    eighths: gate.phase_eighths()?,
+                }
+            }
+            Gate::Unitary { .. } => return None,
+        })
+    }
+
+    /// Applies the gate to a state of exact amplitudes, keyed by basis state.
+    fn apply(
+        &self,
+        state: HashMap<usize, ExactAmplitude>,
+    ) -> Option<HashMap<usize, ExactAmplitude>> {
+        let mut new_state: HashMap<usize, ExactAmplitude> = HashMap::with_capacity(state.len());
+        let mut add = |basis_state: usize, amplitude: ExactAmplitude| -> Option<()> {
+            let sum = new_state.entry(basis_state).or_insert(ExactAmplitude::ZERO);
+            *sum = sum.checked_add(&amplitude)?;
+            Some(())
+        };
+        for (basis_state, amplitude) in state {
+            match *self {
+                ExactGate::H { target } => {
+                    let mask = 1 << target;
+                    add(basis_state & !mask, amplitude)?;
+                    match basis_state & mask {
+                        0 => add(basis_state | mask, amplitude)?,
+                        _ => add(basis_state, amplitude.negated())?,
+                    }
+                }
+                ExactGate::X { target } => add(basis_state ^ (1 << target), amplitude)?,
+                ExactGate::Phase { target, eighths } => match basis_state >> target & 1 {
+                    0 => add(basis_state, amplitude)?,
+                    _ => add(basis_state, amplitude.times_omega(eighths))?,
+                },
+                ExactGate::CX { control, target } => match basis_state >> control & 1 {
+                    0 => add(basis_state, amplitude)?,
+                    _ => add(basis_state ^ (1 << target), amplitude)?,
+                },
+            }
+        }
+        new_state.retain(|_, amplitude| !amplitude.is_zero());
+        if let ExactGate::H { .. } = self {
+            for amplitude in new_state.values_mut() {
+                *amplitude = amplitude.divided_by_sqrt2()?;
+            }
+        }
+        Some(new_state)
+    }
+}
+
+/// Proves whether two circuits of H, X, T, T†, CX and `rz` gates with angles that are
+/// multiples of pi / 4 are equal up to a global phase, by simulating them with exact
+/// arithmetic rather than floating point.
+///
+/// The first circuit followed by the inverse of the second is applied to every basis
+/// state, which it must map to itself with the same phase each time. Comparing a
+/// circuit with an empty one proves whether it is the identity.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::exact::{verify_equivalent, Verification};
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// // Eight T gates are the identity, but seven are not.
+/// let gates = vec![Gate::T { target: QubitRef(1) }; 8];
+/// assert_eq!(verify_equivalent(2, &gates, &[]).unwrap(), Verification::Equal);
+/// assert_eq!(
+///     verify_equivalent(2, &gates[1..], &[]).unwrap(),
+///     Verification::Differs { basis_state: 2 }
+/// );
+/// ```
+pub fn verify_equivalent(
+    num_qubits: usize,
+    first: &[Gate],
+    second: &[Gate],
+) -> io::Result<Verification> {
+    if num_qubits > MAX_EXACT_QUBITS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format![
+                "Exact verification supports at most {MAX_EXACT_QUBITS} qubits, not {num_qubits}"
+            ],
+        ));
+    }
+    let gates: Vec<ExactGate> = first
+        .iter()
+        .cloned()
+        .chain(second.iter().rev().map(Gate::inverse))
+        .map(|gate| {
+            if let Some(qubit) = gate.qubits().into_iter().find(|qubit| *qubit >= num_qubits) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!["The circuit acts on qubit {qubit} but has {num_qubits} qubits"],
+                ));
+            }
+            ExactGate::of(&gate).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!["{gate} is not a Clifford+T gate"],
+                )
+            })
+        })
+        .collect::<io::Result<_>>()?;
+
+    let mut phase = None;
+    for basis_state in 0..1 << num_qubits {
+        let mut state = HashMap::from([(basis_state, ExactAmplitude::ONE)]);
+        for gate in &gates {
+            state = gate.apply(state).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The amplitudes grew too large to represent exactly",
+                )
+            })?;
+        }
+        let amplitude = match state.get(&basis_state) {
+            Some(amplitude) if state.len() == 1 => *amplitude,
+            _ => return Ok(Verification::Differs { basis_state }),
+        };
+        if *phase.get_or_insert(amplitude) != amplitude {
+            return Ok(Verification::Differs { basis_state });
+        }
+    }
+    Ok(Verification::Equal)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::generators::{mirror_circuit, randomized_benchmarking};
+    use crate::quantum::register::QubitRef;
+    use crate::quantum::sampling::Rng;
+
+    /// Tests that the exact amplitudes agree with their floating point values through
+    /// sums and divisions, and that equal numbers have one representation.
+    #[test]
+    fn test_amplitude_arithmetic() {
+        let half = ExactAmplitude::ONE
+            .divided_by_sqrt2()
+            .unwrap()
+            .divided_by_sqrt2()
+            .unwrap();
+        assert_eq!(half.coefficients, [1, 0, 0, 0]);
+        assert_eq!(half.exponent, 2);
+        // 1/2 + 1/2 = 1.
+        assert_eq!(half.checked_add(&half).unwrap(), ExactAmplitude::ONE);
+        // ω² = i, and ω + ω³ = i√2.
+        let omega = ExactAmplitude::ONE.times_omega(1);
+        let sum = omega.checked_add(&omega.times_omega(2)).unwrap();
+        assert_eq!(
+            sum.divided_by_sqrt2().unwrap(),
+            ExactAmplitude::ONE.times_omega(2)
+        );
+        assert!((sum.to_complex() - Complex::new(0.0, 2.0_f64.sqrt())).norm() < 1e-15);
+        assert!(omega.checked_add(&omega.negated()).unwrap().is_zero());
+    }
+
+    /// Tests that circuits followed by their inverse are proven to be the identity, and
+    /// that changing one gate is caught.
+    #[test]
+    fn test_identity_circuits() {
+        for seed in 0..5 {
+            let rb = randomized_benchmarking(4, 6, &mut Rng::new(seed));
+            assert_eq!(verify_equivalent(4, &rb, &[]).unwrap(), Verification::Equal);
+        }
+        // Mirror circuits have arbitrary rotations, so only a Clifford+T one is exact.
+        let gates = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(2),
+            },
+            Gate::H {
+                target: QubitRef(2),
+            },
+            Gate::RZ {
+                target: QubitRef(1),
+                theta: 3.0 * PI / 4.0,
+            },
+            Gate::TDgr {
+                target: QubitRef(2),
+            },
+        ];
+        let mut mirrored = gates.to_vec();
+        mirrored.extend(gates.iter().rev().map(Gate::inverse));
+        assert_eq!(
+            verify_equivalent(3, &mirrored, &[]).unwrap(),
+            Verification::Equal
+        );
+        mirrored[6] = Gate::TDgr {
+            target: QubitRef(2),
+        };
+        assert!(matches!(
+            verify_equivalent(3, &mirrored, &[]).unwrap(),
+            Verification::Differs { .. }
+        ));
+        let error = verify_equivalent(4, &mirror_circuit(4, 2, &mut Rng::new(0)), &[]);
+        assert!(error
+            .unwrap_err()
+            .to_string()
+            .contains("not a Clifford+T gate"));
+    }
+
+    /// Tests equalities that only hold up to a global phase or only differ by a relative
+    /// one, which floating point comparisons with a tolerance can get wrong.
+    #[test]
+    fn test_equivalences() {
+        let h = Gate::H {
+            target: QubitRef(0),
+        };
+        let t = Gate::T {
+            target: QubitRef(0),
+        };
+        // HTH is not THT, and (HT)³ is not the identity.
+        assert!(matches!(
+            verify_equivalent(
+                1,
+                &[h.clone(), t.clone(), h.clone()],
+                &[t.clone(), h.clone(), t.clone()]
+            )
+            .unwrap(),
+            Verification::Differs { .. }
+        ));
+        let ht: Vec<Gate> = (0..3).flat_map(|_| [h.clone(), t.clone()]).collect();
+        assert!(matches!(
+            verify_equivalent(1, &ht, &[]).unwrap(),
+            Verification::Differs { .. }
+        ));
+        // X = HZH, with Z as rz(π), which differs from Z by a global phase.
+        let z = Gate::RZ {
+            target: QubitRef(0),
+            theta: PI,
+        };
+        assert_eq!(
+            verify_equivalent(
+                1,
+                &[Gate::X {
+                    target: QubitRef(0)
+                }],
+                &[h.clone(), z, h.clone()]
+            )
+            .unwrap(),
+            Verification::Equal
+        );
+        // CX with its control and target swapped by Hadamards on both qubits.
+        let hh = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+        ];
+        let mut swapped = hh.to_vec();
+        swapped.push(Gate::CX {
+            control: QubitRef(1),
+            target: QubitRef(0),
+        });
+        swapped.extend(hh);
+        assert_eq!(
+            verify_equivalent(
+                2,
+                &swapped,
+                &[Gate::CX {
+                    control: QubitRef(0),
+                    target: QubitRef(1)
+                }]
+            )
+            .unwrap(),
+            Verification::Equal
+        );
+        // CZ is not the identity, even though every basis state is mapped to itself.
+        let cz = [
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+        ];
+        assert_eq!(
+            verify_equivalent(2, &cz, &[]).unwrap(),
+            Verification::Differs { basis_state: 3 }
+        );
+    }
+}
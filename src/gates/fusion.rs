@@ -0,0 +1,473 @@
+use crate::gates::gate::Gate;
+use crate::gates::kernels::{multiply, Matrix2};
+use crate::gates::origin::Origin;
+use crate::quantum::register::QubitRef;
+use crate::quantum::tolerance::Tolerance;
+use num::Complex;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Matrix entries within this distance of the identity, or of a Hadamard, are treated as
+/// the identity or the Hadamard.
+const IDENTITY_TOLERANCE: Tolerance = Tolerance::absolute(1e-12);
+
+/// The single qubit gates waiting to be applied to one qubit.
+struct Pending {
+    matrix: Matrix2,
+    gates: Vec<Gate>,
+    origin: Origin,
+}
+
+/// Fuses runs of single qubit gates on the same qubit into a single
+/// [`Gate::Unitary`], so that each run costs one pass over the state instead of one per
+/// gate.
+///
+/// Gates are pushed in circuit order and the fuser returns the gates that are ready to
+/// be applied. A run of single qubit gates is only released when a multi-qubit gate
+/// needs its qubit, or when [`GateFuser::finish`] is called. Runs that multiply to the
+/// identity, such as two Hadamards, are dropped altogether.
+///
+/// Pending Hadamards on both qubits of a CX stay pending, as `CX (H ⊗ H)` is the same
+/// as `(H ⊗ H)` after the CX with its control and target swapped. Layers of Hadamards
+/// before CX ladders are then only expanded once another gate needs them.
+///
+/// [`GateFuser::push_with_origin`] and [`GateFuser::finish_with_origins`] also track
+/// where each gate came from, and give a fused gate the [merged](Origin::merge) origin
+/// of the gates in its run.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::fusion::GateFuser;
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let mut fuser = GateFuser::new();
+/// assert!(fuser.push(Gate::H { target: QubitRef(0) }).is_empty());
+/// assert!(fuser.push(Gate::T { target: QubitRef(0) }).is_empty());
+/// assert!(fuser.push(Gate::X { target: QubitRef(1) }).is_empty());
+///
+/// let ready = fuser.push(Gate::CX { control: QubitRef(0), target: QubitRef(2) });
+/// assert!(matches!(ready[..], [Gate::Unitary { target: QubitRef(0), .. }, Gate::CX { .. }]));
+/// assert_eq!(fuser.finish(), vec![Gate::X { target: QubitRef(1) }]);
+/// ```
+#[derive(Default)]
+pub struct GateFuser {
+    pending: BTreeMap<usize, Pending>,
+}
+
+impl GateFuser {
+    /// Creates a new `GateFuser` with no pending gates.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the next gate of the circuit and returns the gates that can now be applied.
+    pub fn push(&mut self, gate: Gate) -> Vec<Gate> {
+        without_origins(self.push_with_origin(gate, Origin::unplaced("")))
+    }
+
+    /// Adds the next gate of the circuit with where it came from, and returns the gates
+    /// that can now be applied with where they came from.
+    pub fn push_with_origin(&mut self, gate: Gate, origin: Origin) -> Vec<(Gate, Origin)> {
+        if let Some(matrix) = gate.single_qubit_matrix() {
+            match self.pending.get_mut(&gate.qubits()[0]) {
+                Some(pending) => {
+                    pending.matrix = multiply(&matrix, &pending.matrix);
+                    pending.origin = pending.origin.merge(&origin);
+                    pending.gates.push(gate);
+                }
+                None => {
+                    let pending = Pending {
+                        matrix,
+                        gates: vec![gate.clone()],
+                        origin,
+                    };
+                    self.pending.insert(gate.qubits()[0], pending);
+                }
+            }
+            return Vec::new();
+        }
+
+        if let Gate::CX { control, target } = gate {
+            if [control, target]
+                .iter()
+                .all(|qubit| self.is_pending_hadamard(qubit.index()))
+            {
+                let swapped = Gate::CX {
+                    control: target,
+                    target: control,
+                };
+                return vec![(swapped, origin)];
+            }
+        }
+
+        let mut ready = Vec::new();
+        for qubit in gate.qubits() {
+            if let Some(pending) = self.pending.remove(&qubit) {
+                ready.extend(release(qubit, pending));
+            }
+        }
+        ready.push((gate, origin));
+        ready
+    }
+
+    /// Returns whether the gates pending on a qubit multiply to a Hadamard.
+    fn is_pending_hadamard(&self, qubit: usize) -> bool {
+        let hadamard = Gate::H {
+            target: QubitRef(qubit),
+        }
+        .single_qubit_matrix()
+        .unwrap();
+        self.pending
+            .get(&qubit)
+            .is_some_and(|pending| is_close(&pending.matrix, &hadamard))
+    }
+
+    /// Returns all of the gates that are still pending, in qubit order.
+    pub fn finish(&mut self) -> Vec<Gate> {
+        without_origins(self.finish_with_origins())
+    }
+
+    /// Returns all of the gates that are still pending with where they came from, in
+    /// qubit order.
+    pub fn finish_with_origins(&mut self) -> Vec<(Gate, Origin)> {
+        let pending = std::mem::take(&mut self.pending);
+        pending
+            .into_iter()
+            .flat_map(|(qubit, pending)| release(qubit, pending))
+            .collect()
+    }
+}
+
+/// Splits the gates released by a [`GateFuser`] into layers of consecutive gates on
+/// disjoint qubits, keeping their order, so that each layer can be applied in a single
+/// pass over the state.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::fusion::disjoint_layers;
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::origin::Origin;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let gates = [
+///     Gate::H { target: QubitRef(0) },
+///     Gate::H { target: QubitRef(1) },
+///     Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+/// ];
+/// let layers = disjoint_layers(gates.map(|gate| (gate, Origin::unplaced(""))).to_vec());
+/// assert_eq!(layers.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 1]);
+/// ```
+pub fn disjoint_layers(gates: Vec<(Gate, Origin)>) -> Vec<Vec<(Gate, Origin)>> {
+    let mut layers: Vec<Vec<(Gate, Origin)>> = Vec::new();
+    let mut used = BTreeSet::new();
+    for (gate, origin) in gates {
+        let qubits = gate.qubits();
+        if layers.is_empty() || qubits.iter().any(|qubit| used.contains(qubit)) {
+            layers.push(Vec::new());
+            used.clear();
+        }
+        used.extend(qubits);
+        layers.last_mut().unwrap().push((gate, origin));
+    }
+    layers
+}
+
+/// Returns the gate to apply for a run of pending gates, if any, with its origin.
+fn release(target: usize, mut pending: Pending) -> Option<(Gate, Origin)> {
+    if pending.gates.len() == 1 {
+        // Keep lone gates as they are, so they keep their names and fast paths.
+        return pending.gates.pop().map(|gate| (gate, pending.origin));
+    }
+    if is_identity(&pending.matrix) {
+        return None;
+    }
+    let gate = Gate::Unitary {
+        target: QubitRef(target),
+        matrix: pending.matrix,
+    };
+    Some((gate, pending.origin))
+}
+
+fn without_origins(gates: Vec<(Gate, Origin)>) -> Vec<Gate> {
+    gates.into_iter().map(|(gate, _)| gate).collect()
+}
+
+fn identity() -> Matrix2 {
+    let zero = Complex::new(0.0, 0.0);
+    let one = Complex::new(1.0, 0.0);
+    [[one, zero], [zero, one]]
+}
+
+fn is_identity(matrix: &Matrix2) -> bool {
+    is_close(matrix, &identity())
+}
+
+fn is_close(matrix: &Matrix2, expected: &Matrix2) -> bool {
+    (0..2).all(|row| {
+        (0..2).all(|column| {
+            IDENTITY_TOLERANCE.approx_eq_complex(matrix[row][column], expected[row][column])
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::gate::apply_gate_to_state;
+    use crate::quantum::{ket::Ket, state::State};
+
+    /// Helper function to apply gates to the zero state.
+    fn simulate(gates: &[Gate]) -> State {
+        let mut state = State::new(3);
+        state.add_or_insert(Ket::new_zero_ket(3));
+        for gate in gates {
+            state = apply_gate_to_state(state, gate);
+        }
+        state
+    }
+
+    /// Tests that fused gates give the same state as the original gates.
+    #[test]
+    fn test_fused_gates_match_original() {
+        let gates = vec![
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::RZ {
+                target: QubitRef(1),
+                theta: 0.7,
+            },
+            Gate::X {
+                target: QubitRef(1),
+            },
+            Gate::H {
+                target: QubitRef(2),
+            },
+            Gate::TDgr {
+                target: QubitRef(0),
+            },
+        ];
+
+        let mut fuser = GateFuser::new();
+        let mut fused: Vec<Gate> = gates
+            .iter()
+            .flat_map(|gate| fuser.push(gate.clone()))
+            .collect();
+        fused.extend(fuser.finish());
+
+        assert_eq!(fused.len(), 6);
+        assert_eq!(simulate(&fused).to_string(), simulate(&gates).to_string());
+    }
+
+    /// Tests that runs multiplying to the identity are dropped.
+    #[test]
+    fn test_identity_runs_dropped() {
+        let mut fuser = GateFuser::new();
+        for gate in [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::T {
+                target: QubitRef(1),
+            },
+            Gate::TDgr {
+                target: QubitRef(1),
+            },
+        ] {
+            assert!(fuser.push(gate).is_empty());
+        }
+        assert_eq!(
+            fuser.push(Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1)
+            }),
+            vec![Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1)
+            }]
+        );
+        assert!(fuser.finish().is_empty());
+    }
+
+    /// Tests that Hadamards on both qubits of a CX stay pending past it.
+    #[test]
+    fn test_hadamards_deferred_past_cx() {
+        let gates = vec![
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::X {
+                target: QubitRef(2),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(2),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(0),
+            },
+        ];
+
+        let mut fuser = GateFuser::new();
+        let mut fused: Vec<Gate> = Vec::new();
+        for gate in &gates[..4] {
+            fused.extend(fuser.push(gate.clone()));
+        }
+        assert_eq!(
+            fused,
+            vec![Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(0)
+            }]
+        );
+        for gate in &gates[4..] {
+            fused.extend(fuser.push(gate.clone()));
+        }
+        fused.extend(fuser.finish());
+        assert_eq!(simulate(&fused).to_string(), simulate(&gates).to_string());
+    }
+
+    /// Tests that a fused run has the merged origin of its gates and that other gates
+    /// keep their own.
+    #[test]
+    fn test_origins_merged() {
+        let mut fuser = GateFuser::new();
+        assert!(fuser
+            .push_with_origin(
+                Gate::H {
+                    target: QubitRef(0)
+                },
+                Origin::new("h", 3)
+            )
+            .is_empty());
+        assert!(fuser
+            .push_with_origin(
+                Gate::T {
+                    target: QubitRef(0)
+                },
+                Origin::new("t", 5)
+            )
+            .is_empty());
+        assert!(fuser
+            .push_with_origin(
+                Gate::X {
+                    target: QubitRef(1)
+                },
+                Origin::new("flip", 4)
+            )
+            .is_empty());
+
+        let cx = Gate::CX {
+            control: QubitRef(0),
+            target: QubitRef(2),
+        };
+        let origins: Vec<String> = fuser
+            .push_with_origin(cx, Origin::new("flip", 4))
+            .iter()
+            .map(|(_, origin)| origin.to_string())
+            .collect();
+        assert_eq!(origins, vec!["'h+t' on lines 3-5", "'flip' on line 4"]);
+        assert_eq!(
+            fuser.finish_with_origins(),
+            vec![(
+                Gate::X {
+                    target: QubitRef(1)
+                },
+                Origin::new("flip", 4)
+            )]
+        );
+    }
+
+    /// Tests that released gates are split into layers on disjoint qubits in their
+    /// original order.
+    #[test]
+    fn test_disjoint_layers() {
+        let mut fuser = GateFuser::new();
+        let mut released = Vec::new();
+        for gate in [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+            Gate::T {
+                target: QubitRef(1),
+            },
+            Gate::X {
+                target: QubitRef(2),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(3),
+            },
+        ] {
+            released.extend(fuser.push_with_origin(gate, Origin::unplaced("")));
+        }
+        released.extend(fuser.finish_with_origins());
+
+        let layers: Vec<Vec<Gate>> = disjoint_layers(released)
+            .into_iter()
+            .map(without_origins)
+            .collect();
+        assert_eq!(layers.len(), 2);
+        assert!(matches!(
+            layers[0][..],
+            [
+                Gate::Unitary {
+                    target: QubitRef(0),
+                    ..
+                },
+                Gate::T {
+                    target: QubitRef(1)
+                }
+            ]
+        ));
+        assert!(matches!(
+            layers[1][..],
+            [
+                Gate::CX { .. },
+                Gate::X {
+                    target: QubitRef(2)
+                },
+                Gate::T {
+                    target: QubitRef(3)
+                }
+            ]
+        ));
+    }
+}
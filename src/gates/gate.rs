@@ -1,15 +1,454 @@
 use num::Complex;
 
-use crate::quantum::{ket::Ket, state::State};
-use std::{f64::consts::PI, string::String};
+use crate::gates::kernels::{adjoint, multiply, Matrix2};
+use crate::quantum::{
+    ket::Ket,
+    register::QubitRef,
+    state::{Accumulation, State},
+    tolerance::Tolerance,
+};
+use std::{f64::consts::PI, fmt, slice, string::String};
+
+/// How close matrix entries must be to count a gate as Clifford or T-like.
+const CLIFFORD_TOLERANCE: Tolerance = Tolerance::absolute(1e-9);
 
 /// Enum representing all supported quantum gates.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Gate {
-    H { target: usize },
-    X { target: usize },
-    T { target: usize },
-    TDgr { target: usize },
-    CX { control: usize, target: usize },
+    H {
+        target: QubitRef,
+    },
+    X {
+        target: QubitRef,
+    },
+    T {
+        target: QubitRef,
+    },
+    TDgr {
+        target: QubitRef,
+    },
+    CX {
+        control: QubitRef,
+        target: QubitRef,
+    },
+    RZ {
+        target: QubitRef,
+        theta: f64,
+    },
+    /// An arbitrary single qubit gate, such as several gates fused together.
+    Unitary {
+        target: QubitRef,
+        matrix: Matrix2,
+    },
+}
+
+/// The number of classical parameters and qubits taken by a gate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GateSignature {
+    pub parameters: usize,
+    pub qubits: usize,
+}
+
+impl Gate {
+    /// Returns the signature of the built-in gate with the given OpenQASM name, or `None`
+    /// if there is no such gate. This includes the instructions that
+    /// [`Gate::expand_builtin`] decomposes.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::{Gate, GateSignature};
+    ///
+    /// let signature = Gate::builtin_signature("rz").unwrap();
+    /// assert_eq!(signature, GateSignature { parameters: 1, qubits: 1 });
+    /// assert_eq!(Gate::builtin_signature("foo"), None);
+    /// ```
+    pub fn builtin_signature(name: &str) -> Option<GateSignature> {
+        let (parameters, qubits) = match name {
+            "h" | "x" | "t" | "tdg" => (0, 1),
+            "cx" => (0, 2),
+            "rz" => (1, 1),
+            "ccx" | "cswap" => (0, 3),
+            _ => return None,
+        };
+        Some(GateSignature { parameters, qubits })
+    }
+
+    /// Creates a built-in gate from its OpenQASM name, classical parameters and qubit
+    /// operands. Returns `None` if the name is unknown or the number of parameters or
+    /// qubits does not match the gate.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let h = Gate::from_qasm("h", &[], &[QubitRef(3)]);
+    /// assert!(matches!(h, Some(Gate::H { target: QubitRef(3) })));
+    /// let rz = Gate::from_qasm("rz", &[0.5], &[QubitRef(1)]);
+    /// assert!(matches!(rz, Some(Gate::RZ { target: QubitRef(1), .. })));
+    /// assert!(Gate::from_qasm("cx", &[], &[QubitRef(0)]).is_none());
+    /// ```
+    pub fn from_qasm(name: &str, parameters: &[f64], qubits: &[QubitRef]) -> Option<Gate> {
+        match (name, parameters, qubits) {
+            ("h", [], [target]) => Some(Gate::H { target: *target }),
+            ("x", [], [target]) => Some(Gate::X { target: *target }),
+            ("t", [], [target]) => Some(Gate::T { target: *target }),
+            ("tdg", [], [target]) => Some(Gate::TDgr { target: *target }),
+            ("cx", [], [control, target]) => Some(Gate::CX {
+                control: *control,
+                target: *target,
+            }),
+            ("rz", [theta], [target]) => Some(Gate::RZ {
+                target: *target,
+                theta: *theta,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Creates the gates of a built-in OpenQASM instruction. The Toffoli `ccx` and the
+    /// Fredkin `cswap` have no gate of their own, so they are decomposed into H, T and CX
+    /// gates as in `qelib1.inc`. Returns `None` if the name is unknown or the number of
+    /// parameters or qubits does not match the instruction.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let x = Gate::expand_builtin("x", &[], &[QubitRef(2)]);
+    /// assert_eq!(x, Some(vec![Gate::X { target: QubitRef(2) }]));
+    /// let qubits = [0, 1, 2].map(QubitRef);
+    /// assert_eq!(Gate::expand_builtin("ccx", &[], &qubits).unwrap().len(), 15);
+    /// assert_eq!(Gate::expand_builtin("cswap", &[], &[QubitRef(0), QubitRef(1)]), None);
+    /// ```
+    pub fn expand_builtin(
+        name: &str,
+        parameters: &[f64],
+        qubits: &[QubitRef],
+    ) -> Option<Vec<Gate>> {
+        match (name, parameters, qubits) {
+            ("ccx", [], &[a, b, c]) => Some(toffoli(a, b, c)),
+            ("cswap", [], &[a, b, c]) => {
+                let mut gates = vec![Gate::CX {
+                    control: c,
+                    target: b,
+                }];
+                gates.extend(toffoli(a, b, c));
+                gates.push(Gate::CX {
+                    control: c,
+                    target: b,
+                });
+                Some(gates)
+            }
+            _ => Gate::from_qasm(name, parameters, qubits).map(|gate| vec![gate]),
+        }
+    }
+
+    /// Returns the OpenQASM name of this gate.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// assert_eq!(Gate::TDgr { target: QubitRef(0) }.name(), "tdg");
+    /// ```
+    pub fn name(&self) -> &'static str {
+        match self {
+            Gate::H { .. } => "h",
+            Gate::X { .. } => "x",
+            Gate::T { .. } => "t",
+            Gate::TDgr { .. } => "tdg",
+            Gate::CX { .. } => "cx",
+            Gate::RZ { .. } => "rz",
+            Gate::Unitary { .. } => "unitary",
+        }
+    }
+
+    /// Returns the matrix of a single qubit gate, or `None` for multi-qubit gates.
+    ///
+    /// # Examples
+    /// ```
+    /// use num::Complex;
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let matrix = Gate::X { target: QubitRef(0) }.single_qubit_matrix().unwrap();
+    /// assert_eq!(matrix[0][1], Complex::new(1.0, 0.0));
+    /// let cx = Gate::CX { control: QubitRef(0), target: QubitRef(1) };
+    /// assert!(cx.single_qubit_matrix().is_none());
+    /// ```
+    pub fn single_qubit_matrix(&self) -> Option<Matrix2> {
+        let zero = Complex::new(0.0, 0.0);
+        let one = Complex::new(1.0, 0.0);
+        let diagonal =
+            |phase0: Complex<f64>, phase1: Complex<f64>| [[phase0, zero], [zero, phase1]];
+        match self {
+            Gate::H { .. } => {
+                let half = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+                Some([[half, half], [half, -half]])
+            }
+            Gate::X { .. } => Some([[zero, one], [one, zero]]),
+            Gate::T { .. } => Some(diagonal(one, Complex::new(0.0, PI / 4.0).exp())),
+            Gate::TDgr { .. } => Some(diagonal(one, Complex::new(0.0, -PI / 4.0).exp())),
+            Gate::RZ { theta, .. } => Some(diagonal(
+                Complex::new(0.0, -theta / 2.0).exp(),
+                Complex::new(0.0, theta / 2.0).exp(),
+            )),
+            Gate::Unitary { matrix, .. } => Some(*matrix),
+            Gate::CX { .. } => None,
+        }
+    }
+
+    /// Returns whether this gate is a Clifford gate, which maps Pauli operators to Pauli
+    /// operators and so can be simulated with the stabilizer formalism.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    /// use std::f64::consts::PI;
+    ///
+    /// assert!(Gate::H { target: QubitRef(0) }.is_clifford());
+    /// assert!(Gate::RZ { target: QubitRef(0), theta: PI / 2.0 }.is_clifford());
+    /// assert!(!Gate::T { target: QubitRef(0) }.is_clifford());
+    /// ```
+    pub fn is_clifford(&self) -> bool {
+        let Some(matrix) = self.single_qubit_matrix() else {
+            // CX is the only built in multi-qubit gate.
+            return true;
+        };
+        let zero = Complex::new(0.0, 0.0);
+        let one = Complex::new(1.0, 0.0);
+        let i = Complex::new(0.0, 1.0);
+        let paulis: [Matrix2; 3] = [
+            [[zero, one], [one, zero]],
+            [[zero, -i], [i, zero]],
+            [[one, zero], [zero, -one]],
+        ];
+        // Conjugating X and Z must give a Pauli operator up to sign, which is the case if
+        // the overlap `Tr(P M) / 2` with one of them has magnitude 1.
+        [paulis[0], paulis[2]].iter().all(|pauli| {
+            let conjugated = multiply(&multiply(&matrix, pauli), &adjoint(&matrix));
+            paulis.iter().any(|other| {
+                let product = multiply(other, &conjugated);
+                CLIFFORD_TOLERANCE.approx_eq((product[0][0] + product[1][1]).norm() / 2.0, 1.0)
+            })
+        })
+    }
+
+    /// Returns whether this gate is a T gate up to a Clifford phase gate, which is the
+    /// case for diagonal gates whose relative phase is an odd multiple of pi / 4.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    /// use std::f64::consts::PI;
+    ///
+    /// assert!(Gate::TDgr { target: QubitRef(0) }.is_t_like());
+    /// assert!(Gate::RZ { target: QubitRef(0), theta: 3.0 * PI / 4.0 }.is_t_like());
+    /// assert!(!Gate::RZ { target: QubitRef(0), theta: 0.1 }.is_t_like());
+    /// ```
+    pub fn is_t_like(&self) -> bool {
+        let Some(matrix) = self.single_qubit_matrix() else {
+            return false;
+        };
+        if !CLIFFORD_TOLERANCE.is_negligible(matrix[0][1])
+            || !CLIFFORD_TOLERANCE.is_negligible(matrix[1][0])
+        {
+            return false;
+        }
+        let eighths = (matrix[1][1] / matrix[0][0]).arg() / (PI / 4.0);
+        CLIFFORD_TOLERANCE.approx_eq(eighths, eighths.round())
+            && (eighths.round() as i64).rem_euclid(2) == 1
+    }
+
+    /// Returns the phase a T, T† or `rz` gate gives the one state of its target relative
+    /// to the zero state as a whole number of eighths of a turn, or `None` for other gates
+    /// and for angles that are not a multiple of pi / 4.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    /// use std::f64::consts::PI;
+    ///
+    /// assert_eq!(Gate::TDgr { target: QubitRef(0) }.phase_eighths(), Some(7));
+    /// assert_eq!(Gate::RZ { target: QubitRef(0), theta: -PI / 2.0 }.phase_eighths(), Some(6));
+    /// assert_eq!(Gate::RZ { target: QubitRef(0), theta: 0.1 }.phase_eighths(), None);
+    /// ```
+    pub fn phase_eighths(&self) -> Option<u32> {
+        match self {
+            Gate::T { .. } => Some(1),
+            Gate::TDgr { .. } => Some(7),
+            Gate::RZ { theta, .. } => {
+                let eighths = theta / (PI / 4.0);
+                CLIFFORD_TOLERANCE
+                    .approx_eq(eighths, eighths.round())
+                    .then(|| (eighths.round() as i64).rem_euclid(8) as u32)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the index of the qubit whose value this gate may flip, if any.
+    pub fn flipped_qubit(&self) -> Option<usize> {
+        match self {
+            Gate::H { target }
+            | Gate::X { target }
+            | Gate::CX { target, .. }
+            | Gate::Unitary { target, .. } => Some(target.index()),
+            Gate::T { .. } | Gate::TDgr { .. } | Gate::RZ { .. } => None,
+        }
+    }
+
+    /// Returns a copy of this gate acting on the qubits whose indices `map` gives for the
+    /// indices of each of its current qubits.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let gate = Gate::CX { control: QubitRef(0), target: QubitRef(1) };
+    /// let gate = gate.remap(|qubit| qubit + 2);
+    /// assert_eq!(gate, Gate::CX { control: QubitRef(2), target: QubitRef(3) });
+    /// ```
+    pub fn remap(&self, map: impl Fn(usize) -> usize) -> Gate {
+        let map = |qubit: &QubitRef| QubitRef(map(qubit.index()));
+        match self {
+            Gate::H { target } => Gate::H {
+                target: map(target),
+            },
+            Gate::X { target } => Gate::X {
+                target: map(target),
+            },
+            Gate::T { target } => Gate::T {
+                target: map(target),
+            },
+            Gate::TDgr { target } => Gate::TDgr {
+                target: map(target),
+            },
+            Gate::CX { control, target } => Gate::CX {
+                control: map(control),
+                target: map(target),
+            },
+            Gate::RZ { target, theta } => Gate::RZ {
+                target: map(target),
+                theta: *theta,
+            },
+            Gate::Unitary { target, matrix } => Gate::Unitary {
+                target: map(target),
+                matrix: *matrix,
+            },
+        }
+    }
+
+    /// Returns the gate that undoes this one.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let t = Gate::T { target: QubitRef(1) };
+    /// assert_eq!(t.inverse(), Gate::TDgr { target: QubitRef(1) });
+    /// assert_eq!(
+    ///     Gate::RZ { target: QubitRef(0), theta: 0.5 }.inverse(),
+    ///     Gate::RZ { target: QubitRef(0), theta: -0.5 }
+    /// );
+    /// ```
+    pub fn inverse(&self) -> Gate {
+        match self {
+            Gate::H { .. } | Gate::X { .. } | Gate::CX { .. } => self.clone(),
+            Gate::T { target } => Gate::TDgr { target: *target },
+            Gate::TDgr { target } => Gate::T { target: *target },
+            Gate::RZ { target, theta } => Gate::RZ {
+                target: *target,
+                theta: -theta,
+            },
+            Gate::Unitary { target, matrix } => Gate::Unitary {
+                target: *target,
+                matrix: adjoint(matrix),
+            },
+        }
+    }
+
+    /// Returns the indices of the qubits this gate acts on, with any control qubits first.
+    pub fn qubits(&self) -> Vec<usize> {
+        match self {
+            Gate::H { target }
+            | Gate::X { target }
+            | Gate::T { target }
+            | Gate::TDgr { target }
+            | Gate::RZ { target, .. }
+            | Gate::Unitary { target, .. } => vec![target.index()],
+            Gate::CX { control, target } => vec![control.index(), target.index()],
+        }
+    }
+}
+
+impl fmt::Display for Gate {
+    /// Writes the gate as its name, any parameters and its qubits, such as `rz(0.5) q[2]`
+    /// or `cx q[0], q[1]`. The matrix of a unitary gate is written row by row.
+    ///
+    /// # Examples
+    /// ```
+    /// use num::Complex;
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let cx = Gate::CX { control: QubitRef(0), target: QubitRef(1) };
+    /// assert_eq!(cx.to_string(), "cx q[0], q[1]");
+    /// assert_eq!(Gate::RZ { target: QubitRef(2), theta: 0.5 }.to_string(), "rz(0.5) q[2]");
+    /// let matrix = Gate::X { target: QubitRef(0) }.single_qubit_matrix().unwrap();
+    /// assert_eq!(
+    ///     Gate::Unitary { target: QubitRef(0), matrix }.to_string(),
+    ///     "unitary(0+0i, 1+0i; 1+0i, 0+0i) q[0]"
+    /// );
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        match self {
+            Gate::RZ { theta, .. } => write!(f, "({theta})")?,
+            Gate::Unitary { matrix, .. } => write!(
+                f,
+                "({}, {}; {}, {})",
+                matrix[0][0], matrix[0][1], matrix[1][0], matrix[1][1]
+            )?,
+            _ => {}
+        }
+        for (index, qubit) in self.qubits().iter().enumerate() {
+            let separator = if index == 0 { " " } else { ", " };
+            write!(f, "{separator}q[{qubit}]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the Clifford+T decomposition of a Toffoli gate with controls `a` and `b` and
+/// target `c`.
+fn toffoli(a: QubitRef, b: QubitRef, c: QubitRef) -> Vec<Gate> {
+    let cx = |control, target| Gate::CX { control, target };
+    vec![
+        Gate::H { target: c },
+        cx(b, c),
+        Gate::TDgr { target: c },
+        cx(a, c),
+        Gate::T { target: c },
+        cx(b, c),
+        Gate::TDgr { target: c },
+        cx(a, c),
+        Gate::T { target: b },
+        Gate::T { target: c },
+        Gate::H { target: c },
+        cx(a, b),
+        Gate::T { target: a },
+        Gate::TDgr { target: b },
+        cx(a, b),
+    ]
 }
 
 /// Enum representing the result of applying a gate to a ket.
@@ -19,17 +458,19 @@ pub enum GateKetResult {
     NotImplemented(String),
 }
 
-/// Apply a gate to a ket.
+/// Apply a gate to a ket. This is a wrapper around [`apply_gate_to_ket_into`] for callers
+/// that want the results as a value.
 ///
 /// # Examples
 /// ```
 /// use num::complex::Complex;
 /// use quantum_simulator::gates::gate::{apply_gate_to_ket, Gate, GateKetResult};
 /// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::register::QubitRef;
 /// use bitvec::prelude::*;
 ///
 /// let ket = Ket::new_zero_ket(1);
-/// let gate = Gate::H { target: 0 };
+/// let gate = Gate::H { target: QubitRef(0) };
 /// let result = apply_gate_to_ket(&gate, ket);
 ///
 /// let expected_ket1 = Ket::from_bit_vec(bitvec![0], Complex::new(1.0 / 2.0_f64.sqrt(), 0.0));
@@ -43,45 +484,100 @@ pub enum GateKetResult {
 ///  _ => panic!("Expected two kets."),
 /// }
 /// ```
-pub fn apply_gate_to_ket(gate: &Gate, mut ket: Ket) -> GateKetResult {
+pub fn apply_gate_to_ket(gate: &Gate, ket: Ket) -> GateKetResult {
+    let mut first = None;
+    let mut second = None;
+    apply_gate_to_ket_into(gate, ket, &mut |new_ket| {
+        if first.is_none() {
+            first = Some(new_ket);
+        } else {
+            second = Some(new_ket);
+        }
+    });
+    // Every gate produces one or two kets.
+    match (first.unwrap(), second) {
+        (ket, None) => GateKetResult::Ket(ket),
+        (ket, Some(flipped_ket)) => GateKetResult::Kets([ket, flipped_ket]),
+    }
+}
+
+/// Applies a gate to a ket, passing each resulting ket to `sink` so that no storage is
+/// needed for the results.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::{apply_gate_to_ket_into, Gate};
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let mut kets = Vec::new();
+/// let h = Gate::H { target: QubitRef(0) };
+/// apply_gate_to_ket_into(&h, Ket::new_zero_ket(1), &mut |ket| kets.push(ket));
+/// assert_eq!(kets.len(), 2);
+/// ```
+pub fn apply_gate_to_ket_into(gate: &Gate, mut ket: Ket, sink: &mut impl FnMut(Ket)) {
     match gate {
         Gate::H { target } => {
             let mut flipped_ket = ket.clone();
-            flipped_ket.flip(*target);
+            flipped_ket.flip(target.index());
 
-            if ket.get(*target) {
+            if ket.get(target.index()) {
                 ket.amplitude *= -1.0;
             }
 
             ket.amplitude *= 1.0 / 2.0_f64.sqrt();
             flipped_ket.amplitude *= 1.0 / 2.0_f64.sqrt();
 
-            GateKetResult::Kets([ket, flipped_ket])
+            sink(ket);
+            sink(flipped_ket);
         }
         Gate::X { target } => {
-            ket.flip(*target);
-            GateKetResult::Ket(ket)
+            ket.flip(target.index());
+            sink(ket);
         }
         Gate::T { target } => {
-            if ket.get(*target) {
+            if ket.get(target.index()) {
                 ket.amplitude *= Complex::new(0.0, 1.0 * PI / 4.0).exp();
             }
 
-            GateKetResult::Ket(ket)
+            sink(ket);
         }
         Gate::TDgr { target } => {
-            if ket.get(*target) {
-                ket.amplitude *= Complex::new(0.0, -1.0 * PI / 4.0).exp();
+            if ket.get(target.index()) {
+                ket.amplitude *= Complex::new(0.0, -PI / 4.0).exp();
             }
 
-            GateKetResult::Ket(ket)
+            sink(ket);
         }
         Gate::CX { control, target } => {
-            if ket.get(*control) {
-                ket.flip(*target);
+            if ket.get(control.index()) {
+                ket.flip(target.index());
             }
 
-            GateKetResult::Ket(ket)
+            sink(ket);
+        }
+        Gate::RZ { target, theta } => {
+            let sign = if ket.get(target.index()) { 1.0 } else { -1.0 };
+            ket.amplitude *= Complex::new(0.0, sign * theta / 2.0).exp();
+
+            sink(ket);
+        }
+        Gate::Unitary { target, matrix } => {
+            let bit = ket.get(target.index()) as usize;
+            let amplitude = ket.amplitude;
+            ket.amplitude = amplitude * matrix[bit][bit];
+
+            // Only allocate bits for the flipped ket if it has an amplitude.
+            let flipped_amplitude = amplitude * matrix[1 - bit][bit];
+            if flipped_amplitude.norm() == 0.0 {
+                sink(ket);
+                return;
+            }
+            let mut flipped_ket = ket.clone();
+            flipped_ket.flip(target.index());
+            flipped_ket.amplitude = flipped_amplitude;
+            sink(ket);
+            sink(flipped_ket);
         }
     }
 }
@@ -93,42 +589,109 @@ pub fn apply_gate_to_ket(gate: &Gate, mut ket: Ket) -> GateKetResult {
 /// use num::complex::Complex;
 /// use quantum_simulator::gates::gate::{apply_gate_to_state, Gate};
 /// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::register::QubitRef;
 /// use quantum_simulator::quantum::state::State;
 /// use bitvec::prelude::*;
 ///
 /// let mut state = State::new(1);
 /// state.add_or_insert(Ket::new_zero_ket(1));
-/// let gate = Gate::H { target: 0 };
+/// let gate = Gate::H { target: QubitRef(0) };
 /// let superposition_state = apply_gate_to_state(state, &gate);
 ///
 /// let expected_ket1 = Ket::from_bit_vec(bitvec![0], Complex::new(1.0 / 2.0_f64.sqrt(), 0.0));
 /// let expected_ket2 = Ket::from_bit_vec(bitvec![1], Complex::new(1.0 / 2.0_f64.sqrt(), 0.0));
-/// let expected_superposition_state = State::from_ket_vec(&vec![expected_ket1, expected_ket2]);
+/// let expected_superposition_state = State::from_ket_vec(&vec![expected_ket1, expected_ket2]).unwrap();
 /// assert_eq!(superposition_state, expected_superposition_state);
 /// ```
 pub fn apply_gate_to_state(state: State, gate: &Gate) -> State {
-    let mut new_state = State::new(state.num_qubits());
-    for ket in state.kets {
-        match apply_gate_to_ket(gate, ket) {
-            GateKetResult::Ket(new_ket) => {
-                new_state.add_or_insert(new_ket);
-            }
-            GateKetResult::Kets([new_ket1, new_ket2]) => {
-                new_state.add_or_insert(new_ket1);
-                new_state.add_or_insert(new_ket2);
-            }
-            GateKetResult::NotImplemented(_) => {
-                panic!("Gate not implemented.");
-            }
+    let new_state = state.empty_like();
+    let kets: Vec<Ket> = state.into_kets().collect();
+    apply_layer_to_kets(kets, slice::from_ref(gate), new_state)
+}
+
+/// Apply a layer of gates to a state in a single pass over its kets.
+///
+/// Each ket is passed through every gate of the layer before its outputs are added to
+/// the new state, so the state is only rebuilt once per layer rather than once per gate.
+/// The result is the same as applying the gates one at a time with
+/// [`apply_gate_to_state`]. Intermediate kets are not merged, so this is meant for gates
+/// on disjoint qubits, such as a layer of a circuit: gates that share a qubit are better
+/// applied one at a time.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::{apply_layer_to_state, Gate};
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::register::QubitRef;
+/// use quantum_simulator::quantum::state::State;
+///
+/// let mut state = State::new(2);
+/// state.add_or_insert(Ket::new_zero_ket(2));
+/// let layer = [Gate::H { target: QubitRef(0) }, Gate::X { target: QubitRef(1) }];
+/// let state = apply_layer_to_state(state, &layer);
+/// assert_eq!(state.to_string(), "(0.707+0i)|10⟩ + (0.707+0i)|11⟩");
+/// ```
+pub fn apply_layer_to_state(state: State, layer: &[Gate]) -> State {
+    let new_state = state.empty_like();
+    let kets: Vec<Ket> = state.into_kets().collect();
+    apply_layer_to_kets(kets, layer, new_state)
+}
+
+/// Applies a layer of gates to each of the given kets and adds the results to
+/// `new_state`, using its ordering and accumulation settings.
+pub(crate) fn apply_layer_to_kets(
+    mut kets: Vec<Ket>,
+    layer: &[Gate],
+    mut new_state: State,
+) -> State {
+    if new_state.is_canonical() {
+        kets.sort();
+    }
+
+    // With compensated accumulation every contribution is collected first so that the
+    // amplitudes of each ket can be summed together.
+    let compensated = new_state.accumulation() == Accumulation::Compensated;
+    let mut contributions = Vec::new();
+    let num_kets = kets.len() as u64;
+    let mut num_outputs = 0;
+    let mut add = |new_ket: Ket| {
+        num_outputs += 1;
+        if compensated {
+            contributions.push(new_ket);
+        } else {
+            new_state.add_or_insert(new_ket);
+        }
+    };
+    for ket in kets {
+        match layer {
+            [gate] => apply_gate_to_ket_into(gate, ket, &mut add),
+            _ => apply_layer_to_ket_into(layer, ket, &mut add),
         }
     }
+    if compensated {
+        new_state.add_all_compensated(contributions);
+    }
+    // Every gate turns a ket into one or two kets.
+    new_state.metrics_mut().branches += num_outputs - num_kets;
     new_state
 }
 
+/// Passes a ket through each gate of a layer in turn, calling `sink` with every ket that
+/// comes out of the last gate.
+fn apply_layer_to_ket_into(layer: &[Gate], ket: Ket, sink: &mut dyn FnMut(Ket)) {
+    match layer.split_first() {
+        Some((gate, rest)) => apply_gate_to_ket_into(gate, ket, &mut |ket| {
+            apply_layer_to_ket_into(rest, ket, sink)
+        }),
+        None => sink(ket),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::quantum::dense::DenseState;
     use bitvec::prelude::*;
     use num::Complex;
 
@@ -141,9 +704,98 @@ mod tests {
     /// Helper function to assert that two states are equal.
     fn assert_state_eq(state1: &State, state2: &State) {
         assert_eq!(state1.num_qubits(), state2.num_qubits());
-        assert_eq!(state1.kets.len(), state2.kets.len());
-        for ket in state1.kets.iter() {
-            assert!(state2.kets.contains(ket));
+        assert_eq!(state1.len(), state2.len());
+        for ket in state1.kets() {
+            assert!(state2.contains(ket));
+        }
+    }
+
+    /// Returns the matrix of a gate acting on a register of `num_qubits` qubits, built
+    /// from [`Gate::single_qubit_matrix`] and the permutation of CX, with qubit 0 as the
+    /// least significant bit of the row and column indices.
+    fn register_matrix(gate: &Gate, num_qubits: usize) -> Vec<Vec<Complex<f64>>> {
+        let dim = 1 << num_qubits;
+        let mut matrix = vec![vec![Complex::new(0.0, 0.0); dim]; dim];
+        for (column, row) in (0..dim).flat_map(|column| (0..dim).map(move |row| (column, row))) {
+            matrix[row][column] = match gate {
+                Gate::CX { control, target } => {
+                    let flip = (column >> control.index() & 1) << target.index();
+                    Complex::new(if row == column ^ flip { 1.0 } else { 0.0 }, 0.0)
+                }
+                _ => {
+                    let target = gate.qubits()[0];
+                    let single = gate.single_qubit_matrix().unwrap();
+                    if (row ^ column) & !(1 << target) != 0 {
+                        Complex::new(0.0, 0.0)
+                    } else {
+                        single[row >> target & 1][column >> target & 1]
+                    }
+                }
+            };
+        }
+        matrix
+    }
+
+    /// Tests every gate on every qubit of a small register against its explicit matrix,
+    /// applying it to each basis state with both the ket level and the dense
+    /// implementations.
+    #[test]
+    fn test_gates_against_matrices() {
+        let num_qubits = 3;
+        let mut gates = Vec::new();
+        for target in (0..num_qubits).map(QubitRef) {
+            gates.push(Gate::H { target });
+            gates.push(Gate::X { target });
+            gates.push(Gate::T { target });
+            gates.push(Gate::TDgr { target });
+            gates.push(Gate::RZ { target, theta: 0.3 });
+            gates.push(Gate::RZ {
+                target,
+                theta: -2.0 * PI / 3.0,
+            });
+            gates.push(Gate::Unitary {
+                target,
+                matrix: [
+                    [Complex::new(0.6, 0.0), Complex::new(0.0, 0.8)],
+                    [Complex::new(0.0, 0.8), Complex::new(0.6, 0.0)],
+                ],
+            });
+            for control in (0..num_qubits)
+                .map(QubitRef)
+                .filter(|control| *control != target)
+            {
+                gates.push(Gate::CX { control, target });
+            }
+        }
+
+        for gate in &gates {
+            let matrix = register_matrix(gate, num_qubits);
+            for column in 0..1 << num_qubits {
+                let bits: BitVec = (0..num_qubits)
+                    .map(|qubit| column >> qubit & 1 == 1)
+                    .collect();
+                let mut state = State::new(num_qubits);
+                state.add_or_insert(Ket::from_bit_vec(bits, Complex::new(1.0, 0.0)));
+                let mut dense = DenseState::from_state(&state).unwrap();
+                dense.apply_gate(gate);
+                let state = apply_gate_to_state(state, gate);
+
+                for (row, expected) in matrix.iter().map(|row| row[column]).enumerate() {
+                    let amplitude = state
+                        .kets()
+                        .find(|ket| ket.basis_index() == row)
+                        .map_or(Complex::new(0.0, 0.0), |ket| ket.amplitude);
+                    assert!(
+                        (amplitude - expected).norm() < 1e-12,
+                        "{gate:?} maps |{column}⟩ to {amplitude} in |{row}⟩ instead of {expected}"
+                    );
+                    assert!(
+                        (dense.amplitude(row) - expected).norm() < 1e-12,
+                        "The dense {gate:?} maps |{column}⟩ to {} in |{row}⟩ instead of {expected}",
+                        dense.amplitude(row)
+                    );
+                }
+            }
         }
     }
 
@@ -151,7 +803,9 @@ mod tests {
     #[test]
     fn test_apply_h_to_ket() {
         let ket = Ket::new_zero_ket(1);
-        let gate = Gate::H { target: 0 };
+        let gate = Gate::H {
+            target: QubitRef(0),
+        };
         let result = apply_gate_to_ket(&gate, ket);
 
         let expected_ket1 = Ket::from_bit_vec(bitvec![0], Complex::new(1.0 / 2.0_f64.sqrt(), 0.0));
@@ -170,17 +824,20 @@ mod tests {
     fn test_apply_h_to_state() {
         let mut state = State::new(1);
         state.add_or_insert(Ket::new_zero_ket(1));
-        let gate = Gate::H { target: 0 };
+        let gate = Gate::H {
+            target: QubitRef(0),
+        };
         let superposition_state = apply_gate_to_state(state, &gate);
 
         let expected_ket1 = Ket::from_bit_vec(bitvec![0], Complex::new(1.0 / 2.0_f64.sqrt(), 0.0));
         let expected_ket2 = Ket::from_bit_vec(bitvec![1], Complex::new(1.0 / 2.0_f64.sqrt(), 0.0));
-        let expected_superposition_state = State::from_ket_vec(&vec![expected_ket1, expected_ket2]);
+        let expected_superposition_state =
+            State::from_ket_vec(&vec![expected_ket1, expected_ket2]).unwrap();
 
         assert_state_eq(&superposition_state, &expected_superposition_state);
 
         let back_to_zero_state = apply_gate_to_state(superposition_state, &gate);
-        let expected_zero_state = State::from_ket_vec(&vec![Ket::new_zero_ket(1)]);
+        let expected_zero_state = State::from_ket_vec(&vec![Ket::new_zero_ket(1)]).unwrap();
 
         assert_state_eq(&back_to_zero_state, &expected_zero_state);
     }
@@ -189,7 +846,9 @@ mod tests {
     #[test]
     fn test_apply_x_to_ket() {
         let ket = Ket::from_bit_vec(bitvec![0, 0], Complex::new(1.0, 0.0));
-        let gate = Gate::X { target: 1 };
+        let gate = Gate::X {
+            target: QubitRef(1),
+        };
         let result = apply_gate_to_ket(&gate, ket);
 
         let expected_ket = Ket::from_bit_vec(bitvec![0, 1], Complex::new(1.0, 0.0));
@@ -206,12 +865,14 @@ mod tests {
     fn test_apply_x_to_gate() {
         let mut state = State::new(2);
         state.add_or_insert(Ket::from_bit_vec(bitvec![0, 0], Complex::new(1.0, 0.0)));
-        let gate = Gate::X { target: 1 };
+        let gate = Gate::X {
+            target: QubitRef(1),
+        };
 
         let new_state = apply_gate_to_state(state, &gate);
 
         let expected_ket = Ket::from_bit_vec(bitvec![0, 1], Complex::new(1.0, 0.0));
-        let expected_state = State::from_ket_vec(&vec![expected_ket]);
+        let expected_state = State::from_ket_vec(&vec![expected_ket]).unwrap();
 
         assert_state_eq(&new_state, &expected_state);
     }
@@ -220,7 +881,9 @@ mod tests {
     #[test]
     fn tets_apply_t_to_ket() {
         let ket = Ket::from_bit_vec(bitvec![1], Complex::new(1.0, 0.0));
-        let gate = Gate::T { target: 0 };
+        let gate = Gate::T {
+            target: QubitRef(0),
+        };
         let result = apply_gate_to_ket(&gate, ket);
 
         let expected_ket = Ket::from_bit_vec(
@@ -240,7 +903,9 @@ mod tests {
     fn test_apply_t_to_gate() {
         let mut state = State::new(1);
         state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(1.0, 0.0)));
-        let gate = Gate::T { target: 0 };
+        let gate = Gate::T {
+            target: QubitRef(0),
+        };
 
         let new_state = apply_gate_to_state(state, &gate);
 
@@ -248,7 +913,7 @@ mod tests {
             bitvec![1],
             Complex::new(1.0, 0.0) * Complex::new(0.0, 1.0 * PI / 4.0).exp(),
         );
-        let expected_state = State::from_ket_vec(&vec![expected_ket]);
+        let expected_state = State::from_ket_vec(&vec![expected_ket]).unwrap();
 
         assert_state_eq(&new_state, &expected_state);
     }
@@ -257,12 +922,14 @@ mod tests {
     #[test]
     fn tets_apply_tdgr_to_ket() {
         let ket = Ket::from_bit_vec(bitvec![1], Complex::new(1.0, 0.0));
-        let gate = Gate::TDgr { target: 0 };
+        let gate = Gate::TDgr {
+            target: QubitRef(0),
+        };
         let result = apply_gate_to_ket(&gate, ket);
 
         let expected_ket = Ket::from_bit_vec(
             bitvec![1],
-            Complex::new(1.0, 0.0) * Complex::new(0.0, -1.0 * PI / 4.0).exp(),
+            Complex::new(1.0, 0.0) * Complex::new(0.0, -PI / 4.0).exp(),
         );
         match result {
             GateKetResult::Ket(ket) => {
@@ -277,16 +944,18 @@ mod tests {
     fn test_apply_tdgr_to_state() {
         let mut state = State::new(1);
         state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(1.0, 0.0)));
-        let gate = Gate::TDgr { target: 0 };
+        let gate = Gate::TDgr {
+            target: QubitRef(0),
+        };
 
         let new_state = apply_gate_to_state(state, &gate);
 
         let expected_ket = Ket::from_bit_vec(
             bitvec![1],
-            Complex::new(1.0, 0.0) * Complex::new(0.0, -1.0 * PI / 4.0).exp(),
+            Complex::new(1.0, 0.0) * Complex::new(0.0, -PI / 4.0).exp(),
         );
 
-        let expected_state = State::from_ket_vec(&vec![expected_ket]);
+        let expected_state = State::from_ket_vec(&vec![expected_ket]).unwrap();
 
         assert_state_eq(&new_state, &expected_state);
     }
@@ -296,8 +965,8 @@ mod tests {
     fn test_apply_cx_to_ket() {
         let ket = Ket::from_bit_vec(bitvec![1, 0], Complex::new(1.0, 0.0));
         let gate = Gate::CX {
-            control: 0,
-            target: 1,
+            control: QubitRef(0),
+            target: QubitRef(1),
         };
         let result = apply_gate_to_ket(&gate, ket);
 
@@ -310,20 +979,253 @@ mod tests {
         }
     }
 
+    /// Test to apply an RZ gate to both basis states of a qubit.
+    #[test]
+    fn test_apply_rz_to_ket() {
+        let gate = Gate::RZ {
+            target: QubitRef(0),
+            theta: PI / 2.0,
+        };
+        for (bit, phase) in [(false, -PI / 4.0), (true, PI / 4.0)] {
+            let ket = Ket::from_bit_vec(BitVec::repeat(bit, 1), Complex::new(1.0, 0.0));
+            let expected_ket =
+                Ket::from_bit_vec(BitVec::repeat(bit, 1), Complex::new(0.0, phase).exp());
+            match apply_gate_to_ket(&gate, ket) {
+                GateKetResult::Ket(ket) => {
+                    assert_ket_eq(&ket, &expected_ket);
+                }
+                _ => panic!("Expected one ket."),
+            }
+        }
+    }
+
+    /// Tests that an arbitrary unitary mixes the amplitudes of the target bit.
+    #[test]
+    fn test_apply_unitary_to_ket() {
+        let zero = Complex::new(0.0, 0.0);
+        let gate = Gate::Unitary {
+            target: QubitRef(1),
+            matrix: [
+                [Complex::new(0.6, 0.0), zero],
+                [Complex::new(0.0, 0.8), Complex::new(1.0, 0.0)],
+            ],
+        };
+        let ket = Ket::from_bit_vec(bitvec![1, 0], Complex::new(1.0, 0.0));
+        match apply_gate_to_ket(&gate, ket) {
+            GateKetResult::Kets([ket1, ket2]) => {
+                assert_ket_eq(
+                    &ket1,
+                    &Ket::from_bit_vec(bitvec![1, 0], Complex::new(0.6, 0.0)),
+                );
+                assert_ket_eq(
+                    &ket2,
+                    &Ket::from_bit_vec(bitvec![1, 1], Complex::new(0.0, 0.8)),
+                );
+            }
+            _ => panic!("Expected two kets."),
+        }
+
+        // The |1⟩ column has no off-diagonal entry, so only one ket is produced.
+        let ket = Ket::from_bit_vec(bitvec![0, 1], Complex::new(1.0, 0.0));
+        assert!(matches!(
+            apply_gate_to_ket(&gate, ket),
+            GateKetResult::Ket(_)
+        ));
+    }
+
     #[test]
     fn test_apply_cx_to_state() {
         let mut state = State::new(2);
         state.add_or_insert(Ket::from_bit_vec(bitvec![1, 1], Complex::new(1.0, 0.0)));
         let gate = Gate::CX {
-            control: 0,
-            target: 1,
+            control: QubitRef(0),
+            target: QubitRef(1),
         };
 
         let new_state = apply_gate_to_state(state, &gate);
 
         let expected_ket = Ket::from_bit_vec(bitvec![1, 0], Complex::new(1.0, 0.0));
-        let expected_state = State::from_ket_vec(&vec![expected_ket]);
+        let expected_state = State::from_ket_vec(&vec![expected_ket]).unwrap();
 
         assert_state_eq(&new_state, &expected_state);
     }
+
+    /// Tests that the decomposed Toffoli and Fredkin gates permute every basis state
+    /// without changing its phase.
+    #[test]
+    fn test_expand_multi_control_builtins() {
+        for index in 0..8usize {
+            let bits: BitVec = (0..3).map(|qubit| index >> qubit & 1 == 1).collect();
+            let [a, b, c] = [bits[0], bits[1], bits[2]];
+            let toffoli = index ^ (((a && b) as usize) << 2);
+            let fredkin = if a {
+                index & 1 | (b as usize) << 2 | (c as usize) << 1
+            } else {
+                index
+            };
+            for (name, expected) in [("ccx", toffoli), ("cswap", fredkin)] {
+                let mut state = State::new(3);
+                state.add_or_insert(Ket::from_bit_slice(&bits, Complex::new(1.0, 0.0)));
+                let gates =
+                    Gate::expand_builtin(name, &[], &[QubitRef(0), QubitRef(1), QubitRef(2)])
+                        .unwrap();
+                let state = gates.iter().fold(state, apply_gate_to_state);
+                let kets = state.sorted_kets();
+                assert_eq!(kets.len(), 1, "{name} of {index:03b}");
+                assert_eq!(kets[0].basis_index(), expected, "{name} of {index:03b}");
+                assert!((kets[0].amplitude - Complex::new(1.0, 0.0)).norm() < 1e-9);
+            }
+        }
+    }
+
+    /// Tests that canonical ordering is kept when applying gates and gives the same
+    /// state as the default ordering.
+    #[test]
+    fn test_apply_gate_to_canonical_state() {
+        let gates = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+        ];
+        let mut state = State::new(2);
+        state.add_or_insert(Ket::new_zero_ket(2));
+        let mut canonical_state = State::new(2);
+        canonical_state.add_or_insert(Ket::new_zero_ket(2));
+        canonical_state.set_canonical(true);
+
+        for gate in &gates {
+            state = apply_gate_to_state(state, gate);
+            canonical_state = apply_gate_to_state(canonical_state, gate);
+        }
+
+        assert!(canonical_state.is_canonical());
+        assert_eq!(canonical_state.to_string(), state.to_string());
+    }
+
+    /// Tests that compensated accumulation is kept when applying gates and gives the
+    /// same state as sequential accumulation.
+    #[test]
+    fn test_apply_gate_to_compensated_state() {
+        let gates = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+        ];
+        let mut state = State::new(2);
+        state.add_or_insert(Ket::new_zero_ket(2));
+        let mut compensated_state = State::new(2);
+        compensated_state.add_or_insert(Ket::new_zero_ket(2));
+        compensated_state.set_accumulation(Accumulation::Compensated);
+
+        for gate in &gates {
+            state = apply_gate_to_state(state, gate);
+            compensated_state = apply_gate_to_state(compensated_state, gate);
+        }
+
+        assert_eq!(compensated_state.accumulation(), Accumulation::Compensated);
+        assert_eq!(compensated_state.to_string(), state.to_string());
+    }
+
+    /// Tests that applying a layer of gates in one pass gives the same state as applying
+    /// them one at a time, with each ordering and accumulation setting.
+    #[test]
+    fn test_apply_layer_to_state() {
+        let h = Gate::H {
+            target: QubitRef(0),
+        }
+        .single_qubit_matrix()
+        .unwrap();
+        let t = Gate::T {
+            target: QubitRef(0),
+        }
+        .single_qubit_matrix()
+        .unwrap();
+        let layers = [
+            vec![
+                Gate::H {
+                    target: QubitRef(0),
+                },
+                Gate::H {
+                    target: QubitRef(1),
+                },
+                Gate::H {
+                    target: QubitRef(2),
+                },
+            ],
+            vec![
+                Gate::T {
+                    target: QubitRef(0),
+                },
+                Gate::CX {
+                    control: QubitRef(1),
+                    target: QubitRef(3),
+                },
+                Gate::Unitary {
+                    target: QubitRef(2),
+                    matrix: multiply(&h, &t),
+                },
+            ],
+            vec![
+                Gate::H {
+                    target: QubitRef(3),
+                },
+                Gate::RZ {
+                    target: QubitRef(1),
+                    theta: 0.3,
+                },
+                Gate::H {
+                    target: QubitRef(0),
+                },
+            ],
+        ];
+
+        for (canonical, accumulation) in [
+            (false, Accumulation::Sequential),
+            (true, Accumulation::Sequential),
+            (false, Accumulation::Compensated),
+        ] {
+            let mut state = State::new(4);
+            state.add_or_insert(Ket::new_zero_ket(4));
+            state.set_canonical(canonical);
+            state.set_accumulation(accumulation);
+            let mut layered_state = state.clone();
+
+            for layer in &layers {
+                for gate in layer {
+                    state = apply_gate_to_state(state, gate);
+                }
+                layered_state = apply_layer_to_state(layered_state, layer);
+            }
+
+            assert_eq!(layered_state.is_canonical(), canonical);
+            assert_eq!(layered_state.accumulation(), accumulation);
+            assert_eq!(layered_state.to_string(), state.to_string());
+        }
+    }
 }
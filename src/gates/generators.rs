@@ -0,0 +1,184 @@
+use crate::gates::gate::Gate;
+use crate::quantum::register::QubitRef;
+use crate::quantum::sampling::Rng;
+use std::f64::consts::PI;
+
+/// Generates a randomized benchmarking sequence: `length` layers of random Clifford
+/// gates followed by the inverse of the whole sequence, so that the circuit is the
+/// identity and the zero state should be measured with certainty.
+///
+/// Each layer applies one of the 24 single qubit Cliffords to every qubit, then a CX to
+/// each of a random set of disjoint pairs of qubits.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::generators::randomized_benchmarking;
+/// use quantum_simulator::quantum::sampling::Rng;
+///
+/// let gates = randomized_benchmarking(3, 10, &mut Rng::new(0));
+/// assert!(gates.iter().all(|gate| gate.is_clifford()));
+/// ```
+pub fn randomized_benchmarking(num_qubits: usize, length: usize, rng: &mut Rng) -> Vec<Gate> {
+    let gates: Vec<Gate> = (0..length)
+        .flat_map(|_| random_layer(num_qubits, rng, random_clifford))
+        .collect();
+    mirror(gates)
+}
+
+/// Generates a mirror circuit: `depth` layers of random single qubit gates, including
+/// non-Clifford rotations, and CX gates, followed by their inverse in reverse order.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::generators::mirror_circuit;
+/// use quantum_simulator::quantum::sampling::Rng;
+///
+/// let gates = mirror_circuit(2, 4, &mut Rng::new(0));
+/// let half = gates.len() / 2;
+/// assert_eq!(gates[half - 1].inverse(), gates[half]);
+/// ```
+pub fn mirror_circuit(num_qubits: usize, depth: usize, rng: &mut Rng) -> Vec<Gate> {
+    let gates: Vec<Gate> = (0..depth)
+        .flat_map(|_| random_layer(num_qubits, rng, random_rotation))
+        .collect();
+    mirror(gates)
+}
+
+/// Returns the gates followed by their inverse, which together are the identity.
+fn mirror(mut gates: Vec<Gate>) -> Vec<Gate> {
+    let inverse: Vec<Gate> = gates.iter().rev().map(Gate::inverse).collect();
+    gates.extend(inverse);
+    gates
+}
+
+/// Returns a layer with a random single qubit gate on each qubit, from `single_qubit`,
+/// followed by CX gates on half of a random pairing of the qubits.
+fn random_layer(
+    num_qubits: usize,
+    rng: &mut Rng,
+    single_qubit: fn(QubitRef, &mut Rng) -> Vec<Gate>,
+) -> Vec<Gate> {
+    let mut layer: Vec<Gate> = (0..num_qubits)
+        .map(QubitRef)
+        .flat_map(|target| single_qubit(target, rng))
+        .collect();
+
+    // Shuffle the qubits and pair up neighbours.
+    let mut qubits: Vec<usize> = (0..num_qubits).collect();
+    for index in (1..qubits.len()).rev() {
+        qubits.swap(index, random_below(index + 1, rng));
+    }
+    for pair in qubits.chunks_exact(2) {
+        if random_below(2, rng) == 1 {
+            layer.push(Gate::CX {
+                control: QubitRef(pair[0]),
+                target: QubitRef(pair[1]),
+            });
+        }
+    }
+    layer
+}
+
+/// Returns a uniformly random single qubit Clifford, up to a global phase, as a Pauli
+/// followed by one of the six Cliffords that permute the X, Y and Z axes.
+fn random_clifford(target: QubitRef, rng: &mut Rng) -> Vec<Gate> {
+    let h = || Gate::H { target };
+    let s = || Gate::RZ {
+        target,
+        theta: PI / 2.0,
+    };
+    let mut gates = match random_below(4, rng) {
+        0 => vec![],
+        1 => vec![Gate::X { target }],
+        2 => vec![Gate::RZ { target, theta: PI }],
+        _ => vec![Gate::X { target }, Gate::RZ { target, theta: PI }],
+    };
+    gates.extend(match random_below(6, rng) {
+        0 => vec![],
+        1 => vec![h()],
+        2 => vec![s()],
+        3 => vec![h(), s()],
+        4 => vec![s(), h()],
+        _ => vec![h(), s(), h()],
+    });
+    gates
+}
+
+/// Returns a random single qubit gate, which is usually not a Clifford.
+fn random_rotation(target: QubitRef, rng: &mut Rng) -> Vec<Gate> {
+    match random_below(4, rng) {
+        0 => vec![Gate::H { target }],
+        1 => vec![Gate::T { target }],
+        2 => vec![Gate::TDgr { target }],
+        _ => vec![
+            Gate::H { target },
+            Gate::RZ {
+                target,
+                theta: 2.0 * PI * rng.next_f64(),
+            },
+        ],
+    }
+}
+
+/// Returns a random number below `bound`.
+fn random_below(bound: usize, rng: &mut Rng) -> usize {
+    (rng.next_f64() * bound as f64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::gate::apply_gate_to_state;
+    use crate::gates::kernels::{multiply, Matrix2};
+    use crate::quantum::ket::Ket;
+    use crate::quantum::state::State;
+    use num::Complex;
+
+    /// Tests that the generated circuits return the zero state to itself.
+    #[test]
+    fn test_circuits_are_identity() {
+        let mut rng = Rng::new(11);
+        for gates in [
+            randomized_benchmarking(4, 20, &mut rng),
+            mirror_circuit(4, 20, &mut rng),
+        ] {
+            let mut state = State::new(4);
+            state.add_or_insert(Ket::new_zero_ket(4));
+            let state = gates.iter().fold(state, apply_gate_to_state);
+            let probabilities = state.marginal_probabilities(&[0, 1, 2, 3]);
+            assert!((probabilities[&0] - 1.0).abs() < 1e-9, "{probabilities:?}");
+        }
+    }
+
+    /// Tests that the random Cliffords cover the 24 distinct single qubit Cliffords.
+    #[test]
+    fn test_random_clifford_coverage() {
+        let mut rng = Rng::new(0);
+        let mut seen: Vec<[(i64, i64); 4]> = Vec::new();
+        for _ in 0..2000 {
+            let matrix = random_clifford(QubitRef(0), &mut rng)
+                .iter()
+                .map(|gate| gate.single_qubit_matrix().unwrap())
+                .fold(identity(), |matrix, gate| multiply(&gate, &matrix));
+            // Compare the matrices up to a global phase, fixed by the first non-zero entry.
+            let entries = [matrix[0][0], matrix[0][1], matrix[1][0], matrix[1][1]];
+            let first = entries.iter().find(|entry| entry.norm() > 1e-6).unwrap();
+            let phase = first.conj() / first.norm();
+            let key = entries.map(|entry| {
+                let entry = entry * phase * 1000.0;
+                (entry.re.round() as i64, entry.im.round() as i64)
+            });
+            if !seen.contains(&key) {
+                seen.push(key);
+            }
+        }
+        assert_eq!(seen.len(), 24);
+    }
+
+    fn identity() -> Matrix2 {
+        let zero = Complex::new(0.0, 0.0);
+        let one = Complex::new(1.0, 0.0);
+        [[one, zero], [zero, one]]
+    }
+}
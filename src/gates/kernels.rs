@@ -0,0 +1,312 @@
+use num::Complex;
+
+/// A 2x2 gate matrix acting on a single qubit.
+pub type Matrix2 = [[Complex<f64>; 2]; 2];
+
+/// A 4x4 gate matrix acting on two qubits `(low, high)`, indexed by `low + 2 * high`.
+pub type Matrix4 = [[Complex<f64>; 4]; 4];
+
+/// Returns the matrix product `lhs * rhs`.
+pub(crate) fn multiply(lhs: &Matrix2, rhs: &Matrix2) -> Matrix2 {
+    let mut product = [[Complex::new(0.0, 0.0); 2]; 2];
+    for (row, values) in product.iter_mut().enumerate() {
+        for (column, value) in values.iter_mut().enumerate() {
+            *value = lhs[row][0] * rhs[0][column] + lhs[row][1] * rhs[1][column];
+        }
+    }
+    product
+}
+
+/// Returns the conjugate transpose of a matrix.
+pub(crate) fn adjoint(matrix: &Matrix2) -> Matrix2 {
+    [
+        [matrix[0][0].conj(), matrix[1][0].conj()],
+        [matrix[0][1].conj(), matrix[1][1].conj()],
+    ]
+}
+
+/// A single complex amplitude as held in registers while a kernel runs.
+///
+/// Dense state vectors are stored as interleaved complex amplitudes, so the real and
+/// imaginary parts of basis state `i` are at `2 * i` and `2 * i + 1`. Each kernel is
+/// written once against this trait and compiled with either the scalar implementation
+/// or, with the `simd` feature on x86_64, an SSE2 implementation that handles a whole
+/// complex amplitude per instruction.
+trait Lane: Copy {
+    fn load(amplitudes: &[f64], index: usize) -> Self;
+    fn store(self, amplitudes: &mut [f64], index: usize);
+    fn zero() -> Self;
+    /// Returns `self * factor + addend`.
+    fn mul_add(self, factor: Complex<f64>, addend: Self) -> Self;
+}
+
+impl Lane for Complex<f64> {
+    #[inline(always)]
+    fn load(amplitudes: &[f64], index: usize) -> Self {
+        Complex::new(amplitudes[2 * index], amplitudes[2 * index + 1])
+    }
+
+    #[inline(always)]
+    fn store(self, amplitudes: &mut [f64], index: usize) {
+        amplitudes[2 * index] = self.re;
+        amplitudes[2 * index + 1] = self.im;
+    }
+
+    #[inline(always)]
+    fn zero() -> Self {
+        Complex::new(0.0, 0.0)
+    }
+
+    #[inline(always)]
+    fn mul_add(self, factor: Complex<f64>, addend: Self) -> Self {
+        self * factor + addend
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod sse2 {
+    use super::Lane;
+    use num::Complex;
+    use std::arch::x86_64::*;
+
+    /// A complex amplitude packed as `[re, im]` in an SSE2 register.
+    #[derive(Clone, Copy)]
+    pub struct Sse2(__m128d);
+
+    // SSE2 is part of the x86_64 baseline, so these intrinsics are always available.
+    impl Lane for Sse2 {
+        #[inline(always)]
+        fn load(amplitudes: &[f64], index: usize) -> Self {
+            let pair = &amplitudes[2 * index..2 * index + 2];
+            unsafe { Sse2(_mm_loadu_pd(pair.as_ptr())) }
+        }
+
+        #[inline(always)]
+        fn store(self, amplitudes: &mut [f64], index: usize) {
+            let pair = &mut amplitudes[2 * index..2 * index + 2];
+            unsafe { _mm_storeu_pd(pair.as_mut_ptr(), self.0) }
+        }
+
+        #[inline(always)]
+        fn zero() -> Self {
+            unsafe { Sse2(_mm_setzero_pd()) }
+        }
+
+        #[inline(always)]
+        fn mul_add(self, factor: Complex<f64>, addend: Self) -> Self {
+            unsafe {
+                // [re * f.re, im * f.re] + [-im * f.im, re * f.im]
+                let real = _mm_mul_pd(self.0, _mm_set1_pd(factor.re));
+                let swapped = _mm_shuffle_pd(self.0, self.0, 0b01);
+                let imaginary = _mm_mul_pd(swapped, _mm_set_pd(factor.im, -factor.im));
+                Sse2(_mm_add_pd(_mm_add_pd(real, imaginary), addend.0))
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+type Native = sse2::Sse2;
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+type Native = Complex<f64>;
+
+/// Applies a single qubit gate matrix to `target`.
+///
+/// # Examples
+/// ```
+/// use num::Complex;
+/// use quantum_simulator::gates::kernels::apply_single_qubit;
+///
+/// let one = Complex::new(1.0, 0.0);
+/// let zero = Complex::new(0.0, 0.0);
+/// let mut amplitudes = vec![1.0, 0.0, 0.0, 0.0];
+/// apply_single_qubit(&mut amplitudes, 0, &[[zero, one], [one, zero]]);
+/// assert_eq!(amplitudes, vec![0.0, 0.0, 1.0, 0.0]);
+/// ```
+pub fn apply_single_qubit(amplitudes: &mut [f64], target: usize, matrix: &Matrix2) {
+    single_qubit::<Native>(amplitudes, target, matrix);
+}
+
+/// Applies a diagonal single qubit gate, multiplying amplitudes where `target` is 0 by
+/// `phases[0]` and amplitudes where it is 1 by `phases[1]`.
+pub fn apply_diagonal(amplitudes: &mut [f64], target: usize, phases: [Complex<f64>; 2]) {
+    diagonal::<Native>(amplitudes, target, phases);
+}
+
+/// Applies a controlled X gate, swapping the amplitude pairs of `target` wherever
+/// `control` is 1.
+pub fn apply_cx(amplitudes: &mut [f64], control: usize, target: usize) {
+    let target_mask = 1 << target;
+    for index in 0..amplitudes.len() / 2 {
+        if index & (1 << control) != 0 && index & target_mask == 0 {
+            let flipped = index | target_mask;
+            amplitudes.swap(2 * index, 2 * flipped);
+            amplitudes.swap(2 * index + 1, 2 * flipped + 1);
+        }
+    }
+}
+
+/// Applies a two qubit gate matrix to the qubits `low` and `high`.
+///
+/// # Examples
+/// ```
+/// use num::Complex;
+/// use quantum_simulator::gates::kernels::apply_two_qubit;
+///
+/// // A swap gate exchanges |01⟩ and |10⟩.
+/// let mut swap = [[Complex::new(0.0, 0.0); 4]; 4];
+/// swap[0][0] = Complex::new(1.0, 0.0);
+/// swap[1][2] = Complex::new(1.0, 0.0);
+/// swap[2][1] = Complex::new(1.0, 0.0);
+/// swap[3][3] = Complex::new(1.0, 0.0);
+///
+/// let mut amplitudes = vec![0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+/// apply_two_qubit(&mut amplitudes, 0, 1, &swap);
+/// assert_eq!(amplitudes, vec![0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0]);
+/// ```
+pub fn apply_two_qubit(amplitudes: &mut [f64], low: usize, high: usize, matrix: &Matrix4) {
+    two_qubit::<Native>(amplitudes, low, high, matrix);
+}
+
+#[inline(always)]
+fn single_qubit<L: Lane>(amplitudes: &mut [f64], target: usize, matrix: &Matrix2) {
+    let stride = 1 << target;
+    let len = amplitudes.len() / 2;
+    for block in (0..len).step_by(2 * stride) {
+        for index in block..block + stride {
+            let zero = L::load(amplitudes, index);
+            let one = L::load(amplitudes, index + stride);
+            let new_zero = zero.mul_add(matrix[0][0], one.mul_add(matrix[0][1], L::zero()));
+            let new_one = zero.mul_add(matrix[1][0], one.mul_add(matrix[1][1], L::zero()));
+            new_zero.store(amplitudes, index);
+            new_one.store(amplitudes, index + stride);
+        }
+    }
+}
+
+#[inline(always)]
+fn diagonal<L: Lane>(amplitudes: &mut [f64], target: usize, phases: [Complex<f64>; 2]) {
+    for index in 0..amplitudes.len() / 2 {
+        let phase = phases[(index >> target) & 1];
+        L::load(amplitudes, index)
+            .mul_add(phase, L::zero())
+            .store(amplitudes, index);
+    }
+}
+
+#[inline(always)]
+fn two_qubit<L: Lane>(amplitudes: &mut [f64], low: usize, high: usize, matrix: &Matrix4) {
+    let offsets = [0, 1 << low, 1 << high, (1 << low) | (1 << high)];
+    let mask = offsets[3];
+    for index in 0..amplitudes.len() / 2 {
+        // Visit each group of four amplitudes once, from its member with both bits 0.
+        if index & mask != 0 {
+            continue;
+        }
+        let inputs = offsets.map(|offset| L::load(amplitudes, index + offset));
+        for (row, offset) in offsets.iter().enumerate() {
+            let output = inputs
+                .iter()
+                .zip(matrix[row])
+                .fold(L::zero(), |sum, (input, factor)| input.mul_add(factor, sum));
+            output.store(amplitudes, index + offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Helper function to create a pseudo-random interleaved state vector.
+    fn amplitudes(num_qubits: usize) -> Vec<f64> {
+        (0..2 << num_qubits)
+            .map(|index| ((index * 7919) % 101) as f64 / 101.0 - 0.5)
+            .collect()
+    }
+
+    /// Helper function to create a pseudo-random matrix.
+    fn matrix4() -> Matrix4 {
+        let mut matrix = [[Complex::new(0.0, 0.0); 4]; 4];
+        for (row, values) in matrix.iter_mut().enumerate() {
+            for (column, value) in values.iter_mut().enumerate() {
+                *value = Complex::new((row * 4 + column) as f64, (row as f64) - (column as f64));
+            }
+        }
+        matrix
+    }
+
+    fn assert_close(left: &[f64], right: &[f64]) {
+        for (left, right) in left.iter().zip(right) {
+            assert!((left - right).abs() < 1e-12, "{left} != {right}");
+        }
+    }
+
+    /// Tests that the native kernels agree with the scalar kernels.
+    #[test]
+    fn test_native_kernels_match_scalar() {
+        let matrix = [
+            [Complex::new(0.1, 0.2), Complex::new(0.3, -0.4)],
+            [Complex::new(-0.5, 0.6), Complex::new(0.7, 0.8)],
+        ];
+        let phases = [Complex::new(0.6, 0.8), Complex::new(0.0, -1.0)];
+        for target in 0..4 {
+            let mut scalar = amplitudes(4);
+            let mut native = scalar.clone();
+            single_qubit::<Complex<f64>>(&mut scalar, target, &matrix);
+            apply_single_qubit(&mut native, target, &matrix);
+            assert_close(&native, &scalar);
+
+            diagonal::<Complex<f64>>(&mut scalar, target, phases);
+            apply_diagonal(&mut native, target, phases);
+            assert_close(&native, &scalar);
+        }
+        for (low, high) in [(0, 1), (1, 3), (3, 0)] {
+            let mut scalar = amplitudes(4);
+            let mut native = scalar.clone();
+            two_qubit::<Complex<f64>>(&mut scalar, low, high, &matrix4());
+            apply_two_qubit(&mut native, low, high, &matrix4());
+            assert_close(&native, &scalar);
+        }
+    }
+
+    /// Tests the single qubit kernel against a hand computed result.
+    #[test]
+    fn test_apply_single_qubit() {
+        let matrix = [
+            [Complex::new(1.0, 0.0), Complex::new(0.0, 1.0)],
+            [Complex::new(2.0, 0.0), Complex::new(0.0, 0.0)],
+        ];
+        // The amplitudes of |0⟩ and |1⟩ for qubit 1 are at indices 0 and 2.
+        let mut amplitudes = vec![1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+        apply_single_qubit(&mut amplitudes, 1, &matrix);
+        assert_close(&amplitudes, &[0.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0]);
+    }
+
+    /// Tests that a controlled X only swaps amplitudes where the control is set.
+    #[test]
+    fn test_apply_cx() {
+        let mut amplitudes: Vec<f64> = (0..8).map(|index| index as f64).collect();
+        apply_cx(&mut amplitudes, 0, 1);
+        assert_eq!(amplitudes, vec![0.0, 1.0, 6.0, 7.0, 4.0, 5.0, 2.0, 3.0]);
+    }
+
+    /// Tests that the two qubit kernel matches applying the matrix to each group.
+    #[test]
+    fn test_apply_two_qubit_identity_and_cx() {
+        let zero = Complex::new(0.0, 0.0);
+        let one = Complex::new(1.0, 0.0);
+        let mut cx = [[zero; 4]; 4];
+        cx[0][0] = one;
+        cx[1][3] = one;
+        cx[2][2] = one;
+        cx[3][1] = one;
+
+        let mut expected = amplitudes(3);
+        let mut amplitudes = expected.clone();
+        apply_cx(&mut expected, 2, 0);
+        apply_two_qubit(&mut amplitudes, 2, 0, &cx);
+        assert_close(&amplitudes, &expected);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::gates::gate::Gate;
+use std::collections::BTreeSet;
+
+/// Removes the gates that cannot affect the state of any of the `outputs` qubits.
+///
+/// Walking backwards from the end of the circuit, a gate is kept if it acts on a qubit
+/// in the lightcone, and then all of its qubits join the lightcone. Every other gate
+/// only changes qubits that never interact with the outputs again, so the reduced state
+/// of the outputs is the same without it.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::lightcone::eliminate_dead_gates;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let gates = [
+///     Gate::H { target: QubitRef(2) },
+///     Gate::H { target: QubitRef(0) },
+///     Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+///     Gate::X { target: QubitRef(0) },
+/// ];
+/// let kept = eliminate_dead_gates(&gates, &[1]);
+/// let [q0, q1] = [0, 1].map(QubitRef);
+/// assert_eq!(kept, vec![Gate::H { target: q0 }, Gate::CX { control: q0, target: q1 }]);
+/// ```
+pub fn eliminate_dead_gates(gates: &[Gate], outputs: &[usize]) -> Vec<Gate> {
+    gates
+        .iter()
+        .zip(lightcone_mask(gates, outputs))
+        .filter(|(_, live)| *live)
+        .map(|(gate, _)| gate.clone())
+        .collect()
+}
+
+/// Returns whether each gate is in the backward lightcone of the `outputs` qubits, as
+/// described in [`eliminate_dead_gates`].
+pub fn lightcone_mask<'a>(
+    gates: impl IntoIterator<Item = &'a Gate, IntoIter = impl DoubleEndedIterator<Item = &'a Gate>>,
+    outputs: &[usize],
+) -> Vec<bool> {
+    let mut lightcone: BTreeSet<usize> = outputs.iter().copied().collect();
+    let mut mask: Vec<bool> = gates
+        .into_iter()
+        .rev()
+        .map(|gate| {
+            let qubits = gate.qubits();
+            let live = qubits.iter().any(|qubit| lightcone.contains(qubit));
+            if live {
+                lightcone.extend(qubits);
+            }
+            live
+        })
+        .collect();
+    mask.reverse();
+    mask
+}
+
+/// Renumbers the qubits so that only those used by `gates` or listed in `outputs` are
+/// left, keeping their order.
+///
+/// Returns the remapped gates along with the original index of each remaining qubit.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::lightcone::compact_qubits;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let cx = Gate::CX { control: QubitRef(4), target: QubitRef(1) };
+/// let (gates, qubits) = compact_qubits(&[cx], &[2]);
+/// assert_eq!(gates, vec![Gate::CX { control: QubitRef(2), target: QubitRef(0) }]);
+/// assert_eq!(qubits, vec![1, 2, 4]);
+/// ```
+pub fn compact_qubits(gates: &[Gate], outputs: &[usize]) -> (Vec<Gate>, Vec<usize>) {
+    let qubits = used_qubits(gates, outputs);
+    let gates = gates
+        .iter()
+        .map(|gate| gate.remap(|qubit| qubits.binary_search(&qubit).unwrap()))
+        .collect();
+    (gates, qubits)
+}
+
+/// Returns the qubits used by `gates` or listed in `outputs`, in increasing order.
+pub fn used_qubits<'a>(gates: impl IntoIterator<Item = &'a Gate>, outputs: &[usize]) -> Vec<usize> {
+    let mut used: BTreeSet<usize> = outputs.iter().copied().collect();
+    for gate in gates {
+        used.extend(gate.qubits());
+    }
+    used.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::register::QubitRef;
+
+    /// Tests that gates after the last interaction with the outputs are removed, while
+    /// gates that feed into the outputs through other qubits are kept.
+    #[test]
+    fn test_eliminate_dead_gates() {
+        let gates = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(3),
+            },
+            Gate::CX {
+                control: QubitRef(3),
+                target: QubitRef(2),
+            },
+            Gate::CX {
+                control: QubitRef(2),
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(2),
+            },
+            Gate::X {
+                target: QubitRef(0),
+            },
+        ];
+
+        assert_eq!(
+            eliminate_dead_gates(&gates, &[1]),
+            gates[1..4].to_vec(),
+            "gates on qubit 0 and the T after the last CX onto qubit 1 are dead"
+        );
+        assert_eq!(eliminate_dead_gates(&gates, &[0]).len(), 2);
+        assert_eq!(eliminate_dead_gates(&gates, &[0, 2]), gates.to_vec());
+        assert!(eliminate_dead_gates(&gates, &[]).is_empty());
+    }
+
+    /// Tests that compacting keeps unused outputs and the order of the qubits.
+    #[test]
+    fn test_compact_qubits() {
+        let gates = [
+            Gate::H {
+                target: QubitRef(5),
+            },
+            Gate::CX {
+                control: QubitRef(5),
+                target: QubitRef(3),
+            },
+        ];
+        let (compacted, qubits) = compact_qubits(&gates, &[0]);
+        assert_eq!(qubits, vec![0, 3, 5]);
+        assert_eq!(
+            compacted,
+            vec![
+                Gate::H {
+                    target: QubitRef(2)
+                },
+                Gate::CX {
+                    control: QubitRef(2),
+                    target: QubitRef(1)
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+
+/// The lines of the source an instruction, or a gate that replaces several instructions,
+/// was written on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub first_line: usize,
+    pub last_line: usize,
+}
+
+impl Span {
+    /// Creates a new `Span` of a single line.
+    pub fn line(line: usize) -> Self {
+        Self {
+            first_line: line,
+            last_line: line,
+        }
+    }
+
+    /// Returns the smallest span covering this span and `other`.
+    pub fn union(self, other: Span) -> Span {
+        Span {
+            first_line: self.first_line.min(other.first_line),
+            last_line: self.last_line.max(other.last_line),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.first_line == self.last_line {
+            true => write!(f, "line {}", self.first_line),
+            false => write!(f, "lines {}-{}", self.first_line, self.last_line),
+        }
+    }
+}
+
+/// Where a gate came from, so that errors and profiles can point to the source even
+/// after a pass such as [fusion](crate::gates::fusion::GateFuser) has rewritten the
+/// gates.
+///
+/// The label names the instruction, which for a gate call is the name of the gate that
+/// was called. Gates that were not written in the source, such as those applied
+/// directly to a simulator, have a label describing them and no span.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::origin::Origin;
+///
+/// let h = Origin::new("h", 3);
+/// assert_eq!(h.to_string(), "'h' on line 3");
+/// assert_eq!(h.merge(&Origin::new("t", 5)).to_string(), "'h+t' on lines 3-5");
+/// assert_eq!(Origin::unplaced("applied directly").to_string(), "applied directly");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub label: String,
+    pub span: Option<Span>,
+}
+
+impl Origin {
+    /// Creates a new `Origin` for an instruction with the given label on a line.
+    pub fn new(label: impl Into<String>, line: usize) -> Self {
+        Self {
+            label: label.into(),
+            span: Some(Span::line(line)),
+        }
+    }
+
+    /// Creates a new `Origin` for a gate that is not in the source.
+    pub fn unplaced(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            span: None,
+        }
+    }
+
+    /// Returns the origin of a gate that replaces gates from this origin and `other`,
+    /// with the labels of both, separated by `+`, and the span covering both.
+    pub fn merge(&self, other: &Origin) -> Origin {
+        let label = match self.label.split('+').any(|label| label == other.label) {
+            true => self.label.clone(),
+            false => format!["{}+{}", self.label, other.label],
+        };
+        let span = match (self.span, other.span) {
+            (Some(span), Some(other)) => Some(span.union(other)),
+            (span, other) => span.or(other),
+        };
+        Origin { label, span }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "'{}' on {span}", self.label),
+            None => write!(f, "{}", self.label),
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use crate::gates::gate::{apply_layer_to_kets, apply_layer_to_state, Gate};
+use crate::quantum::{ket::Ket, state::State};
+use std::{slice, thread};
+
+/// The default minimum number of kets given to each thread.
+pub const DEFAULT_MIN_CHUNK_SIZE: usize = 16384;
+
+/// How the kets of a state are divided between threads when applying a gate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parallelism {
+    /// The maximum number of threads to use.
+    pub threads: usize,
+    /// The minimum number of kets given to each thread. States with fewer kets than
+    /// this per thread use fewer threads, since spawning threads for small batches
+    /// costs more than it saves.
+    pub min_chunk_size: usize,
+}
+
+impl Parallelism {
+    /// Creates a `Parallelism` using the given number of threads and the default chunk
+    /// size.
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads,
+            min_chunk_size: DEFAULT_MIN_CHUNK_SIZE,
+        }
+    }
+
+    /// Returns the number of chunks a state with `num_kets` kets is divided into.
+    fn chunks(&self, num_kets: usize) -> usize {
+        self.threads
+            .min(num_kets / self.min_chunk_size.max(1))
+            .max(1)
+    }
+}
+
+impl Default for Parallelism {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+/// Apply a gate to a state, dividing the kets between several threads.
+///
+/// Kets are partitioned on every bit except the one the gate may flip, so all of the
+/// kets that can collide end up in the same chunk. Each thread then builds its part of
+/// the new state independently and the parts are combined without any further
+/// summation. The result is the same as
+/// [`apply_gate_to_state`](crate::gates::gate::apply_gate_to_state).
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::parallel::{apply_gate_to_state_parallel, Parallelism};
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::register::QubitRef;
+/// use quantum_simulator::quantum::state::State;
+///
+/// let mut state = State::new(2);
+/// state.add_or_insert(Ket::new_zero_ket(2));
+/// let parallelism = Parallelism { threads: 4, min_chunk_size: 1 };
+/// let [h0, h1] = [0, 1].map(|target| Gate::H { target: QubitRef(target) });
+/// let state = apply_gate_to_state_parallel(state, &h0, parallelism);
+/// let state = apply_gate_to_state_parallel(state, &h1, parallelism);
+/// assert_eq!(state.len(), 4);
+/// ```
+pub fn apply_gate_to_state_parallel(state: State, gate: &Gate, parallelism: Parallelism) -> State {
+    apply_layer_to_state_parallel(state, slice::from_ref(gate), parallelism)
+}
+
+/// Apply a layer of gates to a state in a single pass, dividing the kets between several
+/// threads.
+///
+/// Kets are partitioned on every bit except those the gates of the layer may flip, and
+/// each thread passes its kets through the whole layer as
+/// [`apply_layer_to_state`] does. The result is the same as applying the gates one at a
+/// time.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::parallel::{apply_layer_to_state_parallel, Parallelism};
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::register::QubitRef;
+/// use quantum_simulator::quantum::state::State;
+///
+/// let mut state = State::new(2);
+/// state.add_or_insert(Ket::new_zero_ket(2));
+/// let parallelism = Parallelism { threads: 4, min_chunk_size: 1 };
+/// let layer = [Gate::H { target: QubitRef(0) }, Gate::H { target: QubitRef(1) }];
+/// let state = apply_layer_to_state_parallel(state, &layer, parallelism);
+/// assert_eq!(state.len(), 4);
+/// ```
+pub fn apply_layer_to_state_parallel(
+    state: State,
+    layer: &[Gate],
+    parallelism: Parallelism,
+) -> State {
+    let num_chunks = parallelism.chunks(state.len());
+    if num_chunks == 1 {
+        return apply_layer_to_state(state, layer);
+    }
+
+    let flipped: Vec<usize> = layer.iter().filter_map(Gate::flipped_qubit).collect();
+    let mut chunks: Vec<Vec<Ket>> = vec![Vec::new(); num_chunks];
+    let empty_state = state.empty_like();
+    for ket in state.into_kets() {
+        chunks[chunk_of(&ket, &flipped, num_chunks)].push(ket);
+    }
+
+    let mut parts: Vec<State> = thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|kets| {
+                let new_state = empty_state.empty_like();
+                scope.spawn(move || apply_layer_to_kets(kets, layer, new_state))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Gate application thread panicked"))
+            .collect()
+    });
+
+    // The parts never share a ket, so they can simply be combined. Each part started
+    // from the probability pruned and metrics before this layer, so only what it added is
+    // kept.
+    parts.sort_by_key(|part| part.len());
+    let pruned_before = empty_state.pruned_probability();
+    let metrics_before = empty_state.metrics();
+    let mut new_state = parts.pop().unwrap_or(empty_state);
+    new_state.reserve(parts.iter().map(|part| part.len()).sum());
+    for part in parts {
+        new_state.add_pruned_probability(part.pruned_probability() - pruned_before);
+        *new_state.metrics_mut() += part.metrics() - metrics_before;
+        new_state.extend_disjoint(part.into_kets());
+    }
+    new_state
+}
+
+/// Returns the chunk a ket belongs to, ignoring the values of the `flipped` qubits.
+fn chunk_of(ket: &Ket, flipped: &[usize], num_chunks: usize) -> usize {
+    let bits = ket.bit_vec();
+    let word_bits = usize::BITS as usize;
+    let mut key: usize = 0;
+    for (index, word) in bits.as_raw_slice().iter().enumerate() {
+        let mut word = *word;
+        // Ignore any unused bits at the end of the last word.
+        let live_bits = bits.len() - index * word_bits;
+        if live_bits < word_bits {
+            word &= (1 << live_bits) - 1;
+        }
+        for qubit in flipped.iter().filter(|qubit| *qubit / word_bits == index) {
+            word &= !(1 << (qubit % word_bits));
+        }
+        key = (key.rotate_left(5) ^ word).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+    (key ^ (key >> 29)) % num_chunks
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::gate::apply_gate_to_state;
+    use crate::quantum::register::QubitRef;
+    use crate::quantum::state::Accumulation;
+
+    /// Tests that the parallel path gives the same state as the sequential path.
+    #[test]
+    fn test_apply_gate_to_state_parallel() {
+        let mut gates = Vec::new();
+        for target in 0..6 {
+            gates.push(Gate::H {
+                target: QubitRef(target),
+            });
+        }
+        for target in 0..5 {
+            gates.push(Gate::CX {
+                control: QubitRef(target),
+                target: QubitRef(target + 1),
+            });
+            gates.push(Gate::T {
+                target: QubitRef(target),
+            });
+            gates.push(Gate::H {
+                target: QubitRef(target + 1),
+            });
+        }
+
+        let parallelism = Parallelism {
+            threads: 3,
+            min_chunk_size: 2,
+        };
+        for accumulation in [Accumulation::Sequential, Accumulation::Compensated] {
+            let mut state = State::new(6);
+            state.add_or_insert(Ket::new_zero_ket(6));
+            state.set_accumulation(accumulation);
+            let mut parallel_state = state.clone();
+
+            for gate in &gates {
+                state = apply_gate_to_state(state, gate);
+                parallel_state = apply_gate_to_state_parallel(parallel_state, gate, parallelism);
+            }
+
+            assert_eq!(parallel_state.accumulation(), accumulation);
+            assert_eq!(parallel_state.to_string(), state.to_string());
+            let pruned = parallel_state.pruned_probability() - state.pruned_probability();
+            assert!(pruned.abs() < 1e-15);
+            assert_eq!(parallel_state.metrics(), state.metrics());
+        }
+    }
+
+    /// Tests that the parallel path for a layer gives the same state as the sequential
+    /// path, including when the flipped qubits of the layer are in different words.
+    #[test]
+    fn test_apply_layer_to_state_parallel() {
+        let num_qubits = 70;
+        let layers = [
+            vec![
+                Gate::H {
+                    target: QubitRef(0),
+                },
+                Gate::H {
+                    target: QubitRef(5),
+                },
+                Gate::H {
+                    target: QubitRef(64),
+                },
+                Gate::H {
+                    target: QubitRef(69),
+                },
+            ],
+            vec![
+                Gate::CX {
+                    control: QubitRef(0),
+                    target: QubitRef(64),
+                },
+                Gate::T {
+                    target: QubitRef(5),
+                },
+                Gate::H {
+                    target: QubitRef(69),
+                },
+            ],
+            vec![
+                Gate::H {
+                    target: QubitRef(0),
+                },
+                Gate::CX {
+                    control: QubitRef(69),
+                    target: QubitRef(5),
+                },
+                Gate::H {
+                    target: QubitRef(64),
+                },
+            ],
+        ];
+
+        let parallelism = Parallelism {
+            threads: 3,
+            min_chunk_size: 2,
+        };
+        let mut state = State::new(num_qubits);
+        state.add_or_insert(Ket::new_zero_ket(num_qubits));
+        let mut parallel_state = state.clone();
+        for layer in &layers {
+            state = apply_layer_to_state(state, layer);
+            parallel_state = apply_layer_to_state_parallel(parallel_state, layer, parallelism);
+        }
+
+        assert_eq!(parallel_state.to_string(), state.to_string());
+        assert_eq!(parallel_state.metrics(), state.metrics());
+    }
+
+    /// Tests that kets differing only in the flipped qubit share a chunk.
+    #[test]
+    fn test_chunk_of_ignores_flipped_qubit() {
+        let num_qubits = 70;
+        for qubit in [0, 3, 63, 64, 69] {
+            for pattern in 0..16 {
+                let mut ket = Ket::new_zero_ket(num_qubits);
+                for bit in 0..4 {
+                    if pattern & (1 << bit) != 0 {
+                        ket.flip(bit * 17);
+                    }
+                }
+                let mut flipped_ket = ket.clone();
+                flipped_ket.flip(qubit);
+                assert_eq!(
+                    chunk_of(&ket, &[qubit], 7),
+                    chunk_of(&flipped_ket, &[qubit], 7)
+                );
+            }
+        }
+    }
+}
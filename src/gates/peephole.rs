@@ -0,0 +1,530 @@
+use crate::gates::gate::Gate;
+use crate::qasm::definitions::GateDefinitions;
+use crate::qasm::lowering::{Lowering, Operation};
+use crate::qasm::parser::Parser;
+use crate::quantum::dense::DenseState;
+use crate::quantum::register::QubitRef;
+use crate::quantum::tolerance::Tolerance;
+use std::collections::BTreeMap;
+use std::io;
+
+/// The rules every [`PeepholeOptimizer`] starts with, in the format of a rules file.
+pub const BUILT_IN_RULES: &str = "\
+h a; h a ->
+x a; x a ->
+t a; tdg a ->
+tdg a; t a ->
+cx a, b; cx a, b ->
+t a; t a -> rz(pi/2) a
+tdg a; tdg a -> rz(-pi/2) a
+h a; x a; h a -> rz(pi) a
+h a; rz(pi) a; h a -> x a
+h a; rz(pi/2) a; h a -> rz(-pi/2) a; h a; rz(-pi/2) a
+";
+
+/// The most qubits a rule can act on, since it is checked with dense matrices.
+const MAX_RULE_QUBITS: usize = 4;
+
+/// How far apart the matrices of a rule's two sides, or the angles of two gates, can be
+/// and still be treated as the same.
+const RULE_TOLERANCE: Tolerance = Tolerance::absolute(1e-9);
+
+/// A rule that replaces a sequence of gates with an equal one, up to a global phase.
+///
+/// The qubits of both sides are numbered from zero in the order they first appear in
+/// the pattern. Each gate of the pattern after the first shares a qubit with the gates
+/// before it, so a match is a connected piece of the circuit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RewriteRule {
+    pub pattern: Vec<Gate>,
+    pub replacement: Vec<Gate>,
+    /// The number of qubits the rule acts on.
+    pub num_qubits: usize,
+}
+
+impl RewriteRule {
+    /// Creates a rule, checking that the pattern is connected and that the two sides
+    /// have the same matrix up to a global phase.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::gates::peephole::RewriteRule;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let cx = |control, target| Gate::CX {
+    ///     control: QubitRef(control),
+    ///     target: QubitRef(target),
+    /// };
+    /// // Three CX gates swap two qubits whichever way the middle one points.
+    /// let rule = RewriteRule::new(
+    ///     vec![cx(0, 1), cx(1, 0), cx(0, 1)],
+    ///     vec![cx(1, 0), cx(0, 1), cx(1, 0)],
+    /// );
+    /// assert!(rule.is_ok());
+    /// assert!(RewriteRule::new(vec![cx(0, 1)], vec![cx(1, 0)]).is_err());
+    /// ```
+    pub fn new(pattern: Vec<Gate>, replacement: Vec<Gate>) -> io::Result<Self> {
+        let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        if pattern.is_empty() {
+            return invalid("A rule needs at least one gate to match");
+        }
+        let num_qubits = pattern
+            .iter()
+            .chain(&replacement)
+            .flat_map(|gate| gate.qubits())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        if num_qubits > MAX_RULE_QUBITS {
+            return invalid(&format![
+                "A rule can act on at most {MAX_RULE_QUBITS} qubits"
+            ]);
+        }
+        let mut seen = vec![false; num_qubits];
+        for (index, gate) in pattern.iter().enumerate() {
+            if index > 0 && !gate.qubits().iter().any(|qubit| seen[*qubit]) {
+                return invalid(
+                    "Each gate of a pattern must share a qubit with the gates before it",
+                );
+            }
+            gate.qubits().iter().for_each(|qubit| seen[*qubit] = true);
+        }
+        if seen.contains(&false) {
+            return invalid("A replacement can only act on the qubits of its pattern");
+        }
+        if !same_up_to_phase(num_qubits, &pattern, &replacement) {
+            return invalid("The pattern and replacement do not have the same matrix");
+        }
+        Ok(Self {
+            pattern,
+            replacement,
+            num_qubits,
+        })
+    }
+}
+
+/// Returns whether two circuits have the same matrix up to a global phase, comparing the
+/// states they prepare from each basis state.
+fn same_up_to_phase(num_qubits: usize, first: &[Gate], second: &[Gate]) -> bool {
+    let mut phase = None;
+    (0..1 << num_qubits).all(|basis_state: usize| {
+        let [first, second] = [first, second].map(|gates| {
+            // Rules have at most MAX_RULE_QUBITS qubits, so the state is small.
+            let mut state = DenseState::new(num_qubits).unwrap();
+            (0..num_qubits)
+                .filter(|qubit| basis_state >> qubit & 1 == 1)
+                .for_each(|target| {
+                    state.apply_gate(&Gate::X {
+                        target: QubitRef(target),
+                    })
+                });
+            gates.iter().for_each(|gate| state.apply_gate(gate));
+            state
+        });
+        (0..1 << num_qubits).all(|index| {
+            let (a, b) = (first.amplitude(index), second.amplitude(index));
+            if !RULE_TOLERANCE.is_negligible(b) && phase.is_none() {
+                phase = Some(a / b);
+            }
+            RULE_TOLERANCE.approx_eq_complex(a, b * phase.unwrap_or(num::Complex::new(1.0, 0.0)))
+        })
+    })
+}
+
+/// Parses rules, one `pattern -> replacement` per line, where each side is a list of
+/// built in gates in OpenQASM syntax separated by `;`, acting on qubits named by
+/// identifiers. The replacement may be empty. Blank lines and anything after a `#` are
+/// ignored.
+///
+/// Each rule is checked with [`RewriteRule::new`], and an error names the line of the
+/// first rule that is invalid.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::peephole::parse_rules;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let rules = parse_rules("# Hadamards conjugate X into Z.\nh q; x q; h q -> rz(pi) q\n").unwrap();
+/// assert_eq!(
+///     rules[0].replacement,
+///     vec![Gate::RZ { target: QubitRef(0), theta: std::f64::consts::PI }]
+/// );
+///
+/// let error = parse_rules("\nh a -> x a").unwrap_err();
+/// assert!(error.to_string().starts_with("Rule on line 2:"));
+/// ```
+pub fn parse_rules(text: &str) -> io::Result<Vec<RewriteRule>> {
+    let mut rules = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let rule = parse_rule(line).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["Rule on line {}: {error}", index + 1],
+            )
+        })?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+/// Parses a single `pattern -> replacement` rule.
+fn parse_rule(line: &str) -> io::Result<RewriteRule> {
+    let Some((pattern, replacement)) = line.split_once("->") else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected 'pattern -> replacement'",
+        ));
+    };
+    let mut variables = Vec::new();
+    let pattern = parse_side(pattern, &mut variables)?;
+    let replacement = parse_side(replacement, &mut variables)?;
+    RewriteRule::new(pattern, replacement)
+}
+
+/// Parses one side of a rule into gates, numbering the qubit names in `variables` by
+/// when they first appear.
+fn parse_side(side: &str, variables: &mut Vec<String>) -> io::Result<Vec<Gate>> {
+    // Each instruction is rewritten with the qubit names as indices into one register,
+    // so that the parser and lowering can read it as a program.
+    let mut program = String::new();
+    for instruction in side
+        .split(';')
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+    {
+        let operands_start = match instruction.rfind(')') {
+            Some(index) => index + 1,
+            None => instruction
+                .find(char::is_whitespace)
+                .unwrap_or(instruction.len()),
+        };
+        let (call, operands) = instruction.split_at(operands_start);
+        let operands: Vec<String> = operands
+            .split(',')
+            .map(|name| {
+                let name = name.trim();
+                let index = match variables.iter().position(|variable| variable == name) {
+                    Some(index) => index,
+                    None => {
+                        variables.push(name.to_string());
+                        variables.len() - 1
+                    }
+                };
+                format!["q[{index}]"]
+            })
+            .collect();
+        program.push_str(&format!["{call} {};\n", operands.join(", ")]);
+    }
+    let program = format![
+        "OPENQASM 2.0;\nqreg q[{}];\n{program}",
+        variables.len().max(1)
+    ];
+    let mut lowering = Lowering::new(GateDefinitions::new());
+    let mut gates = Vec::new();
+    for statement in Parser::new(program.as_bytes()) {
+        if let Some(Operation::GateCall { gates: call, .. }) = lowering.lower(statement?)? {
+            gates.extend(call);
+        }
+    }
+    Ok(gates)
+}
+
+/// Rewrites circuits with a table of [`RewriteRule`]s, replacing each match of a
+/// pattern with its replacement.
+///
+/// A match is a sequence of gates that are consecutive on the qubits they act on, so
+/// gates on other qubits may come between them. The replacement is put where the last
+/// gate of the match was, which keeps the order of the gates on every qubit. Rules are
+/// tried in the order they were added, and passes over the circuit are repeated for as
+/// long as they make it shorter, so that rules that undo each other cannot loop.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::peephole::PeepholeOptimizer;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let gates = [
+///     Gate::H { target: QubitRef(0) },
+///     Gate::T { target: QubitRef(1) },
+///     Gate::H { target: QubitRef(0) },
+///     Gate::TDgr { target: QubitRef(1) },
+///     Gate::X { target: QubitRef(1) },
+/// ];
+/// let optimized = PeepholeOptimizer::new().optimize(&gates);
+/// assert_eq!(optimized, vec![Gate::X { target: QubitRef(1) }]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct PeepholeOptimizer {
+    rules: Vec<RewriteRule>,
+}
+
+impl Default for PeepholeOptimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeepholeOptimizer {
+    /// Creates an optimizer with the [`BUILT_IN_RULES`].
+    pub fn new() -> Self {
+        Self {
+            rules: parse_rules(BUILT_IN_RULES).unwrap(),
+        }
+    }
+
+    /// Adds rules, which are tried after the ones already in the table.
+    pub fn add_rules(&mut self, rules: impl IntoIterator<Item = RewriteRule>) {
+        self.rules.extend(rules);
+    }
+
+    /// Returns the rules in the order they are tried.
+    pub fn rules(&self) -> &[RewriteRule] {
+        &self.rules
+    }
+
+    /// Returns the circuit with the rules applied until a pass over it no longer makes it
+    /// shorter.
+    pub fn optimize(&self, gates: &[Gate]) -> Vec<Gate> {
+        let mut gates = gates.to_vec();
+        loop {
+            let optimized = self.pass(&gates);
+            let shorter = optimized.len() < gates.len();
+            gates = optimized;
+            if !shorter {
+                return gates;
+            }
+        }
+    }
+
+    /// Makes one pass over the circuit, replacing each match of a rule.
+    fn pass(&self, gates: &[Gate]) -> Vec<Gate> {
+        let num_qubits = gates
+            .iter()
+            .flat_map(|gate| gate.qubits())
+            .max()
+            .map_or(0, |qubit| qubit + 1);
+        let mut timelines: Vec<Vec<usize>> = vec![Vec::new(); num_qubits];
+        for (index, gate) in gates.iter().enumerate() {
+            gate.qubits()
+                .into_iter()
+                .for_each(|qubit| timelines[qubit].push(index));
+        }
+
+        let mut removed = vec![false; gates.len()];
+        let mut replacements: BTreeMap<usize, Vec<Gate>> = BTreeMap::new();
+        for start in 0..gates.len() {
+            if removed[start] {
+                continue;
+            }
+            let matched = self.rules.iter().find_map(|rule| {
+                match_at(rule, gates, start, &timelines, &removed).map(|(indices, qubits)| {
+                    let replacement: Vec<Gate> = rule
+                        .replacement
+                        .iter()
+                        .map(|gate| gate.remap(|qubit| qubits[qubit]))
+                        .collect();
+                    (indices, replacement)
+                })
+            });
+            if let Some((indices, replacement)) = matched {
+                indices.iter().for_each(|index| removed[*index] = true);
+                replacements.insert(*indices.last().unwrap(), replacement);
+            }
+        }
+
+        let mut optimized = Vec::with_capacity(gates.len());
+        for (index, gate) in gates.iter().enumerate() {
+            match replacements.remove(&index) {
+                Some(replacement) => optimized.extend(replacement),
+                None if !removed[index] => optimized.push(gate.clone()),
+                None => {}
+            }
+        }
+        optimized
+    }
+}
+
+/// Matches a rule's pattern starting at the gate at `start`, returning the indices of
+/// the matched gates and the circuit qubit of each of the rule's qubits.
+fn match_at(
+    rule: &RewriteRule,
+    gates: &[Gate],
+    start: usize,
+    timelines: &[Vec<usize>],
+    removed: &[bool],
+) -> Option<(Vec<usize>, Vec<usize>)> {
+    let mut qubits: Vec<Option<usize>> = vec![None; rule.num_qubits];
+    // The index of the last matched gate on each circuit qubit of the match.
+    let mut last_gates: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut indices = Vec::with_capacity(rule.pattern.len());
+    for pattern in &rule.pattern {
+        let index = match indices.is_empty() {
+            true => start,
+            // The next gate on any of the qubits of the match must be the next of the
+            // pattern, or other gates would come between the matched ones.
+            false => last_gates
+                .iter()
+                .filter_map(|(qubit, last)| {
+                    let timeline = &timelines[*qubit];
+                    timeline[timeline.partition_point(|index| index <= last)..]
+                        .iter()
+                        .find(|index| !removed[**index])
+                })
+                .min()
+                .copied()?,
+        };
+        let gate = &gates[index];
+        if !same_gate(pattern, gate) {
+            return None;
+        }
+        for (variable, qubit) in pattern.qubits().into_iter().zip(gate.qubits()) {
+            match qubits[variable] {
+                Some(bound) if bound != qubit => return None,
+                Some(_) => {}
+                None if qubits.contains(&Some(qubit)) => return None,
+                None => qubits[variable] = Some(qubit),
+            }
+            last_gates.insert(qubit, index);
+        }
+        indices.push(index);
+    }
+    Some((indices, qubits.into_iter().map(Option::unwrap).collect()))
+}
+
+/// Returns whether two gates are the same apart from their qubits.
+fn same_gate(pattern: &Gate, gate: &Gate) -> bool {
+    match (pattern, gate) {
+        (Gate::RZ { theta: a, .. }, Gate::RZ { theta: b, .. }) => RULE_TOLERANCE.approx_eq(*a, *b),
+        (Gate::Unitary { .. }, _) | (_, Gate::Unitary { .. }) => false,
+        _ => pattern.name() == gate.name(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::exact::{verify_equivalent, Verification};
+    use crate::gates::generators::randomized_benchmarking;
+    use crate::quantum::sampling::Rng;
+
+    /// Tests that the built in rules are all valid and that optimized circuits stay equal
+    /// to the original while getting no longer.
+    #[test]
+    fn test_optimize_random_circuits() {
+        let optimizer = PeepholeOptimizer::new();
+        assert_eq!(optimizer.rules().len(), BUILT_IN_RULES.lines().count());
+        let mut rng = Rng::new(5);
+        for _ in 0..10 {
+            let mut gates = randomized_benchmarking(3, 4, &mut rng);
+            gates.extend([
+                Gate::T {
+                    target: QubitRef(1),
+                },
+                Gate::T {
+                    target: QubitRef(1),
+                },
+            ]);
+            let optimized = optimizer.optimize(&gates);
+            assert!(optimized.len() < gates.len());
+            assert_eq!(
+                verify_equivalent(3, &gates, &optimized).unwrap(),
+                Verification::Equal
+            );
+        }
+    }
+
+    /// Tests that a match must be consecutive on every qubit it acts on.
+    #[test]
+    fn test_match_is_consecutive_per_qubit() {
+        let optimizer = PeepholeOptimizer::new();
+        let cx = Gate::CX {
+            control: QubitRef(0),
+            target: QubitRef(1),
+        };
+        // A gate on the target between the CX gates stops them cancelling.
+        let blocked = [
+            cx.clone(),
+            Gate::T {
+                target: QubitRef(1),
+            },
+            cx.clone(),
+        ];
+        assert_eq!(optimizer.optimize(&blocked), blocked.to_vec());
+        // One on a third qubit does not.
+        let free = [
+            cx.clone(),
+            Gate::T {
+                target: QubitRef(2),
+            },
+            cx.clone(),
+        ];
+        assert_eq!(
+            optimizer.optimize(&free),
+            vec![Gate::T {
+                target: QubitRef(2)
+            }]
+        );
+        // The same qubit cannot stand for two of the rule's qubits.
+        let commute = PeepholeOptimizer {
+            rules: parse_rules("cx a, b; cx c, b -> cx c, b; cx a, b").unwrap(),
+        };
+        let cx = |control, target| Gate::CX {
+            control: QubitRef(control),
+            target: QubitRef(target),
+        };
+        assert_eq!(
+            commute.optimize(&[cx(0, 1), cx(2, 1)]),
+            vec![cx(2, 1), cx(0, 1)]
+        );
+        assert_eq!(
+            commute.optimize(&[cx(0, 1), cx(0, 1)]),
+            vec![cx(0, 1), cx(0, 1)]
+        );
+    }
+
+    /// Tests that user rules are checked when they are loaded, and applied after the
+    /// built in ones.
+    #[test]
+    fn test_user_rules() {
+        for (text, message) in [
+            ("h a -> x a", "do not have the same matrix"),
+            ("h a; h b ->", "must share a qubit"),
+            ("h a -> h b", "only act on the qubits of its pattern"),
+            ("h a", "Expected 'pattern -> replacement'"),
+            ("foo a -> foo a", "foo"),
+        ] {
+            let error = parse_rules(text).unwrap_err().to_string();
+            assert!(error.contains(message), "{text}: {error}");
+        }
+
+        let mut optimizer = PeepholeOptimizer::new();
+        optimizer.add_rules(
+            parse_rules("cx a, b; cx b, a; cx a, b -> cx b, a; cx a, b; cx b, a").unwrap(),
+        );
+        let swap = [
+            Gate::CX {
+                control: QubitRef(2),
+                target: QubitRef(0),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(2),
+            },
+            Gate::CX {
+                control: QubitRef(2),
+                target: QubitRef(0),
+            },
+        ];
+        let optimized = optimizer.optimize(&swap);
+        assert_eq!(optimized[0], swap[1]);
+        assert_eq!(optimized.len(), 3);
+    }
+}
@@ -0,0 +1,615 @@
+use crate::gates::gate::Gate;
+use crate::quantum::register::QubitRef;
+use bitvec::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::f64::consts::PI;
+use std::fmt;
+
+/// A circuit of X, CX and phase gates that are multiples of pi / 4, written as
+/// `|x⟩ → ω^f(x) |A x ⊕ b⟩` for `ω = e^{iπ/4}`, a phase polynomial `f` and an affine
+/// function of the inputs, up to a global phase.
+///
+/// The phase polynomial is a sum of parities of the input qubits, each weighted by a
+/// whole number of eighths of a turn. Parities with an odd weight need a T gate each to
+/// synthesize, so the number of them bounds the T-count of the circuit, however the
+/// gates that make up the circuit are arranged. Two circuits with the same phase
+/// polynomial and outputs are equal.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::phase_polynomial::PhasePolynomial;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// // The first and last T gates act on the same parity and combine into S.
+/// let gates = [
+///     Gate::T { target: QubitRef(1) },
+///     Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+///     Gate::T { target: QubitRef(1) },
+///     Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+///     Gate::T { target: QubitRef(1) },
+/// ];
+/// let polynomial = PhasePolynomial::of(2, &gates).unwrap();
+/// assert_eq!(polynomial.to_string(), "f(x) = 2·x1 + 1·(x0 ⊕ x1); outputs x0, x1");
+/// assert_eq!(polynomial.t_count(), 1);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhasePolynomial {
+    /// The weight of each parity of the inputs in eighths of a turn, from 1 to 7, keyed
+    /// by the inputs in the parity.
+    pub terms: BTreeMap<BitVec, u32>,
+    /// The parity of the inputs held by each qubit at the end, and whether it is flipped.
+    pub outputs: Vec<(BitVec, bool)>,
+}
+
+impl PhasePolynomial {
+    /// Creates the phase polynomial of the identity on `num_qubits` qubits.
+    pub fn new(num_qubits: usize) -> Self {
+        let outputs = (0..num_qubits)
+            .map(|qubit| {
+                let mut parity = bitvec![0; num_qubits];
+                parity.set(qubit, true);
+                (parity, false)
+            })
+            .collect();
+        Self {
+            terms: BTreeMap::new(),
+            outputs,
+        }
+    }
+
+    /// Returns the phase polynomial of a circuit, or `None` if one of its gates cannot be
+    /// part of one.
+    pub fn of(num_qubits: usize, gates: &[Gate]) -> Option<Self> {
+        let mut polynomial = Self::new(num_qubits);
+        gates
+            .iter()
+            .all(|gate| polynomial.push(gate))
+            .then_some(polynomial)
+    }
+
+    /// Returns whether a gate can be part of a phase polynomial circuit.
+    pub fn accepts(gate: &Gate) -> bool {
+        matches!(gate, Gate::X { .. } | Gate::CX { .. }) || gate.phase_eighths().is_some()
+    }
+
+    /// Appends a gate to the circuit, returning whether it could be. Gates that are not
+    /// accepted leave the polynomial as it was.
+    pub fn push(&mut self, gate: &Gate) -> bool {
+        match gate {
+            Gate::X { target } => self.outputs[target.index()].1 ^= true,
+            Gate::CX { control, target } => {
+                let (parity, flipped) = self.outputs[control.index()].clone();
+                self.outputs[target.index()].0 ^= parity;
+                self.outputs[target.index()].1 ^= flipped;
+            }
+            _ => {
+                let Some(eighths) = gate.phase_eighths() else {
+                    return false;
+                };
+                let (parity, flipped) = &self.outputs[gate.qubits()[0]];
+                // The phase of a flipped parity is ω^k(1 - y) = ω^k ω^-ky, which is the
+                // negated weight up to a global phase.
+                let eighths = if *flipped { 8 - eighths } else { eighths };
+                let weight = self.terms.entry(parity.clone()).or_default();
+                *weight = (*weight + eighths) % 8;
+                if *weight == 0 {
+                    self.terms.remove(parity);
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns the number of qubits the circuit acts on.
+    pub fn num_qubits(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// Returns the number of terms with an odd weight, each of which needs a T gate.
+    pub fn t_count(&self) -> usize {
+        self.terms
+            .values()
+            .filter(|weight| *weight % 2 == 1)
+            .count()
+    }
+
+    /// Returns a circuit with this phase polynomial and outputs, with one T-like gate for
+    /// each term with an odd weight.
+    ///
+    /// Each term is applied by gathering its parity onto one of its qubits with CX gates,
+    /// applying the phase there and undoing the CX gates, after which the outputs are
+    /// built by Gaussian elimination. This keeps the T-count as low as the polynomial
+    /// allows, but unlike gray-synth makes no attempt to share CX gates between terms.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::gates::phase_polynomial::PhasePolynomial;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let gates = [
+    ///     Gate::T { target: QubitRef(0) },
+    ///     Gate::X { target: QubitRef(0) },
+    ///     Gate::T { target: QubitRef(0) },
+    ///     Gate::CX { control: QubitRef(1), target: QubitRef(0) },
+    /// ];
+    /// let polynomial = PhasePolynomial::of(2, &gates).unwrap();
+    /// let synthesized = polynomial.synthesize();
+    /// assert_eq!(PhasePolynomial::of(2, &synthesized), Some(polynomial));
+    /// assert!(!synthesized.iter().any(|gate| gate.is_t_like()));
+    /// ```
+    pub fn synthesize(&self) -> Vec<Gate> {
+        let mut gates = Vec::new();
+        for (parity, weight) in &self.terms {
+            let mut inputs = parity.iter_ones();
+            let Some(target) = inputs.next().map(QubitRef) else {
+                continue;
+            };
+            let gather: Vec<Gate> = inputs
+                .map(|control| Gate::CX {
+                    control: QubitRef(control),
+                    target,
+                })
+                .collect();
+            gates.extend(gather.iter().cloned());
+            gates.push(match weight {
+                1 => Gate::T { target },
+                7 => Gate::TDgr { target },
+                _ => Gate::RZ {
+                    target,
+                    theta: *weight as f64 * PI / 4.0,
+                },
+            });
+            gates.extend(gather.into_iter().rev());
+        }
+
+        // Reduce the outputs to the identity with row operations, each of which is a CX
+        // with the row added to as its target. Applying them in reverse builds the
+        // outputs from the identity.
+        let mut rows: Vec<BitVec> = self
+            .outputs
+            .iter()
+            .map(|(parity, _)| parity.clone())
+            .collect();
+        let mut operations = Vec::new();
+        for column in 0..rows.len() {
+            // The outputs are invertible, so some row from here on has the column set.
+            let pivot = (column..rows.len()).find(|row| rows[*row][column]).unwrap();
+            if pivot != column {
+                let pivot_row = rows[pivot].clone();
+                rows[column] ^= pivot_row;
+                operations.push(Gate::CX {
+                    control: QubitRef(pivot),
+                    target: QubitRef(column),
+                });
+            }
+            for row in 0..rows.len() {
+                if row != column && rows[row][column] {
+                    let column_row = rows[column].clone();
+                    rows[row] ^= column_row;
+                    operations.push(Gate::CX {
+                        control: QubitRef(column),
+                        target: QubitRef(row),
+                    });
+                }
+            }
+        }
+        gates.extend(operations.into_iter().rev());
+        gates.extend(
+            self.outputs
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, flipped))| *flipped)
+                .map(|(target, _)| Gate::X {
+                    target: QubitRef(target),
+                }),
+        );
+        gates
+    }
+}
+
+/// Writes a parity such as `(x0 ⊕ x2)`, without the parentheses for a single input.
+fn write_parity(f: &mut fmt::Formatter, parity: &BitSlice) -> fmt::Result {
+    let inputs: Vec<String> = parity
+        .iter_ones()
+        .map(|qubit| format!["x{qubit}"])
+        .collect();
+    match inputs.len() {
+        0 => write!(f, "0"),
+        1 => write!(f, "{}", inputs[0]),
+        _ => write!(f, "({})", inputs.join(" ⊕ ")),
+    }
+}
+
+impl fmt::Display for PhasePolynomial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "f(x) = ")?;
+        if self.terms.is_empty() {
+            write!(f, "0")?;
+        }
+        for (index, (parity, weight)) in self.terms.iter().enumerate() {
+            if index > 0 {
+                write!(f, " + ")?;
+            }
+            write!(f, "{weight}·")?;
+            write_parity(f, parity)?;
+        }
+        write!(f, "; outputs ")?;
+        for (qubit, (parity, flipped)) in self.outputs.iter().enumerate() {
+            if qubit > 0 {
+                write!(f, ", ")?;
+            }
+            write_parity(f, parity)?;
+            if *flipped {
+                write!(f, " ⊕ 1")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A part of a circuit made only of gates that a [`PhasePolynomial`] accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhasePolynomialRegion {
+    /// The indices of the gates of the region in the circuit, in order.
+    pub gates: Vec<usize>,
+    /// The qubits the region acts on, in order. Qubit `i` of the polynomial is the
+    /// `i`th of these.
+    pub qubits: Vec<usize>,
+    /// The phase polynomial of the region's gates.
+    pub polynomial: PhasePolynomial,
+}
+
+/// Splits the phase polynomial parts of a circuit into regions, each as large as a
+/// single pass over the gates can make it.
+///
+/// A region grows past other gates on qubits it is done with, so it can take in gates
+/// on the rest of its qubits. Other gates act on a single qubit, so the gates of a
+/// region can be moved together to where its first gate is, ahead of the other gates
+/// that were passed over. A region ends at the first gate it accepts on a qubit that
+/// one of those other gates acts on.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::phase_polynomial::phase_polynomial_regions;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let gates = [
+///     Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+///     Gate::H { target: QubitRef(0) },
+///     Gate::T { target: QubitRef(1) },
+///     Gate::T { target: QubitRef(0) },
+/// ];
+/// let regions = phase_polynomial_regions(&gates);
+/// assert_eq!(regions.len(), 2);
+/// assert_eq!(regions[0].gates, vec![0, 2]);
+/// assert_eq!(regions[0].qubits, vec![0, 1]);
+/// assert_eq!(regions[1].gates, vec![3]);
+/// ```
+pub fn phase_polynomial_regions(gates: &[Gate]) -> Vec<PhasePolynomialRegion> {
+    let mut regions = Vec::new();
+    let mut indices: Vec<usize> = Vec::new();
+    let mut blocked: BTreeSet<usize> = BTreeSet::new();
+    for (index, gate) in gates.iter().enumerate() {
+        if !PhasePolynomial::accepts(gate) {
+            if !indices.is_empty() {
+                blocked.extend(gate.qubits());
+            }
+            continue;
+        }
+        if gate.qubits().iter().any(|qubit| blocked.contains(qubit)) {
+            regions.push(region(gates, std::mem::take(&mut indices)));
+            blocked.clear();
+        }
+        indices.push(index);
+    }
+    if !indices.is_empty() {
+        regions.push(region(gates, indices));
+    }
+    regions
+}
+
+/// Lowers the T-count of a circuit by resynthesizing each of its phase polynomial
+/// regions (see [`phase_polynomial_regions`]) that needs fewer T gates than it has, which
+/// happens when T gates act on the same parity at different points of the region.
+///
+/// Regions that would not lose a T gate are left as they were, since resynthesis can
+/// add CX gates. The circuit is only equal to the original up to a global phase.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::phase_polynomial::reduce_t_count;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// // T on the parity x0 ⊕ x1 twice, which together are an S gate. The Hadamard is on
+/// // another qubit, so the region takes in the gates on either side of it.
+/// let cx = Gate::CX { control: QubitRef(0), target: QubitRef(1) };
+/// let gates = [
+///     cx.clone(),
+///     Gate::T { target: QubitRef(1) },
+///     cx.clone(),
+///     Gate::H { target: QubitRef(2) },
+///     cx.clone(),
+///     Gate::T { target: QubitRef(1) },
+///     cx.clone(),
+/// ];
+/// let reduced = reduce_t_count(&gates);
+/// assert_eq!(reduced.iter().filter(|gate| gate.is_t_like()).count(), 0);
+/// ```
+pub fn reduce_t_count(gates: &[Gate]) -> Vec<Gate> {
+    let mut starts: BTreeMap<usize, PhasePolynomialRegion> = BTreeMap::new();
+    let mut in_region = vec![false; gates.len()];
+    for region in phase_polynomial_regions(gates) {
+        region
+            .gates
+            .iter()
+            .for_each(|index| in_region[*index] = true);
+        starts.insert(region.gates[0], region);
+    }
+    let mut reduced = Vec::with_capacity(gates.len());
+    for (index, gate) in gates.iter().enumerate() {
+        if let Some(region) = starts.get(&index) {
+            let t_count = region
+                .gates
+                .iter()
+                .filter(|index| gates[**index].is_t_like())
+                .count();
+            if region.polynomial.t_count() < t_count {
+                let synthesized = region.polynomial.synthesize();
+                reduced.extend(
+                    synthesized
+                        .iter()
+                        .map(|gate| gate.remap(|qubit| region.qubits[qubit])),
+                );
+            } else {
+                reduced.extend(region.gates.iter().map(|index| gates[*index].clone()));
+            }
+        } else if !in_region[index] {
+            reduced.push(gate.clone());
+        }
+    }
+    reduced
+}
+
+/// Returns the region of the gates at `indices`.
+fn region(gates: &[Gate], indices: Vec<usize>) -> PhasePolynomialRegion {
+    let qubits: Vec<usize> = indices
+        .iter()
+        .flat_map(|index| gates[*index].qubits())
+        .collect::<BTreeSet<usize>>()
+        .into_iter()
+        .collect();
+    let mut polynomial = PhasePolynomial::new(qubits.len());
+    for index in &indices {
+        let local = gates[*index].remap(|qubit| qubits.binary_search(&qubit).unwrap());
+        polynomial.push(&local);
+    }
+    PhasePolynomialRegion {
+        gates: indices,
+        qubits,
+        polynomial,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::exact::{verify_equivalent, Verification};
+    use crate::gates::generators::randomized_benchmarking;
+    use crate::quantum::sampling::Rng;
+
+    /// Returns a random circuit of X, CX, T, T† and `rz` gates.
+    fn random_circuit(num_qubits: usize, num_gates: usize, rng: &mut Rng) -> Vec<Gate> {
+        let mut qubit = || (rng.next_u64() % num_qubits as u64) as usize;
+        (0..num_gates)
+            .map(|index| {
+                let (target, other) = (qubit(), qubit());
+                match index % 5 {
+                    0 => Gate::X {
+                        target: QubitRef(target),
+                    },
+                    1 => Gate::T {
+                        target: QubitRef(target),
+                    },
+                    2 => Gate::TDgr {
+                        target: QubitRef(target),
+                    },
+                    3 => Gate::RZ {
+                        target: QubitRef(target),
+                        theta: PI / 2.0,
+                    },
+                    _ if target == other => Gate::X {
+                        target: QubitRef(target),
+                    },
+                    _ => Gate::CX {
+                        control: QubitRef(other),
+                        target: QubitRef(target),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Tests that circuits with the same phase polynomial are proven equal by exact
+    /// simulation.
+    #[test]
+    fn test_equal_polynomials_are_equal_circuits() {
+        let mut rng = Rng::new(4);
+        let circuits: Vec<Vec<Gate>> = (0..40).map(|_| random_circuit(3, 6, &mut rng)).collect();
+        for first in &circuits {
+            for second in &circuits {
+                if PhasePolynomial::of(3, first) == PhasePolynomial::of(3, second) {
+                    assert_eq!(
+                        verify_equivalent(3, first, second).unwrap(),
+                        Verification::Equal
+                    );
+                }
+            }
+        }
+        // Commuting phase gates through a CX changes the gates but not the polynomial.
+        let gates = [
+            Gate::T {
+                target: QubitRef(0),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+        ];
+        let moved = [
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+        ];
+        assert_eq!(
+            PhasePolynomial::of(2, &gates),
+            PhasePolynomial::of(2, &moved)
+        );
+    }
+
+    /// Tests the terms and outputs of a circuit with flipped parities.
+    #[test]
+    fn test_flipped_parities() {
+        let gates = [
+            Gate::X {
+                target: QubitRef(0),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+            Gate::X {
+                target: QubitRef(0),
+            },
+            Gate::T {
+                target: QubitRef(0),
+            },
+        ];
+        // T on a flipped input is T† up to a global phase, and cancels the last T.
+        let polynomial = PhasePolynomial::of(2, &gates).unwrap();
+        assert_eq!(
+            polynomial.to_string(),
+            "f(x) = 7·(x0 ⊕ x1); outputs x0, (x0 ⊕ x1) ⊕ 1"
+        );
+        assert_eq!(polynomial.t_count(), 1);
+        assert_eq!(
+            PhasePolynomial::of(
+                2,
+                &[Gate::H {
+                    target: QubitRef(0)
+                }]
+            ),
+            None
+        );
+    }
+
+    /// Tests that regions cover every accepted gate once, and that moving each region to
+    /// where its first gate is leaves the circuit unchanged.
+    #[test]
+    fn test_regions_reorder_circuit() {
+        let mut rng = Rng::new(1);
+        for _ in 0..10 {
+            let mut gates = randomized_benchmarking(4, 4, &mut rng);
+            gates.extend(random_circuit(4, 10, &mut rng));
+            let regions = phase_polynomial_regions(&gates);
+            let mut reordered = Vec::new();
+            let mut placed = vec![false; gates.len()];
+            for (index, gate) in gates.iter().enumerate() {
+                if placed[index] {
+                    continue;
+                }
+                match regions.iter().find(|region| region.gates[0] == index) {
+                    Some(region) => {
+                        for index in &region.gates {
+                            reordered.push(gates[*index].clone());
+                            placed[*index] = true;
+                        }
+                    }
+                    None => reordered.push(gate.clone()),
+                }
+            }
+            assert_eq!(reordered.len(), gates.len());
+            assert_eq!(
+                verify_equivalent(4, &gates, &reordered).unwrap(),
+                Verification::Equal
+            );
+            for region in &regions {
+                let local: Vec<Gate> = region
+                    .gates
+                    .iter()
+                    .map(|index| {
+                        gates[*index].remap(|qubit| region.qubits.binary_search(&qubit).unwrap())
+                    })
+                    .collect();
+                assert_eq!(
+                    PhasePolynomial::of(region.qubits.len(), &local).as_ref(),
+                    Some(&region.polynomial)
+                );
+            }
+        }
+    }
+
+    /// Tests that synthesized circuits have the polynomial they were synthesized from and
+    /// one T-like gate for each odd term.
+    #[test]
+    fn test_synthesize() {
+        let mut rng = Rng::new(7);
+        for _ in 0..50 {
+            let gates = random_circuit(4, 20, &mut rng);
+            let polynomial = PhasePolynomial::of(4, &gates).unwrap();
+            let synthesized = polynomial.synthesize();
+            assert_eq!(
+                PhasePolynomial::of(4, &synthesized).as_ref(),
+                Some(&polynomial)
+            );
+            assert_eq!(
+                synthesized.iter().filter(|gate| gate.is_t_like()).count(),
+                polynomial.t_count()
+            );
+            assert_eq!(
+                verify_equivalent(4, &gates, &synthesized).unwrap(),
+                Verification::Equal
+            );
+        }
+    }
+
+    /// Tests that reducing the T-count of circuits that mix phase polynomial regions with
+    /// Hadamards keeps them equal and never adds T gates.
+    #[test]
+    fn test_reduce_t_count() {
+        let mut rng = Rng::new(9);
+        let mut reduced_any = false;
+        for _ in 0..20 {
+            let mut gates = Vec::new();
+            for layer in 0..4 {
+                gates.extend(random_circuit(4, 12, &mut rng));
+                gates.push(Gate::H {
+                    target: QubitRef(layer),
+                });
+            }
+            let reduced = reduce_t_count(&gates);
+            let t_count = |gates: &[Gate]| gates.iter().filter(|gate| gate.is_t_like()).count();
+            assert!(t_count(&reduced) <= t_count(&gates));
+            reduced_any |= t_count(&reduced) < t_count(&gates);
+            assert_eq!(
+                verify_equivalent(4, &gates, &reduced).unwrap(),
+                Verification::Equal
+            );
+        }
+        assert!(reduced_any);
+    }
+}
@@ -0,0 +1,275 @@
+use std::io;
+
+/// The deepest nesting of arrays and objects allowed, which bounds the recursion of the
+/// parser on untrusted input.
+pub const MAX_JSON_DEPTH: usize = 128;
+
+/// A JSON value. Objects keep their members in the order they were written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Returns the value of the member with the given key, if this is an object that has
+    /// one.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::json::{parse_json, Json};
+    ///
+    /// let json = parse_json(r#"{"shots": 100, "counts": {"00": 60}}"#).unwrap();
+    /// assert_eq!(json.get("shots"), Some(&Json::Number(100.0)));
+    /// assert!(json.get("missing").is_none());
+    /// ```
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a JSON document, such as the `--json` output of the simulator or the results
+/// exported by a hardware provider.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::json::{parse_json, Json};
+///
+/// let json = parse_json(r#"[1.5, "a\nb", true, null, {}]"#).unwrap();
+/// assert_eq!(
+///     json,
+///     Json::Array(vec![
+///         Json::Number(1.5),
+///         Json::String("a\nb".to_string()),
+///         Json::Boolean(true),
+///         Json::Null,
+///         Json::Object(Vec::new()),
+///     ])
+/// );
+/// assert!(parse_json("[1,]").is_err());
+/// ```
+pub fn parse_json(source: &str) -> io::Result<Json> {
+    let mut parser = JsonParser {
+        chars: source.chars().collect(),
+        position: 0,
+        line: 1,
+    };
+    let value = parser.parse_value(0)?;
+    parser.skip_whitespace();
+    if parser.position < parser.chars.len() {
+        return Err(parser.error("Unexpected characters after the JSON value"));
+    }
+    Ok(value)
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl JsonParser {
+    fn parse_value(&mut self, depth: usize) -> io::Result<Json> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{' | '[') if depth == MAX_JSON_DEPTH => Err(self.error(&format![
+                "JSON nested more than {MAX_JSON_DEPTH} levels deep"
+            ])),
+            Some('{') => {
+                self.position += 1;
+                let mut members = Vec::new();
+                if self.consume('}') {
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() != Some('"') {
+                        return Err(self.error("Expected a string key"));
+                    }
+                    let key = self.parse_string()?;
+                    if !self.consume(':') {
+                        return Err(self.error("Expected ':'"));
+                    }
+                    members.push((key, self.parse_value(depth + 1)?));
+                    if self.consume('}') {
+                        return Ok(Json::Object(members));
+                    }
+                    if !self.consume(',') {
+                        return Err(self.error("Expected ',' or '}'"));
+                    }
+                }
+            }
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                if self.consume(']') {
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.parse_value(depth + 1)?);
+                    if self.consume(']') {
+                        return Ok(Json::Array(values));
+                    }
+                    if !self.consume(',') {
+                        return Err(self.error("Expected ',' or ']'"));
+                    }
+                }
+            }
+            Some('"') => Ok(Json::String(self.parse_string()?)),
+            Some('-' | '0'..='9') => {
+                let start = self.position;
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+                {
+                    self.position += 1;
+                }
+                let number: String = self.chars[start..self.position].iter().collect();
+                match number.parse() {
+                    Ok(number) => Ok(Json::Number(number)),
+                    Err(_) => Err(self.error(&format!["Invalid number '{number}'"])),
+                }
+            }
+            _ => {
+                for (word, value) in [
+                    ("null", Json::Null),
+                    ("true", Json::Boolean(true)),
+                    ("false", Json::Boolean(false)),
+                ] {
+                    if self.chars[self.position..].starts_with(&word.chars().collect::<Vec<_>>()) {
+                        self.position += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("Expected a JSON value"))
+            }
+        }
+    }
+
+    /// Parses a string, starting at its opening quote.
+    fn parse_string(&mut self) -> io::Result<String> {
+        self.position += 1;
+        let mut string = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("Unterminated string"));
+            };
+            self.position += 1;
+            match c {
+                '"' => return Ok(string),
+                '\\' => {
+                    let escaped = match self.peek() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let digits: String =
+                                self.chars.iter().skip(self.position + 1).take(4).collect();
+                            // Surrogate pairs are not combined, so they become U+FFFD.
+                            let code = u32::from_str_radix(&digits, 16)
+                                .ok()
+                                .filter(|_| digits.len() == 4)
+                                .ok_or_else(|| self.error("Invalid unicode escape"))?;
+                            self.position += 4;
+                            char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        _ => return Err(self.error("Invalid escape")),
+                    };
+                    self.position += 1;
+                    string.push(escaped);
+                }
+                '\n' => return Err(self.error("Unterminated string")),
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    /// Skips whitespace, then consumes `expected` if it is next.
+    fn consume(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        let found = self.peek() == Some(expected);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            if c == '\n' {
+                self.line += 1;
+            }
+            self.position += 1;
+        }
+    }
+
+    fn error(&self, message: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!["{message} on line {}", self.line],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Tests parsing nested values, escapes and errors.
+    #[test]
+    fn test_parse_json() {
+        let json =
+            parse_json("{\n  \"a\": [1, -2.5e3, {\"b\": \"\\u00e9\\t\"}],\n  \"c\": false\n}")
+                .unwrap();
+        assert_eq!(
+            json.get("a"),
+            Some(&Json::Array(vec![
+                Json::Number(1.0),
+                Json::Number(-2500.0),
+                Json::Object(vec![("b".to_string(), Json::String("é\t".to_string()))]),
+            ]))
+        );
+        assert_eq!(json.get("c"), Some(&Json::Boolean(false)));
+
+        for (source, message) in [
+            ("", "Expected a JSON value on line 1"),
+            ("{\"a\" 1}", "Expected ':' on line 1"),
+            ("[1 2]", "Expected ',' or ']' on line 1"),
+            ("{\n1: 2}", "Expected a string key on line 2"),
+            ("\"abc", "Unterminated string on line 1"),
+            ("1.2.3", "Invalid number '1.2.3' on line 1"),
+            (
+                "[] x",
+                "Unexpected characters after the JSON value on line 1",
+            ),
+            ("\"\\x\"", "Invalid escape on line 1"),
+        ] {
+            assert_eq!(parse_json(source).unwrap_err().to_string(), message);
+        }
+        let nested = "[".repeat(MAX_JSON_DEPTH + 1);
+        assert_eq!(
+            parse_json(&nested).unwrap_err().to_string(),
+            "JSON nested more than 128 levels deep on line 1"
+        );
+    }
+}
@@ -1,2 +1,6 @@
+pub mod config;
 pub mod gates;
+pub mod json;
+pub mod qasm;
 pub mod quantum;
+pub mod testing;
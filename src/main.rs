@@ -1,216 +1,1557 @@
-// use crate::quantum::ket;
-// use bitvec::prelude::*;
-// use num::complex::Complex;
-use regex::Regex;
+use std::cell::Cell;
 use std::env;
-use std::fs::File;
-use std::io::{self, BufRead};
-use std::time::Instant;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, BufRead, IsTerminal, Write as _};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub mod gates;
-pub mod quantum;
+use quantum_simulator::config::{parse_config, Value};
+use quantum_simulator::gates::analysis::CircuitAnalysis;
+use quantum_simulator::gates::diff::{diff_circuits, GateDiff};
+use quantum_simulator::gates::exact::{verify_equivalent, Verification};
+use quantum_simulator::gates::gate::Gate;
+use quantum_simulator::gates::generators::{mirror_circuit, randomized_benchmarking};
+use quantum_simulator::gates::lightcone::{compact_qubits, eliminate_dead_gates};
+use quantum_simulator::gates::peephole::{parse_rules, PeepholeOptimizer};
+use quantum_simulator::gates::phase_polynomial::{phase_polynomial_regions, reduce_t_count};
+use quantum_simulator::qasm::cache::ResultCache;
+use quantum_simulator::qasm::compiled::CompiledCircuit;
+use quantum_simulator::qasm::definitions::GateDefinitions;
+use quantum_simulator::qasm::lowering::{Lowering, Operation};
+use quantum_simulator::qasm::parser::{Parser, StatementKind};
+use quantum_simulator::qasm::session::Session;
+use quantum_simulator::qasm::simulator::{Options, SimulationResult, Simulator};
+use quantum_simulator::qasm::writer::{upgrade_qasm, write_gate, write_qasm};
+use quantum_simulator::quantum::backend::Backend;
+use quantum_simulator::quantum::bit_order::BitOrder;
+use quantum_simulator::quantum::distribution::{
+    compare_distributions, parse_distribution, Distribution, DistributionDistance,
+};
+use quantum_simulator::quantum::heisenberg::{propagate_observable, HeisenbergResult};
+use quantum_simulator::quantum::observable::{parse_observable, Estimate};
+use quantum_simulator::quantum::reference::{compare, read_npy};
+use quantum_simulator::quantum::sampling::Rng;
+use quantum_simulator::quantum::shadows::{classical_shadow, ShadowEstimate};
+use quantum_simulator::quantum::state::Accumulation;
+use quantum_simulator::quantum::tolerance::Tolerance;
+use quantum_simulator::quantum::tomography::{tomography, Tomography};
+use quantum_simulator::quantum::watchdog::{MemorySample, Watchdog};
+use quantum_simulator::quantum::xeb::{linear_xeb, parse_samples, sample_bitstrings};
 
-use quantum_simulator::gates::gate::{apply_gate_to_state, Gate};
-use quantum_simulator::quantum::ket::Ket;
-use quantum_simulator::quantum::register::Register;
-use quantum_simulator::quantum::state::State;
+const USAGE: &str = "\
+Usage: quantum_simulator [options] <file>
+       quantum_simulator compare --reference <file.npy> [--tolerance <value>]
+                                 [--bit-order little|big] [options] <file>
+       quantum_simulator stats [--keep <qubits>] [--phase-polynomials] [options] <file>
+       quantum_simulator tomography [--qubits <qubits>] [--shots <n>] [options] <file>
+       quantum_simulator shadows --observable-file <file> [--shots <n>] [options] <file>
+       quantum_simulator heisenberg --observable-file <file> [--threshold <t>] [options] <file>
+       quantum_simulator verify [--against <file>] [options] <file>
+       quantum_simulator xeb [--samples <file>] [--shots <n>] [options] <file>
+       quantum_simulator compare-counts [-o <file>] <counts.json> <counts.json>
+       quantum_simulator compare-hardware --counts <counts.json> [options] <file>
+       quantum_simulator compile [--opaque-map <file>] -o <file.qsim> <file>
+       quantum_simulator upgrade [-o <file>] <file>
+       quantum_simulator optimize [--rules <file>] [-o <file>] <file>
+       quantum_simulator diff [-o <file>] <file> <file>
+       quantum_simulator run [options] <file.qsim>
+       quantum_simulator repl [options]
+       quantum_simulator generate --qubits <n> --depth <n> [--seed <n>] [-o <file>] rb|mirror
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let filename = &args[1];
-    // let filename = "./qasm/f2_232.qasm";
+Options:
+  --opaque-map <file>  Bind opaque gates to the gate definitions in <file>
+  --schedule           Print the per-qubit schedule after simulating
+  --timeline <file>    Write the per-qubit schedule with its idle periods to <file>, as an
+                       SVG chart if it ends in .svg and as JSON otherwise
+  --json               Print the result as a JSON object instead of text
+  -q, --quiet          Only print the --json result, with no progress messages
+  -o, --output <file>  Write the result to <file> instead of standard output
+  --no-color           Do not color amplitudes by their phase
+  --canonical          Process kets in basis index order for reproducible runs
+  --compensated        Sum colliding amplitudes with compensated summation
+  --check-finite       Stop at the first gate that produces a NaN or infinite amplitude
+  --fail-on-warning    Fail instead of printing warnings, such as ignored includes or
+                       probability lost to pruned amplitudes
+  --prune-epsilon <e>  Drop kets whose amplitude has a norm of at most <e> (default: 1e-6)
+  --max-pruned-prob <p>
+                       Stop once the amplitudes pruned for being close to zero have lost
+                       more than <p> of the probability
+  --watchdog <ms>      Print the memory used by the process and the number of kets every
+                       <ms> milliseconds while simulating
+  --max-memory <MiB>   Stop once the process uses more than <MiB> mebibytes, checking every
+                       --watchdog interval (default: 100 ms) rather than between gates
+  --diagnostics        Report the inverse participation ratio and non-zero amplitudes
+  --subsystem <qubits> Also report the purity of the comma separated <qubits>
+  --observable-file <file>
+                       Report the expectation value of the weighted Pauli strings in <file>,
+                       one 'coefficient PauliString' term per line
+  --shots <n>          Also estimate the expectation value from <n> shots of each group of
+                       terms measured in the same basis, with its variance and standard error
+                       With shadows, the number of shots in random bases (default: 1000)
+                       With xeb and no --samples, the number of noiseless samples to draw
+  --seed <n>           Seed the sampling of shots (default: 0)
+  --marginal <qubits>  Report the probabilities of the comma separated <qubits>, only
+                       simulating the gates that can affect them
+  --trajectory <file>  Write the expectation values of Z on the --trajectory-qubits as the
+                       circuit runs to <file> as CSV, with one row per recorded instruction
+  --trajectory-qubits <qubits>
+                       Record the trajectory of the comma separated <qubits>
+  --trajectory-every <n>
+                       Record the trajectory after every <n> instructions (default: 1)
+  --profile <file>     Write the time spent applying each type of gate in each instruction
+                       and region between barriers to <file>, as folded stacks for
+                       flamegraph tools
+  --fuse               Combine runs of single qubit gates into one gate before applying them,
+                       and apply the combined gates on disjoint qubits in a single pass
+  --backend <name>     Store the state as 'sparse' kets (default), a 'dense' vector, a
+                       dense vector in a scratch 'file' or a 'trie' of kets sharing their
+                       high qubits
+  --dense-threshold <fraction>
+                       Switch from the sparse to the dense backend once the kets fill this
+                       fraction of the basis states
+  --mmap-dir <dir>     Use the file backend with its scratch file in <dir>
+  --threads <n>        Apply gates using up to <n> threads (default: $QASM_SIM_THREADS or 1)
+  --chunk-size <n>     Give each thread at least <n> kets (default: 16384)
+  --config <file>      Read default options from <file> (default: ./qasm-simulator.toml)
+  --cache-dir <dir>    Reuse the report of an earlier run from <dir> if the circuit and
+                       options are the same, and store the report there otherwise. Ignored
+                       with --trajectory, --timeline and --profile
+  --keep <qubits>      With stats, report the gates and qubits that can affect the comma
+                       separated <qubits>
+  --rules <file>       With optimize, also rewrite the circuit with the rules in <file>
+  --phase-polynomials  With stats, report the phase polynomial of each region of x, cx, t,
+                       tdg and rz gates with multiples of pi/4 as angles
+  --bit-order <order>  With compare, whether qubit 0 is the 'little' (default, as in Qiskit)
+                       or 'big' (as in Cirq and Quil) end of the reference basis indices
+  --samples <file>     With xeb, the measured bitstrings, one per line with qubit 0 last and
+                       optionally followed by a count
+  --qubits <qubits>    With tomography, reconstruct the comma separated <qubits> (default: all)
+                       With generate, the number of qubits in the circuit
+  --depth <n>          With generate, the number of random layers before the inverse
+  --counts <file>      With compare-hardware, the counts measured on a device
+  --against <file>     With verify, the circuit to prove equal to (default: the identity)
+  --threshold <t>      With heisenberg, drop terms whose coefficients are at most <t> in
+                       magnitude after each gate (default: 0)
 
-    let file = File::open(filename)?;
-    let reader = io::BufReader::new(file);
-    let mut reader_lines = reader.lines().peekable();
+Counts files:
+  compare-counts reads JSON objects of bitstrings to counts or probabilities, optionally
+  in a 'counts' member, or the --json output of the simulator. It reports the total
+  variation distance, Hellinger fidelity and KL divergence D(first ‖ second).
+  compare-hardware simulates the circuit and compares the same kind of counts file with
+  its ideal distribution, the marginal one if --marginal is given, reporting
+  D(counts ‖ ideal).
 
-    let mut line_number = 1;
+Verification:
+  verify proves whether a circuit of h, x, t, tdg, cx and rz gates with multiples of pi/4
+  as angles equals another circuit, or the identity, up to a global phase. It simulates
+  every basis state with exact arithmetic, so the circuits can have at most 12 qubits.
 
-    // Handle QASM version header.
-    let header_re = Regex::new(r"OPENQASM\s+(\d+\.\d+)").unwrap();
-    if let Some(Ok(header)) = reader_lines.next() {
-        if let Some(caps) = header_re.captures(&header) {
-            let version = caps.get(1).unwrap().as_str();
-            println!("Using QASM version: {}", version);
-        } else {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid header"));
+Optimization:
+  optimize writes the circuit as OpenQASM 2.0 after a peephole pass that cancels and
+  merges neighbouring gates, and lowers the T-count of each region of x, cx, t, tdg and
+  rz gates by resynthesizing it from its phase polynomial, which merges T gates acting
+  on the same parity. The result is equal up to a global phase.
+  Rules files have one 'pattern -> replacement' rule per line, such as
+  'h a; x a; h a -> rz(pi) a', with '#' comments. Each side is a list of gates on
+  named qubits, and each rule is checked to be an identity when it is read.
+
+Diffs:
+  diff lines up the gates of two circuits by layer, so that gates on different qubits
+  can be written in any order, and lists the gates only in the first with '-', those
+  only in the second with '+' and gates replaced on the same qubits with '~', each with
+  its layer in its own circuit.
+
+Generated circuits:
+  rb      Randomized benchmarking: random Clifford layers followed by their inverse
+  mirror  Random layers that include non-Clifford rotations, followed by their inverse
+
+Exit codes:
+  0  Success
+  1  The compared amplitudes differ, the verified circuits are not equal or the diffed
+     circuits differ
+  2  The command line is invalid
+  3  A QASM or compiled circuit file could not be parsed
+  4  The circuit could not be simulated, had warnings with --fail-on-warning or pruned
+     more than --max-pruned-prob
+  5  The state is too large for the backend or the process used more than --max-memory";
+
+/// The environment variable giving the default number of threads.
+const THREADS_VAR: &str = "QASM_SIM_THREADS";
+
+/// The config file read from the current directory if `--config` is not given.
+const CONFIG_FILE: &str = "qasm-simulator.toml";
+
+/// The options that can be set in a config file for every mode.
+const CONFIG_KEYS: [&str; 23] = [
+    "opaque-map",
+    "schedule",
+    "json",
+    "quiet",
+    "no-color",
+    "canonical",
+    "compensated",
+    "check-finite",
+    "fail-on-warning",
+    "prune-epsilon",
+    "max-pruned-prob",
+    "watchdog",
+    "max-memory",
+    "fuse",
+    "diagnostics",
+    "backend",
+    "dense-threshold",
+    "mmap-dir",
+    "threads",
+    "chunk-size",
+    "cache-dir",
+    "shots",
+    "seed",
+];
+
+/// The options that can be set in a config file that only apply to the compare mode, and
+/// are left out in the other modes.
+const COMPARE_CONFIG_KEYS: [&str; 1] = ["tolerance"];
+
+/// The default number of shots in each measurement basis for tomography.
+const DEFAULT_TOMOGRAPHY_SHOTS: usize = 1000;
+
+/// The default number of shots of a classical shadow.
+const DEFAULT_SHADOW_SHOTS: usize = 1000;
+
+/// The default number of noiseless samples drawn for XEB without a samples file.
+const DEFAULT_XEB_SHOTS: usize = 1000;
+
+/// The default interval in milliseconds between the memory samples of the watchdog.
+const DEFAULT_WATCHDOG_INTERVAL_MS: usize = 100;
+
+// Exit codes, so that scripts can tell why a run failed without parsing messages.
+/// A comparison found amplitudes that differ.
+const EXIT_DIFFERENCE: i32 = 1;
+/// The command line could not be parsed.
+const EXIT_USAGE: i32 = 2;
+/// A QASM file could not be parsed.
+const EXIT_PARSE_ERROR: i32 = 3;
+/// The circuit could not be simulated.
+const EXIT_RUNTIME_ERROR: i32 = 4;
+/// The state is too large for the chosen backend, or the process uses too much memory.
+const EXIT_RESOURCE_LIMIT: i32 = 5;
+
+/// An error along with the exit code it is reported with.
+struct Failure {
+    error: io::Error,
+    exit_code: i32,
+}
+
+impl Failure {
+    fn parse(error: io::Error) -> Self {
+        Self {
+            error,
+            exit_code: EXIT_PARSE_ERROR,
         }
     }
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        let exit_code = match error.kind() {
+            io::ErrorKind::OutOfMemory => EXIT_RESOURCE_LIMIT,
+            _ => EXIT_RUNTIME_ERROR,
+        };
+        Self { error, exit_code }
+    }
+}
+
+fn main() {
+    if let Err(failure) = run() {
+        eprintln!("Error: {}", failure.error);
+        process::exit(failure.exit_code);
+    }
+}
 
-    // Handle any includes.
-    let include_re = Regex::new(r"^include.*").unwrap();
-    while let Some(line_result) = reader_lines.peek() {
-        line_number += 1;
-        match line_result {
-            Ok(line) => {
-                if include_re.is_match(line) {
-                    // For now, just skip the include and advance to the next line.
-                    reader_lines.next();
-                } else {
-                    break;
+fn run() -> Result<(), Failure> {
+    let mut args: Vec<String> = env::args().collect();
+    let compare_mode = args.get(1).is_some_and(|arg| arg == "compare");
+    let stats_mode = args.get(1).is_some_and(|arg| arg == "stats");
+    let tomography_mode = args.get(1).is_some_and(|arg| arg == "tomography");
+    let shadows_mode = args.get(1).is_some_and(|arg| arg == "shadows");
+    let heisenberg_mode = args.get(1).is_some_and(|arg| arg == "heisenberg");
+    let verify_mode = args.get(1).is_some_and(|arg| arg == "verify");
+    let xeb_mode = args.get(1).is_some_and(|arg| arg == "xeb");
+    let compare_counts_mode = args.get(1).is_some_and(|arg| arg == "compare-counts");
+    let compare_hardware_mode = args.get(1).is_some_and(|arg| arg == "compare-hardware");
+    let generate_mode = args.get(1).is_some_and(|arg| arg == "generate");
+    let compile_mode = args.get(1).is_some_and(|arg| arg == "compile");
+    let upgrade_mode = args.get(1).is_some_and(|arg| arg == "upgrade");
+    let optimize_mode = args.get(1).is_some_and(|arg| arg == "optimize");
+    let diff_mode = args.get(1).is_some_and(|arg| arg == "diff");
+    let run_mode = args.get(1).is_some_and(|arg| arg == "run");
+    let repl_mode = args.get(1).is_some_and(|arg| arg == "repl");
+    let first_option = match compare_mode
+        || stats_mode
+        || tomography_mode
+        || shadows_mode
+        || heisenberg_mode
+        || verify_mode
+        || xeb_mode
+        || compare_counts_mode
+        || compare_hardware_mode
+        || generate_mode
+        || compile_mode
+        || upgrade_mode
+        || optimize_mode
+        || diff_mode
+        || run_mode
+        || repl_mode
+    {
+        true => 2,
+        false => 1,
+    };
+    // Settings from the config file go before the command line, so flags override them.
+    let config_args = config_args(&args[first_option..], compare_mode)?;
+    args.splice(first_option..first_option, config_args);
+    let mut filename: Option<&String> = Option::None;
+    let mut second_filename: Option<&String> = Option::None;
+    let mut opaque_map: Option<&String> = Option::None;
+    let mut reference: Option<&String> = Option::None;
+    let mut samples_path: Option<&String> = Option::None;
+    let mut counts_path: Option<&String> = Option::None;
+    let mut against: Option<&String> = Option::None;
+    let mut output_path: Option<&String> = Option::None;
+    let mut cache_dir: Option<&String> = Option::None;
+    let mut trajectory_path: Option<&String> = Option::None;
+    let mut timeline_path: Option<&String> = Option::None;
+    let mut profile_path: Option<&String> = Option::None;
+    let mut rules_path: Option<&String> = Option::None;
+    let mut watchdog_interval: Option<usize> = None;
+    let mut max_memory: Option<usize> = None;
+    let mut tolerance = Tolerance::DEFAULT;
+    let mut threshold = 0.0;
+    let mut bit_order = BitOrder::default();
+    let mut keep: Vec<usize> = Vec::new();
+    let mut tomography_qubits: Vec<usize> = Vec::new();
+    let mut generate_qubits: Option<usize> = None;
+    let mut generate_depth: Option<usize> = None;
+    let mut phase_polynomials = false;
+    let mut print_schedule = false;
+    let mut json_output = false;
+    let mut quiet = false;
+    let mut no_color = false;
+    let mut options = Options::default();
+    // Job scripts can set the thread count for every run through the environment.
+    if let Some(threads) = env::var(THREADS_VAR)
+        .ok()
+        .and_then(|threads| threads.parse().ok())
+    {
+        options.parallelism.threads = threads;
+    }
+    let mut arg_iter = args.iter().skip(first_option);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            // The config file has already been read.
+            "--config" => {
+                arg_iter.next();
+            }
+            "--opaque-map" => {
+                opaque_map = arg_iter.next();
+                if opaque_map.is_none() {
+                    usage();
                 }
             }
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!["Could not parse include on line {line_number}"],
-                ));
-            }
-        }
-    }
-
-    // Search for register definitions.
-    let register_re = Regex::new(r"(qreg|creg)\s([\w]+)(?:\[(\d+)\])").unwrap();
-    let mut classical_register: Option<Register> = Option::None;
-    let mut quantum_register: Option<Register> = Option::None;
-    for line_result in &mut reader_lines {
-        line_number += 1;
-        match line_result {
-            Ok(line) => {
-                if let Some(caps) = register_re.captures(&line) {
-                    let (_, [register_type, register_name, register_size]) = caps.extract();
-                    match register_type {
-                        "qreg" => {
-                            quantum_register = Option::Some(Register {
-                                name: register_name.to_string(),
-                                size: register_size.parse().unwrap(),
-                            });
-                        }
-                        "creg" => {
-                            classical_register = Option::Some(Register {
-                                name: register_name.to_string(),
-                                size: register_size.parse().unwrap(),
-                            });
-                        }
-                        _ => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                format![
-                                    "Unknown register type '{register_type}' on line {line_number}"
-                                ],
-                            ));
-                        }
-                    }
+            "--schedule" => print_schedule = true,
+            "--timeline" => {
+                timeline_path = arg_iter.next();
+                if timeline_path.is_none() {
+                    usage();
                 }
             }
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!["Could not parse register on line {line_number}"],
-                ));
+            "--json" => json_output = true,
+            "--quiet" | "-q" => quiet = true,
+            "--no-color" => no_color = true,
+            "-o" | "--output" => {
+                output_path = arg_iter.next();
+                if output_path.is_none() {
+                    usage();
+                }
+            }
+            "--cache-dir" => {
+                cache_dir = arg_iter.next();
+                if cache_dir.is_none() {
+                    usage();
+                }
+            }
+            "--canonical" => options.canonical = true,
+            "--compensated" => options.accumulation = Accumulation::Compensated,
+            "--check-finite" => options.check_finite = true,
+            "--fail-on-warning" => options.fail_on_warning = true,
+            "--fuse" => options.fuse = true,
+            "--trajectory" => {
+                trajectory_path = arg_iter.next();
+                if trajectory_path.is_none() {
+                    usage();
+                }
+            }
+            "--trajectory-qubits" => options
+                .trajectory_qubits
+                .extend(parse_qubits(arg_iter.next())),
+            "--profile" => {
+                profile_path = arg_iter.next();
+                if profile_path.is_none() {
+                    usage();
+                }
+                options.profile = true;
+            }
+            "--trajectory-every" => options.trajectory_every = parse_count(arg_iter.next()),
+            "--diagnostics" => options.diagnostics = true,
+            "--subsystem" => {
+                options.diagnostics = true;
+                options.subsystems.push(parse_qubits(arg_iter.next()));
+            }
+            "--observable-file" => {
+                let Some(path) = arg_iter.next() else {
+                    usage();
+                };
+                let observable = parse_observable(&fs::read_to_string(path)?).map_err(|error| {
+                    Failure::parse(io::Error::new(error.kind(), format!["{error} of '{path}'"]))
+                })?;
+                options.observable = Some(observable);
+            }
+            "--shots" => options.shots = Some(parse_count(arg_iter.next())),
+            "--seed" => {
+                options.seed = match arg_iter.next().map(|seed| seed.parse()) {
+                    Some(Ok(seed)) => seed,
+                    _ => usage(),
+                }
+            }
+            "--marginal" => options
+                .marginal_qubits
+                .extend(parse_qubits(arg_iter.next())),
+            "--backend" => {
+                options.backend = match arg_iter.next().and_then(|name| Backend::from_name(name)) {
+                    Some(backend) => backend,
+                    None => usage(),
+                }
+            }
+            "--prune-epsilon" => options.tolerance = parse_tolerance(arg_iter.next()),
+            "--max-pruned-prob" => {
+                options.max_pruned_probability = match arg_iter.next().map(|value| value.parse()) {
+                    Some(Ok(value)) if value >= 0.0 => Some(value),
+                    _ => usage(),
+                }
+            }
+            "--watchdog" => watchdog_interval = Some(parse_count(arg_iter.next())),
+            "--max-memory" => max_memory = Some(parse_count(arg_iter.next())),
+            "--dense-threshold" => {
+                options.dense_threshold = match arg_iter.next().map(|value| value.parse()) {
+                    Some(Ok(value)) if value > 0.0 && value <= 1.0 => Some(value),
+                    _ => usage(),
+                }
+            }
+            "--mmap-dir" => {
+                options.backend = Backend::File;
+                options.scratch_dir = arg_iter.next().map(PathBuf::from);
+                if options.scratch_dir.is_none() {
+                    usage();
+                }
+            }
+            "--threads" => options.parallelism.threads = parse_count(arg_iter.next()),
+            "--chunk-size" => options.parallelism.min_chunk_size = parse_count(arg_iter.next()),
+            "--keep" if stats_mode => keep.extend(parse_qubits(arg_iter.next())),
+            "--phase-polynomials" if stats_mode => phase_polynomials = true,
+            "--rules" if optimize_mode => {
+                rules_path = arg_iter.next();
+                if rules_path.is_none() {
+                    usage();
+                }
+            }
+            "--qubits" if tomography_mode => {
+                tomography_qubits.extend(parse_qubits(arg_iter.next()))
+            }
+            "--qubits" if generate_mode => generate_qubits = Some(parse_count(arg_iter.next())),
+            "--depth" if generate_mode => generate_depth = Some(parse_count(arg_iter.next())),
+            "--reference" if compare_mode => {
+                reference = arg_iter.next();
+                if reference.is_none() {
+                    usage();
+                }
+            }
+            "--threshold" if heisenberg_mode => {
+                threshold = match arg_iter.next().map(|value| value.parse()) {
+                    Some(Ok(value)) if value >= 0.0 => value,
+                    _ => usage(),
+                }
+            }
+            "--samples" if xeb_mode => {
+                samples_path = arg_iter.next();
+                if samples_path.is_none() {
+                    usage();
+                }
+            }
+            "--against" if verify_mode => {
+                against = arg_iter.next();
+                if against.is_none() {
+                    usage();
+                }
             }
+            "--counts" if compare_hardware_mode => {
+                counts_path = arg_iter.next();
+                if counts_path.is_none() {
+                    usage();
+                }
+            }
+            "--bit-order" if compare_mode => {
+                bit_order = match arg_iter.next().and_then(|name| BitOrder::from_name(name)) {
+                    Some(bit_order) => bit_order,
+                    None => usage(),
+                }
+            }
+            "--tolerance" if compare_mode => tolerance = parse_tolerance(arg_iter.next()),
+            // Options of other modes, or misspelled ones, are not filenames.
+            _ if arg.starts_with("--") => usage(),
+            _ if (compare_counts_mode || diff_mode) && filename.is_some() => {
+                second_filename = Some(arg)
+            }
+            _ => filename = Option::Some(arg),
+        }
+    }
+    if trajectory_path.is_some() && options.trajectory_qubits.is_empty() {
+        usage();
+    }
+    if repl_mode {
+        let mut definitions = GateDefinitions::new();
+        if let Some(path) = opaque_map {
+            load_opaque_map(path, &mut definitions)?;
+        }
+        return run_session(Session::new(definitions, options));
+    }
+    let Some(filename) = filename else {
+        usage();
+    };
+
+    if compare_counts_mode {
+        let Some(second_filename) = second_filename else {
+            usage();
+        };
+        let distance = compare_distributions(
+            &read_distribution(filename)?,
+            &read_distribution(second_filename)?,
+        )?;
+        let mut report = String::new();
+        writeln!(report, "First:              {filename}").unwrap();
+        writeln!(report, "Second:             {second_filename}").unwrap();
+        write_distance(&mut report, &distance);
+        return write_report(&report, output_path, !quiet);
+    }
+
+    if generate_mode {
+        // The positional argument names the kind of circuit rather than a file.
+        let (Some(num_qubits), Some(depth)) = (generate_qubits, generate_depth) else {
+            usage();
+        };
+        let mut rng = Rng::new(options.seed);
+        let gates = match filename.as_str() {
+            "rb" => randomized_benchmarking(num_qubits, depth, &mut rng),
+            "mirror" => mirror_circuit(num_qubits, depth, &mut rng),
+            _ => usage(),
+        };
+        return write_report(&write_qasm(num_qubits, &gates)?, output_path, !quiet);
+    }
+
+    if upgrade_mode {
+        let file = File::open(filename)?;
+        let program = upgrade_qasm(Parser::new(io::BufReader::new(file))).map_err(|error| {
+            match error.kind() {
+                io::ErrorKind::InvalidData => Failure::parse(error),
+                _ => Failure::from(error),
+            }
+        })?;
+        return write_report(&program, output_path, !quiet);
+    }
+
+    let mut definitions = GateDefinitions::new();
+    if let Some(path) = opaque_map {
+        load_opaque_map(path, &mut definitions)?;
+    }
+
+    if compile_mode {
+        let Some(output_path) = output_path else {
+            usage();
+        };
+        let file = File::open(filename)?;
+        let circuit = CompiledCircuit::compile(Parser::new(io::BufReader::new(file)), definitions)
+            .map_err(Failure::parse)?;
+        circuit.write_to(io::BufWriter::new(File::create(output_path)?))?;
+        if !quiet {
+            let num_gates: usize = circuit
+                .operations
+                .iter()
+                .map(|operation| match operation {
+                    Operation::GateCall { gates, .. } => gates.len(),
+                    Operation::Delay { .. } => 0,
+                })
+                .sum();
+            println!("Compiled {filename} with {num_gates} gates to {output_path}");
         }
+        return Ok(());
+    }
 
-        // Break if we have found both registers.
-        if quantum_register.is_some() && classical_register.is_some() {
-            break;
+    if optimize_mode {
+        let mut optimizer = PeepholeOptimizer::new();
+        if let Some(path) = rules_path {
+            optimizer.add_rules(parse_rules(&fs::read_to_string(path)?).map_err(Failure::parse)?);
+        }
+        let (num_qubits, analysis, gates) = analyze(filename, definitions, true)?;
+        // Resynthesis can leave CX gates next to each other for the second pass to cancel.
+        let reduced = optimizer.optimize(&reduce_t_count(&optimizer.optimize(&gates)));
+        let program = write_qasm(num_qubits, &reduced)?;
+        if let (Some(output_path), false) = (output_path, quiet) {
+            println!(
+                "Reduced {filename} from {} to {} gates with a T-count of {} rather than {} \
+                 and wrote it to {output_path}",
+                gates.len(),
+                reduced.len(),
+                reduced.iter().filter(|gate| gate.is_t_like()).count(),
+                analysis.t_count
+            );
         }
+        return write_report(&program, output_path, !quiet);
     }
 
-    // Create a new quantum state.
-    let mut state = match &quantum_register {
-        Some(register) => {
-            let num_qubits = register.size;
+    let mut report = String::new();
+    if diff_mode {
+        let Some(second_filename) = second_filename else {
+            usage();
+        };
+        let (first_qubits, _, first) = analyze(filename, definitions.clone(), true)?;
+        let (second_qubits, _, second) = analyze(second_filename, definitions, true)?;
+        let diff = diff_circuits(&first, &second)?;
+        writeln!(
+            report,
+            "--- {filename} ({first_qubits} qubits, {} gates)",
+            first.len()
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "+++ {second_filename} ({second_qubits} qubits, {} gates)",
+            second.len()
+        )
+        .unwrap();
+        let (mut removed, mut inserted, mut changed) = (0, 0, 0);
+        for difference in &diff {
+            match difference {
+                GateDiff::Removed { layer, gate } => {
+                    removed += 1;
+                    writeln!(report, "- layer {layer}: {}", write_gate(gate)?).unwrap();
+                }
+                GateDiff::Inserted { layer, gate } => {
+                    inserted += 1;
+                    writeln!(report, "+ layer {layer}: {}", write_gate(gate)?).unwrap();
+                }
+                GateDiff::Changed {
+                    from_layer,
+                    from,
+                    to_layer,
+                    to,
+                } => {
+                    changed += 1;
+                    writeln!(
+                        report,
+                        "~ layer {from_layer}: {} -> layer {to_layer}: {}",
+                        write_gate(from)?,
+                        write_gate(to)?
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        writeln!(
+            report,
+            "{removed} removed, {inserted} inserted, {changed} changed"
+        )
+        .unwrap();
+        write_report(&report, output_path, !quiet)?;
+        if !diff.is_empty() || first_qubits != second_qubits {
+            // Exit with a failure like diff does, so that scripts can check for changes.
+            process::exit(EXIT_DIFFERENCE);
+        }
+        return Ok(());
+    }
+    if stats_mode {
+        let (num_qubits, analysis, gates) =
+            analyze(filename, definitions, !keep.is_empty() || phase_polynomials)?;
+        writeln!(report, "File:             {filename}").unwrap();
+        writeln!(report, "Qubits:           {num_qubits}").unwrap();
+        writeln!(report, "{analysis}").unwrap();
+        if !keep.is_empty() {
+            if let Some(qubit) = keep.iter().find(|qubit| **qubit >= num_qubits) {
+                return Err(Failure::from(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!["Unknown qubit {qubit} in --keep"],
+                )));
+            }
+            let live_gates = eliminate_dead_gates(&gates, &keep);
+            let (_, live_qubits) = compact_qubits(&live_gates, &keep);
+            writeln!(
+                report,
+                "\nLightcone of qubits {keep:?}: {} of {} gates on {} of {num_qubits} qubits",
+                live_gates.len(),
+                gates.len(),
+                live_qubits.len()
+            )
+            .unwrap();
+            writeln!(report, "Qubits in the lightcone: {live_qubits:?}").unwrap();
+        }
+        if phase_polynomials {
+            let regions = phase_polynomial_regions(&gates);
+            writeln!(report, "\nPhase polynomial regions: {}", regions.len()).unwrap();
+            for region in &regions {
+                writeln!(
+                    report,
+                    "\nRegion from gate {} ({} gates, T-count {}) with inputs x0.. on qubits {:?}:",
+                    region.gates[0],
+                    region.gates.len(),
+                    region.polynomial.t_count(),
+                    region.qubits
+                )
+                .unwrap();
+                writeln!(report, "  {}", region.polynomial).unwrap();
+            }
+        }
+        return write_report(&report, output_path, !quiet);
+    }
+    if heisenberg_mode {
+        let Some(observable) = &options.observable else {
+            usage();
+        };
+        let (num_qubits, _, gates) = analyze(filename, definitions, true)?;
+        if observable.num_qubits != num_qubits {
+            return Err(Failure::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format![
+                    "The observable acts on {} qubits but the register has {num_qubits}",
+                    observable.num_qubits
+                ],
+            )));
+        }
+        let result = propagate_observable(observable, &gates, threshold)?;
+        write_heisenberg(&mut report, filename, gates.len(), &result);
+        return write_report(&report, output_path, !quiet);
+    }
+    if verify_mode {
+        let (num_qubits, _, gates) = analyze(filename, definitions.clone(), true)?;
+        let against_gates = match against {
+            Some(path) => {
+                let (against_qubits, _, gates) = analyze(path, definitions, true)?;
+                if against_qubits != num_qubits {
+                    return Err(Failure::from(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format![
+                            "{filename} has {num_qubits} qubits but {path} has {against_qubits}"
+                        ],
+                    )));
+                }
+                gates
+            }
+            None => Vec::new(),
+        };
+        let verification = verify_equivalent(num_qubits, &gates, &against_gates)?;
+        writeln!(report, "File:    {filename}").unwrap();
+        match against {
+            Some(path) => writeln!(report, "Against: {path}").unwrap(),
+            None => writeln!(report, "Against: the identity").unwrap(),
+        }
+        match verification {
+            Verification::Equal => {
+                writeln!(report, "Equal up to a global phase").unwrap();
+            }
+            Verification::Differs { basis_state } => {
+                writeln!(
+                    report,
+                    "Not equal, as shown by the basis state |{basis_state:0num_qubits$b}⟩"
+                )
+                .unwrap();
+                write_report(&report, output_path, !quiet)?;
+                process::exit(EXIT_DIFFERENCE);
+            }
+        }
+        return write_report(&report, output_path, !quiet);
+    }
+    if compare_hardware_mode {
+        let Some(counts_path) = counts_path else {
+            usage();
+        };
+        let counts = read_distribution(counts_path)?;
+        let simulation = simulate(filename, definitions, options, quiet)?;
+        let distance = compare_distributions(&counts, &simulation.distribution())?;
+        writeln!(report, "File:               {filename}").unwrap();
+        writeln!(report, "Counts:             {counts_path}").unwrap();
+        write_distance(&mut report, &distance);
+        return write_report(&report, output_path, !quiet);
+    }
+    if tomography_mode {
+        let shots = options.shots.unwrap_or(DEFAULT_TOMOGRAPHY_SHOTS);
+        let seed = options.seed;
+        // The shots are for tomography rather than for an observable.
+        options.shots = None;
+        let simulation = simulate(filename, definitions, options, quiet)?;
+        let state = &simulation.final_state;
+        if tomography_qubits.is_empty() {
+            tomography_qubits = (0..state.num_qubits()).collect();
+        }
+        let result = tomography(state, &tomography_qubits, shots, &mut Rng::new(seed))?;
+        write_tomography(&mut report, filename, &result);
+        return write_report(&report, output_path, !quiet);
+    }
+    if shadows_mode {
+        let shots = options.shots.take().unwrap_or(DEFAULT_SHADOW_SHOTS);
+        // The observable is estimated from the shadow rather than by the simulator.
+        let Some(observable) = options.observable.take() else {
+            usage();
+        };
+        let seed = options.seed;
+        let simulation = simulate(filename, definitions, options, quiet)?;
+        let shadow = classical_shadow(
+            &simulation.final_state,
+            &observable,
+            shots,
+            &mut Rng::new(seed),
+        )?;
+        write_shadow(&mut report, filename, &shadow);
+        return write_report(&report, output_path, !quiet);
+    }
+    if xeb_mode {
+        let shots = options.shots.take().unwrap_or(DEFAULT_XEB_SHOTS);
+        let seed = options.seed;
+        let simulation = simulate(filename, definitions, options, quiet)?;
+        let state = &simulation.final_state;
+        let samples = match samples_path {
+            Some(path) => {
+                parse_samples(&fs::read_to_string(path)?, state.num_qubits()).map_err(|error| {
+                    Failure::parse(io::Error::new(error.kind(), format!["{error} of '{path}'"]))
+                })?
+            }
+            None => sample_bitstrings(state, shots, &mut Rng::new(seed)),
+        };
+        let xeb = linear_xeb(state, &samples)?;
+        writeln!(report, "File:     {filename}").unwrap();
+        match samples_path {
+            Some(path) => writeln!(report, "Samples:  {} from {path}", xeb.samples).unwrap(),
+            None => writeln!(report, "Samples:  {} noiseless", xeb.samples).unwrap(),
+        }
+        writeln!(
+            report,
+            "Fidelity: {:+.6} ± {:.6} (noiseless {:+.6})",
+            xeb.fidelity.mean, xeb.fidelity.standard_error, xeb.ideal
+        )
+        .unwrap();
+        return write_report(&report, output_path, !quiet);
+    }
+    if compare_mode {
+        let Some(reference) = reference else {
+            usage();
+        };
+        let amplitudes = read_npy(io::BufReader::new(File::open(reference)?))?;
+        let amplitudes = bit_order.reorder(&amplitudes);
+        let simulation = simulate(filename, definitions, options, quiet)?;
+        let comparison = compare(&simulation.final_state, &amplitudes, tolerance)?;
 
-            println!("Simulating file {filename} with {num_qubits} qubits");
+        writeln!(report, "Fidelity: {}", comparison.fidelity).unwrap();
+        writeln!(
+            report,
+            "Max amplitude deviation: {:e}",
+            comparison.max_deviation
+        )
+        .unwrap();
+        match comparison.first_difference {
+            Some(index) => {
+                let num_qubits = simulation.final_state.num_qubits();
+                // The index is given in the order of the reference.
+                writeln!(
+                    report,
+                    "First differing basis state: |{index:0num_qubits$b}⟩ (index {})",
+                    bit_order.index(index, num_qubits)
+                )
+                .unwrap();
+                write_report(&report, output_path, !quiet)?;
+                // Exit with a failure so that comparisons can be used as acceptance tests.
+                process::exit(EXIT_DIFFERENCE);
+            }
+            None => writeln!(
+                report,
+                "No amplitudes differ by more than {}",
+                tolerance.absolute
+            )
+            .unwrap(),
+        }
+        return write_report(&report, output_path, !quiet);
+    }
 
-            let mut state = State::new(num_qubits);
-            state.add_or_insert(Ket::new_zero_ket(num_qubits));
-            state
+    // Only color output that is going straight to a terminal.
+    let color = !no_color
+        && output_path.is_none()
+        && env::var_os("NO_COLOR").is_none()
+        && io::stdout().is_terminal();
+    let write_result = |report: &mut String, simulation: &SimulationResult| {
+        if json_output {
+            writeln!(report, "{}", simulation.to_json()).unwrap();
+            return;
         }
-        None => {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "No quantum register was defined",
-            ));
+        write_summary(report, filename, simulation, color);
+        if print_schedule {
+            writeln!(report, "\nSchedule:\n{}", simulation.schedule).unwrap();
         }
     };
+    // The JSON result is printed even when quiet.
+    let print = json_output || !quiet;
 
-    // Handle instructions.
-
-    // Creates three matching groups. One for the instruction, and two for the possible
-    // qubit registers.
-    let qreg_name = quantum_register.unwrap().name;
-    let instruction_re_str =
-        format![r"([a-z]+)\s(?:{qreg_name}\[([0-9]+)\])*(?:(?:,|\s){qreg_name}\[([0-9]+)\])*"];
-    let instruction_re = Regex::new(&instruction_re_str).unwrap();
-    let start = Instant::now();
-    for line_result in &mut reader_lines {
-        line_number += 1;
-        match line_result {
-            Ok(line) => {
-                if let Some(caps) = instruction_re.captures(&line) {
-                    let instruction = caps.get(1).unwrap().as_str();
-                    let qubit1: Option<usize> =
-                        caps.get(2).map(|qubit| qubit.as_str().parse().unwrap());
-                    let qubit2: Option<usize> =
-                        caps.get(3).map(|qubit| qubit.as_str().parse().unwrap());
-                    match instruction {
-                        "h" => {
-                            state = apply_gate_to_state(
-                                state,
-                                &Gate::H {
-                                    target: qubit1.unwrap(),
-                                },
-                            );
-                        }
-                        "x" => {
-                            state = apply_gate_to_state(
-                                state,
-                                &Gate::X {
-                                    target: qubit1.unwrap(),
-                                },
-                            );
-                        }
-                        "t" => {
-                            state = apply_gate_to_state(
-                                state,
-                                &Gate::T {
-                                    target: qubit1.unwrap(),
-                                },
-                            )
-                        }
-                        "tdg" => {
-                            state = apply_gate_to_state(
-                                state,
-                                &Gate::TDgr {
-                                    target: qubit1.unwrap(),
-                                },
-                            )
-                        }
-                        "cx" => {
-                            state = apply_gate_to_state(
-                                state,
-                                &Gate::CX {
-                                    control: qubit1.unwrap(),
-                                    target: qubit2.unwrap(),
-                                },
-                            )
-                        }
-                        _ => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                format![
-                                    "Unknown instruction '{instruction}' on line {line_number}"
-                                ],
-                            ));
-                        }
-                    }
+    // Stopped when it is dropped, once the simulation has finished.
+    let _watchdog = (watchdog_interval.is_some() || max_memory.is_some()).then(|| {
+        let kets = Arc::new(AtomicUsize::new(0));
+        options.kets_gauge = Some(Arc::clone(&kets));
+        let interval = watchdog_interval.unwrap_or(DEFAULT_WATCHDOG_INTERVAL_MS);
+        let log = watchdog_interval.is_some();
+        Watchdog::spawn(
+            Duration::from_millis(interval as u64),
+            kets,
+            move |sample| watch_memory(sample, log, max_memory),
+        )
+    });
+
+    // The trajectory, timeline and profile are not part of the cached report.
+    let extra_outputs = [trajectory_path, timeline_path, profile_path];
+    if let Some(cache_dir) = cache_dir.filter(|_| extra_outputs.iter().all(Option::is_none)) {
+        let circuit = match run_mode {
+            true => read_compiled(filename)?,
+            false => {
+                let file = File::open(filename)?;
+                CompiledCircuit::compile(Parser::new(io::BufReader::new(file)), definitions)
+                    .map_err(Failure::parse)?
+            }
+        };
+        // Everything other than the circuit that shapes the report.
+        let settings = format![
+            "{options:?} json={json_output} schedule={print_schedule} color={color} file={filename}"
+        ];
+        let cache = ResultCache::new(cache_dir);
+        let key = ResultCache::key(&circuit, &settings);
+        if let Some(report) = cache.get(&key)? {
+            if !quiet {
+                println!("Using the cached result {}", cache.path(&key).display());
+            }
+            return write_report(&report, output_path, print);
+        }
+        if !quiet {
+            println!(
+                "Simulating file {filename} with {} qubits",
+                circuit.register.size
+            );
+        }
+        let simulation = Simulator::new(GateDefinitions::new(), options).run_compiled(circuit)?;
+        print_warnings(&simulation);
+        write_result(&mut report, &simulation);
+        cache.insert(&key, &report)?;
+        return write_report(&report, output_path, print);
+    }
+
+    let simulation = match run_mode {
+        true => simulate_compiled(filename, options, quiet)?,
+        false => simulate(filename, definitions, options, quiet)?,
+    };
+    if let (Some(path), Some(trajectory)) = (trajectory_path, &simulation.trajectory) {
+        fs::write(path, trajectory.to_csv())?;
+    }
+    if let Some(path) = timeline_path {
+        let timeline = match path.ends_with(".svg") {
+            true => simulation.schedule.to_svg(),
+            false => simulation.schedule.to_json(),
+        };
+        fs::write(path, timeline)?;
+    }
+    if let (Some(path), Some(profile)) = (profile_path, &simulation.profile) {
+        fs::write(path, profile.to_folded())?;
+    }
+    write_result(&mut report, &simulation);
+    write_report(&report, output_path, print)
+}
+
+/// Runs an interactive session on standard input until it ends or `:quit` is entered.
+///
+/// A prompt is only shown on a terminal, so that the output stays line oriented when
+/// another program drives the session. Errors are printed and the session continues.
+fn run_session(mut session: Session) -> Result<(), Failure> {
+    let interactive = io::stdin().is_terminal();
+    if interactive {
+        println!("Enter :help for a list of commands");
+    }
+    let mut stdout = io::stdout();
+    let prompt = |stdout: &mut io::Stdout| -> io::Result<()> {
+        if interactive {
+            write!(stdout, "qasm> ")?;
+        }
+        stdout.flush()
+    };
+    prompt(&mut stdout)?;
+    for line in io::stdin().lock().lines() {
+        match session.handle_line(&line?) {
+            Ok(Some(output)) => write!(stdout, "{output}")?,
+            Ok(None) => break,
+            Err(error) => eprintln!("Error: {error}"),
+        }
+        prompt(&mut stdout)?;
+    }
+    Ok(())
+}
+
+/// Returns the settings of the config file as command line options.
+///
+/// The config file is given with `--config`, or is [`CONFIG_FILE`] in the current
+/// directory if there is one. Each `key = value` setting is the same as the option
+/// `--key value`, and a `key = true` setting is the same as the flag `--key`. Settings
+/// in [`COMPARE_CONFIG_KEYS`] are left out unless `compare_mode` is set.
+fn config_args(args: &[String], compare_mode: bool) -> Result<Vec<String>, Failure> {
+    let path = match args.iter().position(|arg| arg == "--config") {
+        Some(index) => match args.get(index + 1) {
+            Some(path) => PathBuf::from(path),
+            None => usage(),
+        },
+        None if Path::new(CONFIG_FILE).exists() => PathBuf::from(CONFIG_FILE),
+        None => return Ok(Vec::new()),
+    };
+    let source = fs::read_to_string(&path)?;
+    let in_file = |error: io::Error| {
+        Failure::parse(io::Error::new(
+            error.kind(),
+            format!["{error} of '{}'", path.display()],
+        ))
+    };
+
+    let mut config_args = Vec::new();
+    for setting in parse_config(&source).map_err(in_file)? {
+        let key = setting.key.as_str();
+        if COMPARE_CONFIG_KEYS.contains(&key) {
+            if !compare_mode {
+                continue;
+            }
+        } else if !CONFIG_KEYS.contains(&key) {
+            return Err(in_file(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["Unknown setting '{}' on line {}", setting.key, setting.line],
+            )));
+        }
+        match setting.value {
+            Value::Boolean(true) => config_args.push(format!["--{}", setting.key]),
+            Value::Boolean(false) => {}
+            value => {
+                config_args.push(format!["--{}", setting.key]);
+                config_args.push(value.to_string());
+            }
+        }
+    }
+    Ok(config_args)
+}
+
+/// Writes the text report of a simulation: a header describing the run, the final state
+/// with one aligned ket per line, and a footer summarising the work done.
+fn write_summary(report: &mut String, filename: &str, simulation: &SimulationResult, color: bool) {
+    let state = &simulation.final_state;
+    writeln!(report, "File:    {filename}").unwrap();
+    writeln!(report, "Qubits:  {}", state.num_qubits()).unwrap();
+    match simulation.dense_after_gates {
+        Some(gates) => writeln!(
+            report,
+            "Backend: {} (switched from sparse after {gates} gates)",
+            simulation.backend.name()
+        ),
+        None => writeln!(report, "Backend: {}", simulation.backend.name()),
+    }
+    .unwrap();
+
+    match &simulation.marginals {
+        Some(marginals) => writeln!(report, "\nFinal state of qubits {:?}:", marginals.lightcone),
+        None => writeln!(report, "\nFinal state:"),
+    }
+    .unwrap();
+    for ket in state.sorted_kets() {
+        let bits: String = ket
+            .bit_vec()
+            .iter()
+            .rev()
+            .map(|bit| if *bit { '1' } else { '0' })
+            .collect();
+        // Adding zero turns a rounded negative zero into a positive one.
+        let real = (ket.amplitude.re * 1000.0).round() / 1000.0 + 0.0;
+        let imaginary = (ket.amplitude.im * 1000.0).round() / 1000.0 + 0.0;
+        let amplitude = format!["{real:+.3}{imaginary:+.3}i"];
+        if color {
+            let code = PHASE_COLORS[phase_sector(ket.amplitude.arg())];
+            writeln!(report, "  |{bits}⟩  \x1b[{code}m{amplitude}\x1b[0m").unwrap();
+        } else {
+            writeln!(report, "  |{bits}⟩  {amplitude}").unwrap();
+        }
+    }
+
+    let gates_applied: usize = simulation.gate_counts.values().sum();
+    writeln!(report, "\nGates applied:  {gates_applied}").unwrap();
+    match simulation.peak_kets {
+        Some(peak_kets) => writeln!(report, "Kets:           {} (peak {peak_kets})", state.len()),
+        None => writeln!(report, "Kets:           {}", state.len()),
+    }
+    .unwrap();
+    if simulation.pruned_probability > 0.0 {
+        writeln!(
+            report,
+            "Pruned:         {:.3e} of the probability",
+            simulation.pruned_probability
+        )
+        .unwrap();
+    }
+    writeln!(report, "Execution time: {:?}", simulation.wall_time).unwrap();
+
+    if let Some(diagnostics) = &simulation.diagnostics {
+        writeln!(
+            report,
+            "\nNon-zero amplitudes:         {}",
+            diagnostics.nonzero_amplitudes
+        )
+        .unwrap();
+        writeln!(
+            report,
+            "Inverse participation ratio: {}",
+            diagnostics.inverse_participation_ratio
+        )
+        .unwrap();
+        for (subsystem, purity) in &diagnostics.purities {
+            writeln!(report, "Purity of qubits {subsystem:?}: {purity}").unwrap();
+        }
+    }
+
+    if let Some(expectation) = &simulation.expectation {
+        writeln!(report, "\nExpectation value: {:+.6}", expectation.value).unwrap();
+        let width = expectation
+            .terms
+            .iter()
+            .map(|(term, _)| term.coefficient.to_string().len())
+            .max()
+            .unwrap_or(0);
+        for (term, value) in &expectation.terms {
+            writeln!(
+                report,
+                "  {:>width$} {}  {value:+.6}",
+                term.coefficient, term.label
+            )
+            .unwrap();
+        }
+    }
+
+    if let Some(sampled) = &simulation.sampled_expectation {
+        let estimate = |estimate: &Estimate| {
+            format![
+                "{:+.6} ± {:.6} (variance {:.6})",
+                estimate.mean, estimate.standard_error, estimate.variance
+            ]
+        };
+        writeln!(
+            report,
+            "\nEstimated from {} shots of {} measurement circuits: {}",
+            sampled.shots,
+            sampled.groups,
+            estimate(&sampled.value)
+        )
+        .unwrap();
+        let width = sampled
+            .terms
+            .iter()
+            .map(|(term, _)| term.coefficient.to_string().len())
+            .max()
+            .unwrap_or(0);
+        for (term, value) in &sampled.terms {
+            writeln!(
+                report,
+                "  {:>width$} {}  {}",
+                term.coefficient,
+                term.label,
+                estimate(value)
+            )
+            .unwrap();
+        }
+    }
+
+    if let Some(marginals) = &simulation.marginals {
+        writeln!(
+            report,
+            "\nMarginal probabilities of qubits {:?}:",
+            marginals.qubits
+        )
+        .unwrap();
+        let width = marginals.qubits.len();
+        for (outcome, probability) in &marginals.probabilities {
+            writeln!(report, "  |{outcome:0width$b}⟩  {probability:.6}").unwrap();
+        }
+    }
+}
+
+/// Writes the report of a tomography run: how well the reconstruction matches the exact
+/// reduced state, followed by the estimated and exact value of each Pauli string.
+fn write_tomography(report: &mut String, filename: &str, result: &Tomography) {
+    let (estimated_purity, exact_purity) = result.purities();
+    writeln!(report, "File:     {filename}").unwrap();
+    writeln!(report, "Qubits:   {:?}", result.qubits).unwrap();
+    writeln!(
+        report,
+        "Settings: {} bases with {} shots each",
+        result.settings, result.shots
+    )
+    .unwrap();
+    // Tr(rho sigma) is only the fidelity when the exact state is pure.
+    if (exact_purity - 1.0).abs() < 1e-9 {
+        writeln!(report, "Fidelity: {:.6}", result.overlap()).unwrap();
+    } else {
+        writeln!(report, "Overlap:  {:.6}", result.overlap()).unwrap();
+    }
+    writeln!(report, "Distance: {:.6}", result.distance()).unwrap();
+    writeln!(
+        report,
+        "Purity:   {estimated_purity:.6} (exact {exact_purity:.6})"
+    )
+    .unwrap();
+
+    let width = result.qubits.len().max("Pauli".len());
+    writeln!(report, "\n{:<width$}  Estimated      Exact", "Pauli").unwrap();
+    for (label, estimated, exact) in &result.paulis {
+        writeln!(report, "{label:<width$}  {estimated:+.6}  {exact:+.6}").unwrap();
+    }
+}
+
+/// Writes the report of a classical shadow: the estimated and exact expectation value of
+/// the observable, followed by those of each term.
+fn write_shadow(report: &mut String, filename: &str, shadow: &ShadowEstimate) {
+    let (estimate, exact) = &shadow.value;
+    writeln!(report, "File:     {filename}").unwrap();
+    writeln!(
+        report,
+        "Shots:    {} in {} random settings",
+        shadow.shots, shadow.settings
+    )
+    .unwrap();
+    writeln!(
+        report,
+        "Value:    {:+.6} ± {:.6} (exact {exact:+.6})",
+        estimate.mean, estimate.standard_error
+    )
+    .unwrap();
+
+    let width = shadow
+        .terms
+        .iter()
+        .map(|(term, ..)| term.coefficient.to_string().len() + 1 + term.label.len())
+        .max()
+        .unwrap_or(0)
+        .max("Term".len());
+    writeln!(
+        report,
+        "\n{:<width$}  Estimated   Std. error  Exact",
+        "Term"
+    )
+    .unwrap();
+    for (term, estimate, exact) in &shadow.terms {
+        let term = format!["{} {}", term.coefficient, term.label];
+        writeln!(
+            report,
+            "{term:<width$}  {:+.6}  {:.6}    {exact:+.6}",
+            estimate.mean, estimate.standard_error
+        )
+        .unwrap();
+    }
+}
+
+/// Reads a distribution from a counts file or the JSON result of a simulation.
+fn read_distribution(path: &str) -> Result<Distribution, Failure> {
+    parse_distribution(&fs::read_to_string(path)?).map_err(|error| {
+        Failure::parse(io::Error::new(error.kind(), format!["{error} of '{path}'"]))
+    })
+}
+
+/// Writes how far apart two distributions are.
+fn write_distance(report: &mut String, distance: &DistributionDistance) {
+    writeln!(report, "Outcomes:           {}", distance.outcomes).unwrap();
+    writeln!(
+        report,
+        "Total variation:    {:.6}",
+        distance.total_variation
+    )
+    .unwrap();
+    writeln!(
+        report,
+        "Hellinger fidelity: {:.6}",
+        distance.hellinger_fidelity
+    )
+    .unwrap();
+    writeln!(report, "KL divergence:      {:.6}", distance.kl_divergence).unwrap();
+}
+
+/// Writes the report of propagating an observable backwards through a circuit: its
+/// expectation value followed by the terms of the evolved observable.
+fn write_heisenberg(
+    report: &mut String,
+    filename: &str,
+    num_gates: usize,
+    result: &HeisenbergResult,
+) {
+    let terms = &result.observable.terms;
+    writeln!(report, "File:        {filename}").unwrap();
+    writeln!(report, "Gates:       {num_gates}").unwrap();
+    writeln!(
+        report,
+        "Terms:       {} (peak {})",
+        terms.len(),
+        result.peak_terms
+    )
+    .unwrap();
+    if result.truncated_weight > 0.0 {
+        writeln!(
+            report,
+            "Truncated:   {:.3e} of the coefficient weight",
+            result.truncated_weight
+        )
+        .unwrap();
+    }
+    writeln!(report, "Expectation: {:+.6}", result.expectation).unwrap();
+
+    writeln!(report, "\nEvolved observable:").unwrap();
+    for term in terms {
+        writeln!(report, "  {:+.6} {}", term.coefficient, term.label).unwrap();
+    }
+}
+
+/// The ANSI colors for amplitudes, by the sixth of the complex plane their phase is in,
+/// starting from a phase of zero.
+const PHASE_COLORS: [u8; 6] = [32, 36, 34, 31, 35, 33];
+
+/// Returns which sixth of the complex plane a phase is in, with each sixth centred on a
+/// multiple of 60 degrees so that real and imaginary amplitudes keep one color.
+fn phase_sector(phase: f64) -> usize {
+    let sector = (phase / (std::f64::consts::PI / 3.0)).round() as i64;
+    sector.rem_euclid(6) as usize
+}
+
+/// Writes the report to the file at `path`, or to standard output if no path is given
+/// and `print` is set.
+fn write_report(report: &str, path: Option<&String>, print: bool) -> Result<(), Failure> {
+    match path {
+        Some(path) => fs::write(path, report)?,
+        None if print => print!("{report}"),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Parses and simulates the QASM file at `filename`, starting from the zero state.
+///
+/// Progress messages are printed unless `quiet` is set.
+fn simulate(
+    filename: &str,
+    definitions: GateDefinitions,
+    options: Options,
+    quiet: bool,
+) -> Result<SimulationResult, Failure> {
+    let file = File::open(filename)?;
+    let parse_failed = Cell::new(false);
+    let statements = Parser::new(io::BufReader::new(file)).inspect(|statement| {
+        match statement.as_ref().map(|statement| &statement.kind) {
+            Err(_) => parse_failed.set(true),
+            _ if quiet => {}
+            Ok(StatementKind::Version(version)) => println!("Using QASM version: {}", version),
+            Ok(StatementKind::QuantumRegister(register)) => {
+                println!("Simulating file {filename} with {} qubits", register.size)
+            }
+            _ => {}
+        }
+    });
+
+    let mut simulator = Simulator::new(definitions, options);
+    match simulator.run_streaming(statements) {
+        Err(error) if parse_failed.get() => return Err(Failure::parse(error)),
+        result => result?,
+    }
+    let simulation = simulator.finish()?;
+    print_warnings(&simulation);
+    Ok(simulation)
+}
+
+/// Reads the compiled circuit at `filename` and simulates it, starting from the zero
+/// state.
+///
+/// A progress message is printed unless `quiet` is set.
+fn simulate_compiled(
+    filename: &str,
+    options: Options,
+    quiet: bool,
+) -> Result<SimulationResult, Failure> {
+    let circuit = read_compiled(filename)?;
+    if !quiet {
+        println!(
+            "Simulating compiled file {filename} with {} qubits",
+            circuit.register.size
+        );
+    }
+    let simulator = Simulator::new(GateDefinitions::new(), options);
+    let simulation = simulator.run_compiled(circuit)?;
+    print_warnings(&simulation);
+    Ok(simulation)
+}
+
+/// Reads the compiled circuit at `filename`.
+fn read_compiled(filename: &str) -> Result<CompiledCircuit, Failure> {
+    let file = File::open(filename)?;
+    CompiledCircuit::read_from(io::BufReader::new(file)).map_err(Failure::parse)
+}
+
+/// Prints the warnings of a simulation to standard error, where they are seen even when
+/// the result is written to a file or only the JSON result is printed.
+fn print_warnings(simulation: &SimulationResult) {
+    for warning in &simulation.warnings {
+        eprintln!("Warning: {warning}");
+    }
+}
+
+/// Parses the QASM file at `filename` and analyses its gates without simulating it,
+/// returning the number of qubits along with the analysis and, if `keep_gates` is set,
+/// the gates themselves.
+fn analyze(
+    filename: &str,
+    definitions: GateDefinitions,
+    keep_gates: bool,
+) -> Result<(usize, CircuitAnalysis, Vec<Gate>), Failure> {
+    let file = File::open(filename)?;
+    let mut lowering = Lowering::new(definitions);
+    let mut analysis = None;
+    let mut all_gates = Vec::new();
+    for statement in Parser::new(io::BufReader::new(file)) {
+        let operation = lowering.lower(statement.map_err(Failure::parse)?)?;
+        if let Some(register) = lowering.register() {
+            let analysis = analysis.get_or_insert_with(|| CircuitAnalysis::new(register.size));
+            if let Some(Operation::GateCall { gates, .. }) = operation {
+                gates.iter().for_each(|gate| analysis.push(gate));
+                if keep_gates {
+                    all_gates.extend(gates);
                 }
             }
+        }
+    }
+    lowering.check_complete()?;
+    let num_qubits = lowering.register().unwrap().size;
+    Ok((num_qubits, analysis.unwrap(), all_gates))
+}
+
+/// Prints a memory sample of the watchdog if `log` is set, and exits if the process uses
+/// more than `max_memory` mebibytes.
+fn watch_memory(sample: &MemorySample, log: bool, max_memory: Option<usize>) {
+    let mebibytes = sample
+        .resident_bytes
+        .map(|bytes| bytes as f64 / (1u64 << 20) as f64);
+    if log {
+        let resident = mebibytes.map_or("unknown".to_string(), |used| format!["{used:.1} MiB"]);
+        eprintln!(
+            "Memory after {:.3}s: {resident} resident with {} kets",
+            sample.elapsed.as_secs_f64(),
+            sample.kets
+        );
+    }
+    if let (Some(used), Some(max_memory)) = (mebibytes, max_memory) {
+        if used > max_memory as f64 {
+            eprintln!(
+                "Error: The process uses {used:.1} MiB with {} kets, more than the --max-memory of {max_memory} MiB",
+                sample.kets
+            );
+            process::exit(EXIT_RESOURCE_LIMIT);
+        }
+    }
+}
+
+/// Parses a positive count given as a command line option value.
+fn parse_count(value: Option<&String>) -> usize {
+    match value.map(|value| value.parse()) {
+        Some(Ok(count)) if count > 0 => count,
+        _ => usage(),
+    }
+}
+
+/// Parses a finite, non-negative absolute tolerance given as a command line option value.
+fn parse_tolerance(value: Option<&String>) -> Tolerance {
+    match value.map(|value| value.parse::<f64>()) {
+        Some(Ok(value)) if value.is_finite() && value >= 0.0 => Tolerance::absolute(value),
+        _ => usage(),
+    }
+}
+
+/// Parses a comma separated list of qubits given as a command line option value.
+fn parse_qubits(value: Option<&String>) -> Vec<usize> {
+    let qubits: Result<Vec<usize>, _> = match value {
+        Some(value) => value.split(',').map(|qubit| qubit.trim().parse()).collect(),
+        None => usage(),
+    };
+    match qubits {
+        Ok(qubits) if !qubits.is_empty() => qubits,
+        _ => usage(),
+    }
+}
+
+/// Prints the command line usage and exits with a failure.
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(EXIT_USAGE);
+}
+
+/// Binds the gate definitions in the file at `path` as implementations of opaque gates.
+fn load_opaque_map(path: &str, definitions: &mut GateDefinitions) -> Result<(), Failure> {
+    let file = File::open(path)?;
+    for statement in Parser::new(io::BufReader::new(file)) {
+        let statement = statement.map_err(Failure::parse)?;
+        match statement.kind {
+            StatementKind::Version(_) | StatementKind::Include(_) => {}
+            StatementKind::GateDefinition(definition) => {
+                definitions.bind_opaque(definition, statement.line)?;
+            }
             _ => {
-                return Err(io::Error::new(
+                return Err(Failure::parse(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    format!["Could not parse data on line {line_number}"],
-                ));
+                    format![
+                        "Only gate definitions are allowed in opaque map '{path}' on line {}",
+                        statement.line
+                    ],
+                )));
             }
         }
-        // println!("State after instruction: {}", state);
     }
-    let duration = start.elapsed();
+    Ok(())
+}
 
-    println!("Final state: {}", state);
-    println!("Execution time: {:?}\n", duration);
+#[cfg(test)]
+mod tests {
 
-    Ok(())
+    use super::*;
+
+    /// Tests that settings of the compare mode in a config file are only used in that
+    /// mode, so that they do not shift the filenames of other modes such as diff.
+    #[test]
+    fn test_config_args_by_mode() {
+        let path = env::temp_dir().join(format!["qasm-simulator-{}.toml", process::id()]);
+        fs::write(&path, "fuse = true\ntolerance = 0.5\n").unwrap();
+        let args: Vec<String> = ["--config", path.to_str().unwrap(), "a.qasm", "b.qasm"]
+            .map(String::from)
+            .to_vec();
+        let diff_args = config_args(&args, false);
+        let compare_args = config_args(&args, true);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(diff_args.ok().unwrap(), vec!["--fuse"]);
+        assert_eq!(
+            compare_args.ok().unwrap(),
+            vec!["--fuse", "--tolerance", "0.5"]
+        );
+    }
 }
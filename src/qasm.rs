@@ -0,0 +1,11 @@
+pub mod cache;
+pub mod compiled;
+pub mod definitions;
+pub mod expression;
+pub mod lexer;
+pub mod lowering;
+pub mod parser;
+pub mod session;
+pub mod simulator;
+pub mod warning;
+pub mod writer;
@@ -0,0 +1,189 @@
+use crate::qasm::compiled::CompiledCircuit;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+
+/// The offset basis of the 128 bit FNV-1a hash.
+const FNV_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+
+/// The prime of the 128 bit FNV-1a hash.
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// Returns the 128 bit FNV-1a hash of some bytes, which unlike the hashers of the
+/// standard library is the same on every platform and in every build.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::cache::stable_hash;
+///
+/// assert_eq!(stable_hash(b""), 0x6c62272e07bb014262b821756295c58d);
+/// assert_ne!(stable_hash(b"h q[0];"), stable_hash(b"h q[1];"));
+/// ```
+pub fn stable_hash(bytes: &[u8]) -> u128 {
+    let mut hasher = StableHasher::new();
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+/// Computes the same hash as [`stable_hash`] from bytes that arrive a piece at a time,
+/// so that they never have to be held at once.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::cache::{stable_hash, StableHasher};
+///
+/// let mut hasher = StableHasher::new();
+/// hasher.update(b"h q[0];");
+/// hasher.update(b"cx q[0], q[1];");
+/// assert_eq!(hasher.finish(), stable_hash(b"h q[0];cx q[0], q[1];"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct StableHasher {
+    hash: u128,
+}
+
+impl StableHasher {
+    /// Creates a hasher that has not been given any bytes.
+    pub fn new() -> Self {
+        Self { hash: FNV_OFFSET }
+    }
+
+    /// Adds some bytes to those hashed so far.
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.hash = bytes.iter().fold(self.hash, |hash, byte| {
+            (hash ^ *byte as u128).wrapping_mul(FNV_PRIME)
+        });
+    }
+
+    /// Returns the hash of all of the bytes given so far.
+    pub fn finish(&self) -> u128 {
+        self.hash
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hashing never fails, so the hasher can be written to like a file.
+impl Write for StableHasher {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.update(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A directory of reports of earlier simulations, so that a circuit that is simulated
+/// again with the same settings is not simulated at all.
+///
+/// Reports are keyed by the [canonical hash](CompiledCircuit::canonical_hash) of the
+/// circuit, the version of the simulator and a description of everything else that
+/// shapes the report, such as the simulation options and the output format. Nothing is
+/// ever removed from the directory.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::cache::ResultCache;
+/// use quantum_simulator::qasm::compiled::CompiledCircuit;
+/// use quantum_simulator::qasm::definitions::GateDefinitions;
+/// use quantum_simulator::qasm::parser::Parser;
+///
+/// let source = "OPENQASM 2.0;\nqreg q[1];\nh q[0];";
+/// let circuit = CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+///     .unwrap();
+/// let key = ResultCache::key(&circuit, "--json");
+/// assert_eq!(key.len(), 32);
+/// assert_ne!(ResultCache::key(&circuit, ""), key);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ResultCache {
+    directory: PathBuf,
+}
+
+impl ResultCache {
+    /// Creates a cache of the reports in `directory`, which is created when the first
+    /// report is stored.
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    /// Returns the key of the report of simulating `circuit`, where `settings` describes
+    /// everything other than the circuit that the report depends on.
+    pub fn key(circuit: &CompiledCircuit, settings: &str) -> String {
+        let mut bytes = circuit.canonical_hash().to_le_bytes().to_vec();
+        bytes.extend(env!("CARGO_PKG_VERSION").as_bytes());
+        bytes.push(0);
+        bytes.extend(settings.as_bytes());
+        format!["{:032x}", stable_hash(&bytes)]
+    }
+
+    /// Returns the path the report with the given key is stored at.
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!["{key}.out"])
+    }
+
+    /// Returns the report stored with the given key, if there is one.
+    pub fn get(&self, key: &str) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.path(key)) {
+            Ok(report) => Ok(Some(report)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Stores a report with the given key, replacing any report stored with it before.
+    pub fn insert(&self, key: &str, report: &str) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        // Renaming a complete file into place means that another run reading the same key
+        // never sees part of a report.
+        let partial = self
+            .directory
+            .join(format!["{key}.{}.partial", process::id()]);
+        fs::write(&partial, report)?;
+        fs::rename(&partial, self.path(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::qasm::definitions::GateDefinitions;
+    use crate::qasm::parser::Parser;
+    use std::env;
+
+    /// Tests that reports are stored and found by key, and that the key depends on the
+    /// gates and the settings.
+    #[test]
+    fn test_result_cache() {
+        let compile = |source: &str| {
+            CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+                .unwrap()
+        };
+        let circuit = compile("OPENQASM 2.0;\nqreg q[2];\nh q[0];\ncx q[0], q[1];");
+        let key = ResultCache::key(&circuit, "sparse");
+        let renamed = compile("OPENQASM 2.0;\nqreg r[2];\nh r[0]; cx r[0], r[1];");
+        assert_eq!(key, ResultCache::key(&renamed, "sparse"));
+        assert_ne!(key, ResultCache::key(&circuit, "dense"));
+        let other = compile("OPENQASM 2.0;\nqreg q[2];\nh q[1];\ncx q[0], q[1];");
+        assert_ne!(key, ResultCache::key(&other, "sparse"));
+
+        let directory = env::temp_dir().join(format!["qasm-cache-{}", process::id()]);
+        let cache = ResultCache::new(&directory);
+        assert_eq!(cache.get(&key).unwrap(), None);
+        cache.insert(&key, "first").unwrap();
+        cache.insert(&key, "second").unwrap();
+        assert_eq!(cache.get(&key).unwrap().as_deref(), Some("second"));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,536 @@
+use crate::gates::gate::Gate;
+use crate::qasm::cache::StableHasher;
+use crate::qasm::definitions::{first_duplicate, GateDefinitions};
+use crate::qasm::lowering::{Lowering, Operation};
+use crate::qasm::parser::Statement;
+use crate::quantum::register::{QubitRef, Register};
+use num::Complex;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// The bytes every compiled circuit starts with.
+const MAGIC: &[u8; 4] = b"QSIM";
+
+/// The version of the compiled circuit format, changed whenever the encoding changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// A circuit that has been parsed, validated and lowered into built in gates, so that it
+/// can be simulated many times without repeating that work.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::compiled::CompiledCircuit;
+/// use quantum_simulator::qasm::definitions::GateDefinitions;
+/// use quantum_simulator::qasm::parser::Parser;
+///
+/// let source = "OPENQASM 2.0;\nqreg q[2];\ngate bell a, b { h a; cx a, b; }\nbell q[0], q[1];";
+/// let circuit = CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+///     .unwrap();
+/// assert_eq!(circuit.register.size, 2);
+///
+/// let mut bytes = Vec::new();
+/// circuit.write_to(&mut bytes).unwrap();
+/// assert_eq!(CompiledCircuit::read_from(bytes.as_slice()).unwrap(), circuit);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledCircuit {
+    /// The QASM version from the header.
+    pub version: String,
+    /// The quantum register.
+    pub register: Register,
+    /// The line the register was declared on.
+    pub register_line: usize,
+    /// The operations of the circuit, in order.
+    pub operations: Vec<Operation>,
+}
+
+impl CompiledCircuit {
+    /// Lowers all of the statements, stopping at the first error.
+    pub fn compile(
+        statements: impl IntoIterator<Item = io::Result<Statement>>,
+        definitions: GateDefinitions,
+    ) -> io::Result<CompiledCircuit> {
+        let mut lowering = Lowering::new(definitions);
+        let mut register_line = None;
+        let mut operations = Vec::new();
+        for statement in statements {
+            let statement = statement?;
+            let line = statement.line;
+            operations.extend(lowering.lower(statement)?);
+            if register_line.is_none() && lowering.register().is_some() {
+                register_line = Some(line);
+            }
+        }
+        lowering.check_complete()?;
+        Ok(CompiledCircuit {
+            version: lowering.version().unwrap().to_string(),
+            register: lowering.register().unwrap().clone(),
+            register_line: register_line.unwrap(),
+            operations,
+        })
+    }
+
+    /// Returns a digest of the gates and delays of this circuit, which is the same however
+    /// the source was written. Whitespace, comments, the names of registers and gate
+    /// definitions, how gate calls were spelled and the QASM version make no difference,
+    /// and the digest is the same on every platform and in every build.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::qasm::compiled::CompiledCircuit;
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::parser::Parser;
+    ///
+    /// let compile = |source: &str| {
+    ///     CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+    ///         .unwrap()
+    /// };
+    /// let first = compile("OPENQASM 2.0;\nqreg q[2];\nh q[0];\ncx q[0], q[1];");
+    /// let second = compile("OPENQASM 3.0;\nqubit[2] r;\ngate bell a, b { h a; cx a, b; }\nbell r[0],r[1];");
+    /// assert_eq!(first.canonical_hash(), second.canonical_hash());
+    /// ```
+    pub fn canonical_hash(&self) -> u128 {
+        let mut hasher = CanonicalHasher::new(self.register.size);
+        for operation in &self.operations {
+            match operation {
+                Operation::GateCall { gates, .. } => {
+                    gates.iter().for_each(|gate| hasher.gate(gate))
+                }
+                Operation::Delay { qubits, duration } => hasher.delay(qubits, *duration),
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Writes the circuit in the compiled circuit format.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let writer = &mut writer;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        write_string(writer, &self.version)?;
+        write_string(writer, &self.register.name)?;
+        write_usize(writer, self.register.size)?;
+        write_usize(writer, self.register_line)?;
+        write_usize(writer, self.operations.len())?;
+        for operation in &self.operations {
+            match operation {
+                Operation::GateCall { name, line, gates } => {
+                    writer.write_all(&[0])?;
+                    write_string(writer, name)?;
+                    write_usize(writer, *line)?;
+                    write_usize(writer, gates.len())?;
+                    for gate in gates {
+                        write_gate(writer, gate)?;
+                    }
+                }
+                Operation::Delay { qubits, duration } => {
+                    writer.write_all(&[1])?;
+                    write_qubits(writer, &indices(qubits))?;
+                    writer.write_all(&duration.as_nanos().to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a circuit in the compiled circuit format.
+    ///
+    /// Returns an error if the data is not a compiled circuit, was written by a different
+    /// version of the format or refers to qubits outside the register.
+    pub fn read_from(mut reader: impl Read) -> io::Result<CompiledCircuit> {
+        let reader = &mut reader;
+        if read_array::<4>(reader)? != *MAGIC {
+            return Err(invalid("The file is not a compiled circuit".to_string()));
+        }
+        let format_version = u32::from_le_bytes(read_array(reader)?);
+        if format_version != FORMAT_VERSION {
+            return Err(invalid(format![
+                "Compiled circuit format version {format_version} is not supported"
+            ]));
+        }
+        let version = read_string(reader)?;
+        let register = Register {
+            name: read_string(reader)?,
+            size: read_usize(reader)?,
+        };
+        let register_line = read_usize(reader)?;
+        let num_operations = read_usize(reader)?;
+        let mut operations = Vec::new();
+        for _ in 0..num_operations {
+            let operation = match read_array::<1>(reader)?[0] {
+                0 => {
+                    let name = read_string(reader)?;
+                    let line = read_usize(reader)?;
+                    let num_gates = read_usize(reader)?;
+                    let gates = (0..num_gates)
+                        .map(|_| read_gate(reader))
+                        .collect::<io::Result<Vec<Gate>>>()?;
+                    Operation::GateCall { name, line, gates }
+                }
+                1 => {
+                    let qubits = read_qubits(reader)?.into_iter().map(QubitRef).collect();
+                    let nanos = u128::from_le_bytes(read_array(reader)?);
+                    let duration = Duration::new(
+                        (nanos / 1_000_000_000) as u64,
+                        (nanos % 1_000_000_000) as u32,
+                    );
+                    Operation::Delay { qubits, duration }
+                }
+                tag => {
+                    return Err(invalid(format![
+                        "Unknown operation {tag} in the compiled circuit"
+                    ]))
+                }
+            };
+            let qubits = match &operation {
+                Operation::GateCall { gates, .. } => gates.iter().flat_map(Gate::qubits).collect(),
+                Operation::Delay { qubits, .. } => indices(qubits),
+            };
+            if let Some(qubit) = qubits.iter().find(|qubit| **qubit >= register.size) {
+                return Err(invalid(format![
+                    "Qubit {qubit} is outside the register of {} qubits in the compiled circuit",
+                    register.size
+                ]));
+            }
+            if let Operation::GateCall { gates, .. } = &operation {
+                if let Some(gate) = gates
+                    .iter()
+                    .find(|gate| first_duplicate(&gate.qubits()).is_some())
+                {
+                    return Err(invalid(format![
+                        "Gate {} uses a qubit more than once in the compiled circuit",
+                        gate.name()
+                    ]));
+                }
+            }
+            operations.push(operation);
+        }
+        Ok(CompiledCircuit {
+            version,
+            register,
+            register_line,
+            operations,
+        })
+    }
+}
+
+impl fmt::Display for CompiledCircuit {
+    /// Writes the version and register, then each operation on its own line after its
+    /// index in [`CompiledCircuit::operations`]. Qubits are written as `q[i]` whatever
+    /// the register is called.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::qasm::compiled::CompiledCircuit;
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::parser::Parser;
+    ///
+    /// let source = "OPENQASM 2.0;\nqreg q[2];\ngate bell a, b { h a; cx a, b; }\nbell q[1], q[0];\nrz(0.25) q[0];";
+    /// let circuit = CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     circuit.to_string(),
+    ///     "OPENQASM 2.0, qreg q[2] on line 2\n\
+    ///      0: bell on line 4: h q[1]; cx q[1], q[0]\n\
+    ///      1: rz on line 5: rz(0.25) q[0]\n"
+    /// );
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "OPENQASM {}, qreg {}[{}] on line {}",
+            self.version, self.register.name, self.register.size, self.register_line
+        )?;
+        for (index, operation) in self.operations.iter().enumerate() {
+            writeln!(f, "{index}: {operation}")?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_usize(writer: &mut impl Write, value: usize) -> io::Result<()> {
+    writer.write_all(&(value as u64).to_le_bytes())
+}
+
+fn write_f64(writer: &mut impl Write, value: f64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_string(writer: &mut impl Write, value: &str) -> io::Result<()> {
+    write_usize(writer, value.len())?;
+    writer.write_all(value.as_bytes())
+}
+
+/// Returns the indices of the given qubits, as they are written to compiled circuits.
+fn indices(qubits: &[QubitRef]) -> Vec<usize> {
+    qubits.iter().map(|qubit| qubit.index()).collect()
+}
+
+fn write_qubits(writer: &mut impl Write, qubits: &[usize]) -> io::Result<()> {
+    write_usize(writer, qubits.len())?;
+    qubits
+        .iter()
+        .try_for_each(|qubit| write_usize(writer, *qubit))
+}
+
+/// Writes a gate as its tag and qubits, followed by its parameters.
+fn write_gate(writer: &mut impl Write, gate: &Gate) -> io::Result<()> {
+    let tag = match gate {
+        Gate::H { .. } => 0,
+        Gate::X { .. } => 1,
+        Gate::T { .. } => 2,
+        Gate::TDgr { .. } => 3,
+        Gate::CX { .. } => 4,
+        Gate::RZ { .. } => 5,
+        Gate::Unitary { .. } => 6,
+    };
+    writer.write_all(&[tag])?;
+    write_qubits(writer, &gate.qubits())?;
+    match gate {
+        Gate::RZ { theta, .. } => write_f64(writer, *theta),
+        Gate::Unitary { matrix, .. } => matrix.iter().flatten().try_for_each(|entry| {
+            write_f64(writer, entry.re)?;
+            write_f64(writer, entry.im)
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Computes the [canonical hash](CompiledCircuit::canonical_hash) of a circuit from its
+/// gates and delays as they are executed, without holding the whole circuit.
+#[derive(Debug, Clone)]
+pub struct CanonicalHasher {
+    hasher: StableHasher,
+}
+
+impl CanonicalHasher {
+    /// Creates a hasher for a circuit on a register of `num_qubits` qubits.
+    pub fn new(num_qubits: usize) -> Self {
+        let mut hasher = StableHasher::new();
+        // Writing to a hasher cannot fail.
+        write_usize(&mut hasher, num_qubits).unwrap();
+        Self { hasher }
+    }
+
+    /// Adds the next gate of the circuit.
+    pub fn gate(&mut self, gate: &Gate) {
+        write_gate(&mut self.hasher, &without_negative_zeros(gate)).unwrap();
+    }
+
+    /// Adds the next delay of the circuit.
+    pub fn delay(&mut self, qubits: &[QubitRef], duration: Duration) {
+        // The tag follows those of the gates.
+        self.hasher.update(&[7]);
+        write_qubits(&mut self.hasher, &indices(qubits)).unwrap();
+        self.hasher.update(&duration.as_nanos().to_le_bytes());
+    }
+
+    /// Returns the hash of the gates and delays added so far.
+    pub fn finish(&self) -> u128 {
+        self.hasher.finish()
+    }
+}
+
+/// Returns a copy of a gate with any negative zero parameter made positive, since the
+/// two are the same rotation.
+fn without_negative_zeros(gate: &Gate) -> Gate {
+    // Adding zero turns a negative zero into a positive one and leaves other values alone.
+    match gate {
+        Gate::RZ { target, theta } => Gate::RZ {
+            target: *target,
+            theta: theta + 0.0,
+        },
+        Gate::Unitary { target, matrix } => Gate::Unitary {
+            target: *target,
+            matrix: matrix.map(|row| row.map(|entry| entry + Complex::new(0.0, 0.0))),
+        },
+        _ => gate.clone(),
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                invalid("Unexpected end of the compiled circuit".to_string())
+            }
+            _ => error,
+        })?;
+    Ok(bytes)
+}
+
+fn read_usize(reader: &mut impl Read) -> io::Result<usize> {
+    let value = u64::from_le_bytes(read_array(reader)?);
+    usize::try_from(value).map_err(|_| {
+        invalid(format![
+            "Value {value} is too large in the compiled circuit"
+        ])
+    })
+}
+
+fn read_f64(reader: &mut impl Read) -> io::Result<f64> {
+    Ok(f64::from_le_bytes(read_array(reader)?))
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    let len = read_usize(reader)?;
+    // Read through a limit so that a corrupt length cannot allocate a huge buffer.
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(invalid(
+            "Unexpected end of the compiled circuit".to_string(),
+        ));
+    }
+    String::from_utf8(bytes)
+        .map_err(|_| invalid("Invalid string in the compiled circuit".to_string()))
+}
+
+fn read_qubits(reader: &mut impl Read) -> io::Result<Vec<usize>> {
+    let len = read_usize(reader)?;
+    let mut qubits = Vec::new();
+    for _ in 0..len {
+        qubits.push(read_usize(reader)?);
+    }
+    Ok(qubits)
+}
+
+fn read_gate(reader: &mut impl Read) -> io::Result<Gate> {
+    let tag = read_array::<1>(reader)?[0];
+    let qubits = read_qubits(reader)?;
+    let expected = if tag == 4 { 2 } else { 1 };
+    if tag > 6 || qubits.len() != expected {
+        return Err(invalid(format![
+            "Invalid gate {tag} on {} qubits in the compiled circuit",
+            qubits.len()
+        ]));
+    }
+    let target = QubitRef(qubits[expected - 1]);
+    Ok(match tag {
+        0 => Gate::H { target },
+        1 => Gate::X { target },
+        2 => Gate::T { target },
+        3 => Gate::TDgr { target },
+        4 => Gate::CX {
+            control: QubitRef(qubits[0]),
+            target,
+        },
+        5 => Gate::RZ {
+            target,
+            theta: read_f64(reader)?,
+        },
+        _ => {
+            let mut matrix = [[Complex::new(0.0, 0.0); 2]; 2];
+            for entry in matrix.iter_mut().flatten() {
+                *entry = Complex::new(read_f64(reader)?, read_f64(reader)?);
+            }
+            Gate::Unitary { target, matrix }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::qasm::parser::Parser;
+
+    /// Tests that every kind of operation and gate survives being written and read back.
+    #[test]
+    fn test_round_trip() {
+        let source = "OPENQASM 2.0;\nqreg q[3];\nh q[0];\nx q[1];\nt q[2];\ntdg q[0];\n\
+            cx q[2], q[1];\nrz(0.25) q[1];\ndelay[1.5ms] q[0], q[2];";
+        let mut circuit =
+            CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+                .unwrap();
+        assert_eq!(circuit.register_line, 2);
+        circuit.operations.push(Operation::GateCall {
+            name: "fused".to_string(),
+            line: 9,
+            gates: vec![Gate::Unitary {
+                target: QubitRef(2),
+                matrix: [[Complex::new(0.5, -0.5); 2]; 2],
+            }],
+        });
+
+        let mut bytes = Vec::new();
+        circuit.write_to(&mut bytes).unwrap();
+        assert_eq!(
+            CompiledCircuit::read_from(bytes.as_slice()).unwrap(),
+            circuit
+        );
+
+        // Truncated data is reported rather than causing a panic.
+        for len in 0..bytes.len() {
+            assert!(CompiledCircuit::read_from(&bytes[..len]).is_err());
+        }
+    }
+
+    /// Tests that the canonical hash ignores how the circuit was written but not what it
+    /// does.
+    #[test]
+    fn test_canonical_hash() {
+        let hash = |source: &str| {
+            CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+                .unwrap()
+                .canonical_hash()
+        };
+        let base = hash("OPENQASM 2.0;\nqreg q[2];\nh q[0];\nrz(0) q[1];\ncx q[0], q[1];");
+        for same in [
+            "OPENQASM 2.0;  // A comment\nqreg  other[2];\n\nh other[0]; rz(-0.0) other[1];\n\
+             cx other[0],other[1];",
+            "OPENQASM 2.0;\ninclude \"qelib1.inc\";\ngate g(t) a, b { h a; rz(t) b; cx a, b; }\n\
+             qreg q[2];\ng(0) q[0], q[1];",
+            "OPENQASM 3.0;\nqubit[2] q;\nh q[0];\nrz(0) q[1];\ncx q[0], q[1];",
+        ] {
+            assert_eq!(hash(same), base, "{same}");
+        }
+        for different in [
+            "OPENQASM 2.0;\nqreg q[3];\nh q[0];\nrz(0) q[1];\ncx q[0], q[1];",
+            "OPENQASM 2.0;\nqreg q[2];\nh q[1];\nrz(0) q[1];\ncx q[0], q[1];",
+            "OPENQASM 2.0;\nqreg q[2];\nh q[0];\nrz(1e-300) q[1];\ncx q[0], q[1];",
+            "OPENQASM 2.0;\nqreg q[2];\nh q[0];\nrz(0) q[1];\ncx q[1], q[0];",
+            "OPENQASM 2.0;\nqreg q[2];\nh q[0];\nrz(0) q[1];\ncx q[0], q[1];\ndelay[1ns] q[0];",
+        ] {
+            assert_ne!(hash(different), base, "{different}");
+        }
+    }
+
+    /// Tests that data that is not a valid compiled circuit is rejected.
+    #[test]
+    fn test_invalid_data() {
+        let source = "OPENQASM 2.0;\nqreg q[2];\ncx q[0], q[1];";
+        let circuit =
+            CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+                .unwrap();
+        let mut bytes = Vec::new();
+        circuit.write_to(&mut bytes).unwrap();
+
+        let error = |bytes: &[u8]| CompiledCircuit::read_from(bytes).unwrap_err().to_string();
+        assert_eq!(
+            error(b"OPENQASM 2.0;"),
+            "The file is not a compiled circuit"
+        );
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(
+            error(&newer),
+            "Compiled circuit format version 2 is not supported"
+        );
+        // The target of the CX is the last 8 bytes.
+        let mut outside = bytes.clone();
+        let len = outside.len();
+        outside[len - 8] = 5;
+        assert_eq!(
+            error(&outside),
+            "Qubit 5 is outside the register of 2 qubits in the compiled circuit"
+        );
+    }
+}
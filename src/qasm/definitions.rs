@@ -0,0 +1,696 @@
+use crate::gates::gate::{Gate, GateSignature};
+use crate::qasm::parser::{GateDefinition, OpaqueDeclaration};
+use crate::quantum::register::QubitRef;
+use std::collections::HashMap;
+use std::io;
+
+/// The default limit on how deeply gate definitions may be nested when expanded.
+pub const DEFAULT_MAX_EXPANSION_DEPTH: usize = 64;
+
+/// The default limit on how many built-in gates a single gate call may expand to. Each
+/// level of nesting can multiply the number of gates, so a few dozen definitions within
+/// the depth limit could otherwise expand to more gates than fit in memory.
+pub const DEFAULT_MAX_EXPANDED_GATES: usize = 1 << 22;
+
+/// A registry of user defined gates that expands gate calls into built-in gates.
+///
+/// Definitions may call other user defined gates, including ones defined later in the
+/// program, as calls are only resolved when they are expanded. Recursive definitions,
+/// nesting beyond the configured depth and calls expanding to more than the configured
+/// number of gates are reported as errors.
+///
+/// Opaque gates have no definition in the program, so calling one is an error unless an
+/// implementation has been bound to it with [`GateDefinitions::bind_opaque`].
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::qasm::definitions::GateDefinitions;
+/// use quantum_simulator::qasm::parser::{Parser, StatementKind};
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let source = "gate swap a, b { cx a, b; cx b, a; cx a, b; }";
+/// let mut definitions = GateDefinitions::new();
+/// for statement in Parser::new(source.as_bytes()) {
+///     let statement = statement.unwrap();
+///     if let StatementKind::GateDefinition(definition) = statement.kind {
+///         definitions.define(definition, statement.line).unwrap();
+///     }
+/// }
+///
+/// let gates = definitions.expand("swap", &[], &[QubitRef(0), QubitRef(1)], 2).unwrap();
+/// assert_eq!(gates[1], Gate::CX { control: QubitRef(1), target: QubitRef(0) });
+/// ```
+#[derive(Clone)]
+pub struct GateDefinitions {
+    definitions: HashMap<String, GateDefinition>,
+    opaque: HashMap<String, OpaqueDeclaration>,
+    opaque_bindings: HashMap<String, GateDefinition>,
+    max_depth: usize,
+    max_gates: usize,
+}
+
+impl GateDefinitions {
+    /// Creates an empty registry using the default maximum expansion depth.
+    pub fn new() -> Self {
+        Self::with_max_depth(DEFAULT_MAX_EXPANSION_DEPTH)
+    }
+
+    /// Creates an empty registry that allows gate definitions to be nested at most
+    /// `max_depth` levels deep.
+    pub fn with_max_depth(max_depth: usize) -> Self {
+        Self::with_limits(max_depth, DEFAULT_MAX_EXPANDED_GATES)
+    }
+
+    /// Creates an empty registry that allows gate definitions to be nested at most
+    /// `max_depth` levels deep, and a gate call to expand to at most `max_gates` built-in
+    /// gates.
+    pub fn with_limits(max_depth: usize, max_gates: usize) -> Self {
+        Self {
+            definitions: HashMap::new(),
+            opaque: HashMap::new(),
+            opaque_bindings: HashMap::new(),
+            max_depth,
+            max_gates,
+        }
+    }
+
+    /// Returns the maximum expansion depth of this registry.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Returns the maximum number of built-in gates a gate call may expand to.
+    pub fn max_gates(&self) -> usize {
+        self.max_gates
+    }
+
+    /// Returns the definition of the gate with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&GateDefinition> {
+        self.definitions.get(name)
+    }
+
+    /// Returns whether a built-in, defined or opaque gate with the given name exists.
+    pub fn contains(&self, name: &str) -> bool {
+        Gate::builtin_signature(name).is_some()
+            || self.definitions.contains_key(name)
+            || self.opaque.contains_key(name)
+    }
+
+    /// Adds a new gate definition, checking that it does not shadow an existing gate,
+    /// that its body only refers to its own arguments, and that calls of gates that are
+    /// already known pass them the right number of arguments.
+    pub fn define(&mut self, definition: GateDefinition, line: usize) -> io::Result<()> {
+        if self.contains(&definition.name) {
+            return Err(already_defined(&definition.name, line));
+        }
+        validate_definition(&definition, line)?;
+        self.check_body_signatures(&definition)?;
+
+        self.definitions.insert(definition.name.clone(), definition);
+        Ok(())
+    }
+
+    /// Declares an opaque gate. If an implementation was already bound to the gate, its
+    /// signature must match the declaration.
+    pub fn declare_opaque(
+        &mut self,
+        declaration: OpaqueDeclaration,
+        line: usize,
+    ) -> io::Result<()> {
+        let name = &declaration.name;
+        if self.contains(name) {
+            return Err(already_defined(name, line));
+        }
+        check_unique_arguments(name, &declaration.parameters, &declaration.qubits, line)?;
+
+        if let Some(binding) = self.opaque_bindings.get(name) {
+            check_binding_signature(&declaration, binding, line)?;
+        }
+
+        self.opaque.insert(name.clone(), declaration);
+        Ok(())
+    }
+
+    /// Binds an implementation to an opaque gate, which may be declared before or after
+    /// the binding is added. Bindings for gates that are never declared are unused.
+    pub fn bind_opaque(&mut self, definition: GateDefinition, line: usize) -> io::Result<()> {
+        let name = &definition.name;
+        if Gate::builtin_signature(name).is_some()
+            || self.definitions.contains_key(name)
+            || self.opaque_bindings.contains_key(name)
+        {
+            return Err(already_defined(name, line));
+        }
+        validate_definition(&definition, line)?;
+        self.check_body_signatures(&definition)?;
+
+        if let Some(declaration) = self.opaque.get(name) {
+            check_binding_signature(declaration, &definition, line)?;
+        }
+
+        self.opaque_bindings.insert(name.clone(), definition);
+        Ok(())
+    }
+
+    /// Expands a call of the named gate with the given parameter values and qubits into
+    /// the sequence of built-in gates it is composed of. `line` is the line of the call,
+    /// used for error messages.
+    pub fn expand(
+        &self,
+        name: &str,
+        parameters: &[f64],
+        qubits: &[QubitRef],
+        line: usize,
+    ) -> io::Result<Vec<Gate>> {
+        let mut gates = Vec::new();
+        let mut call_stack = Vec::new();
+        self.expand_into(name, parameters, qubits, line, &mut call_stack, &mut gates)?;
+        Ok(gates)
+    }
+
+    fn expand_into<'a>(
+        &'a self,
+        name: &'a str,
+        parameters: &[f64],
+        qubits: &[QubitRef],
+        line: usize,
+        call_stack: &mut Vec<(&'a str, usize)>,
+        gates: &mut Vec<Gate>,
+    ) -> io::Result<()> {
+        if let Some(signature) = Gate::builtin_signature(name) {
+            check_signature(name, signature, parameters.len(), qubits.len(), line)?;
+            gates.extend(
+                Gate::expand_builtin(name, parameters, qubits)
+                    .into_iter()
+                    .flatten(),
+            );
+            return Ok(());
+        }
+
+        let definition = match (self.definitions.get(name), self.opaque.contains_key(name)) {
+            (Some(definition), _) => definition,
+            (None, true) => self.opaque_bindings.get(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format![
+                        "Opaque gate '{name}' has no implementation bound to it on line {line}"
+                    ],
+                )
+            })?,
+            (None, false) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!["Unknown instruction '{name}' on line {line}"],
+                ));
+            }
+        };
+        check_signature(
+            name,
+            signature(definition),
+            parameters.len(),
+            qubits.len(),
+            line,
+        )?;
+
+        if call_stack.iter().any(|(called, _)| *called == name) {
+            call_stack.push((name, line));
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format![
+                    "Recursive definition of gate '{name}' ({}) on line {line}",
+                    call_stack
+                        .iter()
+                        .map(|(called, _)| *called)
+                        .collect::<Vec<_>>()
+                        .join(" -> ")
+                ],
+            ));
+        }
+        if call_stack.len() >= self.max_depth {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format![
+                    "Exceeded the maximum gate expansion depth of {} while expanding '{name}' on line {line}",
+                    self.max_depth
+                ],
+            ));
+        }
+
+        let values: HashMap<String, f64> = definition
+            .parameters
+            .iter()
+            .cloned()
+            .zip(parameters.iter().copied())
+            .collect();
+
+        call_stack.push((name, line));
+        for call in &definition.body {
+            // Parameters and operands were checked against the formal arguments when the
+            // gate was defined.
+            let call_parameters: Vec<f64> = call
+                .parameters
+                .iter()
+                .map(|expression| expression.evaluate(&values).unwrap())
+                .collect();
+            let call_qubits: Vec<QubitRef> = call
+                .operands
+                .iter()
+                .map(|operand| {
+                    let position = definition.qubits.iter().position(|q| q == operand);
+                    qubits[position.unwrap()]
+                })
+                .collect();
+            self.expand_into(
+                &call.name,
+                &call_parameters,
+                &call_qubits,
+                call.line,
+                call_stack,
+                gates,
+            )?;
+            if gates.len() > self.max_gates {
+                // Name the call that was expanded rather than the nested one that crossed
+                // the limit, which is usually the innermost definition.
+                let (called, called_line) = call_stack[0];
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format![
+                        "Exceeded the maximum of {} expanded gates while expanding '{called}' on line {called_line}",
+                        self.max_gates
+                    ],
+                ));
+            }
+        }
+        call_stack.pop();
+
+        Ok(())
+    }
+
+    /// Checks the calls in the body of a definition against the signatures of the gates
+    /// they call. Calls of gates that are not known yet are checked when expanded.
+    fn check_body_signatures(&self, definition: &GateDefinition) -> io::Result<()> {
+        for call in &definition.body {
+            let called = Gate::builtin_signature(&call.name)
+                .or_else(|| self.definitions.get(&call.name).map(signature))
+                .or_else(|| {
+                    self.opaque
+                        .get(&call.name)
+                        .map(|declaration| GateSignature {
+                            parameters: declaration.parameters.len(),
+                            qubits: declaration.qubits.len(),
+                        })
+                });
+            if let Some(called) = called {
+                check_signature(
+                    &call.name,
+                    called,
+                    call.parameters.len(),
+                    call.operands.len(),
+                    call.line,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for GateDefinitions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the numbers of parameters and qubits a gate definition takes.
+fn signature(definition: &GateDefinition) -> GateSignature {
+    GateSignature {
+        parameters: definition.parameters.len(),
+        qubits: definition.qubits.len(),
+    }
+}
+
+/// Checks that a gate definition has unique arguments and that its body only refers to
+/// those arguments.
+fn validate_definition(definition: &GateDefinition, line: usize) -> io::Result<()> {
+    let name = &definition.name;
+    check_unique_arguments(name, &definition.parameters, &definition.qubits, line)?;
+
+    for call in &definition.body {
+        if let Some(operand) = call
+            .operands
+            .iter()
+            .find(|operand| !definition.qubits.contains(operand))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format![
+                    "Unknown qubit argument '{operand}' in definition of gate '{name}' on line {}",
+                    call.line
+                ],
+            ));
+        }
+
+        if let Some(operand) = first_duplicate(&call.operands) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format![
+                    "Qubit argument '{operand}' is used more than once by gate '{}' in definition of gate '{name}' on line {}",
+                    call.name, call.line
+                ],
+            ));
+        }
+
+        if let Some(parameter) = call
+            .parameters
+            .iter()
+            .flat_map(|expression| expression.parameters())
+            .find(|parameter| !definition.parameters.iter().any(|p| p == parameter))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format![
+                    "Unknown parameter '{parameter}' in definition of gate '{name}' on line {}",
+                    call.line
+                ],
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that no formal parameter or qubit argument of a gate is repeated.
+fn check_unique_arguments(
+    name: &str,
+    parameters: &[String],
+    qubits: &[String],
+    line: usize,
+) -> io::Result<()> {
+    let arguments: Vec<&String> = parameters.iter().chain(qubits).collect();
+    if let Some(argument) = first_duplicate(&arguments) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!["Duplicate argument '{argument}' for gate '{name}' on line {line}"],
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the first item that is equal to an earlier one.
+pub(crate) fn first_duplicate<T: PartialEq>(items: &[T]) -> Option<&T> {
+    items
+        .iter()
+        .enumerate()
+        .find(|(position, item)| items[..*position].contains(item))
+        .map(|(_, item)| item)
+}
+
+/// Checks that an implementation bound to an opaque gate matches its declaration.
+fn check_binding_signature(
+    declaration: &OpaqueDeclaration,
+    binding: &GateDefinition,
+    line: usize,
+) -> io::Result<()> {
+    if declaration.parameters.len() == binding.parameters.len()
+        && declaration.qubits.len() == binding.qubits.len()
+    {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format![
+            "Implementation of opaque gate '{}' does not match its declaration on line {line}",
+            declaration.name
+        ],
+    ))
+}
+
+fn already_defined(name: &str, line: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!["Gate '{name}' is already defined on line {line}"],
+    )
+}
+
+/// Checks that a gate was called with the number of parameters and qubits it expects.
+fn check_signature(
+    name: &str,
+    signature: GateSignature,
+    parameters: usize,
+    qubits: usize,
+    line: usize,
+) -> io::Result<()> {
+    if signature.parameters != parameters {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format![
+                "Gate '{name}' expects {} parameters but was given {parameters} on line {line}",
+                signature.parameters
+            ],
+        ));
+    }
+    if signature.qubits != qubits {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format![
+                "Gate '{name}' expects {} qubits but was given {qubits} on line {line}",
+                signature.qubits
+            ],
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::qasm::parser::{Parser, Statement, StatementKind};
+
+    /// Helper function to build a registry from the gate definitions and opaque
+    /// declarations in a source string.
+    fn definitions(source: &str, max_depth: usize) -> io::Result<GateDefinitions> {
+        let mut definitions = GateDefinitions::with_max_depth(max_depth);
+        for statement in Parser::new(source.as_bytes()) {
+            let statement = statement?;
+            match statement.kind {
+                StatementKind::GateDefinition(definition) => {
+                    definitions.define(definition, statement.line)?
+                }
+                StatementKind::OpaqueDeclaration(declaration) => {
+                    definitions.declare_opaque(declaration, statement.line)?
+                }
+                _ => {}
+            }
+        }
+        Ok(definitions)
+    }
+
+    /// Helper function to parse the first gate definition in a source string.
+    fn parse_definition(source: &str) -> GateDefinition {
+        match Parser::new(source.as_bytes()).next() {
+            Some(Ok(Statement {
+                kind: StatementKind::GateDefinition(definition),
+                ..
+            })) => definition,
+            _ => panic!("Expected a gate definition."),
+        }
+    }
+
+    /// Tests that definitions calling other definitions are expanded in order, including
+    /// ones that are defined later in the program.
+    #[test]
+    fn test_expand_nested_definitions() {
+        let source = "
+            gate outer a, b { inner b; cx a, b; }
+            gate inner a { h a; innermost a; }
+            gate innermost a { t a; }
+        ";
+        let registry = definitions(source, DEFAULT_MAX_EXPANSION_DEPTH).unwrap();
+        let gates = registry
+            .expand("outer", &[], &[QubitRef(3), QubitRef(5)], 1)
+            .unwrap();
+        assert_eq!(
+            gates,
+            vec![
+                Gate::H {
+                    target: QubitRef(5)
+                },
+                Gate::T {
+                    target: QubitRef(5)
+                },
+                Gate::CX {
+                    control: QubitRef(3),
+                    target: QubitRef(5)
+                },
+            ]
+        );
+    }
+
+    /// Tests that directly and indirectly recursive definitions are reported.
+    #[test]
+    fn test_expand_recursive_definitions() {
+        let registry = definitions(
+            "gate a q { b q; } gate b q { c q; } gate c q { a q; } gate d q { d q; }",
+            DEFAULT_MAX_EXPANSION_DEPTH,
+        )
+        .unwrap();
+
+        let err = registry.expand("a", &[], &[QubitRef(0)], 1).unwrap_err();
+        assert!(err.to_string().contains("a -> b -> c -> a"));
+        assert!(registry.expand("d", &[], &[QubitRef(0)], 1).is_err());
+    }
+
+    /// Tests that the expansion depth limit is enforced.
+    #[test]
+    fn test_expand_depth_limit() {
+        let source = "gate a q { b q; } gate b q { c q; } gate c q { x q; }";
+        assert!(definitions(source, 3)
+            .unwrap()
+            .expand("a", &[], &[QubitRef(0)], 1)
+            .is_ok());
+
+        let err = definitions(source, 2)
+            .unwrap()
+            .expand("a", &[], &[QubitRef(0)], 1)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("maximum gate expansion depth of 2"));
+    }
+
+    /// Tests that a chain of definitions within the depth limit that doubles the number of
+    /// gates at each level is stopped by the gate limit.
+    #[test]
+    fn test_expand_gate_limit() {
+        let mut source = String::from("gate g0 q { x q; }");
+        for level in 1..60 {
+            let previous = level - 1;
+            source += &format![" gate g{level} q {{ g{previous} q; g{previous} q; }}"];
+        }
+        let registry = definitions(&source, DEFAULT_MAX_EXPANSION_DEPTH).unwrap();
+        assert_eq!(
+            registry
+                .expand("g10", &[], &[QubitRef(0)], 1)
+                .unwrap()
+                .len(),
+            1024
+        );
+
+        // Lower the limit so that the test does not expand millions of gates first.
+        let mut registry = definitions(&source, DEFAULT_MAX_EXPANSION_DEPTH).unwrap();
+        registry.max_gates = 1000;
+        assert!(registry.expand("g9", &[], &[QubitRef(0)], 1).is_ok());
+        let err = registry.expand("g59", &[], &[QubitRef(0)], 7).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Exceeded the maximum of 1000 expanded gates while expanding 'g59' on line 7"
+        );
+    }
+
+    /// Tests that parameters are substituted into nested definitions.
+    #[test]
+    fn test_expand_parameters() {
+        let source = "
+            gate outer(theta) a, b { inner(theta / 2, -theta) b; cx a, b; }
+            gate inner(alpha, beta) a { rz(alpha + 1) a; rz(beta) a; }
+        ";
+        let registry = definitions(source, DEFAULT_MAX_EXPANSION_DEPTH).unwrap();
+        let gates = registry
+            .expand("outer", &[4.0], &[QubitRef(0), QubitRef(1)], 1)
+            .unwrap();
+        assert_eq!(
+            gates,
+            vec![
+                Gate::RZ {
+                    target: QubitRef(1),
+                    theta: 3.0
+                },
+                Gate::RZ {
+                    target: QubitRef(1),
+                    theta: -4.0
+                },
+                Gate::CX {
+                    control: QubitRef(0),
+                    target: QubitRef(1)
+                },
+            ]
+        );
+    }
+
+    /// Tests that calling an opaque gate fails until an implementation is bound to it.
+    #[test]
+    fn test_expand_opaque() {
+        let source = "opaque magic(theta) a; gate wrap a { magic(pi) a; }";
+        let mut registry = definitions(source, DEFAULT_MAX_EXPANSION_DEPTH).unwrap();
+        let err = registry.expand("wrap", &[], &[QubitRef(0)], 1).unwrap_err();
+        assert!(err.to_string().contains("Opaque gate 'magic'"));
+
+        let mismatched = parse_definition("gate magic a { x a; }");
+        assert!(registry.bind_opaque(mismatched, 1).is_err());
+
+        let binding = parse_definition("gate magic(theta) a { rz(theta) a; }");
+        registry.bind_opaque(binding.clone(), 1).unwrap();
+        assert!(registry.bind_opaque(binding.clone(), 1).is_err());
+        assert_eq!(
+            registry.expand("magic", &[2.0], &[QubitRef(4)], 1).unwrap(),
+            vec![Gate::RZ {
+                target: QubitRef(4),
+                theta: 2.0
+            }]
+        );
+
+        // A binding added before the declaration must still match it.
+        let mut registry = GateDefinitions::new();
+        registry.bind_opaque(binding, 1).unwrap();
+        let declaration = OpaqueDeclaration {
+            name: "magic".to_string(),
+            parameters: Vec::new(),
+            qubits: vec!["a".to_string()],
+        };
+        assert!(registry.declare_opaque(declaration, 1).is_err());
+    }
+
+    /// Tests the errors reported for invalid definitions and calls.
+    #[test]
+    fn test_invalid_definitions() {
+        let limit = DEFAULT_MAX_EXPANSION_DEPTH;
+        assert!(definitions("gate h a { x a; }", limit).is_err());
+        assert!(definitions("gate g a { x a; } gate g a { x a; }", limit).is_err());
+        assert!(definitions("gate g a, a { cx a, a; }", limit).is_err());
+        assert!(definitions("gate g a { x b; }", limit).is_err());
+        assert!(definitions("gate g(theta) a { rz(phi) a; }", limit).is_err());
+        assert!(definitions("gate g(a) a { x a; }", limit).is_err());
+        assert!(definitions("opaque g a; gate g a { x a; }", limit).is_err());
+        assert!(definitions("opaque x a;", limit).is_err());
+
+        // Calls of gates that are already known are checked when the caller is defined.
+        let error = |source| definitions(source, limit).err().unwrap().to_string();
+        assert_eq!(
+            error("gate g a, b {\n cx a;\n}"),
+            "Gate 'cx' expects 2 qubits but was given 1 on line 2"
+        );
+        assert_eq!(
+            error("gate g a, b { cx a, b; } gate f a { g a; }"),
+            "Gate 'g' expects 2 qubits but was given 1 on line 1"
+        );
+        assert_eq!(
+            error("opaque o(t) a; gate f a { o a; }"),
+            "Gate 'o' expects 1 parameters but was given 0 on line 1"
+        );
+        assert_eq!(
+            error("gate g a, b { h a; cx b, b; }"),
+            "Qubit argument 'b' is used more than once by gate 'cx' in definition of gate 'g' on line 1"
+        );
+
+        // Calls of gates defined later are checked when they are expanded.
+        let registry = definitions("gate f a { g a; } gate g a, b { cx a, b; }", limit).unwrap();
+        assert!(registry.expand("g", &[], &[QubitRef(0)], 1).is_err());
+        assert!(registry.expand("f", &[], &[QubitRef(0)], 1).is_err());
+        assert!(registry.expand("unknown", &[], &[QubitRef(0)], 1).is_err());
+        assert!(registry
+            .expand("g", &[1.0], &[QubitRef(0), QubitRef(1)], 1)
+            .is_err());
+    }
+}
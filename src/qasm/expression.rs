@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+/// The binary operators available in classical expressions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+/// The built-in unary functions available in classical expressions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Exp,
+    Ln,
+    Sqrt,
+}
+
+impl Function {
+    /// Looks up a function by its OpenQASM name.
+    pub fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "sin" => Some(Function::Sin),
+            "cos" => Some(Function::Cos),
+            "tan" => Some(Function::Tan),
+            "exp" => Some(Function::Exp),
+            "ln" => Some(Function::Ln),
+            "sqrt" => Some(Function::Sqrt),
+            _ => None,
+        }
+    }
+
+    /// Returns the OpenQASM name of this function.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Sin => "sin",
+            Function::Cos => "cos",
+            Function::Tan => "tan",
+            Function::Exp => "exp",
+            Function::Ln => "ln",
+            Function::Sqrt => "sqrt",
+        }
+    }
+
+    fn apply(&self, value: f64) -> f64 {
+        match self {
+            Function::Sin => value.sin(),
+            Function::Cos => value.cos(),
+            Function::Tan => value.tan(),
+            Function::Exp => value.exp(),
+            Function::Ln => value.ln(),
+            Function::Sqrt => value.sqrt(),
+        }
+    }
+}
+
+/// A classical expression, such as a gate's angle argument.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    Pi,
+    /// A reference to a formal parameter of a gate definition.
+    Parameter(String),
+    Negate(Box<Expression>),
+    Binary {
+        operator: BinaryOperator,
+        lhs: Box<Expression>,
+        rhs: Box<Expression>,
+    },
+    Call {
+        function: Function,
+        argument: Box<Expression>,
+    },
+}
+
+impl Expression {
+    /// Evaluates this expression using the given values for any parameters. Returns the
+    /// name of the first parameter without a value as an error.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::collections::HashMap;
+    /// use std::f64::consts::PI;
+    /// use quantum_simulator::qasm::expression::{BinaryOperator, Expression};
+    ///
+    /// let expression = Expression::Binary {
+    ///     operator: BinaryOperator::Divide,
+    ///     lhs: Box::new(Expression::Parameter("theta".to_string())),
+    ///     rhs: Box::new(Expression::Number(2.0)),
+    /// };
+    /// let parameters = HashMap::from([("theta".to_string(), PI)]);
+    /// assert_eq!(expression.evaluate(&parameters), Ok(PI / 2.0));
+    /// assert_eq!(expression.evaluate(&HashMap::new()), Err("theta".to_string()));
+    /// ```
+    pub fn evaluate(&self, parameters: &HashMap<String, f64>) -> Result<f64, String> {
+        match self {
+            Expression::Number(value) => Ok(*value),
+            Expression::Pi => Ok(PI),
+            Expression::Parameter(name) => parameters.get(name).copied().ok_or(name.clone()),
+            Expression::Negate(expression) => Ok(-expression.evaluate(parameters)?),
+            Expression::Binary { operator, lhs, rhs } => {
+                let lhs = lhs.evaluate(parameters)?;
+                let rhs = rhs.evaluate(parameters)?;
+                Ok(match operator {
+                    BinaryOperator::Add => lhs + rhs,
+                    BinaryOperator::Subtract => lhs - rhs,
+                    BinaryOperator::Multiply => lhs * rhs,
+                    BinaryOperator::Divide => lhs / rhs,
+                    BinaryOperator::Power => lhs.powf(rhs),
+                })
+            }
+            Expression::Call { function, argument } => {
+                Ok(function.apply(argument.evaluate(parameters)?))
+            }
+        }
+    }
+
+    /// Returns the names of all parameters referenced by this expression.
+    pub fn parameters(&self) -> Vec<&str> {
+        match self {
+            Expression::Number(_) | Expression::Pi => Vec::new(),
+            Expression::Parameter(name) => vec![name.as_str()],
+            Expression::Negate(expression) => expression.parameters(),
+            Expression::Binary { lhs, rhs, .. } => {
+                let mut parameters = lhs.parameters();
+                parameters.extend(rhs.parameters());
+                parameters
+            }
+            Expression::Call { argument, .. } => argument.parameters(),
+        }
+    }
+}
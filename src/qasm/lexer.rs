@@ -0,0 +1,318 @@
+use std::io::{self, BufRead};
+
+/// A single lexical token of an OpenQASM program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Identifier(String),
+    /// A numeric literal, kept as written so that it can later be interpreted as
+    /// either an integer or a real.
+    Number(String),
+    String(String),
+    Semicolon,
+    Comma,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Arrow,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    /// The OpenQASM 3 power operator, which means the same as `^`.
+    StarStar,
+    EqualEqual,
+}
+
+impl Token {
+    /// Returns a short human readable description of this token for error messages.
+    pub fn describe(&self) -> String {
+        match self {
+            Token::Identifier(name) => format!["'{name}'"],
+            Token::Number(number) => format!["'{number}'"],
+            Token::String(string) => format!["\"{string}\""],
+            Token::Semicolon => "';'".to_string(),
+            Token::Comma => "','".to_string(),
+            Token::LBracket => "'['".to_string(),
+            Token::RBracket => "']'".to_string(),
+            Token::LParen => "'('".to_string(),
+            Token::RParen => "')'".to_string(),
+            Token::LBrace => "'{'".to_string(),
+            Token::RBrace => "'}'".to_string(),
+            Token::Arrow => "'->'".to_string(),
+            Token::Plus => "'+'".to_string(),
+            Token::Minus => "'-'".to_string(),
+            Token::Star => "'*'".to_string(),
+            Token::Slash => "'/'".to_string(),
+            Token::Caret => "'^'".to_string(),
+            Token::StarStar => "'**'".to_string(),
+            Token::EqualEqual => "'=='".to_string(),
+        }
+    }
+}
+
+/// Splits an OpenQASM source into tokens.
+///
+/// The lexer reads its input one physical line at a time, but tokens are produced
+/// independently of line boundaries so that statements may share or span lines.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::lexer::{Lexer, Token};
+///
+/// let lexer = Lexer::new("h q[0]; // comment".as_bytes());
+/// let tokens: Vec<Token> = lexer.map(|result| result.unwrap().0).collect();
+/// assert_eq!(
+///     tokens,
+///     vec![
+///         Token::Identifier("h".to_string()),
+///         Token::Identifier("q".to_string()),
+///         Token::LBracket,
+///         Token::Number("0".to_string()),
+///         Token::RBracket,
+///         Token::Semicolon,
+///     ]
+/// );
+/// ```
+pub struct Lexer<R: BufRead> {
+    reader: R,
+    chars: Vec<char>,
+    position: usize,
+    line_number: usize,
+    finished: bool,
+}
+
+impl<R: BufRead> Lexer<R> {
+    /// Creates a new `Lexer` reading from the given source.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            chars: Vec::new(),
+            position: 0,
+            line_number: 0,
+            finished: false,
+        }
+    }
+
+    /// Returns the line number of the most recently read line.
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// Reads the next token along with the line it was found on, or `None` once the
+    /// input is exhausted.
+    pub fn next_token(&mut self) -> io::Result<Option<(Token, usize)>> {
+        loop {
+            // Refill the buffer with the next line once the current one is used up.
+            if self.position >= self.chars.len() {
+                if self.finished || !self.read_line()? {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let c = self.chars[self.position];
+            if c.is_whitespace() {
+                self.position += 1;
+                continue;
+            }
+
+            // Comments run until the end of the physical line.
+            if c == '/' && self.peek_char(1) == Some('/') {
+                self.position = self.chars.len();
+                continue;
+            }
+
+            let line = self.line_number;
+            let token = if c.is_ascii_alphabetic() || c == '_' {
+                Token::Identifier(self.take_while(|c| c.is_ascii_alphanumeric() || c == '_'))
+            } else if c.is_ascii_digit() || (c == '.' && self.peek_is_digit(1)) {
+                Token::Number(self.take_number())
+            } else if c == '"' {
+                self.take_string()?
+            } else {
+                self.take_symbol()?
+            };
+            return Ok(Some((token, line)));
+        }
+    }
+
+    /// Reads the next physical line into the buffer. Returns `false` at end of input.
+    fn read_line(&mut self) -> io::Result<bool> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            self.finished = true;
+            return Ok(false);
+        }
+
+        self.line_number += 1;
+        self.chars = line.chars().collect();
+        self.position = 0;
+        Ok(true)
+    }
+
+    fn peek_char(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn peek_is_digit(&self, offset: usize) -> bool {
+        self.peek_char(offset).is_some_and(|c| c.is_ascii_digit())
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let start = self.position;
+        while self.peek_char(0).is_some_and(&predicate) {
+            self.position += 1;
+        }
+        self.chars[start..self.position].iter().collect()
+    }
+
+    fn take_number(&mut self) -> String {
+        let mut number = self.take_while(|c| c.is_ascii_digit());
+        if self.peek_char(0) == Some('.') {
+            self.position += 1;
+            number.push('.');
+            number.push_str(&self.take_while(|c| c.is_ascii_digit()));
+        }
+
+        // Only treat an 'e' as an exponent if it is actually followed by digits.
+        if matches!(self.peek_char(0), Some('e' | 'E')) {
+            let signed = matches!(self.peek_char(1), Some('+' | '-'));
+            let digit_offset = if signed { 2 } else { 1 };
+            if self.peek_is_digit(digit_offset) {
+                number.extend(&self.chars[self.position..self.position + digit_offset]);
+                self.position += digit_offset;
+                number.push_str(&self.take_while(|c| c.is_ascii_digit()));
+            }
+        }
+        number
+    }
+
+    fn take_string(&mut self) -> io::Result<Token> {
+        // Skip the opening quote.
+        self.position += 1;
+        let string = self.take_while(|c| c != '"' && c != '\n');
+        if self.peek_char(0) != Some('"') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["Unterminated string on line {}", self.line_number],
+            ));
+        }
+        self.position += 1;
+        Ok(Token::String(string))
+    }
+
+    fn take_symbol(&mut self) -> io::Result<Token> {
+        let c = self.chars[self.position];
+        let (token, length) = match (c, self.peek_char(1)) {
+            ('-', Some('>')) => (Token::Arrow, 2),
+            ('=', Some('=')) => (Token::EqualEqual, 2),
+            ('*', Some('*')) => (Token::StarStar, 2),
+            (';', _) => (Token::Semicolon, 1),
+            (',', _) => (Token::Comma, 1),
+            ('[', _) => (Token::LBracket, 1),
+            (']', _) => (Token::RBracket, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('{', _) => (Token::LBrace, 1),
+            ('}', _) => (Token::RBrace, 1),
+            ('+', _) => (Token::Plus, 1),
+            ('-', _) => (Token::Minus, 1),
+            ('*', _) => (Token::Star, 1),
+            ('/', _) => (Token::Slash, 1),
+            ('^', _) => (Token::Caret, 1),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!["Unexpected character '{c}' on line {}", self.line_number],
+                ));
+            }
+        };
+        self.position += length;
+        Ok(token)
+    }
+}
+
+impl<R: BufRead> Iterator for Lexer<R> {
+    type Item = io::Result<(Token, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Helper function to lex a source string into tokens, panicking on errors.
+    fn tokens(source: &str) -> Vec<Token> {
+        Lexer::new(source.as_bytes())
+            .map(|result| result.unwrap().0)
+            .collect()
+    }
+
+    /// Tests that tokens are produced independently of line breaks.
+    #[test]
+    fn test_tokens_span_lines() {
+        let lexer = Lexer::new("cx q[0],\n   q[1];".as_bytes());
+        let lines: Vec<usize> = lexer.map(|result| result.unwrap().1).collect();
+        assert_eq!(lines, vec![1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+    }
+
+    /// Tests lexing of numeric literals in their different forms.
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            tokens("2.0 10 .5 1e-3 3e"),
+            vec![
+                Token::Number("2.0".to_string()),
+                Token::Number("10".to_string()),
+                Token::Number(".5".to_string()),
+                Token::Number("1e-3".to_string()),
+                Token::Number("3".to_string()),
+                Token::Identifier("e".to_string()),
+            ]
+        );
+    }
+
+    /// Tests lexing of strings and multi-character symbols.
+    #[test]
+    fn test_strings_and_symbols() {
+        assert_eq!(
+            tokens("include \"qelib1.inc\"; c->{}==-**"),
+            vec![
+                Token::Identifier("include".to_string()),
+                Token::String("qelib1.inc".to_string()),
+                Token::Semicolon,
+                Token::Identifier("c".to_string()),
+                Token::Arrow,
+                Token::LBrace,
+                Token::RBrace,
+                Token::EqualEqual,
+                Token::Minus,
+                Token::StarStar,
+            ]
+        );
+    }
+
+    /// Tests that comments are skipped up to the end of their line.
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            tokens("// a comment; h q[0];\nx"),
+            vec![Token::Identifier("x".to_string())]
+        );
+    }
+
+    /// Tests that unexpected characters and unterminated strings are reported.
+    #[test]
+    fn test_errors() {
+        assert!(Lexer::new("h q[0]; @".as_bytes()).any(|result| result.is_err()));
+        assert!(Lexer::new("include \"qelib1.inc;".as_bytes()).any(|result| result.is_err()));
+    }
+}
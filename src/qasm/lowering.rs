@@ -0,0 +1,413 @@
+use crate::gates::gate::Gate;
+use crate::qasm::definitions::{first_duplicate, GateDefinitions};
+use crate::qasm::expression::Expression;
+use crate::qasm::parser::{Operand, Statement, StatementKind};
+use crate::qasm::warning::{Warning, WarningKind};
+use crate::quantum::register::{ClbitRef, QubitRef, Register};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// The largest number of distinct gate calls whose expansions are cached.
+pub const MAX_CACHED_EXPANSIONS: usize = 4096;
+
+/// A call of a user defined gate, with its parameter values as bits so that it can be
+/// hashed.
+type ExpansionKey = (String, Vec<u64>, Vec<QubitRef>);
+
+/// An operation on the quantum register produced by lowering a statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// The built in gates a gate call expands to.
+    GateCall {
+        name: String,
+        line: usize,
+        gates: Vec<Gate>,
+    },
+    /// Idles the qubits for the duration.
+    Delay {
+        qubits: Vec<QubitRef>,
+        duration: Duration,
+    },
+}
+
+impl fmt::Display for Operation {
+    /// Writes the operation on one line: a gate call as its name and line followed by the
+    /// gates it expands to, and a delay as its duration and qubits.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::qasm::lowering::Operation;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let call = Operation::GateCall {
+    ///     name: String::from("bell"),
+    ///     line: 4,
+    ///     gates: vec![
+    ///         Gate::H { target: QubitRef(0) },
+    ///         Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+    ///     ],
+    /// };
+    /// assert_eq!(call.to_string(), "bell on line 4: h q[0]; cx q[0], q[1]");
+    /// let delay = Operation::Delay {
+    ///     qubits: vec![QubitRef(1), QubitRef(2)],
+    ///     duration: Duration::from_nanos(100),
+    /// };
+    /// assert_eq!(delay.to_string(), "delay[100ns] q[1], q[2]");
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::GateCall { name, line, gates } => {
+                write!(f, "{name} on line {line}")?;
+                for (index, gate) in gates.iter().enumerate() {
+                    let separator = if index == 0 { ": " } else { "; " };
+                    write!(f, "{separator}{gate}")?;
+                }
+                Ok(())
+            }
+            Operation::Delay { qubits, duration } => {
+                let operands: Vec<String> = qubits.iter().map(QubitRef::to_string).collect();
+                write!(f, "delay[{duration:?}] {}", operands.join(", "))
+            }
+        }
+    }
+}
+
+/// Lowers parsed QASM statements into operations on the qubits of a single register.
+///
+/// Lowering checks the version header and the register declaration, resolves operands
+/// into qubit indices and expands gate calls with the gate definitions seen so far, so
+/// that simulating and analysing a circuit see the same gates.
+///
+/// The expansions of calls of user defined gates are cached by name, parameter values
+/// and qubits, since circuits often repeat the same block many times.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::qasm::definitions::GateDefinitions;
+/// use quantum_simulator::qasm::lowering::{Lowering, Operation};
+/// use quantum_simulator::qasm::parser::Parser;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let source = "OPENQASM 2.0;\nqreg q[2];\ngate bell a, b { h a; cx a, b; }\nbell q[1], q[0];";
+/// let mut lowering = Lowering::new(GateDefinitions::new());
+/// let mut operations = Vec::new();
+/// for statement in Parser::new(source.as_bytes()) {
+///     operations.extend(lowering.lower(statement.unwrap()).unwrap());
+/// }
+///
+/// let Operation::GateCall { gates, .. } = &operations[0] else { panic!() };
+/// let [q0, q1] = [0, 1].map(QubitRef);
+/// assert_eq!(gates, &vec![Gate::H { target: q1 }, Gate::CX { control: q1, target: q0 }]);
+/// assert_eq!(lowering.register().unwrap().size, 2);
+/// ```
+pub struct Lowering {
+    definitions: GateDefinitions,
+    version: Option<String>,
+    register: Option<Register>,
+    classical_registers: Vec<Register>,
+    expansions: HashMap<ExpansionKey, Vec<Gate>>,
+    warnings: Vec<Warning>,
+}
+
+impl Lowering {
+    /// Creates a new `Lowering` that expands gate calls with `definitions`.
+    pub fn new(definitions: GateDefinitions) -> Self {
+        Self {
+            definitions,
+            version: None,
+            register: None,
+            classical_registers: Vec::new(),
+            expansions: HashMap::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Returns the QASM version from the header, once it has been lowered.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Returns the quantum register, once it has been declared.
+    pub fn register(&self) -> Option<&Register> {
+        self.register.as_ref()
+    }
+
+    /// Returns the classical bit `register[index]`, numbered across the classical registers
+    /// declared so far in the order they were declared, or `None` if there is no such bit.
+    pub fn clbit(&self, register: &str, index: usize) -> Option<ClbitRef> {
+        let mut offset = 0;
+        for declared in &self.classical_registers {
+            if declared.name == register {
+                return (index < declared.size).then_some(ClbitRef(offset + index));
+            }
+            offset += declared.size;
+        }
+        None
+    }
+
+    /// Forgets the quantum register, so that it can be declared again after the
+    /// declaration was rejected.
+    pub(crate) fn undeclare_register(&mut self) {
+        self.register = None;
+    }
+
+    /// Returns the warnings about the statements lowered so far.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Returns the number of gate calls whose expansions are cached.
+    pub fn cached_expansions(&self) -> usize {
+        self.expansions.len()
+    }
+
+    /// Returns an error unless both the header and the register have been lowered.
+    pub fn check_complete(&self) -> io::Result<()> {
+        if self.version.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid header"));
+        }
+        if self.register.is_none() {
+            return Err(no_register());
+        }
+        Ok(())
+    }
+
+    /// Lowers a single statement, returning the operation it performs if any.
+    pub fn lower(&mut self, statement: Statement) -> io::Result<Option<Operation>> {
+        let line_number = statement.line;
+        if self.version.is_none() {
+            let StatementKind::Version(version) = statement.kind else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid header"));
+            };
+            self.version = Some(version);
+            return Ok(None);
+        }
+
+        match statement.kind {
+            StatementKind::Version(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["Unexpected version header on line {line_number}"],
+            )),
+            // The gates of the standard libraries are built in, and other files are not
+            // read yet.
+            StatementKind::Include(path) => {
+                if path != "qelib1.inc" && path != "stdgates.inc" {
+                    self.warnings.push(Warning::new(
+                        WarningKind::IgnoredStatement,
+                        format!["The include of '{path}' on line {line_number} is ignored"],
+                    ));
+                }
+                Ok(None)
+            }
+            StatementKind::QuantumRegister(register) => {
+                if self.register.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format![
+                            "Only a single quantum register is supported on line {line_number}"
+                        ],
+                    ));
+                }
+                self.register = Some(register);
+                Ok(None)
+            }
+            // Classical registers are not used by any supported instructions yet, but their
+            // bits are numbered for `clbit`.
+            StatementKind::ClassicalRegister(register) => {
+                self.classical_registers.push(register);
+                Ok(None)
+            }
+            StatementKind::GateDefinition(definition) => {
+                self.definitions.define(definition, line_number)?;
+                Ok(None)
+            }
+            StatementKind::OpaqueDeclaration(declaration) => {
+                self.definitions.declare_opaque(declaration, line_number)?;
+                Ok(None)
+            }
+            StatementKind::GateCall {
+                name,
+                parameters,
+                operands,
+            } => {
+                let Some(register) = &self.register else {
+                    return Err(no_register());
+                };
+                let parameters = evaluate_parameters(&parameters, line_number)?;
+                let qubits = resolve_qubits(&operands, register, line_number)?;
+                if let Some(operand) = first_duplicate(&operands) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format![
+                            "Qubit '{}[{}]' is used more than once by gate '{name}' on line {line_number}",
+                            operand.register, operand.index
+                        ],
+                    ));
+                }
+                // Barriers only keep gates from being moved across them, and the gates
+                // are applied in order anyway, so they are calls of no gates that still
+                // mark where the circuit is divided.
+                let gates = match name.as_str() {
+                    "barrier" if parameters.is_empty() => Vec::new(),
+                    _ => self.expand(&name, &parameters, qubits, line_number)?,
+                };
+                Ok(Some(Operation::GateCall {
+                    name,
+                    line: line_number,
+                    gates,
+                }))
+            }
+            StatementKind::Delay { duration, operands } => {
+                let Some(register) = &self.register else {
+                    return Err(no_register());
+                };
+                let qubits = resolve_qubits(&operands, register, line_number)?;
+                Ok(Some(Operation::Delay { qubits, duration }))
+            }
+        }
+    }
+
+    /// Expands a gate call, using the cached expansion of an identical earlier call of a
+    /// user defined gate if there is one.
+    ///
+    /// Definitions cannot be replaced once they are added, so a cached expansion stays
+    /// valid. Calls that fail are not cached, so they are reported again.
+    fn expand(
+        &mut self,
+        name: &str,
+        parameters: &[f64],
+        qubits: Vec<QubitRef>,
+        line_number: usize,
+    ) -> io::Result<Vec<Gate>> {
+        if Gate::builtin_signature(name).is_some() {
+            return self
+                .definitions
+                .expand(name, parameters, &qubits, line_number);
+        }
+        let key = (
+            name.to_string(),
+            parameters.iter().map(|value| value.to_bits()).collect(),
+            qubits,
+        );
+        if let Some(gates) = self.expansions.get(&key) {
+            return Ok(gates.clone());
+        }
+        let gates = self
+            .definitions
+            .expand(name, parameters, &key.2, line_number)?;
+        if self.expansions.len() < MAX_CACHED_EXPANSIONS {
+            self.expansions.insert(key, gates.clone());
+        }
+        Ok(gates)
+    }
+}
+
+fn no_register() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "No quantum register was defined",
+    )
+}
+
+/// Evaluates the classical parameters of a top level gate call.
+fn evaluate_parameters(parameters: &[Expression], line_number: usize) -> io::Result<Vec<f64>> {
+    parameters
+        .iter()
+        .map(|expression| {
+            expression.evaluate(&HashMap::new()).map_err(|name| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!["Unknown parameter '{name}' on line {line_number}"],
+                )
+            })
+        })
+        .collect()
+}
+
+/// Converts the operands of a gate call into qubits of the quantum register.
+fn resolve_qubits(
+    operands: &[Operand],
+    register: &Register,
+    line_number: usize,
+) -> io::Result<Vec<QubitRef>> {
+    operands
+        .iter()
+        .map(|operand| {
+            if operand.register != register.name || operand.index >= register.size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format![
+                        "Unknown qubit '{}[{}]' on line {line_number}",
+                        operand.register, operand.index
+                    ],
+                ));
+            }
+            Ok(QubitRef(operand.index))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::qasm::parser::Parser;
+
+    /// Tests that repeated calls of a user defined gate reuse the cached expansion, and that
+    /// invalid calls are reported each time.
+    #[test]
+    fn test_cached_expansions() {
+        let source = "OPENQASM 2.0;\nqreg q[3];\ngate g(t) a, b { h a; rz(t) b; cx a, b; }\n\
+            g(0.5) q[0], q[1];\ng(0.5) q[0], q[1];\ng(0.5) q[1], q[2];\nh q[0];\n\
+            g(0.5) q[0], q[1];\nmissing q[0];\nmissing q[0];\ng(0.5) q[2], q[2];\ncx q[1], q[1];";
+        let mut lowering = Lowering::new(GateDefinitions::new());
+        let mut calls = Vec::new();
+        let mut errors = Vec::new();
+        for statement in Parser::new(source.as_bytes()) {
+            match lowering.lower(statement.unwrap()) {
+                Ok(Some(Operation::GateCall { gates, .. })) => calls.push(gates),
+                Ok(_) => {}
+                Err(error) => errors.push(error.to_string()),
+            }
+        }
+        assert_eq!(lowering.cached_expansions(), 2);
+        assert_eq!(calls.len(), 5);
+        assert_eq!(calls[0], calls[1]);
+        assert_eq!(calls[0], calls[4]);
+        assert_eq!(
+            calls[2][0],
+            Gate::H {
+                target: QubitRef(1)
+            }
+        );
+        assert_eq!(
+            errors,
+            [
+                "Unknown instruction 'missing' on line 9",
+                "Unknown instruction 'missing' on line 10",
+                "Qubit 'q[2]' is used more than once by gate 'g' on line 11",
+                "Qubit 'q[1]' is used more than once by gate 'cx' on line 12"
+            ]
+        );
+    }
+    /// Tests that classical bits are numbered across the classical registers in the order
+    /// they were declared.
+    #[test]
+    fn test_clbit() {
+        let source = "OPENQASM 2.0;\nqreg q[1];\ncreg a[2];\ncreg b[3];";
+        let mut lowering = Lowering::new(GateDefinitions::new());
+        for statement in Parser::new(source.as_bytes()) {
+            lowering.lower(statement.unwrap()).unwrap();
+        }
+        assert_eq!(lowering.clbit("a", 1), Some(ClbitRef(1)));
+        assert_eq!(lowering.clbit("b", 0), Some(ClbitRef(2)));
+        assert_eq!(lowering.clbit("b", 2), Some(ClbitRef(4)));
+        assert_eq!(lowering.clbit("b", 3), None);
+        assert_eq!(lowering.clbit("q", 0), None);
+    }
+}
@@ -0,0 +1,839 @@
+use crate::qasm::expression::{BinaryOperator, Expression, Function};
+use crate::qasm::lexer::{Lexer, Token};
+use crate::quantum::register::Register;
+use std::io::{self, BufRead};
+use std::time::Duration;
+
+/// The deepest nesting of parentheses, negations and powers allowed in a classical
+/// expression, which bounds the recursion of the parser on untrusted input.
+pub const MAX_EXPRESSION_DEPTH: usize = 256;
+
+/// A reference to a single bit of a register, e.g. `q[3]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operand {
+    pub register: String,
+    pub index: usize,
+}
+
+/// A gate call inside the body of a gate definition, with operands referring to the
+/// definition's formal qubit arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GateBodyCall {
+    pub name: String,
+    pub parameters: Vec<Expression>,
+    pub operands: Vec<String>,
+    pub line: usize,
+}
+
+/// A user defined gate of the form `gate name(theta) a, b { ... }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GateDefinition {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub qubits: Vec<String>,
+    pub body: Vec<GateBodyCall>,
+}
+
+/// A gate declared with `opaque name(theta) a, b;`, which has no definition in the
+/// program itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpaqueDeclaration {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub qubits: Vec<String>,
+}
+
+/// The different kinds of statements that can appear in an OpenQASM program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    Version(String),
+    Include(String),
+    QuantumRegister(Register),
+    ClassicalRegister(Register),
+    GateDefinition(GateDefinition),
+    OpaqueDeclaration(OpaqueDeclaration),
+    GateCall {
+        name: String,
+        parameters: Vec<Expression>,
+        operands: Vec<Operand>,
+    },
+    /// An OpenQASM 3 `delay[duration] q[0];` instruction, which idles its operands.
+    Delay {
+        duration: Duration,
+        operands: Vec<Operand>,
+    },
+}
+
+/// A parsed statement along with the line it starts on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub line: usize,
+}
+
+/// Parses OpenQASM statements from a source.
+///
+/// Statements are delimited by semicolons rather than by physical lines, so several
+/// statements may share a line and a single statement may be wrapped across lines.
+/// The source is read lazily so that statements can be consumed while parsing.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::parser::{Parser, StatementKind};
+///
+/// let source = "OPENQASM 2.0; qreg q[2];\nh q[0]; cx q[0],\n  q[1];";
+/// let statements: Vec<StatementKind> = Parser::new(source.as_bytes())
+///     .map(|statement| statement.unwrap().kind)
+///     .collect();
+/// assert_eq!(statements.len(), 4);
+/// assert_eq!(statements[0], StatementKind::Version("2.0".to_string()));
+/// ```
+pub struct Parser<R: BufRead> {
+    lexer: Lexer<R>,
+    peeked: Option<(Token, usize)>,
+    /// The nesting depth of the expression being parsed.
+    expression_depth: usize,
+}
+
+impl<R: BufRead> Parser<R> {
+    /// Creates a new `Parser` reading from the given source.
+    pub fn new(reader: R) -> Self {
+        Self {
+            lexer: Lexer::new(reader),
+            peeked: None,
+            expression_depth: 0,
+        }
+    }
+
+    /// Parses the next statement, or returns `None` once the source is exhausted.
+    pub fn next_statement(&mut self) -> io::Result<Option<Statement>> {
+        let (token, line) = match self.next_token()? {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+
+        let kind = match token {
+            // Gate definitions end with their closing brace rather than a semicolon.
+            Token::Identifier(keyword) if keyword == "gate" => {
+                let definition = self.parse_gate_definition()?;
+                return Ok(Some(Statement {
+                    kind: StatementKind::GateDefinition(definition),
+                    line,
+                }));
+            }
+            Token::Identifier(keyword) => match keyword.as_str() {
+                "OPENQASM" => StatementKind::Version(self.expect_number()?),
+                "include" => StatementKind::Include(self.expect_string()?),
+                "qreg" => StatementKind::QuantumRegister(self.parse_register()?),
+                "creg" => StatementKind::ClassicalRegister(self.parse_register()?),
+                "qubit" => StatementKind::QuantumRegister(self.parse_declaration()?),
+                "bit" => StatementKind::ClassicalRegister(self.parse_declaration()?),
+                "opaque" => {
+                    let (name, parameters, qubits) = self.parse_gate_signature()?;
+                    StatementKind::OpaqueDeclaration(OpaqueDeclaration {
+                        name,
+                        parameters,
+                        qubits,
+                    })
+                }
+                "delay" => StatementKind::Delay {
+                    duration: self.parse_duration()?,
+                    operands: self.parse_operands()?,
+                },
+                _ => StatementKind::GateCall {
+                    name: keyword,
+                    parameters: self.parse_call_parameters()?,
+                    operands: self.parse_operands()?,
+                },
+            },
+            token => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!["Unexpected {} on line {line}", token.describe()],
+                ));
+            }
+        };
+        self.expect(Token::Semicolon)?;
+
+        Ok(Some(Statement { kind, line }))
+    }
+
+    /// Parses a register declaration of the form `name[size]`.
+    fn parse_register(&mut self) -> io::Result<Register> {
+        let name = self.expect_identifier()?;
+        self.expect(Token::LBracket)?;
+        let size = self.expect_integer()?;
+        self.expect(Token::RBracket)?;
+        Ok(Register { name, size })
+    }
+
+    /// Parses an OpenQASM 3 register declaration of the form `[size] name`.
+    fn parse_declaration(&mut self) -> io::Result<Register> {
+        self.expect(Token::LBracket)?;
+        let size = self.expect_integer()?;
+        self.expect(Token::RBracket)?;
+        let name = self.expect_identifier()?;
+        Ok(Register { name, size })
+    }
+
+    /// Parses the name, formal parameters and formal qubit arguments of a gate
+    /// definition or opaque declaration.
+    fn parse_gate_signature(&mut self) -> io::Result<(String, Vec<String>, Vec<String>)> {
+        let name = self.expect_identifier()?;
+        let mut parameters = Vec::new();
+        if self.next_is(&Token::LParen)? {
+            self.next_token()?;
+            if !self.next_is(&Token::RParen)? {
+                parameters = self.parse_identifiers()?;
+            }
+            self.expect(Token::RParen)?;
+        }
+        let qubits = self.parse_identifiers()?;
+        Ok((name, parameters, qubits))
+    }
+
+    /// Parses the remainder of a gate definition after the `gate` keyword.
+    fn parse_gate_definition(&mut self) -> io::Result<GateDefinition> {
+        let (name, parameters, qubits) = self.parse_gate_signature()?;
+        self.expect(Token::LBrace)?;
+
+        let mut body = Vec::new();
+        while !self.next_is(&Token::RBrace)? {
+            let (name, line) = match self.expect_any("a gate call or '}'")? {
+                (Token::Identifier(name), line) => (name, line),
+                (token, line) => return Err(unexpected("a gate call or '}'", &token, line)),
+            };
+            let call_parameters = self.parse_call_parameters()?;
+            let operands = self.parse_identifiers()?;
+            self.expect(Token::Semicolon)?;
+            body.push(GateBodyCall {
+                name,
+                parameters: call_parameters,
+                operands,
+                line,
+            });
+        }
+        self.next_token()?;
+
+        Ok(GateDefinition {
+            name,
+            parameters,
+            qubits,
+            body,
+        })
+    }
+
+    /// Parses a bracketed duration literal such as `[100ns]`. Durations in `dt` are not
+    /// supported, since their length depends on the target device.
+    fn parse_duration(&mut self) -> io::Result<Duration> {
+        self.expect(Token::LBracket)?;
+        let (value, line) = match self.expect_any("a duration")? {
+            (Token::Number(number), line) => match number.parse::<f64>() {
+                Ok(value) => (value, line),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!["Invalid number '{number}' on line {line}"],
+                    ));
+                }
+            },
+            (token, line) => return Err(unexpected("a duration", &token, line)),
+        };
+        let unit = self.expect_identifier()?;
+        let seconds_per_unit = match unit.as_str() {
+            "ns" => 1e-9,
+            "us" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!["Unsupported duration unit '{unit}' on line {line}"],
+                ));
+            }
+        };
+        self.expect(Token::RBracket)?;
+        Duration::try_from_secs_f64(value * seconds_per_unit).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["Duration {value}{unit} is out of range on line {line}"],
+            )
+        })
+    }
+
+    /// Parses the optional parenthesised list of classical arguments of a gate call.
+    fn parse_call_parameters(&mut self) -> io::Result<Vec<Expression>> {
+        let mut parameters = Vec::new();
+        if !self.next_is(&Token::LParen)? {
+            return Ok(parameters);
+        }
+        self.next_token()?;
+
+        if !self.next_is(&Token::RParen)? {
+            parameters.push(self.parse_expression()?);
+            while self.next_is(&Token::Comma)? {
+                self.next_token()?;
+                parameters.push(self.parse_expression()?);
+            }
+        }
+        self.expect(Token::RParen)?;
+        Ok(parameters)
+    }
+
+    /// Parses a classical expression, handling operator precedence from lowest to highest:
+    /// addition and subtraction, multiplication and division, negation, then powers.
+    fn parse_expression(&mut self) -> io::Result<Expression> {
+        let mut expression = self.parse_term()?;
+        loop {
+            let operator = if self.next_is(&Token::Plus)? {
+                BinaryOperator::Add
+            } else if self.next_is(&Token::Minus)? {
+                BinaryOperator::Subtract
+            } else {
+                return Ok(expression);
+            };
+            self.next_token()?;
+            expression = binary(operator, expression, self.parse_term()?);
+        }
+    }
+
+    fn parse_term(&mut self) -> io::Result<Expression> {
+        let mut expression = self.parse_unary()?;
+        loop {
+            let operator = if self.next_is(&Token::Star)? {
+                BinaryOperator::Multiply
+            } else if self.next_is(&Token::Slash)? {
+                BinaryOperator::Divide
+            } else {
+                return Ok(expression);
+            };
+            self.next_token()?;
+            expression = binary(operator, expression, self.parse_unary()?);
+        }
+    }
+
+    /// Parses a possibly negated power. Every nested expression is parsed through here, so
+    /// this is where the nesting depth is limited.
+    fn parse_unary(&mut self) -> io::Result<Expression> {
+        if self.expression_depth == MAX_EXPRESSION_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format![
+                    "Expression nested more than {MAX_EXPRESSION_DEPTH} levels deep on line {}",
+                    self.lexer.line_number()
+                ],
+            ));
+        }
+        self.expression_depth += 1;
+        let expression = self.parse_negation();
+        self.expression_depth -= 1;
+        expression
+    }
+
+    fn parse_negation(&mut self) -> io::Result<Expression> {
+        if self.next_is(&Token::Minus)? {
+            self.next_token()?;
+            return Ok(Expression::Negate(Box::new(self.parse_unary()?)));
+        }
+        self.parse_power()
+    }
+
+    fn parse_power(&mut self) -> io::Result<Expression> {
+        let base = self.parse_primary()?;
+        if self.next_is(&Token::Caret)? || self.next_is(&Token::StarStar)? {
+            self.next_token()?;
+            // Powers are right associative, so the exponent may itself be a power.
+            return Ok(binary(BinaryOperator::Power, base, self.parse_unary()?));
+        }
+        Ok(base)
+    }
+
+    fn parse_primary(&mut self) -> io::Result<Expression> {
+        match self.expect_any("an expression")? {
+            (Token::Number(number), line) => number.parse().map(Expression::Number).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!["Invalid number '{number}' on line {line}"],
+                )
+            }),
+            (Token::Identifier(name), _) if name == "pi" => Ok(Expression::Pi),
+            (Token::Identifier(name), line) => match Function::from_name(&name) {
+                Some(function) => {
+                    self.expect(Token::LParen)?;
+                    let argument = self.parse_expression()?;
+                    self.expect(Token::RParen)?;
+                    Ok(Expression::Call {
+                        function,
+                        argument: Box::new(argument),
+                    })
+                }
+                None if self.next_is(&Token::LParen)? => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!["Unknown function '{name}' on line {line}"],
+                )),
+                None => Ok(Expression::Parameter(name)),
+            },
+            (Token::LParen, _) => {
+                let expression = self.parse_expression()?;
+                self.expect(Token::RParen)?;
+                Ok(expression)
+            }
+            (token, line) => Err(unexpected("an expression", &token, line)),
+        }
+    }
+
+    /// Parses a comma separated list of identifiers.
+    fn parse_identifiers(&mut self) -> io::Result<Vec<String>> {
+        let mut identifiers = vec![self.expect_identifier()?];
+        while self.next_is(&Token::Comma)? {
+            self.next_token()?;
+            identifiers.push(self.expect_identifier()?);
+        }
+        Ok(identifiers)
+    }
+
+    /// Parses a comma separated list of `register[index]` operands.
+    fn parse_operands(&mut self) -> io::Result<Vec<Operand>> {
+        let mut operands = Vec::new();
+        loop {
+            let register = self.expect_identifier()?;
+            self.expect(Token::LBracket)?;
+            let index = self.expect_integer()?;
+            self.expect(Token::RBracket)?;
+            operands.push(Operand { register, index });
+
+            if !self.next_is(&Token::Comma)? {
+                return Ok(operands);
+            }
+            self.next_token()?;
+        }
+    }
+
+    fn next_token(&mut self) -> io::Result<Option<(Token, usize)>> {
+        match self.peeked.take() {
+            Some(token) => Ok(Some(token)),
+            None => self.lexer.next_token(),
+        }
+    }
+
+    fn next_is(&mut self, expected: &Token) -> io::Result<bool> {
+        if self.peeked.is_none() {
+            self.peeked = self.lexer.next_token()?;
+        }
+        Ok(matches!(&self.peeked, Some((token, _)) if token == expected))
+    }
+
+    /// Consumes the next token, failing with a description of `expected` if there is
+    /// none.
+    fn expect_any(&mut self, expected: &str) -> io::Result<(Token, usize)> {
+        match self.next_token()? {
+            Some(token) => Ok(token),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format![
+                    "Expected {expected} on line {} but reached the end of the file",
+                    self.lexer.line_number()
+                ],
+            )),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> io::Result<()> {
+        let (token, line) = self.expect_any(&expected.describe())?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(unexpected(&expected.describe(), &token, line))
+        }
+    }
+
+    fn expect_identifier(&mut self) -> io::Result<String> {
+        match self.expect_any("an identifier")? {
+            (Token::Identifier(name), _) => Ok(name),
+            (token, line) => Err(unexpected("an identifier", &token, line)),
+        }
+    }
+
+    fn expect_number(&mut self) -> io::Result<String> {
+        match self.expect_any("a number")? {
+            (Token::Number(number), _) => Ok(number),
+            (token, line) => Err(unexpected("a number", &token, line)),
+        }
+    }
+
+    fn expect_integer(&mut self) -> io::Result<usize> {
+        match self.expect_any("an integer")? {
+            (Token::Number(number), line) => number.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!["Expected an integer on line {line} but found '{number}'"],
+                )
+            }),
+            (token, line) => Err(unexpected("an integer", &token, line)),
+        }
+    }
+
+    fn expect_string(&mut self) -> io::Result<String> {
+        match self.expect_any("a string")? {
+            (Token::String(string), _) => Ok(string),
+            (token, line) => Err(unexpected("a string", &token, line)),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Parser<R> {
+    type Item = io::Result<Statement>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_statement().transpose()
+    }
+}
+
+fn binary(operator: BinaryOperator, lhs: Expression, rhs: Expression) -> Expression {
+    Expression::Binary {
+        operator,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+/// Creates an error for a token that did not match what the grammar expected.
+fn unexpected(expected: &str, found: &Token, line: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format![
+            "Expected {expected} on line {line} but found {}",
+            found.describe()
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::qasm::definitions::GateDefinitions;
+    use crate::qasm::lowering::Lowering;
+    use crate::quantum::sampling::Rng;
+    use std::collections::HashMap;
+
+    /// Helper function to parse a source string, panicking on errors.
+    fn parse(source: &str) -> Vec<Statement> {
+        Parser::new(source.as_bytes())
+            .map(|statement| statement.unwrap())
+            .collect()
+    }
+
+    /// Helper function to create a gate call statement kind.
+    fn gate_call(name: &str, operands: &[usize]) -> StatementKind {
+        StatementKind::GateCall {
+            name: name.to_string(),
+            parameters: Vec::new(),
+            operands: operands
+                .iter()
+                .map(|index| Operand {
+                    register: "q".to_string(),
+                    index: *index,
+                })
+                .collect(),
+        }
+    }
+
+    /// Tests parsing of a program header and register declarations.
+    #[test]
+    fn test_parse_header_and_registers() {
+        let statements = parse("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[16];\ncreg c[4];");
+        let kinds: Vec<StatementKind> = statements.into_iter().map(|s| s.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                StatementKind::Version("2.0".to_string()),
+                StatementKind::Include("qelib1.inc".to_string()),
+                StatementKind::QuantumRegister(Register {
+                    name: "q".to_string(),
+                    size: 16
+                }),
+                StatementKind::ClassicalRegister(Register {
+                    name: "c".to_string(),
+                    size: 4
+                }),
+            ]
+        );
+
+        // OpenQASM 3 declarations give the same registers.
+        let statements = parse("OPENQASM 3.0;\nqubit[16] q;\nbit[4] c;");
+        assert_eq!(statements[1].kind, kinds[2]);
+        assert_eq!(statements[2].kind, kinds[3]);
+    }
+
+    /// Tests that several statements on a single line are parsed separately.
+    #[test]
+    fn test_parse_multiple_statements_per_line() {
+        let statements = parse("h q[0]; h q[1];\nx q[2];");
+        assert_eq!(
+            statements,
+            vec![
+                Statement {
+                    kind: gate_call("h", &[0]),
+                    line: 1
+                },
+                Statement {
+                    kind: gate_call("h", &[1]),
+                    line: 1
+                },
+                Statement {
+                    kind: gate_call("x", &[2]),
+                    line: 2
+                },
+            ]
+        );
+    }
+
+    /// Tests that a statement wrapped across lines is parsed as one statement starting
+    /// on its first line.
+    #[test]
+    fn test_parse_statement_spanning_lines() {
+        let statements = parse("\ncx q[0],\n   q[1]\n;");
+        assert_eq!(
+            statements,
+            vec![Statement {
+                kind: gate_call("cx", &[0, 1]),
+                line: 2
+            }]
+        );
+    }
+
+    /// Tests parsing of a gate definition spread over several lines.
+    #[test]
+    fn test_parse_gate_definition() {
+        let statements =
+            parse("gate swap a, b {\n  cx a, b; cx b, a;\n  cx a, b;\n}\nswap q[0], q[1];");
+        let call = |operands: [&str; 2], line| GateBodyCall {
+            name: "cx".to_string(),
+            parameters: Vec::new(),
+            operands: operands.iter().map(|operand| operand.to_string()).collect(),
+            line,
+        };
+        assert_eq!(
+            statements,
+            vec![
+                Statement {
+                    kind: StatementKind::GateDefinition(GateDefinition {
+                        name: "swap".to_string(),
+                        parameters: Vec::new(),
+                        qubits: vec!["a".to_string(), "b".to_string()],
+                        body: vec![
+                            call(["a", "b"], 2),
+                            call(["b", "a"], 2),
+                            call(["a", "b"], 3)
+                        ],
+                    }),
+                    line: 1
+                },
+                Statement {
+                    kind: gate_call("swap", &[0, 1]),
+                    line: 5
+                },
+            ]
+        );
+    }
+
+    /// Tests parsing of gate definitions and calls with classical parameters.
+    #[test]
+    fn test_parse_parameters() {
+        let statements =
+            parse("gate rot(theta, phi) a { rz(theta) a; rz(-phi / 2) a; }\nrz(pi) q[0];");
+        let StatementKind::GateDefinition(definition) = &statements[0].kind else {
+            panic!("Expected a gate definition.");
+        };
+        assert_eq!(definition.parameters, vec!["theta", "phi"]);
+        assert_eq!(
+            definition.body[0].parameters,
+            vec![Expression::Parameter("theta".to_string())]
+        );
+        assert_eq!(definition.body[1].parameters[0].parameters(), vec!["phi"]);
+
+        let StatementKind::GateCall { parameters, .. } = &statements[1].kind else {
+            panic!("Expected a gate call.");
+        };
+        assert_eq!(parameters, &vec![Expression::Pi]);
+    }
+
+    /// Tests parsing of opaque gate declarations.
+    #[test]
+    fn test_parse_opaque_declaration() {
+        let statements = parse("opaque magic(theta) a, b;\nopaque plain a;");
+        assert_eq!(
+            statements[0].kind,
+            StatementKind::OpaqueDeclaration(OpaqueDeclaration {
+                name: "magic".to_string(),
+                parameters: vec!["theta".to_string()],
+                qubits: vec!["a".to_string(), "b".to_string()],
+            })
+        );
+        assert_eq!(statements[1].line, 2);
+    }
+
+    /// Tests that expressions are parsed with the expected precedence.
+    #[test]
+    fn test_parse_expression_precedence() {
+        let evaluate = |source: &str| -> f64 {
+            let mut parser = Parser::new(source.as_bytes());
+            parser
+                .parse_expression()
+                .unwrap()
+                .evaluate(&HashMap::new())
+                .unwrap()
+        };
+        assert_eq!(evaluate("1 + 2 * 3"), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9.0);
+        assert_eq!(evaluate("8 / 4 / 2"), 1.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(evaluate("2 ** 3 ** 2"), 512.0);
+        assert_eq!(evaluate("-2 ^ 2"), -4.0);
+        assert_eq!(evaluate("1 - -1"), 2.0);
+        assert_eq!(evaluate("2 * pi / 4"), std::f64::consts::PI / 2.0);
+        assert_eq!(evaluate("sqrt(16) + cos(0)"), 5.0);
+        assert_eq!(evaluate("1.5e1"), 15.0);
+    }
+
+    /// Tests parsing of delay instructions and their duration units.
+    #[test]
+    fn test_parse_delay() {
+        let statements = parse("delay[100ns] q[0]; delay[1.5us] q[0], q[1]; delay[2 ms] q[1];");
+        let durations: Vec<Duration> = statements
+            .iter()
+            .map(|statement| match &statement.kind {
+                StatementKind::Delay { duration, .. } => *duration,
+                kind => panic!("Expected a delay but found {kind:?}"),
+            })
+            .collect();
+        assert_eq!(
+            durations,
+            vec![
+                Duration::from_nanos(100),
+                Duration::from_nanos(1500),
+                Duration::from_millis(2)
+            ]
+        );
+        assert!(matches!(
+            &statements[1].kind,
+            StatementKind::Delay { operands, .. } if operands.len() == 2
+        ));
+    }
+
+    /// Tests that malformed statements produce errors rather than panics.
+    #[test]
+    fn test_parse_errors() {
+        let sources = [
+            "h q[0]",
+            "qreg q[x];",
+            "h q[0] q[1];",
+            "; h q[0];",
+            "cx q[0],",
+            "gate g a { h a; ",
+            "gate g a { h q[0]; }",
+            "rz(pi q[0];",
+            "rz(foo(1)) q[0];",
+            "gate g(theta a { h a; }",
+            "opaque g a { h a; }",
+            "delay q[0];",
+            "delay[100] q[0];",
+            "delay[100dt] q[0];",
+            "delay[1e30s] q[0];",
+        ];
+        for source in sources {
+            let result: io::Result<Vec<Statement>> = Parser::new(source.as_bytes()).collect();
+            assert!(result.is_err(), "Expected '{source}' to fail to parse");
+        }
+
+        let depth = MAX_EXPRESSION_DEPTH;
+        let nested = format![
+            "rz({}1{}) q[0];",
+            "(".repeat(depth - 1),
+            ")".repeat(depth - 1)
+        ];
+        assert!(Parser::new(nested.as_bytes()).all(|result| result.is_ok()));
+        for source in [
+            format!["rz({}1{}) q[0];", "(".repeat(depth), ")".repeat(depth)],
+            format!["rz({}1) q[0];", "-".repeat(100 * depth)],
+            format!["rz(2{}) q[0];", "^2".repeat(100 * depth)],
+        ] {
+            let error = Parser::new(source.as_bytes()).next().unwrap().unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Expression nested more than 256 levels deep on line 1"
+            );
+        }
+    }
+
+    /// Tests that random input, both raw bytes and jumbled QASM, never panics the parser
+    /// or the lowering of what it parses.
+    #[test]
+    fn test_random_input() {
+        let fragments = [
+            "OPENQASM 2.0;",
+            "qreg q[3];",
+            "gate",
+            "opaque",
+            "delay",
+            "[10ns]",
+            "include",
+            "\"a\"",
+            "g",
+            "a",
+            "q",
+            "[",
+            "]",
+            "(",
+            ")",
+            "{",
+            "}",
+            ";",
+            ",",
+            "->",
+            "==",
+            "0",
+            "2",
+            "0.5",
+            "1e400",
+            "99999999999999999999",
+            "-",
+            "+",
+            "*",
+            "/",
+            "^",
+            "pi",
+            "sin",
+            "ln",
+            "h",
+            "cx",
+            "rz",
+            "u3",
+            "ccx",
+            "U",
+            "CX",
+            "\n",
+            "//",
+            "\u{e9}",
+        ];
+        let mut rng = Rng::new(0);
+        for _ in 0..2000 {
+            let bytes: Vec<u8> = if rng.next_f64() < 0.5 {
+                (0..(rng.next_f64() * 64.0) as usize)
+                    .map(|_| (rng.next_f64() * 256.0) as u8)
+                    .collect()
+            } else {
+                (0..(rng.next_f64() * 40.0) as usize)
+                    .map(|_| fragments[(rng.next_f64() * fragments.len() as f64) as usize])
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+                    .into_bytes()
+            };
+            let mut lowering = Lowering::new(GateDefinitions::new());
+            for statement in Parser::new(&bytes[..]).map_while(Result::ok) {
+                let _ = lowering.lower(statement);
+            }
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use crate::qasm::definitions::GateDefinitions;
+use crate::qasm::simulator::{Options, Simulator};
+use crate::quantum::ket::Ket;
+use std::fmt::Write;
+use std::io;
+
+/// The help text printed by the `:help` command.
+pub const SESSION_HELP: &str = "\
+Enter QASM statements to apply them to the state, or one of these commands:
+  :state        Print the current state
+  :prob <bits>  Print the probability of a basis state, such as :prob 0110
+  :undo [n]     Undo the gates of the last n inputs that applied gates (default: 1)
+  :help         Print this help
+  :quit         End the session";
+
+/// A line oriented interactive session that applies QASM statements to a live state as
+/// they are entered, for driving the simulator from a terminal or a notebook kernel.
+///
+/// Each line is either a meta-command starting with `:` or QASM source. Source is held
+/// until it ends a statement, with a `;` or the `}` of a gate definition, so statements
+/// and gate definitions may be wrapped across lines. Line numbers in error messages count
+/// the lines of the whole session.
+///
+/// Gates are undone by applying their inverses, which is exact up to rounding since every
+/// gate is unitary. Gate definitions and declarations are not undone.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::definitions::GateDefinitions;
+/// use quantum_simulator::qasm::session::Session;
+/// use quantum_simulator::qasm::simulator::Options;
+///
+/// let mut session = Session::new(GateDefinitions::new(), Options::default());
+/// for line in ["OPENQASM 2.0;", "qreg q[2];", "h q[0];", "cx q[0],", "  q[1];"] {
+///     assert_eq!(session.handle_line(line).unwrap(), Some(String::new()));
+/// }
+/// let output = session.handle_line(":prob 11").unwrap().unwrap();
+/// assert_eq!(output, "0.5000000000\n");
+/// assert_eq!(session.handle_line(":quit").unwrap(), None);
+/// ```
+pub struct Session {
+    simulator: Simulator,
+    /// Source that does not end a statement yet.
+    pending: String,
+    /// The number of lines before the pending source.
+    lines: usize,
+    /// The number of lines of pending source.
+    pending_lines: usize,
+    /// The length of the gate history after each input that applied gates.
+    inputs: Vec<usize>,
+}
+
+impl Session {
+    /// Creates a new session with an empty circuit.
+    pub fn new(definitions: GateDefinitions, options: Options) -> Self {
+        let options = Options {
+            history: true,
+            ..options
+        };
+        Self {
+            simulator: Simulator::new(definitions, options),
+            pending: String::new(),
+            lines: 0,
+            pending_lines: 0,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Handles a line of input, returning the output to print, or `None` once the session
+    /// has been ended with `:quit`.
+    ///
+    /// Statements that fail leave the state as it was after the statements before them,
+    /// so the session can continue after an error.
+    pub fn handle_line(&mut self, line: &str) -> io::Result<Option<String>> {
+        let trimmed = line.trim();
+        if self.pending.is_empty() && trimmed.starts_with(':') {
+            self.lines += 1;
+            return self.command(trimmed);
+        }
+
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        self.pending_lines += 1;
+        let open_braces = self.pending.matches('{').count() > self.pending.matches('}').count();
+        if open_braces || !(trimmed.is_empty() || trimmed.ends_with(';') || trimmed.ends_with('}'))
+        {
+            return Ok(Some(String::new()));
+        }
+        // Blank lines keep the line numbers of the parser in step with the session.
+        let source = "\n".repeat(self.lines) + &self.pending;
+        self.lines += self.pending_lines;
+        self.pending.clear();
+        self.pending_lines = 0;
+        let result = self.simulator.append_qasm(&source);
+        // Gates before an error were still applied, so they can be undone too.
+        let history_len = self.simulator.history_len();
+        if history_len > self.inputs.last().copied().unwrap_or(0) {
+            self.inputs.push(history_len);
+        }
+        result?;
+        Ok(Some(String::new()))
+    }
+
+    /// Runs a meta-command.
+    fn command(&mut self, command: &str) -> io::Result<Option<String>> {
+        let mut words = command.split_whitespace();
+        let mut output = String::new();
+        match (words.next(), words.next(), words.next()) {
+            (Some(":quit"), None, _) => return Ok(None),
+            (Some(":help"), None, _) => writeln!(output, "{SESSION_HELP}").unwrap(),
+            (Some(":state"), None, _) => {
+                for ket in self.simulator.state()?.sorted_kets() {
+                    writeln!(output, "{ket}").unwrap();
+                }
+            }
+            (Some(":undo"), count, None) => {
+                let count = match count.map(|count| count.parse()) {
+                    None => 1,
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => return Err(unknown_command(command)),
+                };
+                if count > self.inputs.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format![
+                            "Cannot undo {count} inputs when only {} have applied gates",
+                            self.inputs.len()
+                        ],
+                    ));
+                }
+                self.inputs.truncate(self.inputs.len() - count);
+                let history_len = self.inputs.last().copied().unwrap_or(0);
+                let num_gates = self.simulator.history_len() - history_len;
+                self.simulator.undo(num_gates)?;
+                writeln!(output, "Undid {num_gates} gates").unwrap();
+            }
+            (Some(":prob"), Some(bits), None) => {
+                let probability = self.probability(bits)?;
+                writeln!(output, "{probability:.10}").unwrap();
+            }
+            _ => return Err(unknown_command(command)),
+        }
+        Ok(Some(output))
+    }
+
+    /// Returns the probability of measuring the basis state written as `bits`, with the
+    /// first qubit as the last character.
+    fn probability(&mut self, bits: &str) -> io::Result<f64> {
+        let state = self.simulator.state()?;
+        let num_qubits = state.num_qubits();
+        if bits.len() != num_qubits || !bits.chars().all(|bit| bit == '0' || bit == '1') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!["Expected a basis state of {num_qubits} bits but found '{bits}'"],
+            ));
+        }
+        // Registers may be too large for the basis index to fit in a usize.
+        let mut basis_state = Ket::new_zero_ket(num_qubits);
+        for (qubit, _) in bits
+            .chars()
+            .rev()
+            .enumerate()
+            .filter(|(_, bit)| *bit == '1')
+        {
+            basis_state.flip(qubit);
+        }
+        Ok(state
+            .get(&basis_state)
+            .map_or(0.0, |ket| ket.amplitude.norm_sqr()))
+    }
+}
+
+fn unknown_command(command: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!["Unknown command '{command}', enter :help for a list of commands"],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Tests meta-commands and that errors leave the session usable.
+    #[test]
+    fn test_session() {
+        let mut session = Session::new(GateDefinitions::new(), Options::default());
+        let mut handle = |line: &str| match session.handle_line(line) {
+            Ok(output) => output.unwrap(),
+            Err(error) => format!["Error: {error}"],
+        };
+        assert_eq!(handle(":state"), "Error: No quantum register was defined");
+        assert_eq!(handle("OPENQASM 2.0;"), "");
+        assert_eq!(handle("qreg q[2];"), "");
+        assert_eq!(handle("gate flip a {"), "");
+        assert_eq!(handle("  x a;"), "");
+        assert_eq!(handle("}"), "");
+        assert_eq!(
+            handle("flip q[1]; foo q[0];"),
+            "Error: Unknown instruction 'foo' on line 7"
+        );
+        // The statements before the error were applied.
+        assert_eq!(handle(":state"), "(1+0i)|10⟩\n");
+        assert_eq!(handle(""), "");
+        assert_eq!(handle("h q[3];"), "Error: Unknown qubit 'q[3]' on line 10");
+        assert_eq!(handle(":prob 10"), "1.0000000000\n");
+        assert_eq!(handle(":prob 01"), "0.0000000000\n");
+        assert_eq!(
+            handle(":prob 1"),
+            "Error: Expected a basis state of 2 bits but found '1'"
+        );
+        assert_eq!(
+            handle(":undo x"),
+            "Error: Unknown command ':undo x', enter :help for a list of commands"
+        );
+    }
+
+    /// Tests undoing the gates of earlier inputs.
+    #[test]
+    fn test_undo() {
+        let mut session = Session::new(GateDefinitions::new(), Options::default());
+        let mut handle = |line: &str| match session.handle_line(line) {
+            Ok(output) => output.unwrap(),
+            Err(error) => format!["Error: {error}"],
+        };
+        for line in [
+            "OPENQASM 2.0;",
+            "qreg q[2];",
+            "h q[0]; h q[1];",
+            "gate g a { t a; }",
+        ] {
+            assert_eq!(handle(line), "");
+        }
+        assert_eq!(handle("cx q[0], q[1]; g q[1];"), "");
+        assert_eq!(handle(":undo"), "Undid 2 gates\n");
+        assert_eq!(
+            handle(":state"),
+            "(0.5+0i)|00⟩\n(0.5+0i)|01⟩\n(0.5+0i)|10⟩\n(0.5+0i)|11⟩\n"
+        );
+        assert_eq!(handle("x q[0];"), "");
+        assert_eq!(handle(":undo 2"), "Undid 3 gates\n");
+        assert_eq!(handle(":state"), "(1+0i)|00⟩\n");
+        assert_eq!(
+            handle(":undo"),
+            "Error: Cannot undo 1 inputs when only 0 have applied gates"
+        );
+    }
+
+    /// Tests that the session continues after a rejected register declaration.
+    #[test]
+    fn test_rejected_register() {
+        let options = Options {
+            trajectory_qubits: vec![5],
+            ..Options::default()
+        };
+        let mut session = Session::new(GateDefinitions::new(), options);
+        let mut handle = |line: &str| match session.handle_line(line) {
+            Ok(output) => output.unwrap(),
+            Err(error) => format!["Error: {error}"],
+        };
+        assert_eq!(handle("OPENQASM 2.0;"), "");
+        assert_eq!(
+            handle("qreg q[2];"),
+            "Error: Unknown trajectory qubit 5 on line 2"
+        );
+        assert_eq!(handle("h q[0];"), "Error: No quantum register was defined");
+        assert_eq!(handle(":state"), "Error: No quantum register was defined");
+        assert_eq!(handle("qreg q[6];"), "");
+        assert_eq!(handle("x q[5];"), "");
+        assert_eq!(handle(":state"), "(1+0i)|100000⟩\n");
+    }
+
+    /// Tests the probability of basis states of registers too large for a basis index.
+    #[test]
+    fn test_large_register_probability() {
+        let mut session = Session::new(GateDefinitions::new(), Options::default());
+        for line in ["OPENQASM 2.0;", "qreg q[70];", "x q[69];"] {
+            assert_eq!(session.handle_line(line).unwrap(), Some(String::new()));
+        }
+        let one = format!["1{}", "0".repeat(69)];
+        let output = session.handle_line(&format![":prob {one}"]).unwrap();
+        assert_eq!(output.unwrap(), "1.0000000000\n");
+        let zero = "0".repeat(70);
+        let output = session.handle_line(&format![":prob {zero}"]).unwrap();
+        assert_eq!(output.unwrap(), "0.0000000000\n");
+    }
+}
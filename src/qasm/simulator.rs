@@ -0,0 +1,2228 @@
+use crate::gates::fusion::{disjoint_layers, GateFuser};
+use crate::gates::gate::{apply_gate_to_ket_into, Gate};
+use crate::gates::kernels::Matrix2;
+use crate::gates::lightcone::{lightcone_mask, used_qubits};
+use crate::gates::origin::Origin;
+use crate::gates::parallel::Parallelism;
+use crate::qasm::compiled::{CanonicalHasher, CompiledCircuit};
+use crate::qasm::definitions::{first_duplicate, GateDefinitions};
+use crate::qasm::lowering::{Lowering, Operation};
+use crate::qasm::parser::{Parser, Statement, StatementKind};
+use crate::qasm::warning::{Warning, WarningKind};
+use crate::quantum::backend::{Backend, BackendState};
+use crate::quantum::dense::{DenseState, MAX_DENSE_QUBITS};
+use crate::quantum::diagnostics::Diagnostics;
+use crate::quantum::distribution::Distribution;
+use crate::quantum::file_backed::{FileBackedState, DEFAULT_CHUNK_QUBITS};
+use crate::quantum::ket::Ket;
+use crate::quantum::metrics::Metrics;
+use crate::quantum::observable::{Estimate, Expectation, Observable, SampledExpectation};
+use crate::quantum::sampling::Rng;
+use crate::quantum::schedule::Schedule;
+use crate::quantum::state::{Accumulation, State};
+use crate::quantum::tolerance::Tolerance;
+use crate::quantum::trie::TrieState;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Settings that control how a circuit is simulated.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Process kets in basis index order so that runs are reproducible.
+    pub canonical: bool,
+    /// How colliding amplitudes are summed.
+    pub accumulation: Accumulation,
+    /// The tolerance below which amplitudes are dropped by the sparse backends, and
+    /// within which the final state must keep all of its probability to avoid a
+    /// [`WarningKind::LostProbability`] warning.
+    pub tolerance: Tolerance,
+    /// Check for non-finite amplitudes after every gate.
+    pub check_finite: bool,
+    /// How the kets are divided between threads when applying gates.
+    pub parallelism: Parallelism,
+    /// Fuse runs of single qubit gates before applying them.
+    pub fuse: bool,
+    /// How the state is stored.
+    pub backend: Backend,
+    /// The directory for the scratch file of the file backend, or the system temporary
+    /// directory if not set.
+    pub scratch_dir: Option<PathBuf>,
+    /// Compute [`Diagnostics`] of the final state.
+    pub diagnostics: bool,
+    /// The subsystems whose purity is included in the diagnostics.
+    pub subsystems: Vec<Vec<usize>>,
+    /// Report the marginal probabilities of these qubits, simulating only the gates in
+    /// their backward lightcone. If set, the gates are held until the end of the circuit.
+    pub marginal_qubits: Vec<usize>,
+    /// An observable whose expectation value in the final state is reported.
+    pub observable: Option<Observable>,
+    /// Also estimate the expectation value of the observable from this many shots of each
+    /// group of terms that can be measured together.
+    pub shots: Option<usize>,
+    /// The seed for sampling shots.
+    pub seed: u64,
+    /// Keep the gates that have been applied, so that they can be undone with
+    /// [`Simulator::undo`].
+    pub history: bool,
+    /// Switch from the sparse to the dense backend once the kets fill this fraction of
+    /// the `2^n` basis states, if the register is small enough for the dense backend.
+    pub dense_threshold: Option<f64>,
+    /// Fail at the end of the circuit if there are any warnings, rather than reporting
+    /// them in the result.
+    pub fail_on_warning: bool,
+    /// Stop once the probability of the amplitudes pruned by the sparse backends exceeds
+    /// this, see [`State::pruned_probability`].
+    pub max_pruned_probability: Option<f64>,
+    /// Record the expectation value of Pauli Z on these qubits as the circuit runs, see
+    /// [`Trajectory`]. This cannot be combined with marginal qubits.
+    pub trajectory_qubits: Vec<usize>,
+    /// Record the trajectory after every this many instructions rather than after every
+    /// one. Zero is treated as one.
+    pub trajectory_every: usize,
+    /// Time how long each gate takes to apply, see [`Profile`].
+    pub profile: bool,
+    /// Set to the number of kets after every gate, which is zero for the dense backends,
+    /// so that a [`Watchdog`](crate::quantum::watchdog::Watchdog) can follow it.
+    pub kets_gauge: Option<Arc<AtomicUsize>>,
+    /// Return an error rather than panicking if the [`Simulator`] reaches a bug, for
+    /// services that embed it and must keep running. The panic message is still printed
+    /// by the panic hook, and a build that aborts on panic cannot catch it.
+    pub catch_panics: bool,
+}
+
+/// The marginal probabilities of some of the qubits, found by simulating only the
+/// qubits in their backward lightcone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marginals {
+    /// The qubits the probabilities are of.
+    pub qubits: Vec<usize>,
+    /// The qubits that were simulated, which are the qubits of the final state.
+    pub lightcone: Vec<usize>,
+    /// The probability of each outcome, where bit `i` is the value of `qubits[i]`.
+    pub probabilities: BTreeMap<usize, f64>,
+}
+
+/// The expectation values of Pauli Z on some qubits as a circuit runs, for plotting how
+/// they change with the depth of the circuit.
+///
+/// Each gate call, delay and directly applied gate is one instruction. The values are
+/// recorded before the first instruction, after every [`Options::trajectory_every`]
+/// instructions and after the last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trajectory {
+    /// The qubits the expectation values are of.
+    pub qubits: Vec<usize>,
+    /// The number of instructions executed at each point, and the expectation value of Z
+    /// on each of the qubits at that point.
+    pub points: Vec<(usize, Vec<f64>)>,
+}
+
+impl Trajectory {
+    /// Returns the trajectory as CSV, with a header row and a row for each point.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::parser::Parser;
+    /// use quantum_simulator::qasm::simulator::{Options, Simulator};
+    ///
+    /// let source = "OPENQASM 2.0;\nqreg q[2];\nx q[0];\ncx q[0], q[1];";
+    /// let options = Options { trajectory_qubits: vec![1], ..Options::default() };
+    /// let simulator = Simulator::new(GateDefinitions::new(), options);
+    /// let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+    /// assert_eq!(result.trajectory.unwrap().to_csv(), "instruction,z1\n0,1\n1,1\n2,-1\n");
+    /// ```
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("instruction");
+        for qubit in &self.qubits {
+            csv.push_str(&format![",z{qubit}"]);
+        }
+        csv.push('\n');
+        for (instruction, expectations) in &self.points {
+            csv.push_str(&instruction.to_string());
+            for expectation in expectations {
+                csv.push_str(&format![",{expectation}"]);
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// The time spent applying gates, by the region of the circuit between barriers, the
+/// instruction the gates came from and the type of gate.
+///
+/// Gates held back for fusion are timed in the instructions they came from, with a fused
+/// run in a frame merging them such as `'h+t' on lines 3-4`. Gates held back for the
+/// lightcone of marginal qubits are all in the last region.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    /// The number of barriers executed so far.
+    barriers: usize,
+    /// Each stack of frames and the time spent in it, in the order they were first seen.
+    stacks: Vec<(String, Duration)>,
+    indices: HashMap<String, usize>,
+}
+
+impl Profile {
+    /// Returns each stack of frames, separated by semicolons, and the time spent in it.
+    pub fn stacks(&self) -> &[(String, Duration)] {
+        &self.stacks
+    }
+
+    /// Returns the stacks in the folded format read by flamegraph tools such as inferno,
+    /// with one stack per line followed by its time in nanoseconds.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::parser::Parser;
+    /// use quantum_simulator::qasm::simulator::{Options, Simulator};
+    ///
+    /// let source = "OPENQASM 2.0;\nqreg q[2];\nh q[0];\nbarrier q[0], q[1];\ncx q[0], q[1];";
+    /// let options = Options { profile: true, ..Options::default() };
+    /// let simulator = Simulator::new(GateDefinitions::new(), options);
+    /// let folded = simulator.run(Parser::new(source.as_bytes())).unwrap().profile.unwrap().to_folded();
+    /// let stacks: Vec<&str> = folded.lines().map(|line| line.rsplit_once(' ').unwrap().0).collect();
+    /// assert_eq!(stacks, vec!["region 0;'h' on line 3;h", "region 1;'cx' on line 5;cx"]);
+    /// ```
+    pub fn to_folded(&self) -> String {
+        self.stacks
+            .iter()
+            .map(|(stack, time)| format!["{stack} {}\n", time.as_nanos()])
+            .collect()
+    }
+
+    /// Adds the time spent applying a gate, where `location` is where it came from as in
+    /// error messages.
+    fn record(&mut self, location: &str, gate: &Gate, time: Duration) {
+        // Frames cannot contain the separator of the folded format.
+        let instruction = location
+            .strip_prefix("of ")
+            .unwrap_or(location)
+            .replace(';', ",");
+        let stack = format!["region {};{instruction};{}", self.barriers, gate.name()];
+        match self.indices.get(&stack) {
+            Some(index) => self.stacks[*index].1 += time,
+            None => {
+                self.indices.insert(stack.clone(), self.stacks.len());
+                self.stacks.push((stack, time));
+            }
+        }
+    }
+}
+
+/// How a result was produced, so that a result that has been archived can be traced back
+/// to the simulator, circuit and machine that produced it. The backend is
+/// [`SimulationResult::backend`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Provenance {
+    /// The version of the simulator.
+    pub version: &'static str,
+    /// The seed for sampling shots.
+    pub seed: u64,
+    /// The [canonical hash](CompiledCircuit::canonical_hash) of the gates and delays that
+    /// were executed, including any that were later undone.
+    pub circuit_hash: u128,
+    /// The operating system of the machine, such as `linux`.
+    pub os: &'static str,
+    /// The architecture of the machine, such as `x86_64`.
+    pub arch: &'static str,
+    /// The number of threads the machine can run at once.
+    pub threads: usize,
+}
+
+impl Provenance {
+    /// Returns the provenance of a result produced on this machine by this build.
+    pub fn new(seed: u64, circuit_hash: u128) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            seed,
+            circuit_hash,
+            os: env::consts::OS,
+            arch: env::consts::ARCH,
+            threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        }
+    }
+}
+
+/// The outcome of simulating a circuit, holding everything the command line reports.
+#[derive(Debug)]
+pub struct SimulationResult {
+    /// The final state of the quantum register.
+    pub final_state: State,
+    /// When each operation ran on each qubit.
+    pub schedule: Schedule,
+    /// The time spent applying gates, from the register declaration to the end.
+    pub wall_time: Duration,
+    /// The number of times each built in gate was applied, after expanding gate
+    /// definitions and before fusion.
+    pub gate_counts: BTreeMap<String, usize>,
+    /// The largest number of kets held at once, for the sparse backend.
+    pub peak_kets: Option<usize>,
+    /// The backend used to store the final state.
+    pub backend: Backend,
+    /// The number of gates that had been applied when the state was switched from the
+    /// sparse to the dense backend, if it was.
+    pub dense_after_gates: Option<usize>,
+    /// The total probability of the amplitudes dropped over the whole run, see
+    /// [`State::pruned_probability`].
+    pub pruned_probability: f64,
+    /// The counters of the work done by the sparse backend, which are zero for the other
+    /// backends.
+    pub metrics: Metrics,
+    /// Metrics of the final state, if they were requested.
+    pub diagnostics: Option<Diagnostics>,
+    /// The marginal probabilities, if they were requested. The final state then only
+    /// holds the qubits of the lightcone.
+    pub marginals: Option<Marginals>,
+    /// The expectation value of the observable, if one was given.
+    pub expectation: Option<Expectation>,
+    /// The expectation value estimated from shots, if shots were requested.
+    pub sampled_expectation: Option<SampledExpectation>,
+    /// The expectation values of Z as the circuit ran, if trajectory qubits were given.
+    pub trajectory: Option<Trajectory>,
+    /// The time spent applying each gate, if it was requested.
+    pub profile: Option<Profile>,
+    /// Problems with the circuit or its simulation that did not stop it.
+    pub warnings: Vec<Warning>,
+    /// How the result was produced.
+    pub provenance: Provenance,
+}
+
+impl SimulationResult {
+    /// Returns the probabilities of measuring each outcome, which are the marginal
+    /// probabilities if they were requested and those of the final state otherwise, in the
+    /// same way as [`parse_distribution`] reads the JSON result.
+    ///
+    /// [`parse_distribution`]: crate::quantum::distribution::parse_distribution
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::parser::Parser;
+    /// use quantum_simulator::qasm::simulator::{Options, Simulator};
+    ///
+    /// let source = "OPENQASM 2.0;\nqreg q[2];\nh q[0];\nx q[1];";
+    /// let simulator = Simulator::new(GateDefinitions::new(), Options::default());
+    /// let distribution = simulator.run(Parser::new(source.as_bytes())).unwrap().distribution();
+    /// assert_eq!(distribution.keys().collect::<Vec<_>>(), vec!["10", "11"]);
+    /// assert!((distribution["11"] - 0.5).abs() < 1e-12);
+    /// ```
+    pub fn distribution(&self) -> Distribution {
+        let mut distribution: Distribution = match &self.marginals {
+            Some(marginals) => marginals
+                .probabilities
+                .iter()
+                .map(|(outcome, probability)| {
+                    let width = marginals.qubits.len();
+                    (format!["{outcome:0width$b}"], *probability)
+                })
+                .collect(),
+            None => self
+                .final_state
+                .kets()
+                .map(|ket| {
+                    let basis: String = ket
+                        .bit_vec()
+                        .iter()
+                        .rev()
+                        .map(|bit| if *bit { '1' } else { '0' })
+                        .collect();
+                    (basis, ket.amplitude.norm_sqr())
+                })
+                .collect(),
+        };
+        let total: f64 = distribution.values().sum();
+        distribution.retain(|_, probability| *probability > 0.0);
+        distribution
+            .values_mut()
+            .for_each(|probability| *probability /= total);
+        distribution
+    }
+
+    /// Returns this result as a JSON object, with the final state as a list of basis
+    /// states and their amplitudes in basis index order.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::parser::Parser;
+    /// use quantum_simulator::qasm::simulator::{Options, Simulator};
+    ///
+    /// let source = "OPENQASM 2.0;\nqreg q[2];\nx q[1];";
+    /// let simulator = Simulator::new(GateDefinitions::new(), Options::default());
+    /// let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+    /// assert!(result.to_json().contains(r#""final_state":[{"basis":"10","re":1,"im":0}]"#));
+    /// ```
+    pub fn to_json(&self) -> String {
+        let gate_counts: Vec<String> = self
+            .gate_counts
+            .iter()
+            .map(|(name, count)| format!["{}:{count}", json_string(name)])
+            .collect();
+        let kets: Vec<String> = self
+            .final_state
+            .sorted_kets()
+            .iter()
+            .map(|ket| {
+                let basis: String = ket
+                    .bit_vec()
+                    .iter()
+                    .rev()
+                    .map(|bit| if *bit { '1' } else { '0' })
+                    .collect();
+                format![
+                    r#"{{"basis":"{basis}","re":{},"im":{}}}"#,
+                    json_number(ket.amplitude.re),
+                    json_number(ket.amplitude.im)
+                ]
+            })
+            .collect();
+        let diagnostics = self.diagnostics.as_ref().map_or("".to_string(), |diagnostics| {
+            let purities: Vec<String> = diagnostics
+                .purities
+                .iter()
+                .map(|(subsystem, purity)| {
+                    format![
+                        r#"{{"qubits":{subsystem:?},"purity":{}}}"#,
+                        json_number(*purity)
+                    ]
+                })
+                .collect();
+            format![
+                r#","diagnostics":{{"nonzero_amplitudes":{},"inverse_participation_ratio":{},"purities":[{}]}}"#,
+                diagnostics.nonzero_amplitudes,
+                json_number(diagnostics.inverse_participation_ratio),
+                purities.join(",")
+            ]
+        });
+        let marginals = self.marginals.as_ref().map_or("".to_string(), |marginals| {
+            let probabilities: Vec<String> = marginals
+                .probabilities
+                .iter()
+                .map(|(outcome, probability)| {
+                    format![
+                        r#""{outcome:0width$b}":{}"#,
+                        json_number(*probability),
+                        width = marginals.qubits.len()
+                    ]
+                })
+                .collect();
+            format![
+                r#","marginals":{{"qubits":{:?},"lightcone":{:?},"probabilities":{{{}}}}}"#,
+                marginals.qubits,
+                marginals.lightcone,
+                probabilities.join(",")
+            ]
+        });
+        let expectation = self
+            .expectation
+            .as_ref()
+            .map_or("".to_string(), |expectation| {
+                let terms: Vec<String> = expectation
+                    .terms
+                    .iter()
+                    .map(|(term, value)| {
+                        format![
+                            r#"{{"coefficient":{},"pauli":{},"expectation":{}}}"#,
+                            json_number(term.coefficient),
+                            json_string(&term.label),
+                            json_number(*value)
+                        ]
+                    })
+                    .collect();
+                format![
+                    r#","expectation":{{"value":{},"terms":[{}]}}"#,
+                    json_number(expectation.value),
+                    terms.join(",")
+                ]
+            });
+        let sampled_expectation =
+            self.sampled_expectation
+                .as_ref()
+                .map_or("".to_string(), |sampled| {
+                    let terms: Vec<String> = sampled
+                        .terms
+                        .iter()
+                        .map(|(term, estimate)| {
+                            format![
+                                r#"{{"pauli":{},{}}}"#,
+                                json_string(&term.label),
+                                json_estimate(estimate)
+                            ]
+                        })
+                        .collect();
+                    format![
+                        r#","sampled_expectation":{{"shots":{},"groups":{},{},"terms":[{}]}}"#,
+                        sampled.shots,
+                        sampled.groups,
+                        json_estimate(&sampled.value),
+                        terms.join(",")
+                    ]
+                });
+        let trajectory = self
+            .trajectory
+            .as_ref()
+            .map_or("".to_string(), |trajectory| {
+                let points: Vec<String> = trajectory
+                    .points
+                    .iter()
+                    .map(|(instruction, expectations)| {
+                        let expectations: Vec<String> = expectations
+                            .iter()
+                            .map(|value| json_number(*value))
+                            .collect();
+                        format![
+                            r#"{{"instruction":{instruction},"z":[{}]}}"#,
+                            expectations.join(",")
+                        ]
+                    })
+                    .collect();
+                format![
+                    r#","trajectory":{{"qubits":{:?},"points":[{}]}}"#,
+                    trajectory.qubits,
+                    points.join(",")
+                ]
+            });
+        let warnings: Vec<String> = self
+            .warnings
+            .iter()
+            .map(|warning| {
+                format![
+                    r#"{{"kind":{},"message":{}}}"#,
+                    json_string(warning.kind.name()),
+                    json_string(&warning.message)
+                ]
+            })
+            .collect();
+        let dense_after_gates = self.dense_after_gates.map_or("".to_string(), |gates| {
+            format![r#","dense_after_gates":{gates}"#]
+        });
+        let provenance = &self.provenance;
+        let provenance = format![
+            r#","provenance":{{"version":{},"seed":{},"circuit_hash":"{:032x}","os":{},"arch":{},"threads":{}}}"#,
+            json_string(provenance.version),
+            provenance.seed,
+            provenance.circuit_hash,
+            json_string(provenance.os),
+            json_string(provenance.arch),
+            provenance.threads
+        ];
+        format![
+            r#"{{"backend":{}{dense_after_gates},"num_qubits":{},"wall_time_seconds":{},"gate_counts":{{{}}},"peak_kets":{},"pruned_probability":{},"metrics":{{"branches":{},"collisions":{},"kets_merged":{},"kets_pruned":{}}}{diagnostics}{marginals}{expectation}{sampled_expectation}{trajectory}{provenance},"warnings":[{}],"final_state":[{}]}}"#,
+            json_string(self.backend.name()),
+            self.final_state.num_qubits(),
+            self.wall_time.as_secs_f64(),
+            gate_counts.join(","),
+            self.peak_kets
+                .map_or("null".to_string(), |peak_kets| peak_kets.to_string()),
+            json_number(self.pruned_probability),
+            self.metrics.branches,
+            self.metrics.collisions,
+            self.metrics.kets_merged,
+            self.metrics.kets_pruned,
+            warnings.join(","),
+            kets.join(",")
+        ]
+    }
+}
+
+/// Executes parsed QASM statements one at a time, starting from the zero state.
+///
+/// Statements are applied as they arrive and are never stored, so a circuit can be
+/// simulated while it is still being parsed and the memory used does not grow with the
+/// length of the circuit. The exception is when [`Options::marginal_qubits`] is set,
+/// since the lightcone is only known once the whole circuit has been read. The first
+/// statement must be the version header.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::definitions::GateDefinitions;
+/// use quantum_simulator::qasm::parser::Parser;
+/// use quantum_simulator::qasm::simulator::{Options, Simulator};
+///
+/// let source = "OPENQASM 2.0;\nqreg q[2];\nh q[0];\ncx q[0], q[1];";
+/// let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+/// simulator.run_streaming(Parser::new(source.as_bytes())).unwrap();
+///
+/// let simulation = simulator.finish().unwrap();
+/// assert_eq!(simulation.final_state.to_string(), "(0.707+0i)|00⟩ + (0.707+0i)|11⟩");
+/// ```
+pub struct Simulator {
+    lowering: Lowering,
+    options: Options,
+    state: Option<BackendState>,
+    /// The line the register was declared on, once it has been.
+    register_line: Option<usize>,
+    /// The gates held until the end of the circuit along with where they came from, when
+    /// only the lightcone of the marginal qubits is simulated.
+    deferred: Option<Vec<(Gate, Origin)>>,
+    schedule: Schedule,
+    fuser: Option<GateFuser>,
+    start: Instant,
+    gate_counts: BTreeMap<String, usize>,
+    peak_kets: Option<usize>,
+    /// The gates applied so far, if the history is kept.
+    history: Vec<Gate>,
+    dense_after_gates: Option<usize>,
+    /// The probability pruned by the sparse state before it was switched to the dense
+    /// backend, which does not keep track of it.
+    pruned_before_dense: f64,
+    /// The metrics of the sparse state before it was switched to the dense backend.
+    metrics_before_dense: Metrics,
+    /// The hash of the circuit executed so far, once the register has been declared.
+    circuit_hasher: Option<CanonicalHasher>,
+    /// The number of instructions executed so far, see [`Trajectory`].
+    instructions: usize,
+    trajectory: Option<Trajectory>,
+    profile: Option<Profile>,
+    /// Whether a panic has been caught, see [`Options::catch_panics`].
+    panicked: bool,
+}
+
+impl Simulator {
+    /// Creates a new `Simulator` that expands gate calls with `definitions`.
+    pub fn new(definitions: GateDefinitions, options: Options) -> Self {
+        Self {
+            lowering: Lowering::new(definitions),
+            fuser: options.fuse.then(GateFuser::new),
+            deferred: (!options.marginal_qubits.is_empty()).then(Vec::new),
+            trajectory: (!options.trajectory_qubits.is_empty()).then(|| Trajectory {
+                qubits: options.trajectory_qubits.clone(),
+                points: Vec::new(),
+            }),
+            profile: options.profile.then(Profile::default),
+            options,
+            state: None,
+            register_line: None,
+            schedule: Schedule::new(0),
+            start: Instant::now(),
+            gate_counts: BTreeMap::new(),
+            peak_kets: None,
+            history: Vec::new(),
+            dense_after_gates: None,
+            pruned_before_dense: 0.0,
+            metrics_before_dense: Metrics::default(),
+            circuit_hasher: None,
+            instructions: 0,
+            panicked: false,
+        }
+    }
+
+    /// Returns the QASM version from the header, once it has been executed.
+    pub fn version(&self) -> Option<&str> {
+        self.lowering.version()
+    }
+
+    /// Executes all of the statements and returns the result.
+    pub fn run(
+        mut self,
+        statements: impl IntoIterator<Item = io::Result<Statement>>,
+    ) -> io::Result<SimulationResult> {
+        self.run_streaming(statements)?;
+        self.finish()
+    }
+
+    /// Executes each statement as soon as it is produced, stopping at the first error.
+    pub fn run_streaming(
+        &mut self,
+        statements: impl IntoIterator<Item = io::Result<Statement>>,
+    ) -> io::Result<()> {
+        for statement in statements {
+            self.execute(statement?)?;
+        }
+        Ok(())
+    }
+
+    /// Parses and executes more QASM statements, continuing from the current state. Line
+    /// numbers in error messages count from the start of `source`.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::simulator::{Options, Simulator};
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+    /// simulator.append_qasm("OPENQASM 2.0;\nqreg q[2];\nh q[0];").unwrap();
+    /// assert_eq!(simulator.state().unwrap().to_string(), "(0.707+0i)|00⟩ + (0.707+0i)|01⟩");
+    ///
+    /// simulator.apply(Gate::CX { control: QubitRef(0), target: QubitRef(1) }).unwrap();
+    /// let simulation = simulator.finish().unwrap();
+    /// assert_eq!(simulation.final_state.to_string(), "(0.707+0i)|00⟩ + (0.707+0i)|11⟩");
+    /// ```
+    pub fn append_qasm(&mut self, source: &str) -> io::Result<()> {
+        self.run_streaming(Parser::new(source.as_bytes()))
+    }
+
+    /// Applies a built in gate to the current state, once the register has been declared.
+    pub fn apply(&mut self, gate: Gate) -> io::Result<()> {
+        self.apply_with_origin(gate, Origin::unplaced("applied directly"))
+    }
+
+    /// Applies a built in gate like [`Simulator::apply`], with where it came from, so
+    /// that errors and the profile point to the gate's label and lines.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::gates::origin::Origin;
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::simulator::{Options, Simulator};
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+    /// simulator.append_qasm("OPENQASM 2.0;\nqreg q[2];").unwrap();
+    /// let gate = Gate::CX { control: QubitRef(2), target: QubitRef(0) };
+    /// let error = simulator.apply_with_origin(gate, Origin::new("entangle", 12)).unwrap_err();
+    /// assert_eq!(
+    ///     error.to_string(),
+    ///     "Qubit 2 is outside the register of 2 qubits of 'entangle' on line 12"
+    /// );
+    /// ```
+    pub fn apply_with_origin(&mut self, gate: Gate, origin: Origin) -> io::Result<()> {
+        self.guarded(|simulator| simulator.apply_unguarded(gate, origin))
+    }
+
+    /// Applies a gate, see [`Simulator::apply_with_origin`].
+    fn apply_unguarded(&mut self, gate: Gate, origin: Origin) -> io::Result<()> {
+        let num_qubits = self.declared_qubits()?;
+        // Gates applied directly are only located when they have lines in a source.
+        let location = match origin.span {
+            Some(_) => format![" {}", location(&origin)],
+            None => String::new(),
+        };
+        if let Some(qubit) = gate.qubits().into_iter().find(|qubit| *qubit >= num_qubits) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!["Qubit {qubit} is outside the register of {num_qubits} qubits{location}"],
+            ));
+        }
+        if let Some(qubit) = first_duplicate(&gate.qubits()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format![
+                    "Qubit {qubit} is used more than once by gate {}{location}",
+                    gate.name()
+                ],
+            ));
+        }
+        self.push_gate(gate, origin)?;
+        self.finish_instruction()
+    }
+
+    /// Returns a copy of the current state, applying any gates held back for fusion.
+    ///
+    /// Returns an error before the register is declared, and when only the lightcone of
+    /// marginal qubits is simulated, since then the state is only created at the end.
+    pub fn state(&mut self) -> io::Result<State> {
+        self.guarded(Self::state_unguarded)
+    }
+
+    /// Returns the current state, see [`Simulator::state`].
+    fn state_unguarded(&mut self) -> io::Result<State> {
+        self.declared_qubits()?;
+        let Some(state) = &mut self.state else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The state is only simulated at the end of the circuit when marginal qubits are set",
+            ));
+        };
+        if let Some(fuser) = &mut self.fuser {
+            for layer in disjoint_layers(fuser.finish_with_origins()) {
+                apply_layer(state, &layer, &self.options, &mut self.profile)?;
+                self.peak_kets = self.peak_kets.max(state.num_kets());
+            }
+        }
+        state.to_state()
+    }
+
+    /// Returns the number of gates that have been applied, when the history is kept.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Undoes the last `num_gates` gates by applying their inverses, when the history is
+    /// kept. The gates are removed from the gate counts but stay in the schedule.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::qasm::definitions::GateDefinitions;
+    /// use quantum_simulator::qasm::simulator::{Options, Simulator};
+    ///
+    /// let options = Options { history: true, ..Options::default() };
+    /// let mut simulator = Simulator::new(GateDefinitions::new(), options);
+    /// simulator.append_qasm("OPENQASM 2.0;\nqreg q[1];\nh q[0];\nt q[0];").unwrap();
+    /// simulator.undo(1).unwrap();
+    /// assert_eq!(simulator.state().unwrap().to_string(), "(0.707+0i)|0⟩ + (0.707+0i)|1⟩");
+    /// ```
+    pub fn undo(&mut self, num_gates: usize) -> io::Result<()> {
+        self.guarded(|simulator| simulator.undo_unguarded(num_gates))
+    }
+
+    /// Undoes gates, see [`Simulator::undo`].
+    fn undo_unguarded(&mut self, num_gates: usize) -> io::Result<()> {
+        if num_gates > self.history.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format![
+                    "Cannot undo {num_gates} gates when only {} are in the history",
+                    self.history.len()
+                ],
+            ));
+        }
+        for _ in 0..num_gates {
+            let gate = self.history.pop().unwrap();
+            if let Some(count) = self.gate_counts.get_mut(gate.name()) {
+                *count -= 1;
+                if *count == 0 {
+                    self.gate_counts.remove(gate.name());
+                }
+            }
+            match &mut self.deferred {
+                Some(deferred) => {
+                    deferred.pop();
+                }
+                None => self.apply_ready(gate.inverse(), Origin::unplaced("undoing a gate"))?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f`, turning a panic into an error if [`Options::catch_panics`] is set. Every
+    /// later call then fails, since the panic may have left the simulator inconsistent.
+    fn guarded<T>(&mut self, f: impl FnOnce(&mut Self) -> io::Result<T>) -> io::Result<T> {
+        if !self.options.catch_panics {
+            return f(self);
+        }
+        self.check_not_panicked()?;
+        panic::catch_unwind(AssertUnwindSafe(|| f(self))).unwrap_or_else(|payload| {
+            self.panicked = true;
+            Err(internal_error(payload))
+        })
+    }
+
+    /// Returns an error if a panic has been caught, see [`Options::catch_panics`].
+    fn check_not_panicked(&self) -> io::Result<()> {
+        match self.panicked {
+            true => Err(io::Error::other(
+                "The simulator cannot be used after an internal error",
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns the number of qubits in the register, or an error if it has not been
+    /// declared yet.
+    fn declared_qubits(&self) -> io::Result<usize> {
+        self.lowering
+            .register()
+            .map(|register| register.size)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "No quantum register was defined",
+                )
+            })
+    }
+
+    /// Executes a compiled circuit and returns the result, without parsing or expanding
+    /// any gate calls.
+    pub fn run_compiled(mut self, circuit: CompiledCircuit) -> io::Result<SimulationResult> {
+        // The header and register are lowered as usual, so that they are checked and set
+        // up in the same way as for a QASM file.
+        self.execute(Statement {
+            kind: StatementKind::Version(circuit.version),
+            line: 1,
+        })?;
+        self.execute(Statement {
+            kind: StatementKind::QuantumRegister(circuit.register),
+            line: circuit.register_line,
+        })?;
+        for operation in circuit.operations {
+            self.guarded(|simulator| simulator.execute_operation(Some(operation)))?;
+        }
+        self.finish()
+    }
+
+    /// Executes a single statement.
+    pub fn execute(&mut self, statement: Statement) -> io::Result<()> {
+        self.guarded(|simulator| simulator.execute_unguarded(statement))
+    }
+
+    /// Executes a statement, see [`Simulator::execute`].
+    fn execute_unguarded(&mut self, statement: Statement) -> io::Result<()> {
+        let line_number = statement.line;
+        let operation = self.lowering.lower(statement)?;
+        if self.register_line.is_none() {
+            if let Some(num_qubits) = self.lowering.register().map(|register| register.size) {
+                if let Err(error) = self.declare_register(num_qubits, line_number) {
+                    // Leave the register undeclared, so that later statements fail instead
+                    // of finding no state.
+                    self.lowering.undeclare_register();
+                    self.state = None;
+                    return Err(error);
+                }
+                self.register_line = Some(line_number);
+            }
+        }
+        self.execute_operation(operation)
+    }
+
+    /// Checks the options against a newly declared register of `num_qubits` qubits and
+    /// creates its state and schedule.
+    fn declare_register(&mut self, num_qubits: usize, line_number: usize) -> io::Result<()> {
+        let marginal_qubits = &self.options.marginal_qubits;
+        if let Some(qubit) = marginal_qubits.iter().find(|qubit| **qubit >= num_qubits) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!["Unknown marginal qubit {qubit} on line {line_number}"],
+            ));
+        }
+        let trajectory_qubits = &self.options.trajectory_qubits;
+        if let Some(qubit) = trajectory_qubits.iter().find(|qubit| **qubit >= num_qubits) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!["Unknown trajectory qubit {qubit} on line {line_number}"],
+            ));
+        }
+        if self.trajectory.is_some() && self.deferred.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A trajectory cannot be recorded when only the lightcone of marginal qubits is simulated",
+            ));
+        }
+        if let Some(observable) = &self.options.observable {
+            if observable.num_qubits != num_qubits {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format![
+                        "The observable acts on {} qubits but the register has {num_qubits} on line {line_number}",
+                        observable.num_qubits
+                    ],
+                ));
+            }
+        }
+        // The state for the lightcone is only created at the end of the circuit.
+        if self.deferred.is_none() {
+            let state = self.new_state(num_qubits, line_number)?;
+            self.peak_kets = state.num_kets();
+            self.state = Some(state);
+        }
+        self.schedule = Schedule::new(num_qubits);
+        self.circuit_hasher = Some(CanonicalHasher::new(num_qubits));
+        self.start = Instant::now();
+        self.record_trajectory()
+    }
+
+    /// Counts an instruction as executed and records the trajectory if it is due.
+    fn finish_instruction(&mut self) -> io::Result<()> {
+        self.instructions += 1;
+        if self
+            .instructions
+            .is_multiple_of(self.options.trajectory_every.max(1))
+        {
+            self.record_trajectory()?;
+        }
+        Ok(())
+    }
+
+    /// Records the expectation values of Z on the trajectory qubits in the current state,
+    /// if a trajectory is being recorded and not already recorded at this point.
+    fn record_trajectory(&mut self) -> io::Result<()> {
+        let recorded = match &self.trajectory {
+            Some(trajectory) => trajectory
+                .points
+                .last()
+                .map(|(instruction, _)| *instruction),
+            None => return Ok(()),
+        };
+        if recorded == Some(self.instructions) {
+            return Ok(());
+        }
+        let state = self.state()?;
+        // Only reached when a trajectory is being recorded.
+        let trajectory = self.trajectory.as_mut().unwrap();
+        let expectations = state.z_expectations(&trajectory.qubits);
+        trajectory.points.push((self.instructions, expectations));
+        Ok(())
+    }
+
+    /// Executes an operation once the register has been declared.
+    fn execute_operation(&mut self, operation: Option<Operation>) -> io::Result<()> {
+        match operation {
+            None => return Ok(()),
+            Some(Operation::GateCall { name, line, gates }) => {
+                if let Some(profile) = self.profile.as_mut().filter(|_| name == "barrier") {
+                    profile.barriers += 1;
+                }
+                let origin = Origin::new(name, line);
+                for gate in gates {
+                    self.push_gate(gate, origin.clone())?;
+                }
+            }
+            // Delays leave the state unchanged and only affect the schedule.
+            Some(Operation::Delay { qubits, duration }) => {
+                let indices: Vec<usize> = qubits.iter().map(|qubit| qubit.index()).collect();
+                self.schedule.push("delay", &indices, duration);
+                if let Some(hasher) = &mut self.circuit_hasher {
+                    hasher.delay(&qubits, duration);
+                }
+            }
+        }
+        self.finish_instruction()
+    }
+
+    /// Schedules a gate and applies it, or holds it back until the end of the circuit when
+    /// only the lightcone of the marginal qubits is simulated.
+    fn push_gate(&mut self, gate: Gate, origin: Origin) -> io::Result<()> {
+        // Gates are treated as instantaneous until gate durations are known.
+        self.schedule
+            .push(gate.name(), &gate.qubits(), Duration::ZERO);
+        if let Some(hasher) = &mut self.circuit_hasher {
+            hasher.gate(&gate);
+        }
+        if self.options.history {
+            self.history.push(gate.clone());
+        }
+        match &mut self.deferred {
+            Some(deferred) => deferred.push((gate, origin)),
+            None => self.apply_next(gate, origin)?,
+        }
+        Ok(())
+    }
+
+    /// Applies any gates still held back for fusion and returns the final state.
+    pub fn finish(self) -> io::Result<SimulationResult> {
+        if !self.options.catch_panics {
+            return self.finish_unguarded();
+        }
+        self.check_not_panicked()?;
+        panic::catch_unwind(AssertUnwindSafe(|| self.finish_unguarded()))
+            .unwrap_or_else(|payload| Err(internal_error(payload)))
+    }
+
+    /// Finishes the simulation, see [`Simulator::finish`].
+    fn finish_unguarded(mut self) -> io::Result<SimulationResult> {
+        self.lowering.check_complete()?;
+        self.record_trajectory()?;
+        let lightcone = match self.deferred.take() {
+            Some(deferred) => Some(self.simulate_lightcone(deferred)?),
+            None => None,
+        };
+        let mut state = self.state.take().unwrap();
+        if let Some(fuser) = &mut self.fuser {
+            for layer in disjoint_layers(fuser.finish_with_origins()) {
+                apply_layer(&mut state, &layer, &self.options, &mut self.profile)?;
+                self.peak_kets = self.peak_kets.max(state.num_kets());
+            }
+        }
+        let wall_time = self.start.elapsed();
+
+        let mut metrics = self.metrics_before_dense;
+        metrics += state.metrics();
+        let mut final_state = state.into_state()?;
+        final_state.add_pruned_probability(self.pruned_before_dense);
+        let pruned_probability = final_state.pruned_probability();
+        check_pruned_probability(&self.options, pruned_probability, "at the end of the file")?;
+        let mut warnings = self.lowering.warnings().to_vec();
+        let total_probability: f64 = final_state.kets().map(|ket| ket.amplitude.norm_sqr()).sum();
+        let lost_probability = 1.0 - total_probability;
+        if lost_probability > 0.0 && !self.options.tolerance.approx_eq(total_probability, 1.0) {
+            warnings.push(Warning::new(
+                WarningKind::LostProbability,
+                format![
+                    "The final state has lost {lost_probability:.3e} of its probability to pruned amplitudes and rounding"
+                ],
+            ));
+        }
+        if let Some(warning) = warnings.first().filter(|_| self.options.fail_on_warning) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["{warning}, and warnings are treated as errors"],
+            ));
+        }
+        let diagnostics = match self.options.diagnostics {
+            true => {
+                let subsystems = match &lightcone {
+                    Some(lightcone) => lightcone_subsystems(&self.options.subsystems, lightcone)?,
+                    None => self.options.subsystems.clone(),
+                };
+                Some(Diagnostics::of(&final_state, &subsystems)?)
+            }
+            false => None,
+        };
+        let observable = self
+            .options
+            .observable
+            .as_ref()
+            .map(|observable| match &lightcone {
+                Some(lightcone) => observable.remap(lightcone.len(), |qubit| {
+                    lightcone.binary_search(&qubit).unwrap()
+                }),
+                None => observable.clone(),
+            });
+        let expectation = match &observable {
+            Some(observable) => Some(observable.expectation(&final_state)?),
+            None => None,
+        };
+        let sampled_expectation = match (&observable, self.options.shots) {
+            (Some(observable), Some(shots)) => {
+                let mut rng = Rng::new(self.options.seed);
+                Some(observable.sample(&final_state, shots, &mut rng)?)
+            }
+            _ => None,
+        };
+        let marginals = lightcone.map(|lightcone| {
+            let qubits = self.options.marginal_qubits.clone();
+            let positions: Vec<usize> = qubits
+                .iter()
+                .map(|qubit| lightcone.binary_search(qubit).unwrap())
+                .collect();
+            Marginals {
+                probabilities: final_state.marginal_probabilities(&positions),
+                qubits,
+                lightcone,
+            }
+        });
+        Ok(SimulationResult {
+            final_state,
+            schedule: self.schedule,
+            wall_time,
+            gate_counts: self.gate_counts,
+            peak_kets: self.peak_kets,
+            backend: match self.dense_after_gates {
+                Some(_) => Backend::Dense,
+                None => self.options.backend,
+            },
+            dense_after_gates: self.dense_after_gates,
+            pruned_probability,
+            metrics,
+            diagnostics,
+            marginals,
+            expectation,
+            sampled_expectation,
+            trajectory: self.trajectory,
+            profile: self.profile,
+            warnings,
+            // The register has been declared once the circuit is complete.
+            provenance: Provenance::new(self.options.seed, self.circuit_hasher.unwrap().finish()),
+        })
+    }
+
+    /// Counts a gate and applies it to the state, or holds it back for fusion.
+    fn apply_next(&mut self, gate: Gate, origin: Origin) -> io::Result<()> {
+        *self.gate_counts.entry(gate.name().to_string()).or_default() += 1;
+        self.apply_ready(gate, origin)
+    }
+
+    /// Applies a gate to the state, or holds it back for fusion.
+    fn apply_ready(&mut self, gate: Gate, origin: Origin) -> io::Result<()> {
+        // Only called once the state has been created.
+        let state = self.state.as_mut().unwrap();
+        let ready = match &mut self.fuser {
+            Some(fuser) => fuser.push_with_origin(gate, origin),
+            None => vec![(gate, origin)],
+        };
+        for layer in disjoint_layers(ready) {
+            apply_layer(state, &layer, &self.options, &mut self.profile)?;
+            self.peak_kets = self.peak_kets.max(state.num_kets());
+            let pruned = self.pruned_before_dense + state.pruned_probability();
+            // Layers are never empty.
+            let (gate, origin) = layer.last().unwrap();
+            let location = format!["after gate {} {}", gate.name(), location(origin)];
+            check_pruned_probability(&self.options, pruned, &location)?;
+        }
+        self.switch_to_dense_if_full()
+    }
+
+    /// Converts a sparse state to the dense backend once it holds enough kets, as set by
+    /// [`Options::dense_threshold`].
+    fn switch_to_dense_if_full(&mut self) -> io::Result<()> {
+        let (Some(threshold), Some(BackendState::Sparse(state))) =
+            (self.options.dense_threshold, &self.state)
+        else {
+            return Ok(());
+        };
+        let num_qubits = state.num_qubits();
+        if num_qubits > MAX_DENSE_QUBITS
+            || (state.len() as f64) < threshold * (1u64 << num_qubits) as f64
+        {
+            return Ok(());
+        }
+        self.pruned_before_dense = state.pruned_probability();
+        self.metrics_before_dense = state.metrics();
+        self.state = Some(BackendState::Dense(DenseState::from_state(state)?));
+        self.dense_after_gates = Some(self.gate_counts.values().sum());
+        Ok(())
+    }
+
+    /// Simulates the deferred gates in the backward lightcone of the marginal qubits, and
+    /// of the qubits the observable acts on, on a register of just the qubits they use.
+    /// Returns those qubits.
+    fn simulate_lightcone(&mut self, deferred: Vec<(Gate, Origin)>) -> io::Result<Vec<usize>> {
+        let mut outputs = self.options.marginal_qubits.clone();
+        if let Some(observable) = &self.options.observable {
+            outputs.extend(observable.support());
+        }
+        let outputs = &outputs;
+        let mask = lightcone_mask(deferred.iter().map(|(gate, _)| gate), outputs);
+        let live: Vec<(Gate, Origin)> = deferred
+            .into_iter()
+            .zip(mask)
+            .filter_map(|(gate, live)| live.then_some(gate))
+            .collect();
+        let lightcone = used_qubits(live.iter().map(|(gate, _)| gate), outputs);
+
+        let state = self.new_state(lightcone.len(), self.register_line.unwrap())?;
+        self.peak_kets = state.num_kets();
+        self.state = Some(state);
+        for (gate, origin) in live {
+            let gate = gate.remap(|qubit| lightcone.binary_search(&qubit).unwrap());
+            self.apply_next(gate, origin)?;
+        }
+        Ok(lightcone)
+    }
+
+    /// Creates the zero state for a register of `num_qubits` qubits.
+    fn new_state(&self, num_qubits: usize, line_number: usize) -> io::Result<BackendState> {
+        let backend = self.options.backend;
+        if num_qubits > backend.max_qubits() {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format![
+                    "The {backend:?} backend supports at most {} qubits on line {line_number}",
+                    backend.max_qubits()
+                ],
+            ));
+        }
+        Ok(match backend {
+            Backend::Sparse => {
+                let mut state = State::new(num_qubits);
+                state.add_or_insert(Ket::new_zero_ket(num_qubits));
+                state.set_canonical(self.options.canonical);
+                state.set_accumulation(self.options.accumulation);
+                state.set_tolerance(self.options.tolerance);
+                BackendState::Sparse(state)
+            }
+            Backend::Dense => {
+                let mut state = DenseState::new(num_qubits)?;
+                state.set_tolerance(self.options.tolerance);
+                BackendState::Dense(state)
+            }
+            Backend::Trie => {
+                let mut state = TrieState::new(num_qubits);
+                state.set_tolerance(self.options.tolerance);
+                BackendState::Trie(state)
+            }
+            Backend::File => {
+                let directory = self
+                    .options
+                    .scratch_dir
+                    .clone()
+                    .unwrap_or_else(env::temp_dir);
+                let mut state = FileBackedState::new(num_qubits, &directory, DEFAULT_CHUNK_QUBITS)?;
+                state.set_tolerance(self.options.tolerance);
+                BackendState::File(state)
+            }
+        })
+    }
+}
+
+/// Renumbers the qubits of each subsystem by their position in the simulated lightcone,
+/// returning an error if one of them was not simulated.
+fn lightcone_subsystems(
+    subsystems: &[Vec<usize>],
+    lightcone: &[usize],
+) -> io::Result<Vec<Vec<usize>>> {
+    subsystems
+        .iter()
+        .map(|subsystem| {
+            subsystem
+                .iter()
+                .map(|qubit| {
+                    lightcone.binary_search(qubit).map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format![
+                                "Qubit {qubit} in subsystem {subsystem:?} is outside the lightcone of the marginal qubits"
+                            ],
+                        )
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns a string as a quoted JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!["\\u{:04x}", c as u32]),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns the fields of an estimate as the members of a JSON object.
+fn json_estimate(estimate: &Estimate) -> String {
+    format![
+        r#""mean":{},"variance":{},"standard_error":{}"#,
+        json_number(estimate.mean),
+        json_number(estimate.variance),
+        json_number(estimate.standard_error)
+    ]
+}
+
+/// Returns a number as JSON, which has no representation for NaN or infinity.
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+/// Returns where a gate came from for error messages, such as `of 'h' on line 3`, or the
+/// label of a gate that is not in the source.
+fn location(origin: &Origin) -> String {
+    match origin.span {
+        Some(_) => format!["of {origin}"],
+        None => origin.label.clone(),
+    }
+}
+
+/// Applies a gate to the state, checking for non-finite amplitudes if requested.
+///
+/// `location` describes where the gate came from for error messages.
+fn apply_gate(
+    state: &mut BackendState,
+    gate: &Gate,
+    options: &Options,
+    location: &str,
+) -> io::Result<()> {
+    if !options.check_finite {
+        state.apply_gate(gate, options.parallelism)?;
+        update_kets_gauge(state, options);
+        return Ok(());
+    }
+
+    // Only the sparse backend can report the input ket, since the dense backends mix
+    // every amplitude the gate acts on. Its kets are checked before the gate is applied,
+    // so that finding the input needs no copy of the state.
+    if let BackendState::Sparse(sparse) = &*state {
+        let matrix = gate.single_qubit_matrix();
+        let source = sparse
+            .kets()
+            .filter(|ket| gate_output_is_non_finite(gate, matrix.as_ref(), ket))
+            .min();
+        if let Some(source) = source {
+            let mut outputs = Vec::new();
+            apply_gate_to_ket_into(gate, source.clone(), &mut |ket| outputs.push(ket));
+            // The filter only keeps kets with a non-finite output.
+            let ket = outputs.into_iter().find(|ket| !ket.is_finite()).unwrap();
+            return Err(non_finite_error(&ket, Some(source), gate, location));
+        }
+    }
+    state.apply_gate(gate, options.parallelism)?;
+    // Amplitudes that each stay finite can still overflow when they are summed.
+    if let Some(ket) = state.non_finite_ket()? {
+        return Err(non_finite_error(&ket, None, gate, location));
+    }
+    update_kets_gauge(state, options);
+    Ok(())
+}
+
+/// Sets [`Options::kets_gauge`] to the number of kets in the state, if there is a gauge.
+fn update_kets_gauge(state: &BackendState, options: &Options) {
+    if let Some(gauge) = &options.kets_gauge {
+        gauge.store(state.num_kets().unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+/// Applies a gate with [`apply_gate`], adding the time it takes to the profile if there is
+/// one.
+fn apply_profiled(
+    state: &mut BackendState,
+    gate: &Gate,
+    options: &Options,
+    location: &str,
+    profile: &mut Option<Profile>,
+) -> io::Result<()> {
+    let Some(profile) = profile else {
+        return apply_gate(state, gate, options, location);
+    };
+    let start = Instant::now();
+    apply_gate(state, gate, options, location)?;
+    profile.record(location, gate, start.elapsed());
+    Ok(())
+}
+
+/// Applies a layer of gates on disjoint qubits to the state in a single pass.
+///
+/// Profiles time each gate and finiteness checks name the gate that produced a
+/// non-finite amplitude, so with either of them the gates are applied one at a time with
+/// [`apply_profiled`] instead.
+fn apply_layer(
+    state: &mut BackendState,
+    layer: &[(Gate, Origin)],
+    options: &Options,
+    profile: &mut Option<Profile>,
+) -> io::Result<()> {
+    if layer.len() == 1 || options.check_finite || profile.is_some() {
+        for (gate, origin) in layer {
+            apply_profiled(state, gate, options, &location(origin), profile)?;
+        }
+        return Ok(());
+    }
+    let gates: Vec<Gate> = layer.iter().map(|(gate, _)| gate.clone()).collect();
+    state.apply_layer(&gates, options.parallelism)?;
+    update_kets_gauge(state, options);
+    Ok(())
+}
+
+/// Returns the error for a panic caught with [`Options::catch_panics`], with its message.
+fn internal_error(payload: Box<dyn Any + Send>) -> io::Error {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    };
+    io::Error::other(format!["Internal error: {message}"])
+}
+
+/// Returns an error if `pruned` is more than [`Options::max_pruned_probability`].
+fn check_pruned_probability(options: &Options, pruned: f64, location: &str) -> io::Result<()> {
+    match options.max_pruned_probability {
+        Some(max) if pruned > max => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format![
+                "Pruned amplitudes have lost {pruned:.3e} of the probability, more than the maximum of {max:e}, {location}"
+            ],
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns whether applying `gate`, whose matrix is `matrix` if it acts on a single qubit,
+/// to `ket` produces a ket with a non-finite amplitude, without building the kets.
+fn gate_output_is_non_finite(gate: &Gate, matrix: Option<&Matrix2>, ket: &Ket) -> bool {
+    match matrix {
+        Some(matrix) => {
+            let bit = ket.get(gate.qubits()[0]) as usize;
+            (0..2).any(|row| !(ket.amplitude * matrix[row][bit]).is_finite())
+        }
+        // CX only moves amplitudes.
+        None => !ket.is_finite(),
+    }
+}
+
+/// Returns the error for a non-finite amplitude in `ket` after `gate`, produced from the
+/// ket `source` if it is known.
+fn non_finite_error(ket: &Ket, source: Option<&Ket>, gate: &Gate, location: &str) -> io::Error {
+    let source = source.map_or(String::new(), |source| format![" from ket {source}"]);
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format![
+            "Non-finite amplitude in ket {ket}{source} after gate {} on qubits {:?} {location}",
+            gate.name(),
+            gate.qubits()
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::qasm::parser::{Operand, Parser, StatementKind};
+    use crate::quantum::observable::parse_observable;
+    use crate::quantum::register::QubitRef;
+    use crate::quantum::register::Register;
+    use num::Complex;
+    use std::iter;
+
+    /// Helper function to create a statement on the given line.
+    fn statement(kind: StatementKind, line: usize) -> io::Result<Statement> {
+        Ok(Statement { kind, line })
+    }
+
+    /// Tests that statements generated on the fly are executed as they are produced.
+    #[test]
+    fn test_run_streaming_generated_statements() {
+        let header = [
+            statement(StatementKind::Version("2.0".to_string()), 1),
+            statement(
+                StatementKind::QuantumRegister(Register {
+                    name: "q".to_string(),
+                    size: 2,
+                }),
+                2,
+            ),
+        ];
+        // An even number of X gates on each qubit, which is never held in memory at once.
+        let num_gates = 100_000;
+        let gates = (0..num_gates).map(|index| {
+            statement(
+                StatementKind::GateCall {
+                    name: "x".to_string(),
+                    parameters: Vec::new(),
+                    operands: vec![Operand {
+                        register: "q".to_string(),
+                        index: index % 2,
+                    }],
+                },
+                index + 3,
+            )
+        });
+
+        let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        simulator
+            .run_streaming(header.into_iter().chain(gates))
+            .unwrap();
+        let simulation = simulator.finish().unwrap();
+        assert_eq!(simulation.final_state.to_string(), "(1+0i)|00⟩");
+        assert_eq!(simulation.schedule.timeline(0).len(), num_gates / 2);
+    }
+
+    /// Tests that statements are executed before later statements are parsed.
+    #[test]
+    fn test_run_streaming_stops_at_first_error() {
+        let source = "OPENQASM 2.0;\nqreg q[1];\nx q[0];\nx q[1];\nnot valid";
+        let mut parsed = 0;
+        let statements = Parser::new(source.as_bytes()).inspect(|_| parsed += 1);
+
+        let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        let error = simulator.run_streaming(statements).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown qubit 'q[1]' on line 4");
+        assert_eq!(parsed, 4);
+        assert_eq!(simulator.version(), Some("2.0"));
+    }
+
+    /// Tests that the result records gate counts, the peak number of kets and the
+    /// metrics of the sparse backend.
+    #[test]
+    fn test_result_metadata() {
+        let source = "OPENQASM 2.0;\nqreg q[2];\nh q[0];\nh q[1];\nh q[1];\ncx q[0], q[1];";
+        let simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+
+        assert_eq!(result.final_state.len(), 2);
+        assert_eq!(result.peak_kets, Some(4));
+        assert_eq!(
+            result.gate_counts,
+            BTreeMap::from([("cx".to_string(), 1), ("h".to_string(), 3)])
+        );
+        assert!(result
+            .to_json()
+            .starts_with(r#"{"backend":"sparse","num_qubits":2,"#));
+        assert!(result
+            .to_json()
+            .contains(r#""gate_counts":{"cx":1,"h":3},"peak_kets":4,"#));
+
+        // The second H on qubit 1 merges two of the four basis states and prunes the rest.
+        let metrics = Metrics {
+            branches: 7,
+            collisions: 4,
+            kets_merged: 2,
+            kets_pruned: 2,
+        };
+        assert_eq!(result.metrics, metrics);
+        assert!(result.to_json().contains(
+            r#""metrics":{"branches":7,"collisions":4,"kets_merged":2,"kets_pruned":2}"#
+        ));
+    }
+
+    /// Tests that the trajectory is recorded at the requested interval and after the last
+    /// instruction, and is rejected along with marginal qubits.
+    #[test]
+    fn test_trajectory() {
+        let source = "OPENQASM 2.0;\nqreg q[2];\ngate flip a, b { x a; cx a, b; }\n\
+                      flip q[0], q[1];\ndelay[5ns] q[0];\nh q[0];\nx q[1];\nh q[0];";
+        let options = Options {
+            trajectory_qubits: vec![1, 0],
+            trajectory_every: 2,
+            fuse: true,
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options.clone());
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+        let trajectory = result.trajectory.unwrap();
+        let expected = [
+            (0, [1.0, 1.0]),
+            (2, [-1.0, -1.0]),
+            (4, [1.0, 0.0]),
+            (5, [1.0, -1.0]),
+        ];
+        assert_eq!(trajectory.points.len(), expected.len());
+        for ((instruction, expectations), (expected_instruction, expected)) in
+            trajectory.points.iter().zip(expected)
+        {
+            assert_eq!(*instruction, expected_instruction);
+            for (expectation, expected) in expectations.iter().zip(expected) {
+                assert!((expectation - expected).abs() < 1e-12, "{trajectory:?}");
+            }
+        }
+
+        let options = Options {
+            marginal_qubits: vec![0],
+            ..options
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let error = simulator.run(Parser::new(source.as_bytes())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "A trajectory cannot be recorded when only the lightcone of marginal qubits is simulated"
+        );
+    }
+
+    /// Tests that the layers of fused gates applied in a single pass each give the same
+    /// state as applying the gates one at a time.
+    #[test]
+    fn test_fused_layers() {
+        let source = "OPENQASM 2.0;\nqreg q[4];\nh q[0];\nh q[1];\nt q[1];\nh q[2];\nx q[3];\n\
+                      cx q[0], q[3];\nh q[1];\nt q[2];\ncx q[1], q[2];\nh q[0];\nh q[3];";
+        let run = |fuse| {
+            let options = Options {
+                fuse,
+                parallelism: Parallelism {
+                    threads: 2,
+                    min_chunk_size: 1,
+                },
+                ..Options::default()
+            };
+            let simulator = Simulator::new(GateDefinitions::new(), options);
+            simulator.run(Parser::new(source.as_bytes())).unwrap()
+        };
+        let fused = run(true);
+        let unfused = run(false);
+        assert_eq!(
+            fused.final_state.to_string(),
+            unfused.final_state.to_string()
+        );
+        assert_eq!(fused.gate_counts, unfused.gate_counts);
+    }
+
+    /// Tests that the profile times each gate in its region and instruction, with gates
+    /// held back for fusion in the instructions they came from.
+    #[test]
+    fn test_profile() {
+        let source = "OPENQASM 2.0;\nqreg q[2];\ngate flip a, b { x a; cx a, b; }\n\
+                      flip q[0], q[1];\nh q[0];\nbarrier q[0], q[1];\nflip q[1], q[0];\nh q[1];\nt q[1];";
+        let options = Options {
+            profile: true,
+            fuse: true,
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+        let stacks: Vec<&str> = result
+            .profile
+            .as_ref()
+            .unwrap()
+            .stacks()
+            .iter()
+            .map(|(stack, _)| stack.as_str())
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                "region 0;'flip' on line 4;x",
+                "region 0;'flip' on line 4;cx",
+                "region 1;'flip' on line 7;x",
+                "region 1;'h' on line 5;h",
+                "region 1;'flip' on line 7;cx",
+                "region 1;'h+t' on lines 8-9;unitary",
+            ]
+        );
+    }
+
+    /// Tests that a non-finite amplitude stops the simulation with the gate and the ket
+    /// it came from, leaving the state as it was before the gate.
+    #[test]
+    fn test_check_finite() {
+        let options = Options {
+            check_finite: true,
+            ..Options::default()
+        };
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        simulator
+            .append_qasm("OPENQASM 2.0;\nqreg q[2];\nh q[0];\nx q[1];")
+            .unwrap();
+        let one = Complex::new(1.0, 0.0);
+        let gate = Gate::Unitary {
+            target: QubitRef(0),
+            matrix: [[one, one], [Complex::new(f64::INFINITY, 0.0), one]],
+        };
+        let error = simulator
+            .apply_with_origin(gate, Origin::new("blow_up", 5))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Non-finite amplitude in ket (inf+NaNi)|11⟩ from ket (0.707+0i)|10⟩ after gate unitary on qubits [0] of 'blow_up' on line 5"
+        );
+        assert_eq!(
+            simulator.state().unwrap().to_string(),
+            "(0.707+0i)|10⟩ + (0.707+0i)|11⟩"
+        );
+    }
+
+    /// Tests that the gauge follows the number of kets after every gate.
+    #[test]
+    fn test_kets_gauge() {
+        let gauge = Arc::new(AtomicUsize::new(0));
+        let options = Options {
+            kets_gauge: Some(Arc::clone(&gauge)),
+            ..Options::default()
+        };
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        simulator
+            .append_qasm("OPENQASM 2.0;\nqreg q[2];\nh q[0];\nh q[1];")
+            .unwrap();
+        assert_eq!(gauge.load(Ordering::Relaxed), 4);
+        simulator
+            .apply(Gate::H {
+                target: QubitRef(1),
+            })
+            .unwrap();
+        assert_eq!(gauge.load(Ordering::Relaxed), 2);
+    }
+
+    /// Tests that delays too long for the schedule and invalid gates are not bugs, and
+    /// that with panics caught a panic becomes an error after which the simulator refuses
+    /// to continue.
+    #[test]
+    fn test_catch_panics() {
+        let options = Options {
+            catch_panics: true,
+            ..Options::default()
+        };
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        simulator
+            .append_qasm(
+                "OPENQASM 2.0;\nqreg q[2];\ndelay[1e19s] q[0];\ndelay[1e19s] q[0];\nh q[0];",
+            )
+            .unwrap();
+        let error = simulator
+            .apply(Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(1),
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Qubit 1 is used more than once by gate cx"
+        );
+
+        let error = simulator
+            .guarded(|_| -> io::Result<()> { panic!("a bug") })
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Internal error: a bug");
+        let error = simulator
+            .apply(Gate::H {
+                target: QubitRef(0),
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The simulator cannot be used after an internal error"
+        );
+        assert!(simulator.finish().is_err());
+    }
+
+    /// Tests that a rejected register declaration followed by more statements, and a
+    /// register too large to allocate with the dense backend, are errors rather than
+    /// internal errors.
+    #[test]
+    fn test_rejected_inputs_are_not_bugs() {
+        let options = Options {
+            catch_panics: true,
+            marginal_qubits: vec![3],
+            ..Options::default()
+        };
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        let error = simulator
+            .append_qasm("OPENQASM 2.0;\nqreg q[2];")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Unknown marginal qubit 3 on line 2");
+        for source in ["h q[0];", "delay[5ns] q[0];", "barrier q[0];"] {
+            let error = simulator.append_qasm(source).unwrap_err();
+            assert_eq!(error.to_string(), "No quantum register was defined");
+        }
+        assert!(simulator
+            .apply(Gate::H {
+                target: QubitRef(0)
+            })
+            .is_err());
+        let error = simulator.finish().unwrap_err();
+        assert_eq!(error.to_string(), "No quantum register was defined");
+
+        let options = Options {
+            catch_panics: true,
+            backend: Backend::Dense,
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let source = "OPENQASM 2.0;\nqreg q[40];\nh q[0];";
+        let error = simulator.run(Parser::new(source.as_bytes())).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+        assert_eq!(
+            error.to_string(),
+            "A state with 40 qubits is too large to store"
+        );
+    }
+
+    /// Tests that the provenance records the seed and the canonical hash of the circuit,
+    /// whether it was parsed, compiled or built up gate by gate.
+    #[test]
+    fn test_provenance() {
+        let source = "OPENQASM 2.0;\nqreg q[2];\nh q[0];\ncx q[0], q[1];\ndelay[5ns] q[1];";
+        let options = Options {
+            seed: 42,
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options.clone());
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+        let circuit =
+            CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+                .unwrap();
+        let hash = circuit.canonical_hash();
+        assert_eq!(result.provenance, Provenance::new(42, hash));
+        assert_eq!(result.provenance.version, env!("CARGO_PKG_VERSION"));
+        assert!(result.to_json().contains(&format![
+            r#""provenance":{{"version":"{}","seed":42,"circuit_hash":"{hash:032x}","os":"#,
+            env!("CARGO_PKG_VERSION")
+        ]));
+
+        let simulator = Simulator::new(GateDefinitions::new(), options.clone());
+        assert_eq!(
+            simulator
+                .run_compiled(circuit)
+                .unwrap()
+                .provenance
+                .circuit_hash,
+            hash
+        );
+
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        simulator
+            .append_qasm("OPENQASM 3.0;\nqubit[2] r;\nh r[0];")
+            .unwrap();
+        simulator
+            .apply(Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            })
+            .unwrap();
+        simulator.append_qasm("delay[5ns] r[1];").unwrap();
+        assert_eq!(simulator.finish().unwrap().provenance.circuit_hash, hash);
+    }
+
+    /// Tests that ignored includes and lost probability are reported as warnings, and
+    /// are errors when warnings are treated as errors.
+    #[test]
+    fn test_warnings() {
+        let source = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\ninclude \"extra.inc\";\nqreg q[1];";
+        let simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+        assert_eq!(
+            result.warnings,
+            vec![Warning::new(
+                WarningKind::IgnoredStatement,
+                "The include of 'extra.inc' on line 3 is ignored".to_string()
+            )]
+        );
+        assert!(result.to_json().contains(
+            r#""warnings":[{"kind":"ignored_statement","message":"The include of 'extra.inc' on line 3 is ignored"}]"#
+        ));
+
+        // A gate that is not quite unitary loses probability.
+        let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        simulator.append_qasm("OPENQASM 2.0;\nqreg q[1];").unwrap();
+        let shrink = Gate::Unitary {
+            target: QubitRef(0),
+            matrix: [
+                [Complex::new(0.999, 0.0), Complex::new(0.0, 0.0)],
+                [Complex::new(0.0, 0.0), Complex::new(0.999, 0.0)],
+            ],
+        };
+        simulator.apply(shrink.clone()).unwrap();
+        let result = simulator.finish().unwrap();
+        assert_eq!(result.warnings.len(), 1);
+        assert_eq!(result.warnings[0].kind, WarningKind::LostProbability);
+        assert_eq!(
+            result.warnings[0].message,
+            "The final state has lost 1.999e-3 of its probability to pruned amplitudes and rounding"
+        );
+
+        let options = Options {
+            fail_on_warning: true,
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options.clone());
+        let error = simulator.run(Parser::new(source.as_bytes())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The include of 'extra.inc' on line 3 is ignored, and warnings are treated as errors"
+        );
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        simulator
+            .append_qasm("OPENQASM 2.0;\nqreg q[1];\nh q[0];")
+            .unwrap();
+        assert!(simulator.finish().unwrap().warnings.is_empty());
+    }
+
+    /// Tests that the probability of pruned amplitudes is reported by every backend, and
+    /// stops the simulation once it exceeds the maximum.
+    #[test]
+    fn test_pruned_probability() {
+        // The amplitude of |1⟩ is about 5e-7, which the sparse backends prune.
+        let source = "OPENQASM 2.0;\nqreg q[1];\nh q[0];\nrz(1e-6) q[0];\nh q[0];";
+        for backend in [
+            Backend::Sparse,
+            Backend::Dense,
+            Backend::File,
+            Backend::Trie,
+        ] {
+            let options = Options {
+                backend,
+                ..Options::default()
+            };
+            let simulator = Simulator::new(GateDefinitions::new(), options);
+            let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+            assert_eq!(result.final_state.len(), 1);
+            assert!((result.pruned_probability - 2.5e-13).abs() < 1e-18);
+        }
+
+        let options = Options {
+            max_pruned_probability: Some(1e-13),
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options.clone());
+        let error = simulator.run(Parser::new(source.as_bytes())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Pruned amplitudes have lost 2.500e-13 of the probability, more than the maximum of 1e-13, after gate h of 'h' on line 5"
+        );
+        let options = Options {
+            backend: Backend::Dense,
+            ..options
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let error = simulator.run(Parser::new(source.as_bytes())).unwrap_err();
+        assert!(error.to_string().ends_with("at the end of the file"));
+    }
+
+    /// Tests that the tolerance set on the simulator is used by every backend, so that
+    /// an exact tolerance keeps the small amplitude the default one prunes.
+    #[test]
+    fn test_tolerance() {
+        let source = "OPENQASM 2.0;\nqreg q[1];\nh q[0];\nrz(1e-6) q[0];\nh q[0];";
+        for backend in [
+            Backend::Sparse,
+            Backend::Dense,
+            Backend::File,
+            Backend::Trie,
+        ] {
+            let options = Options {
+                backend,
+                tolerance: Tolerance::EXACT,
+                ..Options::default()
+            };
+            let simulator = Simulator::new(GateDefinitions::new(), options);
+            let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+            assert_eq!(result.final_state.len(), 2, "{backend:?}");
+            assert_eq!(result.pruned_probability, 0.0);
+            assert_eq!(result.final_state.tolerance(), Tolerance::EXACT);
+        }
+    }
+
+    /// Tests that diagnostics are computed when requested and included in the JSON.
+    #[test]
+    fn test_result_diagnostics() {
+        let source = "OPENQASM 2.0;\nqreg q[3];\nh q[0];\ncx q[0], q[1];";
+        let options = Options {
+            diagnostics: true,
+            subsystems: vec![vec![0], vec![2]],
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+
+        let diagnostics = result.diagnostics.as_ref().unwrap();
+        assert_eq!(diagnostics.nonzero_amplitudes, 2);
+        assert!((diagnostics.purities[0].1 - 0.5).abs() < 1e-12);
+        assert!((diagnostics.purities[1].1 - 1.0).abs() < 1e-12);
+        assert!(result
+            .to_json()
+            .contains(r#""diagnostics":{"nonzero_amplitudes":2,"#));
+    }
+
+    /// Tests that only the lightcone of the marginal qubits is simulated.
+    #[test]
+    fn test_lightcone_marginals() {
+        let source =
+            "OPENQASM 2.0;\nqreg q[4];\nh q[3];\ncx q[3], q[1];\nx q[2];\nh q[0];\nt q[3];";
+        let options = Options {
+            marginal_qubits: vec![1],
+            subsystems: vec![vec![3]],
+            diagnostics: true,
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+
+        let marginals = result.marginals.as_ref().unwrap();
+        assert_eq!(marginals.lightcone, vec![1, 3]);
+        assert_eq!(marginals.probabilities.len(), 2);
+        for probability in marginals.probabilities.values() {
+            assert!((probability - 0.5).abs() < 1e-12);
+        }
+        assert_eq!(result.final_state.num_qubits(), 2);
+        assert_eq!(
+            result.gate_counts,
+            BTreeMap::from([("cx".to_string(), 1), ("h".to_string(), 1)])
+        );
+        let distribution = result.distribution();
+        assert_eq!(distribution.keys().collect::<Vec<_>>(), vec!["0", "1"]);
+        assert!((distribution["1"] - 0.5).abs() < 1e-12);
+        assert!((result.diagnostics.unwrap().purities[0].1 - 0.5).abs() < 1e-12);
+
+        let options = Options {
+            marginal_qubits: vec![4],
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let error = simulator.run(Parser::new(source.as_bytes())).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown marginal qubit 4 on line 2");
+    }
+
+    /// Tests that the observable is evaluated on the final state, including when only a
+    /// lightcone is simulated.
+    #[test]
+    fn test_observable_expectation() {
+        let source = "OPENQASM 2.0;\nqreg q[3];\nh q[0];\ncx q[0], q[1];\nx q[2];";
+        let observable = parse_observable("2 IZZ\n1 ZII\n0.5 IXX").unwrap();
+        for marginal_qubits in [vec![], vec![0]] {
+            let options = Options {
+                observable: Some(observable.clone()),
+                marginal_qubits,
+                ..Options::default()
+            };
+            let simulator = Simulator::new(GateDefinitions::new(), options);
+            let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+            let expectation = result.expectation.as_ref().unwrap();
+            assert!((expectation.value - 1.5).abs() < 1e-12);
+            assert!(result
+                .to_json()
+                .contains(r#""terms":[{"coefficient":2,"pauli":"IZZ","expectation":1"#));
+            assert!(result.sampled_expectation.is_none());
+        }
+
+        let options = Options {
+            observable: Some(parse_observable("1 ZII\n1 XII").unwrap()),
+            shots: Some(100),
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+        let sampled = result.sampled_expectation.as_ref().unwrap();
+        // Qubit 2 is in |1⟩, so only measuring X has random outcomes.
+        assert_eq!(sampled.terms[0].1.mean, -1.0);
+        assert_eq!(sampled.terms[0].1.variance, 0.0);
+        assert!(sampled.terms[1].1.variance > 0.9);
+        assert!(sampled.value.standard_error > 0.0);
+        assert!(result
+            .to_json()
+            .contains(r#""sampled_expectation":{"shots":100,"groups":2,"mean":"#));
+
+        let options = Options {
+            observable: Some(parse_observable("1 ZZ").unwrap()),
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let error = simulator.run(Parser::new(source.as_bytes())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The observable acts on 2 qubits but the register has 3 on line 2"
+        );
+    }
+
+    /// Tests that a compiled circuit simulates to the same state as its source.
+    #[test]
+    fn test_run_compiled() {
+        let source =
+            "OPENQASM 2.0;\nqreg q[3];\ngate ccz a, b, c { cx b, c; tdg c; cx a, c; t c; }\n\
+            h q[0];\nh q[2];\nccz q[0], q[1], q[2];\ndelay[10ns] q[1];\nrz(0.3) q[1];";
+        let circuit =
+            CompiledCircuit::compile(Parser::new(source.as_bytes()), GateDefinitions::new())
+                .unwrap();
+        let options = Options {
+            fuse: true,
+            ..Options::default()
+        };
+        let compiled = Simulator::new(GateDefinitions::new(), options.clone())
+            .run_compiled(circuit)
+            .unwrap();
+        let parsed = Simulator::new(GateDefinitions::new(), options)
+            .run(Parser::new(source.as_bytes()))
+            .unwrap();
+        assert_eq!(compiled.final_state, parsed.final_state);
+        assert_eq!(compiled.gate_counts, parsed.gate_counts);
+        assert_eq!(compiled.schedule.to_string(), parsed.schedule.to_string());
+    }
+
+    /// Tests extending a simulation with gates and QASM, and reading the state on the way.
+    #[test]
+    fn test_incremental_simulation() {
+        let options = Options {
+            fuse: true,
+            ..Options::default()
+        };
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        let error = simulator
+            .apply(Gate::H {
+                target: QubitRef(0),
+            })
+            .unwrap_err();
+        assert_eq!(error.to_string(), "No quantum register was defined");
+
+        simulator
+            .append_qasm("OPENQASM 2.0;\nqreg q[2];\nx q[1];")
+            .unwrap();
+        // The pending fused gate is applied before the state is read.
+        assert_eq!(simulator.state().unwrap().to_string(), "(1+0i)|10⟩");
+        simulator
+            .apply(Gate::H {
+                target: QubitRef(1),
+            })
+            .unwrap();
+        let error = simulator
+            .apply(Gate::X {
+                target: QubitRef(2),
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Qubit 2 is outside the register of 2 qubits"
+        );
+        simulator.append_qasm("h q[1];\ncx q[1], q[0];").unwrap();
+
+        let simulation = simulator.finish().unwrap();
+        assert_eq!(simulation.final_state.to_string(), "(1+0i)|11⟩");
+        assert_eq!(simulation.gate_counts["h"], 2);
+
+        let options = Options {
+            marginal_qubits: vec![0],
+            ..Options::default()
+        };
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        simulator.append_qasm("OPENQASM 2.0;\nqreg q[1];").unwrap();
+        assert!(simulator.state().is_err());
+    }
+
+    /// Tests that a rejected register declaration leaves the register undeclared, so that
+    /// later statements fail instead of panicking and the register can be declared again.
+    #[test]
+    fn test_rejected_register() {
+        let options = Options {
+            trajectory_qubits: vec![5],
+            ..Options::default()
+        };
+        let mut simulator = Simulator::new(GateDefinitions::new(), options);
+        let error = simulator
+            .append_qasm("OPENQASM 2.0;\nqreg q[2];")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Unknown trajectory qubit 5 on line 2");
+        let error = simulator.append_qasm("h q[0];").unwrap_err();
+        assert_eq!(error.to_string(), "No quantum register was defined");
+        let error = simulator
+            .apply(Gate::H {
+                target: QubitRef(0),
+            })
+            .unwrap_err();
+        assert_eq!(error.to_string(), "No quantum register was defined");
+        assert!(simulator.state().is_err());
+
+        simulator.append_qasm("qreg q[6];\nx q[5];").unwrap();
+        let simulation = simulator.finish().unwrap();
+        assert_eq!(simulation.final_state.to_string(), "(1+0i)|100000⟩");
+    }
+
+    /// Tests that the sparse state switches to the dense backend once it is full enough.
+    #[test]
+    fn test_dense_threshold() {
+        let source = "OPENQASM 2.0;\nqreg q[3];\nh q[0];\nh q[1];\nt q[1];\nh q[2];";
+        let options = Options {
+            dense_threshold: Some(0.5),
+            ..Options::default()
+        };
+        let simulator = Simulator::new(GateDefinitions::new(), options);
+        let result = simulator.run(Parser::new(source.as_bytes())).unwrap();
+        assert_eq!(result.backend, Backend::Dense);
+        assert_eq!(result.dense_after_gates, Some(2));
+        assert_eq!(result.peak_kets, Some(4));
+        assert_eq!(result.metrics.branches, 3);
+        assert!(result
+            .to_json()
+            .starts_with(r#"{"backend":"dense","dense_after_gates":2,"#));
+
+        let sparse = Simulator::new(GateDefinitions::new(), Options::default())
+            .run(Parser::new(source.as_bytes()))
+            .unwrap();
+        assert_eq!(
+            result.final_state.to_string(),
+            sparse.final_state.to_string()
+        );
+        assert_eq!(sparse.dense_after_gates, None);
+    }
+
+    /// Tests that JSON strings are escaped.
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\u000a""#);
+        assert_eq!(json_number(f64::NAN), "null");
+    }
+
+    /// Tests that a missing header or register is reported.
+    #[test]
+    fn test_invalid_programs() {
+        let simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        assert_eq!(
+            simulator.finish().unwrap_err().to_string(),
+            "Invalid header"
+        );
+
+        let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        let error = simulator
+            .run_streaming(iter::once(statement(StatementKind::Include("a".into()), 1)))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid header");
+
+        let mut simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        simulator
+            .run_streaming(Parser::new("OPENQASM 2.0;".as_bytes()))
+            .unwrap();
+        assert_eq!(
+            simulator.finish().unwrap_err().to_string(),
+            "No quantum register was defined"
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+
+/// The kinds of problem that are reported as warnings rather than errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind {
+    /// A statement that was read but has no effect, such as the include of a file other
+    /// than the standard gate library.
+    IgnoredStatement,
+    /// The final state has lost more of its probability, to pruned amplitudes and
+    /// rounding, than [`Options::tolerance`](crate::qasm::simulator::Options::tolerance)
+    /// allows, so the result is only an approximation.
+    LostProbability,
+}
+
+impl WarningKind {
+    /// Returns the name of this kind in the JSON result.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::IgnoredStatement => "ignored_statement",
+            WarningKind::LostProbability => "lost_probability",
+        }
+    }
+}
+
+/// Something that may make the result differ from what the circuit intended, without
+/// stopping the simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+impl Warning {
+    /// Creates a new `Warning` of the given kind.
+    pub fn new(kind: WarningKind, message: String) -> Self {
+        Self { kind, message }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
@@ -0,0 +1,412 @@
+use crate::gates::gate::Gate;
+use crate::qasm::expression::{BinaryOperator, Expression};
+use crate::qasm::parser::{Operand, Statement, StatementKind};
+use std::fmt::Write;
+use std::io;
+
+/// Words that are reserved in OpenQASM 3.0 but were free to use as names in 2.0.
+const QASM3_KEYWORDS: [&str; 41] = [
+    "angle",
+    "array",
+    "bit",
+    "bool",
+    "box",
+    "break",
+    "case",
+    "complex",
+    "const",
+    "continue",
+    "ctrl",
+    "def",
+    "defcal",
+    "default",
+    "duration",
+    "durationof",
+    "else",
+    "end",
+    "euler",
+    "extern",
+    "false",
+    "float",
+    "for",
+    "gphase",
+    "if",
+    "im",
+    "in",
+    "input",
+    "int",
+    "inv",
+    "let",
+    "measure",
+    "negctrl",
+    "output",
+    "pow",
+    "qubit",
+    "reset",
+    "return",
+    "stretch",
+    "switch",
+    "true",
+];
+
+/// Writes a circuit of built in gates on a single register `q` as an OpenQASM 2.0
+/// program that the parser can read back.
+///
+/// Returns an error for gates with no OpenQASM form, such as fused unitaries.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::qasm::writer::write_qasm;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let gates = [
+///     Gate::H { target: QubitRef(0) },
+///     Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+/// ];
+/// let program = write_qasm(2, &gates).unwrap();
+/// assert_eq!(program, "OPENQASM 2.0;\nqreg q[2];\nh q[0];\ncx q[0], q[1];\n");
+/// ```
+pub fn write_qasm(num_qubits: usize, gates: &[Gate]) -> io::Result<String> {
+    let mut program = format!["OPENQASM 2.0;\nqreg q[{num_qubits}];\n"];
+    for gate in gates {
+        writeln!(program, "{}", write_gate(gate)?).unwrap();
+    }
+    Ok(program)
+}
+
+/// Writes a built in gate on the register `q` as an OpenQASM statement.
+///
+/// Returns an error for gates with no OpenQASM form, such as fused unitaries.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::qasm::writer::write_gate;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let statement = write_gate(&Gate::RZ { target: QubitRef(2), theta: 0.5 }).unwrap();
+/// assert_eq!(statement, "rz(0.5) q[2];");
+/// ```
+pub fn write_gate(gate: &Gate) -> io::Result<String> {
+    let operands: Vec<String> = gate
+        .qubits()
+        .iter()
+        .map(|qubit| format!["q[{qubit}]"])
+        .collect();
+    let operands = operands.join(", ");
+    match gate {
+        // Enough digits to read back the same angle.
+        Gate::RZ { theta, .. } => Ok(format!["rz({theta:?}) {operands};"]),
+        Gate::Unitary { target, .. } => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format![
+                "The unitary gate on qubit {} has no OpenQASM form",
+                target.index()
+            ],
+        )),
+        _ => Ok(format!["{} {operands};", gate.name()]),
+    }
+}
+
+/// Upgrades a parsed OpenQASM 2.0 program to OpenQASM 3.0.
+///
+/// Register declarations become `qubit` and `bit` declarations, `qelib1.inc` becomes
+/// `stdgates.inc`, which defines the same gates under the same names, and powers are
+/// written with `**`. Gate definitions and calls are otherwise valid OpenQASM 3.0 as
+/// they are. The built in gates of the simulator need no include, so `stdgates.inc` is
+/// also included if the program calls one without including `qelib1.inc`. The parser
+/// has no measure, reset or `if` statements, so programs that use them fail to parse
+/// rather than being upgraded.
+///
+/// Returns an error for opaque declarations, which have no OpenQASM 3.0 form, and for
+/// names that are keywords in OpenQASM 3.0.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::parser::Parser;
+/// use quantum_simulator::qasm::writer::upgrade_qasm;
+///
+/// let source = "OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[2];\nrz(pi^2) q[1];";
+/// assert_eq!(
+///     upgrade_qasm(Parser::new(source.as_bytes())).unwrap(),
+///     "OPENQASM 3.0;\ninclude \"stdgates.inc\";\nqubit[2] q;\nrz(pi ** 2) q[1];\n"
+/// );
+/// ```
+pub fn upgrade_qasm(
+    statements: impl IntoIterator<Item = io::Result<Statement>>,
+) -> io::Result<String> {
+    let mut includes_standard_gates = false;
+    let mut calls_builtin = false;
+    let mut body = String::new();
+    for statement in statements {
+        let Statement { kind, line } = statement?;
+        let check_name = |name: &str| match QASM3_KEYWORDS.contains(&name) {
+            true => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!["The name '{name}' on line {line} is a keyword in OpenQASM 3.0"],
+            )),
+            false => Ok(()),
+        };
+        match kind {
+            StatementKind::Version(_) => {}
+            StatementKind::Include(path) if path == "qelib1.inc" || path == "stdgates.inc" => {
+                if !includes_standard_gates {
+                    writeln!(body, "include \"stdgates.inc\";").unwrap();
+                }
+                includes_standard_gates = true;
+            }
+            StatementKind::Include(path) => writeln!(body, "include \"{path}\";").unwrap(),
+            StatementKind::QuantumRegister(register) => {
+                check_name(&register.name)?;
+                writeln!(body, "qubit[{}] {};", register.size, register.name).unwrap();
+            }
+            StatementKind::ClassicalRegister(register) => {
+                check_name(&register.name)?;
+                writeln!(body, "bit[{}] {};", register.size, register.name).unwrap();
+            }
+            StatementKind::GateDefinition(definition) => {
+                let names = definition.parameters.iter().chain(&definition.qubits);
+                std::iter::once(&definition.name)
+                    .chain(names)
+                    .try_for_each(|name| check_name(name))?;
+                write!(body, "gate {}", definition.name).unwrap();
+                if !definition.parameters.is_empty() {
+                    write!(body, "({})", definition.parameters.join(", ")).unwrap();
+                }
+                writeln!(body, " {} {{", definition.qubits.join(", ")).unwrap();
+                for call in &definition.body {
+                    calls_builtin |= Gate::builtin_signature(&call.name).is_some();
+                    writeln!(
+                        body,
+                        "    {}{} {};",
+                        call.name,
+                        write_parameters(&call.parameters),
+                        call.operands.join(", ")
+                    )
+                    .unwrap();
+                }
+                writeln!(body, "}}").unwrap();
+            }
+            StatementKind::OpaqueDeclaration(declaration) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format![
+                        "The opaque gate '{}' on line {line} has no OpenQASM 3.0 form",
+                        declaration.name
+                    ],
+                ))
+            }
+            StatementKind::GateCall {
+                name,
+                parameters,
+                operands,
+            } => {
+                check_name(&name)?;
+                calls_builtin |= Gate::builtin_signature(&name).is_some();
+                writeln!(
+                    body,
+                    "{name}{} {};",
+                    write_parameters(&parameters),
+                    write_operands(&operands)
+                )
+                .unwrap();
+            }
+            StatementKind::Delay { duration, operands } => writeln!(
+                body,
+                "delay[{}ns] {};",
+                duration.as_nanos(),
+                write_operands(&operands)
+            )
+            .unwrap(),
+        }
+    }
+
+    let mut program = "OPENQASM 3.0;\n".to_string();
+    if calls_builtin && !includes_standard_gates {
+        program.push_str("include \"stdgates.inc\";\n");
+    }
+    program.push_str(&body);
+    Ok(program)
+}
+
+/// Writes the parenthesised parameters of a gate call, or nothing if there are none.
+fn write_parameters(parameters: &[Expression]) -> String {
+    if parameters.is_empty() {
+        return String::new();
+    }
+    let parameters: Vec<String> = parameters.iter().map(write_expression).collect();
+    format!["({})", parameters.join(", ")]
+}
+
+fn write_operands(operands: &[Operand]) -> String {
+    let operands: Vec<String> = operands
+        .iter()
+        .map(|operand| format!["{}[{}]", operand.register, operand.index])
+        .collect();
+    operands.join(", ")
+}
+
+/// Writes an expression in OpenQASM 3.0 syntax, parenthesising nested binary operations
+/// so that they keep their grouping.
+fn write_expression(expression: &Expression) -> String {
+    // A negation binds more tightly than every binary operator except a power, so it
+    // only needs parentheses as the base of a power or inside another negation.
+    let write_operand = |operand: &Expression, in_power: bool| match operand {
+        Expression::Binary { .. } => format!["({})", write_expression(operand)],
+        Expression::Negate(_) if in_power => format!["({})", write_expression(operand)],
+        _ => write_expression(operand),
+    };
+    match expression {
+        Expression::Number(value) => value.to_string(),
+        Expression::Pi => "pi".to_string(),
+        Expression::Parameter(name) => name.clone(),
+        Expression::Negate(operand) => format!["-{}", write_operand(operand, true)],
+        Expression::Binary { operator, lhs, rhs } => {
+            let symbol = match operator {
+                BinaryOperator::Add => "+",
+                BinaryOperator::Subtract => "-",
+                BinaryOperator::Multiply => "*",
+                BinaryOperator::Divide => "/",
+                BinaryOperator::Power => "**",
+            };
+            let in_power = *operator == BinaryOperator::Power;
+            format![
+                "{} {symbol} {}",
+                write_operand(lhs, in_power),
+                write_operand(rhs, false)
+            ]
+        }
+        Expression::Call { function, argument } => {
+            format!["{}({})", function.name(), write_expression(argument)]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::qasm::definitions::GateDefinitions;
+    use crate::qasm::lowering::{Lowering, Operation};
+    use crate::qasm::parser::Parser;
+    use crate::quantum::register::QubitRef;
+    use num::Complex;
+
+    /// Tests that written programs parse back into the same gates.
+    #[test]
+    fn test_round_trip() {
+        let gates = vec![
+            Gate::X {
+                target: QubitRef(2),
+            },
+            Gate::TDgr {
+                target: QubitRef(0),
+            },
+            Gate::RZ {
+                target: QubitRef(1),
+                theta: 0.1 + 0.2,
+            },
+            Gate::CX {
+                control: QubitRef(2),
+                target: QubitRef(0),
+            },
+        ];
+        let program = write_qasm(3, &gates).unwrap();
+
+        let mut lowering = Lowering::new(GateDefinitions::new());
+        let mut parsed = Vec::new();
+        for statement in Parser::new(program.as_bytes()) {
+            if let Some(Operation::GateCall { gates, .. }) =
+                lowering.lower(statement.unwrap()).unwrap()
+            {
+                parsed.extend(gates);
+            }
+        }
+        assert_eq!(parsed, gates);
+        // Writing the parsed circuit again gives the same program, byte for byte.
+        assert_eq!(write_qasm(3, &parsed).unwrap(), program);
+
+        let unitary = Gate::Unitary {
+            target: QubitRef(1),
+            matrix: [[Complex::new(1.0, 0.0); 2]; 2],
+        };
+        assert_eq!(
+            write_qasm(2, &[unitary]).unwrap_err().to_string(),
+            "The unitary gate on qubit 1 has no OpenQASM form"
+        );
+    }
+
+    /// Tests upgrading a program to OpenQASM 3.0, that the upgraded program simulates the
+    /// same gates, and that programs with no OpenQASM 3.0 form are rejected.
+    #[test]
+    fn test_upgrade() {
+        let source = "\
+OPENQASM 2.0;
+include \"qelib1.inc\";
+qreg q[3];
+creg c[3];
+gate g(theta, phi) a, b {
+  rz(-theta / 2) a;
+  cx a, b;
+  rz(2 * (theta - phi) ^ 2 + --phi ^ -2) b;
+}
+g(pi / 4, -sqrt(2)) q[0], q[2];
+delay[1.5us] q[1];
+ccx q[0], q[1], q[2];
+";
+        let upgraded = upgrade_qasm(Parser::new(source.as_bytes())).unwrap();
+        assert_eq!(
+            upgraded,
+            "\
+OPENQASM 3.0;
+include \"stdgates.inc\";
+qubit[3] q;
+bit[3] c;
+gate g(theta, phi) a, b {
+    rz(-theta / 2) a;
+    cx a, b;
+    rz((2 * ((theta - phi) ** 2)) + -(-(phi ** -2))) b;
+}
+g(pi / 4, -sqrt(2)) q[0], q[2];
+delay[1500ns] q[1];
+ccx q[0], q[1], q[2];
+"
+        );
+
+        let lower = |source: &str| {
+            let mut lowering = Lowering::new(GateDefinitions::new());
+            let mut operations = Vec::new();
+            for statement in Parser::new(source.as_bytes()) {
+                operations.extend(lowering.lower(statement.unwrap()).unwrap());
+            }
+            operations
+        };
+        assert_eq!(lower(&upgraded), lower(source));
+
+        // Built in gates still need the standard gates in OpenQASM 3.0.
+        let upgraded = upgrade_qasm(Parser::new("qreg q[1];\nh q[0];".as_bytes())).unwrap();
+        assert_eq!(
+            upgraded,
+            "OPENQASM 3.0;\ninclude \"stdgates.inc\";\nqubit[1] q;\nh q[0];\n"
+        );
+
+        for (source, message) in [
+            (
+                "opaque magic a;",
+                "The opaque gate 'magic' on line 1 has no OpenQASM 3.0 form",
+            ),
+            (
+                "qreg q[1];\nqreg input[2];",
+                "The name 'input' on line 2 is a keyword in OpenQASM 3.0",
+            ),
+            (
+                "gate f(pow) a { rz(pow) a; }",
+                "The name 'pow' on line 1 is a keyword in OpenQASM 3.0",
+            ),
+        ] {
+            let error = upgrade_qasm(Parser::new(source.as_bytes())).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
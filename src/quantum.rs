@@ -1,3 +1,21 @@
+pub mod backend;
+pub mod bit_order;
+pub mod dense;
+pub mod diagnostics;
+pub mod distribution;
+pub mod file_backed;
+pub mod heisenberg;
 pub mod ket;
+pub mod metrics;
+pub mod observable;
+pub mod reference;
 pub mod register;
+pub mod sampling;
+pub mod schedule;
+pub mod shadows;
 pub mod state;
+pub mod tolerance;
+pub mod tomography;
+pub mod trie;
+pub mod watchdog;
+pub mod xeb;
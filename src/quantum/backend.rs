@@ -0,0 +1,190 @@
+use crate::gates::gate::Gate;
+use crate::gates::parallel::{
+    apply_gate_to_state_parallel, apply_layer_to_state_parallel, Parallelism,
+};
+use crate::quantum::dense::{DenseState, MAX_DENSE_QUBITS};
+use crate::quantum::file_backed::FileBackedState;
+use crate::quantum::ket::Ket;
+use crate::quantum::metrics::Metrics;
+use crate::quantum::state::State;
+use crate::quantum::trie::TrieState;
+use std::io;
+use std::mem;
+
+/// The ways a quantum state can be stored while simulating.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Store only the kets with a non-zero amplitude, see [`State`].
+    #[default]
+    Sparse,
+    /// Store all `2^n` amplitudes, see [`DenseState`].
+    Dense,
+    /// Store all `2^n` amplitudes in a scratch file, see [`FileBackedState`].
+    File,
+    /// Store the kets with a non-zero amplitude in a trie that shares their high qubits,
+    /// see [`TrieState`].
+    Trie,
+}
+
+impl Backend {
+    /// Looks up a backend by its command line name.
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "sparse" => Some(Backend::Sparse),
+            "dense" => Some(Backend::Dense),
+            "file" => Some(Backend::File),
+            "trie" => Some(Backend::Trie),
+            _ => None,
+        }
+    }
+
+    /// Returns the command line name of this backend.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Sparse => "sparse",
+            Backend::Dense => "dense",
+            Backend::File => "file",
+            Backend::Trie => "trie",
+        }
+    }
+
+    /// Returns the largest number of qubits this backend can address. Creating a state
+    /// smaller than this can still fail when there is not enough memory for it.
+    pub fn max_qubits(&self) -> usize {
+        match self {
+            Backend::Sparse | Backend::File | Backend::Trie => usize::MAX,
+            Backend::Dense => MAX_DENSE_QUBITS,
+        }
+    }
+}
+
+/// A quantum state held by one of the backends.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::gates::parallel::Parallelism;
+/// use quantum_simulator::quantum::backend::BackendState;
+/// use quantum_simulator::quantum::dense::DenseState;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let mut state = BackendState::Dense(DenseState::new(1).unwrap());
+/// state.apply_gate(&Gate::X { target: QubitRef(0) }, Parallelism::default()).unwrap();
+/// assert_eq!(state.into_state().unwrap().to_string(), "(1+0i)|1⟩");
+/// ```
+#[derive(Debug)]
+pub enum BackendState {
+    Sparse(State),
+    Dense(DenseState),
+    File(FileBackedState),
+    Trie(TrieState),
+}
+
+impl BackendState {
+    /// Applies a gate to this state. The sparse backend divides the work between
+    /// threads according to `parallelism`. Only the file backend can fail.
+    pub fn apply_gate(&mut self, gate: &Gate, parallelism: Parallelism) -> io::Result<()> {
+        match self {
+            BackendState::Sparse(state) => {
+                let empty_state = state.empty_like();
+                let old_state = mem::replace(state, empty_state);
+                *state = apply_gate_to_state_parallel(old_state, gate, parallelism);
+            }
+            BackendState::Dense(state) => state.apply_gate(gate),
+            BackendState::File(state) => state.apply_gate(gate)?,
+            BackendState::Trie(state) => state.apply_gate(gate),
+        }
+        Ok(())
+    }
+
+    /// Applies a layer of gates on disjoint qubits to this state. The sparse backend
+    /// passes each ket through the whole layer in a single pass over the state, see
+    /// [`apply_layer_to_state_parallel`]. The other backends apply the gates one at a
+    /// time.
+    pub fn apply_layer(&mut self, layer: &[Gate], parallelism: Parallelism) -> io::Result<()> {
+        match self {
+            BackendState::Sparse(state) => {
+                let empty_state = state.empty_like();
+                let old_state = mem::replace(state, empty_state);
+                *state = apply_layer_to_state_parallel(old_state, layer, parallelism);
+            }
+            BackendState::Dense(_) | BackendState::File(_) | BackendState::Trie(_) => {
+                for gate in layer {
+                    self.apply_gate(gate, parallelism)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a ket whose amplitude is NaN or infinite, if there is one.
+    pub fn non_finite_ket(&mut self) -> io::Result<Option<Ket>> {
+        Ok(match self {
+            BackendState::Sparse(state) => state.non_finite_ket().cloned(),
+            BackendState::Dense(state) => state.non_finite_index().map(|index| state.ket(index)),
+            BackendState::File(state) => state.non_finite_ket()?,
+            BackendState::Trie(state) => state.non_finite_ket(),
+        })
+    }
+
+    /// Returns the number of kets held by the sparse backends. The dense backends always
+    /// hold every basis state, so they return `None`.
+    pub fn num_kets(&self) -> Option<usize> {
+        match self {
+            BackendState::Sparse(state) => Some(state.len()),
+            BackendState::Trie(state) => Some(state.num_kets()),
+            BackendState::Dense(_) | BackendState::File(_) => None,
+        }
+    }
+
+    /// Returns the probability pruned by the sparse backends so far. The dense backends
+    /// only drop amplitudes when converted to a sparse state, so they return zero.
+    pub fn pruned_probability(&self) -> f64 {
+        match self {
+            BackendState::Sparse(state) => state.pruned_probability(),
+            BackendState::Trie(state) => state.pruned_probability(),
+            BackendState::Dense(_) | BackendState::File(_) => 0.0,
+        }
+    }
+
+    /// Returns the counters of the sparse backend, see [`Metrics`]. The other backends do
+    /// not count their work, so they return zeros.
+    pub fn metrics(&self) -> Metrics {
+        match self {
+            BackendState::Sparse(state) => state.metrics(),
+            BackendState::Dense(_) | BackendState::File(_) | BackendState::Trie(_) => {
+                Metrics::default()
+            }
+        }
+    }
+
+    /// Returns the number of qubits in this state.
+    pub fn num_qubits(&self) -> usize {
+        match self {
+            BackendState::Sparse(state) => state.num_qubits(),
+            BackendState::Dense(state) => state.num_qubits(),
+            BackendState::File(state) => state.num_qubits(),
+            BackendState::Trie(state) => state.num_qubits(),
+        }
+    }
+
+    /// Returns a copy of this state as a sparse state.
+    pub fn to_state(&mut self) -> io::Result<State> {
+        match self {
+            BackendState::Sparse(state) => Ok(state.clone()),
+            BackendState::Dense(state) => Ok(state.to_state()),
+            BackendState::File(state) => state.to_state(),
+            BackendState::Trie(state) => Ok(state.to_state()),
+        }
+    }
+
+    /// Converts this state into a sparse state.
+    pub fn into_state(self) -> io::Result<State> {
+        match self {
+            BackendState::Sparse(state) => Ok(state),
+            BackendState::Dense(state) => Ok(state.to_state()),
+            BackendState::File(mut state) => state.to_state(),
+            BackendState::Trie(state) => Ok(state.to_state()),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+/// The convention for how the qubits of a register map onto the bits of a basis state
+/// index, which differs between front ends.
+///
+/// The simulator itself always uses [`BitOrder::LittleEndian`], so states and circuits
+/// from other conventions are converted when they are imported or exported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BitOrder {
+    /// Qubit 0 is the least significant bit, as in Qiskit.
+    #[default]
+    LittleEndian,
+    /// Qubit 0 is the most significant bit, as in Cirq and Quil.
+    BigEndian,
+}
+
+impl BitOrder {
+    /// Returns the bit order with the given command line name, `little` or `big`.
+    pub fn from_name(name: &str) -> Option<BitOrder> {
+        match name {
+            "little" => Some(BitOrder::LittleEndian),
+            "big" => Some(BitOrder::BigEndian),
+            _ => None,
+        }
+    }
+
+    /// Returns the command line name of this bit order.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BitOrder::LittleEndian => "little",
+            BitOrder::BigEndian => "big",
+        }
+    }
+
+    /// Converts a basis state index of `num_qubits` qubits between this order and the
+    /// little endian order of the simulator. Converting twice gives back the index.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::bit_order::BitOrder;
+    ///
+    /// assert_eq!(BitOrder::BigEndian.index(0b001, 3), 0b100);
+    /// assert_eq!(BitOrder::BigEndian.index(0b110, 3), 0b011);
+    /// assert_eq!(BitOrder::LittleEndian.index(0b110, 3), 0b110);
+    /// ```
+    pub fn index(&self, index: usize, num_qubits: usize) -> usize {
+        match self {
+            BitOrder::LittleEndian => index,
+            BitOrder::BigEndian if num_qubits == 0 => index,
+            BitOrder::BigEndian => index.reverse_bits() >> (usize::BITS as usize - num_qubits),
+        }
+    }
+
+    /// Converts a state vector between this order and the little endian order of the
+    /// simulator. The length of the vector must be a power of two.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::bit_order::BitOrder;
+    ///
+    /// assert_eq!(BitOrder::BigEndian.reorder(&[0, 1, 2, 3]), vec![0, 2, 1, 3]);
+    /// ```
+    pub fn reorder<T: Clone>(&self, amplitudes: &[T]) -> Vec<T> {
+        let num_qubits = amplitudes.len().trailing_zeros() as usize;
+        (0..amplitudes.len())
+            .map(|index| amplitudes[self.index(index, num_qubits)].clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Tests that converting to and from each order gives back every index, and that the
+    /// big endian order reverses the qubits.
+    #[test]
+    fn test_round_trip() {
+        for num_qubits in 0..8 {
+            for index in 0..1usize << num_qubits {
+                for order in [BitOrder::LittleEndian, BitOrder::BigEndian] {
+                    let converted = order.index(index, num_qubits);
+                    assert!(converted < 1 << num_qubits.max(1));
+                    assert_eq!(order.index(converted, num_qubits), index);
+                }
+                let converted = BitOrder::BigEndian.index(index, num_qubits);
+                for qubit in 0..num_qubits {
+                    assert_eq!(
+                        index >> qubit & 1,
+                        converted >> (num_qubits - 1 - qubit) & 1
+                    );
+                }
+            }
+        }
+
+        let amplitudes: Vec<usize> = (0..16).collect();
+        let reordered = BitOrder::BigEndian.reorder(&amplitudes);
+        assert_eq!(BitOrder::BigEndian.reorder(&reordered), amplitudes);
+        assert_eq!(BitOrder::LittleEndian.reorder(&amplitudes), amplitudes);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::gates::gate::Gate;
+use crate::gates::kernels::{apply_cx, apply_diagonal, apply_single_qubit};
+use crate::quantum::ket::Ket;
+use crate::quantum::state::{State, StateError};
+use crate::quantum::tolerance::Tolerance;
+use num::complex::Complex;
+use std::io;
+
+/// The largest number of qubits a dense state vector can be indexed for. States this
+/// large need far more memory than any machine has, so allocating one fails well before
+/// this limit.
+pub const MAX_DENSE_QUBITS: usize = usize::BITS as usize - 6;
+
+/// A quantum state stored as a full vector of `2^n` amplitudes.
+///
+/// Unlike [`State`], which only stores the kets with non-zero amplitude, the memory used
+/// does not depend on how entangled the state is, which makes it faster for circuits
+/// that spread the state over most of the basis states.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::dense::DenseState;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let mut state = DenseState::new(2).unwrap();
+/// state.apply_gate(&Gate::H { target: QubitRef(0) });
+/// state.apply_gate(&Gate::CX { control: QubitRef(0), target: QubitRef(1) });
+/// assert!((state.amplitude(0b11).re - 1.0 / 2.0_f64.sqrt()).abs() < 1e-12);
+/// assert_eq!(state.amplitude(0b01).norm(), 0.0);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct DenseState {
+    num_qubits: usize,
+    /// Interleaved real and imaginary parts, indexed with qubit 0 as the least
+    /// significant bit.
+    amplitudes: Vec<f64>,
+    /// The tolerance given to the sparse states this state is converted into.
+    tolerance: Tolerance,
+}
+
+impl DenseState {
+    /// Creates a new `DenseState` in the all zero basis state.
+    ///
+    /// Returns an error if `num_qubits` is larger than [`MAX_DENSE_QUBITS`], or if the
+    /// amplitudes cannot be allocated.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::io;
+    /// use quantum_simulator::quantum::dense::DenseState;
+    ///
+    /// let error = DenseState::new(50).unwrap_err();
+    /// assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+    /// ```
+    pub fn new(num_qubits: usize) -> io::Result<Self> {
+        if num_qubits > MAX_DENSE_QUBITS {
+            return Err(StateError::TooManyQubits {
+                num_qubits,
+                max: MAX_DENSE_QUBITS,
+            }
+            .into());
+        }
+        let len = 2 << num_qubits;
+        let mut amplitudes = Vec::new();
+        amplitudes.try_reserve_exact(len).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!["A state with {num_qubits} qubits is too large to store"],
+            )
+        })?;
+        amplitudes.resize(len, 0.0);
+        amplitudes[0] = 1.0;
+        Ok(Self {
+            num_qubits,
+            amplitudes,
+            tolerance: Tolerance::DEFAULT,
+        })
+    }
+
+    /// Creates a new `DenseState` with the same amplitudes and tolerance as a sparse
+    /// state, or returns an error if the state is too large, see [`DenseState::new`].
+    pub fn from_state(state: &State) -> io::Result<Self> {
+        let mut dense = DenseState::new(state.num_qubits())?;
+        dense.amplitudes[0] = 0.0;
+        dense.tolerance = state.tolerance();
+        for ket in state.kets() {
+            let index = ket.basis_index();
+            dense.amplitudes[2 * index] = ket.amplitude.re;
+            dense.amplitudes[2 * index + 1] = ket.amplitude.im;
+        }
+        Ok(dense)
+    }
+
+    /// Sets the tolerance of the sparse states this state is converted into.
+    pub fn set_tolerance(&mut self, tolerance: Tolerance) {
+        self.tolerance = tolerance;
+    }
+
+    /// Converts this state into a sparse state, dropping basis states with a negligible
+    /// amplitude (see [`DenseState::set_tolerance`]) as the sparse state would when they
+    /// cancel out. Their probability is added to [`State::pruned_probability`].
+    pub fn to_state(&self) -> State {
+        let mut state = State::new(self.num_qubits);
+        state.set_tolerance(self.tolerance);
+        for index in 0..self.len() {
+            let amplitude = self.amplitude(index);
+            if !self.tolerance.is_negligible(amplitude) {
+                state.add_or_insert(self.ket(index));
+            } else {
+                state.add_pruned_probability(amplitude.norm_sqr());
+            }
+        }
+        state
+    }
+
+    /// Returns the ket for the basis state with the given index.
+    pub fn ket(&self, index: usize) -> Ket {
+        Ket::from_basis_index(index, self.num_qubits, self.amplitude(index))
+    }
+
+    /// Returns the index of a basis state whose amplitude is NaN or infinite, if there is
+    /// one.
+    pub fn non_finite_index(&self) -> Option<usize> {
+        (0..self.len()).find(|index| !self.amplitude(*index).is_finite())
+    }
+
+    /// Returns the number of qubits in this state.
+    pub fn num_qubits(&self) -> usize {
+        self.num_qubits
+    }
+
+    /// Returns the number of basis states, which is `2^num_qubits`.
+    pub fn len(&self) -> usize {
+        self.amplitudes.len() / 2
+    }
+
+    /// Returns whether this state has no basis states, which is never the case.
+    pub fn is_empty(&self) -> bool {
+        self.amplitudes.is_empty()
+    }
+
+    /// Returns the amplitude of the basis state with the given index.
+    pub fn amplitude(&self, index: usize) -> Complex<f64> {
+        Complex::new(self.amplitudes[2 * index], self.amplitudes[2 * index + 1])
+    }
+
+    /// Applies a gate to this state in place.
+    pub fn apply_gate(&mut self, gate: &Gate) {
+        apply_gate_to_amplitudes(&mut self.amplitudes, gate);
+    }
+}
+
+/// Applies a gate in place to interleaved amplitudes, using the matching dense kernel.
+pub(crate) fn apply_gate_to_amplitudes(amplitudes: &mut [f64], gate: &Gate) {
+    match gate {
+        Gate::CX { control, target } => apply_cx(amplitudes, control.index(), target.index()),
+        Gate::T { target } | Gate::TDgr { target } | Gate::RZ { target, .. } => {
+            let matrix = gate.single_qubit_matrix().unwrap();
+            apply_diagonal(amplitudes, target.index(), [matrix[0][0], matrix[1][1]]);
+        }
+        Gate::H { target } | Gate::X { target } => {
+            let matrix = gate.single_qubit_matrix().unwrap();
+            apply_single_qubit(amplitudes, target.index(), &matrix);
+        }
+        Gate::Unitary { target, matrix } => {
+            if matrix[0][1].norm() == 0.0 && matrix[1][0].norm() == 0.0 {
+                apply_diagonal(amplitudes, target.index(), [matrix[0][0], matrix[1][1]]);
+            } else {
+                apply_single_qubit(amplitudes, target.index(), matrix);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::gate::apply_gate_to_state;
+    use crate::quantum::register::QubitRef;
+
+    /// Tests that the dense and sparse backends give the same state.
+    #[test]
+    fn test_dense_matches_sparse() {
+        let gates = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(2),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::T {
+                target: QubitRef(1),
+            },
+            Gate::RZ {
+                target: QubitRef(2),
+                theta: 0.3,
+            },
+            Gate::X {
+                target: QubitRef(0),
+            },
+            Gate::TDgr {
+                target: QubitRef(2),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+        ];
+        let mut sparse = State::new(3);
+        sparse.add_or_insert(Ket::new_zero_ket(3));
+        let mut dense = DenseState::new(3).unwrap();
+
+        for gate in &gates {
+            sparse = apply_gate_to_state(sparse, gate);
+            dense.apply_gate(gate);
+        }
+
+        assert_eq!(dense.to_state().to_string(), sparse.to_string());
+        assert_eq!(
+            DenseState::from_state(&sparse)
+                .unwrap()
+                .to_state()
+                .to_string(),
+            sparse.to_string()
+        );
+    }
+
+    /// Tests that a new dense state is the all zero basis state.
+    #[test]
+    fn test_new_dense_state() {
+        let state = DenseState::new(3).unwrap();
+        assert_eq!(state.len(), 8);
+        assert_eq!(state.amplitude(0), Complex::new(1.0, 0.0));
+        assert_eq!(state.to_state().to_string(), "(1+0i)|000⟩");
+    }
+
+    /// Tests that states too large to allocate or to index are errors rather than aborts.
+    #[test]
+    fn test_oversized_dense_state() {
+        for num_qubits in [50, MAX_DENSE_QUBITS, MAX_DENSE_QUBITS + 1] {
+            let error = DenseState::new(num_qubits).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::OutOfMemory, "{num_qubits}");
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::quantum::state::State;
+use bitvec::prelude::*;
+use num::complex::Complex;
+use std::collections::HashMap;
+use std::io;
+
+/// Metrics describing how a state is spread over the basis states and how entangled
+/// chosen subsystems are with the rest of the register.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostics {
+    /// The number of kets with a non-zero amplitude.
+    pub nonzero_amplitudes: usize,
+    /// The inverse participation ratio `sum |a|^4` of the normalised state, which is 1
+    /// for a basis state and `1 / 2^n` for a uniform superposition over `n` qubits.
+    pub inverse_participation_ratio: f64,
+    /// The purity `Tr(rho^2)` of the reduced state of each subsystem, which is 1 if the
+    /// subsystem is not entangled with the other qubits.
+    pub purities: Vec<(Vec<usize>, f64)>,
+}
+
+impl Diagnostics {
+    /// Computes the diagnostics of a state, including the purity of each of the given
+    /// subsystems. Returns an error if a subsystem refers to a qubit outside the state.
+    ///
+    /// # Examples
+    /// ```
+    /// use num::complex::Complex;
+    /// use quantum_simulator::quantum::diagnostics::Diagnostics;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use quantum_simulator::quantum::state::State;
+    ///
+    /// // A Bell state, where each qubit on its own is maximally mixed.
+    /// let amplitude = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+    /// let mut state = State::new(2);
+    /// state.add_or_insert(Ket::from_basis_index(0b00, 2, amplitude));
+    /// state.add_or_insert(Ket::from_basis_index(0b11, 2, amplitude));
+    ///
+    /// let diagnostics = Diagnostics::of(&state, &[vec![0]]).unwrap();
+    /// assert_eq!(diagnostics.nonzero_amplitudes, 2);
+    /// assert!((diagnostics.inverse_participation_ratio - 0.5).abs() < 1e-12);
+    /// assert!((diagnostics.purities[0].1 - 0.5).abs() < 1e-12);
+    /// ```
+    pub fn of(state: &State, subsystems: &[Vec<usize>]) -> io::Result<Diagnostics> {
+        let norm_squared: f64 = state
+            .iter()
+            .map(|(_, amplitude)| amplitude.norm_sqr())
+            .sum();
+        let inverse_participation_ratio = state
+            .iter()
+            .map(|(_, amplitude)| amplitude.norm_sqr().powi(2))
+            .sum::<f64>()
+            / norm_squared.powi(2);
+
+        let purities = subsystems
+            .iter()
+            .map(|subsystem| {
+                let num_qubits = state.num_qubits();
+                if let Some(qubit) = subsystem.iter().find(|qubit| **qubit >= num_qubits) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!["Unknown qubit {qubit} in subsystem {subsystem:?}"],
+                    ));
+                }
+                Ok((
+                    subsystem.clone(),
+                    purity(state, subsystem) / norm_squared.powi(2),
+                ))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Diagnostics {
+            nonzero_amplitudes: state
+                .iter()
+                .filter(|(_, amplitude)| amplitude.norm() != 0.0)
+                .count(),
+            inverse_participation_ratio,
+            purities,
+        })
+    }
+}
+
+/// Returns `Tr(rho^2)` for the reduced state of `subsystem`, without normalising.
+fn purity(state: &State, subsystem: &[usize]) -> f64 {
+    // Group the amplitudes by the bits outside the subsystem, so that each group is a
+    // vector on the subsystem. The reduced state is the sum of their outer products.
+    let mut groups: HashMap<BitVec, Vec<(BitVec, Complex<f64>)>> = HashMap::new();
+    for (bits, amplitude) in state.iter() {
+        let inside: BitVec = subsystem.iter().map(|qubit| bits[*qubit]).collect();
+        let mut outside = bits.to_bitvec();
+        for qubit in subsystem {
+            outside.set(*qubit, false);
+        }
+        groups.entry(outside).or_default().push((inside, amplitude));
+    }
+
+    let mut reduced: HashMap<(&BitVec, &BitVec), Complex<f64>> = HashMap::new();
+    for group in groups.values() {
+        for (row, row_amplitude) in group {
+            for (column, column_amplitude) in group {
+                *reduced.entry((row, column)).or_default() +=
+                    row_amplitude * column_amplitude.conj();
+            }
+        }
+    }
+    reduced.values().map(|entry| entry.norm_sqr()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::ket::Ket;
+
+    /// Tests the diagnostics of a product state and of a partly entangled state.
+    #[test]
+    fn test_diagnostics() {
+        let half = Complex::new(0.5, 0.0);
+
+        // |+⟩|+⟩ is a product state, so every subsystem is pure.
+        let mut state = State::new(2);
+        for bits in 0..4 {
+            state.add_or_insert(Ket::from_basis_index(bits, 2, half));
+        }
+        let diagnostics = Diagnostics::of(&state, &[vec![0], vec![1], vec![0, 1]]).unwrap();
+        assert_eq!(diagnostics.nonzero_amplitudes, 4);
+        assert!((diagnostics.inverse_participation_ratio - 0.25).abs() < 1e-12);
+        for (_, purity) in diagnostics.purities {
+            assert!((purity - 1.0).abs() < 1e-12);
+        }
+
+        // A GHZ state on qubits 0 and 2, with qubit 1 left in |0⟩ and an unnormalised
+        // amplitude.
+        let mut state = State::new(3);
+        state.add_or_insert(Ket::from_basis_index(0b000, 3, Complex::new(2.0, 0.0)));
+        state.add_or_insert(Ket::from_basis_index(0b101, 3, Complex::new(0.0, 2.0)));
+        let diagnostics = Diagnostics::of(&state, &[vec![1], vec![2], vec![0, 2]]).unwrap();
+        let purities: Vec<f64> = diagnostics.purities.iter().map(|(_, p)| *p).collect();
+        for (purity, expected) in purities.iter().zip([1.0, 0.5, 1.0]) {
+            assert!((purity - expected).abs() < 1e-12, "{purity} != {expected}");
+        }
+    }
+
+    /// Tests that subsystems outside the state are rejected.
+    #[test]
+    fn test_unknown_subsystem_qubit() {
+        let mut state = State::new(2);
+        state.add_or_insert(Ket::new_zero_ket(2));
+        let error = Diagnostics::of(&state, &[vec![0, 2]]).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown qubit 2 in subsystem [0, 2]");
+    }
+}
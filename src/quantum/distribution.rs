@@ -0,0 +1,237 @@
+use crate::json::{parse_json, Json};
+use std::collections::BTreeMap;
+use std::io;
+
+/// A probability distribution over measurement outcomes, keyed by bitstrings with qubit 0
+/// as the last character.
+pub type Distribution = BTreeMap<String, f64>;
+
+/// How far apart two distributions over the same outcomes are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistributionDistance {
+    /// The number of outcomes with a non-zero probability in either distribution.
+    pub outcomes: usize,
+    /// Half the sum of the absolute differences of the probabilities, from zero for the
+    /// same distributions to one for distributions with no outcomes in common.
+    pub total_variation: f64,
+    /// The square of the Bhattacharyya coefficient `Σ √(p q)`, as reported by Qiskit.
+    pub hellinger_fidelity: f64,
+    /// The Kullback-Leibler divergence `D(first ‖ second) = Σ p ln(p / q)`, in nats.
+    /// Infinite if the second distribution misses an outcome of the first.
+    pub kl_divergence: f64,
+}
+
+/// Compares two distributions, which must have outcomes of the same number of bits.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::distribution::{compare_distributions, Distribution};
+///
+/// let ideal = Distribution::from([("00".to_string(), 0.5), ("11".to_string(), 0.5)]);
+/// let noisy = Distribution::from([
+///     ("00".to_string(), 0.5),
+///     ("01".to_string(), 0.1),
+///     ("11".to_string(), 0.4),
+/// ]);
+/// let distance = compare_distributions(&noisy, &ideal).unwrap();
+/// assert!((distance.total_variation - 0.1).abs() < 1e-12);
+/// assert!(distance.hellinger_fidelity < 1.0);
+/// assert_eq!(distance.kl_divergence, f64::INFINITY);
+/// ```
+pub fn compare_distributions(
+    first: &Distribution,
+    second: &Distribution,
+) -> io::Result<DistributionDistance> {
+    let widths: Vec<usize> = first.keys().chain(second.keys()).map(String::len).collect();
+    if widths.iter().any(|width| *width != widths[0]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The distributions have outcomes with different numbers of bits",
+        ));
+    }
+
+    let mut outcomes: Vec<&String> = first.keys().chain(second.keys()).collect();
+    outcomes.sort();
+    outcomes.dedup();
+    let mut distance = DistributionDistance {
+        outcomes: outcomes.len(),
+        total_variation: 0.0,
+        hellinger_fidelity: 0.0,
+        kl_divergence: 0.0,
+    };
+    for outcome in outcomes {
+        let p = first.get(outcome).copied().unwrap_or(0.0);
+        let q = second.get(outcome).copied().unwrap_or(0.0);
+        distance.total_variation += (p - q).abs() / 2.0;
+        distance.hellinger_fidelity += (p * q).sqrt();
+        if p > 0.0 {
+            distance.kl_divergence += p * (p / q).ln();
+        }
+    }
+    distance.hellinger_fidelity = distance.hellinger_fidelity.powi(2);
+    Ok(distance)
+}
+
+/// Parses a distribution from a JSON file, normalising it so that it sums to one.
+///
+/// The file may be an object of bitstrings to counts or probabilities, as exported by
+/// hardware providers, or such an object in a `counts` member. Spaces in the bitstrings,
+/// which Qiskit puts between classical registers, are dropped. The `--json` output of the
+/// simulator is also accepted, giving its marginal probabilities if it has them and the
+/// probabilities of its final state otherwise.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::distribution::parse_distribution;
+///
+/// let counts = parse_distribution(r#"{"00": 30, "11": 10}"#).unwrap();
+/// assert_eq!(counts["00"], 0.75);
+///
+/// let simulated = parse_distribution(
+///     r#"{"final_state":[{"basis":"01","re":0.6,"im":0},{"basis":"10","re":0,"im":-0.8}]}"#,
+/// )
+/// .unwrap();
+/// assert!((simulated["10"] - 0.64).abs() < 1e-12);
+/// ```
+pub fn parse_distribution(source: &str) -> io::Result<Distribution> {
+    let json = parse_json(source)?;
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut weights: Vec<(String, f64)> = Vec::new();
+    let probabilities = json
+        .get("marginals")
+        .and_then(|marginals| marginals.get("probabilities"));
+    // The marginals are of the qubits that were asked for, so they take precedence.
+    let final_state = json.get("final_state").filter(|_| probabilities.is_none());
+    if let Some(Json::Array(kets)) = final_state {
+        for ket in kets {
+            let Some(Json::String(basis)) = ket.get("basis") else {
+                return Err(invalid("Expected a 'basis' string in each ket"));
+            };
+            let re = ket.get("re").map_or(Ok(0.0), number)?;
+            let im = ket.get("im").map_or(Ok(0.0), number)?;
+            weights.push((basis.clone(), re * re + im * im));
+        }
+    } else {
+        let Some(Json::Object(members)) = probabilities.or(json.get("counts")).or(Some(&json))
+        else {
+            return Err(invalid("Expected an object of outcomes"));
+        };
+        for (outcome, value) in members {
+            weights.push((outcome.clone(), number(value)?));
+        }
+    }
+
+    let mut distribution = Distribution::new();
+    for (outcome, weight) in weights {
+        let outcome: String = outcome.chars().filter(|c| *c != ' ').collect();
+        if outcome.is_empty() || !outcome.chars().all(|bit| bit == '0' || bit == '1') {
+            return Err(invalid(&format![
+                "Expected a bitstring but found '{outcome}'"
+            ]));
+        }
+        if !weight.is_finite() || weight < 0.0 {
+            return Err(invalid(&format![
+                "Invalid count or probability {weight} of '{outcome}'"
+            ]));
+        }
+        *distribution.entry(outcome).or_default() += weight;
+    }
+    let total: f64 = distribution.values().sum();
+    if total == 0.0 {
+        return Err(invalid("The distribution has no outcomes"));
+    }
+    distribution.retain(|_, weight| *weight > 0.0);
+    distribution
+        .values_mut()
+        .for_each(|weight| *weight /= total);
+    Ok(distribution)
+}
+
+fn number(value: &Json) -> io::Result<f64> {
+    match value {
+        Json::Number(number) => Ok(*number),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a number",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Tests the distances of known distributions.
+    #[test]
+    fn test_compare_distributions() {
+        let uniform: Distribution = ["00", "01", "10", "11"]
+            .iter()
+            .map(|outcome| (outcome.to_string(), 0.25))
+            .collect();
+        let distance = compare_distributions(&uniform, &uniform).unwrap();
+        assert_eq!(distance.outcomes, 4);
+        assert_eq!(distance.total_variation, 0.0);
+        assert!((distance.hellinger_fidelity - 1.0).abs() < 1e-12);
+        assert_eq!(distance.kl_divergence, 0.0);
+
+        // A Bell state against uniform noise.
+        let bell = Distribution::from([("00".to_string(), 0.5), ("11".to_string(), 0.5)]);
+        let distance = compare_distributions(&bell, &uniform).unwrap();
+        assert!((distance.total_variation - 0.5).abs() < 1e-12);
+        assert!((distance.hellinger_fidelity - 0.5).abs() < 1e-12);
+        assert!((distance.kl_divergence - 2.0_f64.ln()).abs() < 1e-12);
+        let reverse = compare_distributions(&uniform, &bell).unwrap();
+        assert_eq!(reverse.total_variation, distance.total_variation);
+        assert_eq!(reverse.kl_divergence, f64::INFINITY);
+
+        let disjoint = Distribution::from([("01".to_string(), 1.0)]);
+        let distance = compare_distributions(&bell, &disjoint).unwrap();
+        assert!((distance.total_variation - 1.0).abs() < 1e-12);
+        assert_eq!(distance.hellinger_fidelity, 0.0);
+
+        let wider = Distribution::from([("001".to_string(), 1.0)]);
+        assert!(compare_distributions(&bell, &wider).is_err());
+    }
+
+    /// Tests parsing counts, wrapped counts and the output of the simulator, and
+    /// rejecting malformed distributions.
+    #[test]
+    fn test_parse_distribution() {
+        let counts = parse_distribution(r#"{"0 01": 3, "1 10": 1, "0 00": 0}"#).unwrap();
+        assert_eq!(
+            counts,
+            Distribution::from([("001".to_string(), 0.75), ("110".to_string(), 0.25)])
+        );
+        let wrapped = parse_distribution(r#"{"shots": 4, "counts": {"001": 3, "110": 1}}"#);
+        assert_eq!(wrapped.unwrap(), counts);
+
+        let simulated = r#"{"gate_counts":{"h":1},"final_state":[
+            {"basis":"00","re":0.7071067811865476,"im":0},
+            {"basis":"11","re":0,"im":0.7071067811865476}],
+            "marginals":{"qubits":[0],"lightcone":[0],"probabilities":{"0":0.5,"1":0.5}}}"#;
+        assert_eq!(
+            parse_distribution(simulated).unwrap(),
+            Distribution::from([("0".to_string(), 0.5), ("1".to_string(), 0.5)])
+        );
+        let final_state = simulated
+            .split(",\n            \"marginals\"")
+            .next()
+            .unwrap();
+        let final_state = parse_distribution(&format!["{final_state}}}"]).unwrap();
+        assert!((final_state["11"] - 0.5).abs() < 1e-12);
+
+        for (source, message) in [
+            ("[1, 2]", "Expected an object of outcomes"),
+            (r#"{"0x3": 1}"#, "Expected a bitstring but found '0x3'"),
+            (r#"{"01": "1"}"#, "Expected a number"),
+            (r#"{"01": -1}"#, "Invalid count or probability -1 of '01'"),
+            (r#"{"01": 0}"#, "The distribution has no outcomes"),
+            ("{}", "The distribution has no outcomes"),
+            (r#"{"01" 1}"#, "Expected ':' on line 1"),
+        ] {
+            assert_eq!(parse_distribution(source).unwrap_err().to_string(), message);
+        }
+    }
+}
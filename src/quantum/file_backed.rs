@@ -0,0 +1,309 @@
+use crate::gates::gate::Gate;
+use crate::quantum::dense::apply_gate_to_amplitudes;
+use crate::quantum::ket::Ket;
+use crate::quantum::register::QubitRef;
+use crate::quantum::state::State;
+use crate::quantum::tolerance::Tolerance;
+use num::complex::Complex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The default number of qubits covered by each chunk held in memory, giving 16 MiB
+/// chunks.
+pub const DEFAULT_CHUNK_QUBITS: usize = 20;
+
+/// The number of bytes used to store each amplitude.
+const AMPLITUDE_BYTES: usize = 16;
+
+/// Used to give every scratch file created by this process a unique name.
+static NEXT_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A dense state vector kept in a scratch file rather than in memory, so that states
+/// larger than the available RAM can be simulated.
+///
+/// The amplitudes are divided into chunks of `2^chunk_qubits` basis states. Each gate is
+/// applied in one streaming pass over the file: gates on qubits inside a chunk are
+/// applied a chunk at a time, and gates on higher qubits load the two chunks that
+/// differ in that qubit together. The scratch file is removed when the state is dropped.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::file_backed::FileBackedState;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let mut state = FileBackedState::new(3, &std::env::temp_dir(), 1).unwrap();
+/// state.apply_gate(&Gate::X { target: QubitRef(2) }).unwrap();
+/// assert_eq!(state.to_state().unwrap().to_string(), "(1+0i)|100⟩");
+/// ```
+#[derive(Debug)]
+pub struct FileBackedState {
+    num_qubits: usize,
+    chunk_qubits: usize,
+    file: File,
+    path: PathBuf,
+    /// Space for two chunks of amplitudes.
+    buffer: Vec<f64>,
+    /// Space for the raw bytes of one chunk.
+    bytes: Vec<u8>,
+    /// The tolerance given to the sparse states this state is converted into.
+    tolerance: Tolerance,
+}
+
+impl FileBackedState {
+    /// Creates a new `FileBackedState` in the all zero basis state, with its scratch file
+    /// in `directory` and chunks of up to `2^chunk_qubits` basis states.
+    pub fn new(num_qubits: usize, directory: &Path, chunk_qubits: usize) -> io::Result<Self> {
+        let too_large = || {
+            io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!["A state with {num_qubits} qubits is too large to store"],
+            )
+        };
+        let len = u32::try_from(num_qubits)
+            .ok()
+            .and_then(|num_qubits| 1_u64.checked_shl(num_qubits))
+            .and_then(|len| len.checked_mul(AMPLITUDE_BYTES as u64))
+            .ok_or_else(too_large)?;
+
+        let id = NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+        let path = directory.join(format!["qasm-state-{}-{id}.bin", process::id()]);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Extending the file fills it with zeros without writing them on most file systems.
+        file.set_len(len)?;
+
+        let chunk_qubits = chunk_qubits.min(num_qubits);
+        let mut state = Self {
+            num_qubits,
+            chunk_qubits,
+            file,
+            path,
+            buffer: vec![0.0; 4 << chunk_qubits],
+            bytes: vec![0; AMPLITUDE_BYTES << chunk_qubits],
+            tolerance: Tolerance::DEFAULT,
+        };
+        state.buffer[0] = 1.0;
+        state.write_chunk(0, 0)?;
+        Ok(state)
+    }
+
+    /// Returns the number of qubits in this state.
+    pub fn num_qubits(&self) -> usize {
+        self.num_qubits
+    }
+
+    /// Returns the path of the scratch file holding the amplitudes.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Applies a gate to this state with one pass over the scratch file.
+    pub fn apply_gate(&mut self, gate: &Gate) -> io::Result<()> {
+        let chunk_qubits = self.chunk_qubits;
+        let num_chunks = 1 << (self.num_qubits - chunk_qubits);
+        let high_bit = |chunk: usize, qubit: usize| (chunk >> (qubit - chunk_qubits)) & 1 == 1;
+        let target = *gate.qubits().last().unwrap();
+
+        if target < chunk_qubits {
+            for chunk in 0..num_chunks {
+                let local_gate = match gate {
+                    // A control outside the chunk is the same for the whole chunk.
+                    Gate::CX { control, target } if control.index() >= chunk_qubits => {
+                        if !high_bit(chunk, control.index()) {
+                            continue;
+                        }
+                        Gate::X { target: *target }
+                    }
+                    _ => gate.clone(),
+                };
+                self.read_chunk(chunk, 0)?;
+                let len = 2 << chunk_qubits;
+                apply_gate_to_amplitudes(&mut self.buffer[..len], &local_gate);
+                self.write_chunk(chunk, 0)?;
+            }
+            return Ok(());
+        }
+
+        // Load each pair of chunks that differ in the target qubit together, so the target
+        // becomes the highest qubit of the buffer.
+        let pair_bit = 1 << (target - chunk_qubits);
+        for chunk in (0..num_chunks).filter(|chunk| chunk & pair_bit == 0) {
+            let local_gate = match gate {
+                Gate::CX { control, .. } if control.index() >= chunk_qubits => {
+                    if !high_bit(chunk, control.index()) {
+                        continue;
+                    }
+                    Gate::X {
+                        target: QubitRef(chunk_qubits),
+                    }
+                }
+                Gate::CX { control, .. } => Gate::CX {
+                    control: *control,
+                    target: QubitRef(chunk_qubits),
+                },
+                _ => gate.remap(|_| chunk_qubits),
+            };
+            self.read_chunk(chunk, 0)?;
+            self.read_chunk(chunk | pair_bit, 1)?;
+            apply_gate_to_amplitudes(&mut self.buffer, &local_gate);
+            self.write_chunk(chunk, 0)?;
+            self.write_chunk(chunk | pair_bit, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the tolerance of the sparse states this state is converted into.
+    pub fn set_tolerance(&mut self, tolerance: Tolerance) {
+        self.tolerance = tolerance;
+    }
+
+    /// Converts this state into a sparse state, dropping basis states with a negligible
+    /// amplitude (see [`FileBackedState::set_tolerance`]).
+    pub fn to_state(&mut self) -> io::Result<State> {
+        let mut state = State::new(self.num_qubits);
+        let tolerance = self.tolerance;
+        state.set_tolerance(tolerance);
+        self.for_each_amplitude(|index, amplitude| {
+            if !tolerance.is_negligible(amplitude) {
+                state.add_or_insert(Ket::from_basis_index(index, state.num_qubits(), amplitude));
+            } else {
+                state.add_pruned_probability(amplitude.norm_sqr());
+            }
+            true
+        })?;
+        Ok(state)
+    }
+
+    /// Returns a ket whose amplitude is NaN or infinite, if there is one.
+    pub fn non_finite_ket(&mut self) -> io::Result<Option<Ket>> {
+        let num_qubits = self.num_qubits;
+        let mut found = None;
+        self.for_each_amplitude(|index, amplitude| {
+            if !amplitude.is_finite() {
+                found = Some(Ket::from_basis_index(index, num_qubits, amplitude));
+            }
+            found.is_none()
+        })?;
+        Ok(found)
+    }
+
+    /// Calls `visit` with the index and amplitude of each basis state in order, until it
+    /// returns false.
+    fn for_each_amplitude(
+        &mut self,
+        mut visit: impl FnMut(usize, Complex<f64>) -> bool,
+    ) -> io::Result<()> {
+        let chunk_len = 1 << self.chunk_qubits;
+        for chunk in 0..1 << (self.num_qubits - self.chunk_qubits) {
+            self.read_chunk(chunk, 0)?;
+            for offset in 0..chunk_len {
+                let amplitude = Complex::new(self.buffer[2 * offset], self.buffer[2 * offset + 1]);
+                if !visit(chunk * chunk_len + offset, amplitude) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a chunk from the scratch file into the first or second half of the buffer.
+    fn read_chunk(&mut self, chunk: usize, half: usize) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.chunk_offset(chunk)))?;
+        self.file.read_exact(&mut self.bytes)?;
+        let len = 2 << self.chunk_qubits;
+        let values = &mut self.buffer[half * len..(half + 1) * len];
+        for (value, bytes) in values.iter_mut().zip(self.bytes.chunks_exact(8)) {
+            *value = f64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Ok(())
+    }
+
+    /// Writes the first or second half of the buffer to a chunk of the scratch file.
+    fn write_chunk(&mut self, chunk: usize, half: usize) -> io::Result<()> {
+        let len = 2 << self.chunk_qubits;
+        let values = &self.buffer[half * len..(half + 1) * len];
+        for (value, bytes) in values.iter().zip(self.bytes.chunks_exact_mut(8)) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+        self.file.seek(SeekFrom::Start(self.chunk_offset(chunk)))?;
+        self.file.write_all(&self.bytes)
+    }
+
+    fn chunk_offset(&self, chunk: usize) -> u64 {
+        (chunk as u64) * (self.bytes.len() as u64)
+    }
+}
+
+impl Drop for FileBackedState {
+    fn drop(&mut self) {
+        // There is nothing useful to do if the scratch file has already gone.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::dense::DenseState;
+    use std::env;
+
+    /// Tests that the file backed state matches the in memory dense state, including for
+    /// gates on qubits above the chunk size.
+    #[test]
+    fn test_file_backed_matches_dense() {
+        let mut gates = Vec::new();
+        for target in 0..4 {
+            gates.push(Gate::H {
+                target: QubitRef(target),
+            });
+            gates.push(Gate::RZ {
+                target: QubitRef(target),
+                theta: 0.1 * target as f64,
+            });
+        }
+        for (control, target) in [(0, 3), (3, 0), (2, 3), (0, 1), (3, 2)] {
+            gates.push(Gate::CX {
+                control: QubitRef(control),
+                target: QubitRef(target),
+            });
+            gates.push(Gate::T {
+                target: QubitRef(control),
+            });
+            gates.push(Gate::H {
+                target: QubitRef(target),
+            });
+        }
+
+        for chunk_qubits in [0, 1, 2, 4, 8] {
+            let mut dense = DenseState::new(4).unwrap();
+            let mut file_backed = FileBackedState::new(4, &env::temp_dir(), chunk_qubits).unwrap();
+            for gate in &gates {
+                dense.apply_gate(gate);
+                file_backed.apply_gate(gate).unwrap();
+            }
+            assert_eq!(
+                file_backed.to_state().unwrap().to_string(),
+                dense.to_state().to_string()
+            );
+            assert!(file_backed.non_finite_ket().unwrap().is_none());
+        }
+    }
+
+    /// Tests that the scratch file is removed when the state is dropped.
+    #[test]
+    fn test_file_backed_removes_scratch_file() {
+        let state = FileBackedState::new(2, &env::temp_dir(), 1).unwrap();
+        let path = state.path().to_path_buf();
+        assert!(path.exists());
+        drop(state);
+        assert!(!path.exists());
+    }
+}
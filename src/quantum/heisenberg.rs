@@ -0,0 +1,281 @@
+use crate::gates::clifford::{pauli_index, pauli_matrix, Conjugation};
+use crate::gates::gate::Gate;
+use crate::gates::kernels::{adjoint, multiply, Matrix2};
+use crate::quantum::observable::{Observable, Pauli, PauliTerm};
+use crate::quantum::tolerance::Tolerance;
+use std::collections::BTreeMap;
+use std::io;
+
+/// Coefficients of a conjugated Pauli operator at most this large are rounding errors,
+/// and are dropped.
+const ROUNDING_TOLERANCE: Tolerance = Tolerance::absolute(1e-12);
+
+/// A Pauli string as the Pauli operator on each qubit that is not the identity.
+type PauliString = BTreeMap<usize, Pauli>;
+
+/// The outcome of propagating an observable backwards through a circuit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeisenbergResult {
+    /// The evolved observable `U† O U`, with its terms in order of decreasing magnitude
+    /// of their coefficients.
+    pub observable: Observable,
+    /// The expectation value `⟨0|U† O U|0⟩` of the observable in the state the circuit
+    /// prepares from the zero state.
+    pub expectation: f64,
+    /// The largest number of terms held at once.
+    pub peak_terms: usize,
+    /// The sum of the magnitudes of the coefficients of the terms dropped for being at
+    /// most the threshold, which bounds the error of the expectation value.
+    pub truncated_weight: f64,
+}
+
+/// Propagates an observable backwards through the gates of a circuit `U` in the
+/// Heisenberg picture, giving `U† O U` as a sum of Pauli strings along with its
+/// expectation value in the zero state.
+///
+/// Each Clifford gate maps every Pauli string onto a single other one, looked up in the
+/// tables of [`Conjugation`], while other gates split the strings they act on into up
+/// to three. The number of terms therefore depends
+/// on the non-Clifford gates in the backward lightcone of the observable rather than on
+/// the number of qubits, which for low weight observables under shallow circuits is far
+/// cheaper than simulating the state. Terms whose coefficients are at most `threshold`
+/// in magnitude are dropped after each gate to bound the growth.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::heisenberg::propagate_observable;
+/// use quantum_simulator::quantum::observable::parse_observable;
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// // A Bell state is measured as +1 by ZZ and XX.
+/// let gates = [
+///     Gate::H { target: QubitRef(0) },
+///     Gate::CX { control: QubitRef(0), target: QubitRef(1) },
+/// ];
+/// let observable = parse_observable("1 ZZ\n0.5 XX").unwrap();
+/// let result = propagate_observable(&observable, &gates, 0.0).unwrap();
+/// assert_eq!(result.expectation, 1.5);
+/// let labels: Vec<&str> = result.observable.terms.iter().map(|term| term.label.as_str()).collect();
+/// assert_eq!(labels, vec!["ZI", "IZ"]);
+/// ```
+pub fn propagate_observable(
+    observable: &Observable,
+    gates: &[Gate],
+    threshold: f64,
+) -> io::Result<HeisenbergResult> {
+    let num_qubits = observable.num_qubits;
+    if let Some(qubit) = gates
+        .iter()
+        .flat_map(|gate| gate.qubits())
+        .find(|qubit| *qubit >= num_qubits)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!["The circuit acts on qubit {qubit} but the observable has {num_qubits}"],
+        ));
+    }
+
+    let mut terms: BTreeMap<PauliString, f64> = BTreeMap::new();
+    for term in &observable.terms {
+        *terms
+            .entry(term.paulis.iter().copied().collect())
+            .or_default() += term.coefficient;
+    }
+    let mut peak_terms = terms.len();
+    let mut truncated_weight = 0.0;
+    for gate in gates.iter().rev() {
+        terms = conjugate(terms, gate);
+        peak_terms = peak_terms.max(terms.len());
+        terms.retain(|_, coefficient| {
+            let keep = coefficient.abs() > threshold;
+            if !keep {
+                truncated_weight += coefficient.abs();
+            }
+            keep
+        });
+    }
+
+    // Only strings of Z and the identity have a non-zero expectation value in the zero
+    // state, where it is one.
+    let expectation = terms
+        .iter()
+        .filter(|(paulis, _)| paulis.values().all(|pauli| *pauli == Pauli::Z))
+        .map(|(_, coefficient)| coefficient)
+        .sum();
+    let mut terms: Vec<PauliTerm> = terms
+        .into_iter()
+        .map(|(paulis, coefficient)| PauliTerm {
+            coefficient,
+            label: label(&paulis, num_qubits),
+            paulis: paulis.into_iter().collect(),
+        })
+        .collect();
+    terms.sort_by(|a, b| b.coefficient.abs().total_cmp(&a.coefficient.abs()));
+    Ok(HeisenbergResult {
+        observable: Observable { num_qubits, terms },
+        expectation,
+        peak_terms,
+        truncated_weight,
+    })
+}
+
+/// Returns `G† O G` for the sum of Pauli strings `O`.
+fn conjugate(terms: BTreeMap<PauliString, f64>, gate: &Gate) -> BTreeMap<PauliString, f64> {
+    let mut conjugated: BTreeMap<PauliString, f64> = BTreeMap::new();
+    match Conjugation::of(gate) {
+        Some(conjugation) => {
+            for (mut paulis, coefficient) in terms {
+                let sign = conjugation.apply(&mut paulis);
+                *conjugated.entry(paulis).or_default() += sign * coefficient;
+            }
+        }
+        None => {
+            // Every built in gate that is not a Clifford gate acts on a single qubit.
+            let target = gate.qubits()[0];
+            let images = single_qubit_images(&gate.single_qubit_matrix().unwrap());
+            for (mut paulis, coefficient) in terms {
+                let Some(pauli) = paulis.remove(&target) else {
+                    *conjugated.entry(paulis).or_default() += coefficient;
+                    continue;
+                };
+                for (image, factor) in &images[pauli_index(pauli)] {
+                    let mut paulis = paulis.clone();
+                    paulis.insert(target, *image);
+                    *conjugated.entry(paulis).or_default() += factor * coefficient;
+                }
+            }
+        }
+    }
+    conjugated.retain(|_, coefficient| *coefficient != 0.0);
+    conjugated
+}
+
+/// Returns the expansion of `U† P U` in Pauli operators for each of X, Y and Z, where the
+/// coefficient of `Q` is `Tr(Q U† P U) / 2`.
+fn single_qubit_images(matrix: &Matrix2) -> [Vec<(Pauli, f64)>; 3] {
+    let paulis = [Pauli::X, Pauli::Y, Pauli::Z];
+    paulis.map(|pauli| {
+        let conjugated = multiply(&multiply(&adjoint(matrix), &pauli_matrix(pauli)), matrix);
+        paulis
+            .iter()
+            .filter_map(|other| {
+                let product = multiply(&pauli_matrix(*other), &conjugated);
+                let factor = (product[0][0] + product[1][1]).re / 2.0;
+                (!ROUNDING_TOLERANCE.approx_eq(factor, 0.0)).then_some((*other, factor))
+            })
+            .collect()
+    })
+}
+
+/// Returns the label of a Pauli string, with the last character acting on qubit 0.
+fn label(paulis: &PauliString, num_qubits: usize) -> String {
+    (0..num_qubits)
+        .rev()
+        .map(|qubit| match paulis.get(&qubit) {
+            None => 'I',
+            Some(Pauli::X) => 'X',
+            Some(Pauli::Y) => 'Y',
+            Some(Pauli::Z) => 'Z',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::gate::apply_gate_to_state;
+    use crate::gates::generators::mirror_circuit;
+    use crate::quantum::ket::Ket;
+    use crate::quantum::observable::parse_observable;
+    use crate::quantum::register::QubitRef;
+    use crate::quantum::sampling::Rng;
+    use crate::quantum::state::State;
+
+    /// Tests that the expectation value in the Heisenberg picture matches that of the
+    /// state simulated in the Schrödinger picture, for circuits with non-Clifford gates.
+    #[test]
+    fn test_matches_state_simulation() {
+        let observable =
+            parse_observable("1 ZZII\n-0.5 IXYI\n0.25 YIIX\n2 IIZI\n0.75 XXXX").unwrap();
+        for seed in 0..5 {
+            let gates = mirror_circuit(4, 3, &mut Rng::new(seed));
+            // The first half, since the whole mirror circuit is the identity.
+            let gates = &gates[..gates.len() / 2];
+            let result = propagate_observable(&observable, gates, 0.0).unwrap();
+
+            let mut state = State::new(4);
+            state.add_or_insert(Ket::new_zero_ket(4));
+            for gate in gates {
+                state = apply_gate_to_state(state, gate);
+            }
+            let expected = observable.expectation(&state).unwrap().value;
+            assert!(
+                (result.expectation - expected).abs() < 1e-9,
+                "seed {seed}: {} != {expected}",
+                result.expectation
+            );
+            assert_eq!(result.truncated_weight, 0.0);
+        }
+    }
+
+    /// Tests that each term of CX conjugation matches the known images of Pauli strings,
+    /// including their signs.
+    #[test]
+    fn test_cx() {
+        let gates = [Gate::CX {
+            control: QubitRef(0),
+            target: QubitRef(1),
+        }];
+        // The control is qubit 0, the last character of each label.
+        for (pauli, image, sign) in [
+            ("XI", "XI", 1.0),
+            ("IX", "XX", 1.0),
+            ("IZ", "IZ", 1.0),
+            ("ZI", "ZZ", 1.0),
+            ("IY", "XY", 1.0),
+            ("YI", "YZ", 1.0),
+            ("YY", "ZX", -1.0),
+            ("YX", "ZY", 1.0),
+            ("XY", "IY", 1.0),
+            ("ZX", "YY", -1.0),
+        ] {
+            let observable = parse_observable(&format!["1 {pauli}"]).unwrap();
+            let result = propagate_observable(&observable, &gates, 0.0).unwrap();
+            let term = &result.observable.terms[0];
+            assert_eq!(
+                (term.label.as_str(), term.coefficient),
+                (image, sign),
+                "{pauli}"
+            );
+        }
+    }
+
+    /// Tests that terms at most the threshold are dropped and their weight reported.
+    #[test]
+    fn test_threshold() {
+        // T† X T = (X - Y) / √2 on qubit 0, and the observable is Z on qubit 1 as well.
+        let gates = [Gate::T {
+            target: QubitRef(0),
+        }];
+        let observable = parse_observable("1 IX\n0.5 ZI").unwrap();
+        let result = propagate_observable(&observable, &gates, 0.6).unwrap();
+        assert_eq!(result.peak_terms, 3);
+        assert_eq!(result.observable.terms.len(), 2);
+        assert!((result.truncated_weight - 0.5).abs() < 1e-12);
+        assert_eq!(result.expectation, 0.0);
+
+        let error = propagate_observable(
+            &observable,
+            &[Gate::H {
+                target: QubitRef(2),
+            }],
+            0.0,
+        );
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "The circuit acts on qubit 2 but the observable has 2"
+        );
+    }
+}
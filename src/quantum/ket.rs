@@ -1,5 +1,6 @@
 use bitvec::prelude::*;
 use num::complex::Complex;
+use std::cmp::Ordering;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 
@@ -76,6 +77,28 @@ impl Ket {
         Ket::from_bit_vec(bitvec![0; num_qubits], Complex::new(1.0, 0.0))
     }
 
+    /// Creates a new `Ket` of size `num_qubits` for the basis state with the given index
+    /// in a state vector, where qubit 0 is the least significant bit. This is the inverse
+    /// of [`Ket::basis_index`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use num::complex::Complex;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use bitvec::prelude::*;
+    ///
+    /// let ket = Ket::from_basis_index(0b110, 3, Complex::new(1.0, 0.0));
+    /// assert_eq!(*ket.bit_vec(), bitvec![0, 1, 1]);
+    /// assert_eq!(ket.basis_index(), 0b110);
+    /// ```
+    pub fn from_basis_index(index: usize, num_qubits: usize, amplitude: Complex<f64>) -> Ket {
+        let bits: BitVec = (0..num_qubits)
+            .map(|qubit| index & (1 << qubit) != 0)
+            .collect();
+        Ket::from_bit_vec(bits, amplitude)
+    }
+
     /// Get an immutable bitvector reference to the underlying bits
     ///
     /// # Examples
@@ -89,14 +112,39 @@ impl Ket {
     /// let bit_vec = ket.bit_vec();
     ///
     ///
-    /// assert_eq!(bit_vec.value(), 0b0000_0100);
+    /// assert_eq!(bit_vec.as_raw_slice(), [0b0000_0100]);
     ///
     /// ```
     pub fn bit_vec(&self) -> &BitVec {
         &self.bits
     }
 
-    /// Gets a bit at the desired index.
+    /// Returns whether both parts of this ket's amplitude are finite.
+    pub fn is_finite(&self) -> bool {
+        self.amplitude.is_finite()
+    }
+
+    /// Returns the index of this ket's basis state in a state vector, where qubit 0 is
+    /// the least significant bit.
+    ///
+    /// # Examples
+    /// ```
+    /// use num::complex::Complex;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use bitvec::prelude::*;
+    ///
+    /// let ket = Ket::from_bit_vec(bitvec![1, 0, 1], Complex::new(1.0, 0.0));
+    /// assert_eq!(ket.basis_index(), 0b101);
+    /// ```
+    pub fn basis_index(&self) -> usize {
+        self.bits
+            .iter()
+            .enumerate()
+            .filter(|(_, bit)| **bit)
+            .fold(0, |index, (qubit, _)| index | (1 << qubit))
+    }
+
+    /// Gets a bit at the desired index. Panics if the index is outside the ket.
     ///
     /// # Examples
     ///
@@ -111,7 +159,7 @@ impl Ket {
     /// ```
     pub fn get(&self, index: usize) -> bool {
         if let Some(bit) = self.bits.get(index) {
-            return *bit;
+            *bit
         } else {
             panic!(
                 "Index out of bounds. Needs to be less than {}",
@@ -148,6 +196,25 @@ impl PartialEq for Ket {
 
 impl Eq for Ket {}
 
+// Order kets by their basis index so that sorting gives the canonical state vector order.
+impl Ord for Ket {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bits.len().cmp(&other.bits.len()).then_with(|| {
+            self.bits
+                .iter()
+                .by_vals()
+                .rev()
+                .cmp(other.bits.iter().by_vals().rev())
+        })
+    }
+}
+
+impl PartialOrd for Ket {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 // Hash kets on only the bits and not the amplitude so that they clash
 // in a hashset if they have the same bits.
 impl Hash for Ket {
@@ -158,12 +225,16 @@ impl Hash for Ket {
 
 impl fmt::Display for Ket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Take the sign from the rounded values so tiny negative parts print as zero.
+        // Adding zero turns a rounded negative zero into a positive one.
+        let real = (self.amplitude.re * 1000.0).round() / 1000.0 + 0.0;
+        let imaginary = (self.amplitude.im * 1000.0).round() / 1000.0;
         write!(
             f,
-            "({:.3}{}{:.3}i)",
-            (self.amplitude.re * 1000.0).round() / 1000.0,
-            if self.amplitude.im < 0.0 { "-" } else { "+" },
-            (self.amplitude.im.abs() * 1000.0).round() / 1000.0
+            "({}{}{}i)",
+            real,
+            if imaginary < 0.0 { "-" } else { "+" },
+            imaginary.abs()
         )?;
         write!(f, "|")?;
         for bit in self.bits.iter().rev() {
@@ -182,5 +253,23 @@ mod tests {
     fn test_fmt_display() {
         let ket = Ket::from_bit_vec(bitvec![0, 1, 0, 0], Complex::new(1.0, 0.0));
         assert_eq!(format!("{}", ket), "(1+0i)|0010⟩");
+
+        let ket = Ket::from_bit_vec(bitvec![1], Complex::new(-1e-17, -1e-17));
+        assert_eq!(format!("{}", ket), "(0+0i)|1⟩");
+    }
+
+    /// Tests that kets are ordered by basis index rather than by their raw bits.
+    #[test]
+    fn test_ord_basis_index() {
+        let amplitude = Complex::new(1.0, 0.0);
+        let mut kets = [
+            Ket::from_bit_vec(bitvec![1, 1, 0], amplitude),
+            Ket::from_bit_vec(bitvec![0, 0, 1], amplitude),
+            Ket::from_bit_vec(bitvec![1, 0, 0], amplitude),
+            Ket::from_bit_vec(bitvec![0, 1, 0], amplitude),
+        ];
+        kets.sort();
+        let indices: Vec<usize> = kets.iter().map(Ket::basis_index).collect();
+        assert_eq!(indices, vec![1, 2, 3, 4]);
     }
 }
@@ -0,0 +1,55 @@
+use std::ops::{AddAssign, Sub};
+
+/// Counters of the work done by the sparse backend while applying gates, for studying how
+/// sparse simulation behaves on a circuit.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::{apply_gate_to_state, Gate};
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::register::QubitRef;
+/// use quantum_simulator::quantum::state::State;
+///
+/// let mut state = State::new(1);
+/// state.add_or_insert(Ket::new_zero_ket(1));
+/// let state = apply_gate_to_state(state, &Gate::H { target: QubitRef(0) });
+/// let state = apply_gate_to_state(state, &Gate::H { target: QubitRef(0) });
+/// let metrics = state.metrics();
+/// assert_eq!(metrics.branches, 3);
+/// assert_eq!((metrics.collisions, metrics.kets_merged, metrics.kets_pruned), (2, 1, 1));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// The number of kets that a gate split into two, such as with a Hadamard gate.
+    pub branches: u64,
+    /// The number of contributions added to a basis state that already had one.
+    pub collisions: u64,
+    /// The number of basis states whose colliding contributions were summed and kept.
+    pub kets_merged: u64,
+    /// The number of basis states dropped because their colliding contributions cancelled
+    /// out, see [`State::tolerance`](crate::quantum::state::State::tolerance).
+    pub kets_pruned: u64,
+}
+
+impl AddAssign for Metrics {
+    fn add_assign(&mut self, other: Metrics) {
+        self.branches += other.branches;
+        self.collisions += other.collisions;
+        self.kets_merged += other.kets_merged;
+        self.kets_pruned += other.kets_pruned;
+    }
+}
+
+/// Returns the counts since `earlier`, which must not be larger than these.
+impl Sub for Metrics {
+    type Output = Metrics;
+
+    fn sub(self, earlier: Metrics) -> Metrics {
+        Metrics {
+            branches: self.branches - earlier.branches,
+            collisions: self.collisions - earlier.collisions,
+            kets_merged: self.kets_merged - earlier.kets_merged,
+            kets_pruned: self.kets_pruned - earlier.kets_pruned,
+        }
+    }
+}
@@ -0,0 +1,583 @@
+use crate::gates::gate::{apply_gate_to_state, Gate};
+use crate::quantum::register::QubitRef;
+use crate::quantum::sampling::{sample_counts, Rng};
+use crate::quantum::state::State;
+use num::complex::Complex;
+use std::collections::BTreeMap;
+use std::io;
+
+/// A single qubit Pauli operator other than the identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pauli {
+    X,
+    Y,
+    Z,
+}
+
+/// A Pauli string with a real coefficient, such as `0.5 ZZIII`.
+///
+/// The last character of the string acts on qubit 0, matching the order kets are
+/// printed in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PauliTerm {
+    pub coefficient: f64,
+    /// The Pauli string as it was written.
+    pub label: String,
+    /// The qubits the string acts on with something other than the identity.
+    pub paulis: Vec<(usize, Pauli)>,
+}
+
+impl PauliTerm {
+    /// Returns the expectation value of the Pauli string, without the coefficient, in the
+    /// normalised state.
+    ///
+    /// # Examples
+    /// ```
+    /// use bitvec::prelude::*;
+    /// use num::complex::Complex;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use quantum_simulator::quantum::observable::parse_observable;
+    /// use quantum_simulator::quantum::state::State;
+    ///
+    /// // |0⟩|+⟩, written with qubit 0 first.
+    /// let amplitude = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+    /// let state = State::from_ket_vec(&vec![
+    ///     Ket::from_bit_vec(bitvec![0, 0], amplitude),
+    ///     Ket::from_bit_vec(bitvec![1, 0], amplitude),
+    /// ]).unwrap();
+    /// let observable = parse_observable("1 ZX\n1 XZ").unwrap();
+    /// assert!((observable.terms[0].expectation(&state) - 1.0).abs() < 1e-12);
+    /// assert!(observable.terms[1].expectation(&state).abs() < 1e-12);
+    /// ```
+    pub fn expectation(&self, state: &State) -> f64 {
+        state.expectations(std::slice::from_ref(self))[0]
+    }
+}
+
+/// A weighted sum of Pauli strings, such as a Hamiltonian.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observable {
+    /// The number of qubits each Pauli string acts on.
+    pub num_qubits: usize,
+    pub terms: Vec<PauliTerm>,
+}
+
+/// The expectation value of an observable along with that of each of its terms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectation {
+    /// The weighted sum of the term expectation values.
+    pub value: f64,
+    /// Each term along with the expectation value of its Pauli string, without the
+    /// coefficient.
+    pub terms: Vec<(PauliTerm, f64)>,
+}
+
+/// An expectation value estimated from measurement shots, with its error bars.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    /// The mean of the sampled outcomes.
+    pub mean: f64,
+    /// The unbiased sample variance of a single outcome.
+    pub variance: f64,
+    /// The standard error of the mean, `sqrt(variance / shots)`.
+    pub standard_error: f64,
+}
+
+impl Estimate {
+    /// Returns the estimate from the sum and the sum of squares of `shots` outcomes.
+    pub(crate) fn from_sums(sum: f64, sum_of_squares: f64, shots: usize) -> Estimate {
+        let count = shots as f64;
+        let mean = sum / count;
+        let variance = match shots {
+            0 | 1 => 0.0,
+            _ => ((sum_of_squares - count * mean * mean) / (count - 1.0)).max(0.0),
+        };
+        Estimate {
+            mean,
+            variance,
+            standard_error: (variance / count).sqrt(),
+        }
+    }
+}
+
+/// The expectation value of an observable and of each of its terms estimated by
+/// measuring each Pauli string a number of times.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampledExpectation {
+    /// The number of shots taken with each measurement circuit.
+    pub shots: usize,
+    /// The number of distinct measurement circuits, one for each group of terms that
+    /// were estimated from the same shots.
+    pub groups: usize,
+    /// The estimate of the weighted sum, where the variance is that of a single shot of
+    /// every measurement circuit, which are independent of each other.
+    pub value: Estimate,
+    /// Each term along with the estimate of its Pauli string, without the coefficient.
+    pub terms: Vec<(PauliTerm, Estimate)>,
+}
+
+impl Expectation {
+    /// Estimates the expectation value as if each Pauli string were measured `shots`
+    /// times, where each shot gives +1 with probability `(1 + ⟨P⟩) / 2` and -1
+    /// otherwise.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use quantum_simulator::quantum::observable::parse_observable;
+    /// use quantum_simulator::quantum::sampling::Rng;
+    /// use quantum_simulator::quantum::state::State;
+    ///
+    /// let mut state = State::new(1);
+    /// state.add_or_insert(Ket::new_zero_ket(1));
+    /// let observable = parse_observable("0.5 Z\n1 X").unwrap();
+    /// let sampled = observable.expectation(&state).unwrap().sample(1000, &mut Rng::new(1));
+    ///
+    /// // Z is certain to give +1, so its estimate has no error.
+    /// assert_eq!(sampled.terms[0].1.mean, 1.0);
+    /// assert_eq!(sampled.terms[0].1.standard_error, 0.0);
+    /// assert!((sampled.value.mean - 0.5).abs() < 4.0 * sampled.value.standard_error);
+    /// ```
+    pub fn sample(&self, shots: usize, rng: &mut Rng) -> SampledExpectation {
+        let terms: Vec<(PauliTerm, Estimate)> = self
+            .terms
+            .iter()
+            .map(|(term, expectation)| {
+                let probability = (1.0 + expectation) / 2.0;
+                let positive = (0..shots).filter(|_| rng.next_f64() < probability).count();
+                let sum = 2.0 * positive as f64 - shots as f64;
+                (term.clone(), Estimate::from_sums(sum, shots as f64, shots))
+            })
+            .collect();
+        let groups = terms.len();
+        SampledExpectation::from_independent_terms(shots, groups, terms)
+    }
+}
+
+impl SampledExpectation {
+    /// Combines the estimates of terms measured with independent shots.
+    fn from_independent_terms(
+        shots: usize,
+        groups: usize,
+        terms: Vec<(PauliTerm, Estimate)>,
+    ) -> SampledExpectation {
+        let variance: f64 = terms
+            .iter()
+            .map(|(term, estimate)| term.coefficient.powi(2) * estimate.variance)
+            .sum();
+        let value = Estimate {
+            mean: terms
+                .iter()
+                .map(|(term, estimate)| term.coefficient * estimate.mean)
+                .sum(),
+            variance,
+            standard_error: (variance / shots as f64).sqrt(),
+        };
+        SampledExpectation {
+            shots,
+            groups,
+            value,
+            terms,
+        }
+    }
+}
+
+/// Terms that can be estimated from the same shots, because on every qubit they all act
+/// with either the identity or the same Pauli.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeasurementGroup {
+    /// The Pauli each qubit is measured in.
+    pub basis: BTreeMap<usize, Pauli>,
+    /// The indices of the terms in the group.
+    pub terms: Vec<usize>,
+}
+
+impl MeasurementGroup {
+    /// Returns the gates that rotate the measurement basis of the group onto the
+    /// computational basis, so that measuring each qubit in Z afterwards measures it in
+    /// the basis of the group.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::gates::gate::Gate;
+    /// use quantum_simulator::quantum::observable::parse_observable;
+    /// use quantum_simulator::quantum::register::QubitRef;
+    ///
+    /// let observable = parse_observable("1 ZXI\n2 IXZ").unwrap();
+    /// let groups = observable.measurement_groups();
+    /// assert_eq!(groups.len(), 1);
+    /// assert_eq!(groups[0].basis_change(), vec![Gate::H { target: QubitRef(1) }]);
+    /// ```
+    pub fn basis_change(&self) -> Vec<Gate> {
+        let half = 1.0 / 2.0_f64.sqrt();
+        self.basis
+            .iter()
+            .filter_map(|(qubit, pauli)| match pauli {
+                Pauli::X => Some(Gate::H {
+                    target: QubitRef(*qubit),
+                }),
+                // H S†, which takes Y to Z.
+                Pauli::Y => Some(Gate::Unitary {
+                    target: QubitRef(*qubit),
+                    matrix: [
+                        [Complex::new(half, 0.0), Complex::new(0.0, -half)],
+                        [Complex::new(half, 0.0), Complex::new(0.0, half)],
+                    ],
+                }),
+                Pauli::Z => None,
+            })
+            .collect()
+    }
+}
+
+impl Observable {
+    /// Returns the expectation value of the observable in the normalised state, or an
+    /// error if the Pauli strings do not match the number of qubits of the state.
+    pub fn expectation(&self, state: &State) -> io::Result<Expectation> {
+        if self.num_qubits != state.num_qubits() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format![
+                    "The observable acts on {} qubits but the state has {}",
+                    self.num_qubits,
+                    state.num_qubits()
+                ],
+            ));
+        }
+        let terms: Vec<(PauliTerm, f64)> = self
+            .terms
+            .iter()
+            .cloned()
+            .zip(state.expectations(&self.terms))
+            .collect();
+        let value = terms
+            .iter()
+            .map(|(term, expectation)| term.coefficient * expectation)
+            .sum();
+        Ok(Expectation { value, terms })
+    }
+
+    /// Sorts the terms into groups that can be measured with the same circuit, placing
+    /// each term in the first group it is compatible with.
+    pub fn measurement_groups(&self) -> Vec<MeasurementGroup> {
+        let mut groups: Vec<MeasurementGroup> = Vec::new();
+        for (index, term) in self.terms.iter().enumerate() {
+            let compatible = |group: &MeasurementGroup| {
+                term.paulis
+                    .iter()
+                    .all(|(qubit, pauli)| group.basis.get(qubit).is_none_or(|basis| basis == pauli))
+            };
+            let group = match groups.iter().position(compatible) {
+                Some(position) => &mut groups[position],
+                None => {
+                    groups.push(MeasurementGroup {
+                        basis: BTreeMap::new(),
+                        terms: Vec::new(),
+                    });
+                    groups.last_mut().unwrap()
+                }
+            };
+            group.basis.extend(term.paulis.iter().copied());
+            group.terms.push(index);
+        }
+        groups
+    }
+
+    /// Estimates the expectation value by taking `shots` shots of each measurement group:
+    /// rotating the state into the basis of the group and sampling its qubits in the
+    /// computational basis. Each Pauli string is then the parity of its qubits.
+    ///
+    /// # Examples
+    /// ```
+    /// use num::complex::Complex;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use quantum_simulator::quantum::observable::parse_observable;
+    /// use quantum_simulator::quantum::sampling::Rng;
+    /// use quantum_simulator::quantum::state::State;
+    /// use bitvec::prelude::*;
+    ///
+    /// // A Bell state, which is an eigenstate of XX, -YY and ZZ.
+    /// let amplitude = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+    /// let state = State::from_ket_vec(&vec![
+    ///     Ket::from_bit_vec(bitvec![0, 0], amplitude),
+    ///     Ket::from_bit_vec(bitvec![1, 1], amplitude),
+    /// ]).unwrap();
+    /// let observable = parse_observable("1 XX\n1 YY\n0.5 ZZ").unwrap();
+    /// let sampled = observable.sample(&state, 100, &mut Rng::new(0)).unwrap();
+    /// assert_eq!(sampled.groups, 3);
+    /// assert!((sampled.value.mean - 0.5).abs() < 1e-12);
+    /// assert!(sampled.value.standard_error < 1e-6);
+    /// ```
+    pub fn sample(
+        &self,
+        state: &State,
+        shots: usize,
+        rng: &mut Rng,
+    ) -> io::Result<SampledExpectation> {
+        // Checks the number of qubits.
+        self.expectation(state)?;
+        let groups = self.measurement_groups();
+        let mut estimates: Vec<Option<Estimate>> = vec![None; self.terms.len()];
+        let mut value = Estimate {
+            mean: 0.0,
+            variance: 0.0,
+            standard_error: 0.0,
+        };
+        for group in &groups {
+            let rotated = group
+                .basis_change()
+                .iter()
+                .fold(state.clone(), apply_gate_to_state);
+            let qubits: Vec<usize> = group.basis.keys().copied().collect();
+            let counts = sample_counts(&rotated.marginal_probabilities(&qubits), shots, rng);
+
+            // Each term's outcome is the parity of its qubits, as bits of the outcomes.
+            let masks: Vec<usize> = group
+                .terms
+                .iter()
+                .map(|index| {
+                    self.terms[*index]
+                        .paulis
+                        .iter()
+                        .map(|(qubit, _)| 1 << qubits.binary_search(qubit).unwrap())
+                        .sum()
+                })
+                .collect();
+            let sign = |outcome: usize, mask: usize| match (outcome & mask).count_ones() % 2 {
+                0 => 1.0,
+                _ => -1.0,
+            };
+            for (index, mask) in group.terms.iter().zip(&masks) {
+                let sum: f64 = counts
+                    .iter()
+                    .map(|(outcome, count)| sign(*outcome, *mask) * *count as f64)
+                    .sum();
+                estimates[*index] = Some(Estimate::from_sums(sum, shots as f64, shots));
+            }
+
+            // Terms in a group share shots, so their weighted sum is estimated per shot.
+            let (sum, sum_of_squares) =
+                counts
+                    .iter()
+                    .fold((0.0, 0.0), |(sum, squares), (outcome, count)| {
+                        let shot: f64 = group
+                            .terms
+                            .iter()
+                            .zip(&masks)
+                            .map(|(index, mask)| {
+                                self.terms[*index].coefficient * sign(*outcome, *mask)
+                            })
+                            .sum();
+                        let count = *count as f64;
+                        (sum + count * shot, squares + count * shot * shot)
+                    });
+            let estimate = Estimate::from_sums(sum, sum_of_squares, shots);
+            value.mean += estimate.mean;
+            value.variance += estimate.variance;
+        }
+        value.standard_error = (value.variance / shots as f64).sqrt();
+
+        let terms = self
+            .terms
+            .iter()
+            .cloned()
+            .zip(estimates.into_iter().map(Option::unwrap))
+            .collect();
+        Ok(SampledExpectation {
+            shots,
+            groups: groups.len(),
+            value,
+            terms,
+        })
+    }
+
+    /// Returns the observable with each qubit renumbered by `map`, acting on
+    /// `num_qubits` qubits.
+    pub fn remap(&self, num_qubits: usize, map: impl Fn(usize) -> usize) -> Observable {
+        let terms = self
+            .terms
+            .iter()
+            .map(|term| PauliTerm {
+                paulis: term
+                    .paulis
+                    .iter()
+                    .map(|(qubit, pauli)| (map(*qubit), *pauli))
+                    .collect(),
+                ..term.clone()
+            })
+            .collect();
+        Observable { num_qubits, terms }
+    }
+
+    /// Returns the qubits that some term acts on with something other than the identity,
+    /// in increasing order.
+    pub fn support(&self) -> Vec<usize> {
+        let mut qubits: Vec<usize> = self
+            .terms
+            .iter()
+            .flat_map(|term| term.paulis.iter().map(|(qubit, _)| *qubit))
+            .collect();
+        qubits.sort();
+        qubits.dedup();
+        qubits
+    }
+}
+
+/// Parses an observable file, where each line is a coefficient followed by a Pauli string
+/// of `I`, `X`, `Y` and `Z`, such as `0.5 ZZIII`. Blank lines and `#` comments are
+/// ignored, and every Pauli string must have the same length.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::observable::{parse_observable, Pauli};
+///
+/// let observable = parse_observable("# Transverse field Ising\n-1 ZZI\n-1 IZZ\n0.5 XII\n").unwrap();
+/// assert_eq!(observable.num_qubits, 3);
+/// assert_eq!(observable.terms[2].coefficient, 0.5);
+/// assert_eq!(observable.terms[2].paulis, vec![(2, Pauli::X)]);
+/// ```
+pub fn parse_observable(source: &str) -> io::Result<Observable> {
+    let mut num_qubits: Option<usize> = None;
+    let mut terms: Vec<PauliTerm> = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["{message} on line {line_number}"],
+            )
+        };
+
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [coefficient, label] = fields[..] else {
+            return Err(invalid("Expected 'coefficient PauliString'"));
+        };
+        let coefficient: f64 = match coefficient.parse() {
+            Ok(coefficient) if f64::is_finite(coefficient) => coefficient,
+            _ => return Err(invalid(&format!["Invalid coefficient '{coefficient}'"])),
+        };
+        let paulis = label
+            .chars()
+            .rev()
+            .enumerate()
+            .filter_map(|(qubit, c)| match c {
+                'I' => None,
+                'X' => Some(Ok((qubit, Pauli::X))),
+                'Y' => Some(Ok((qubit, Pauli::Y))),
+                'Z' => Some(Ok((qubit, Pauli::Z))),
+                _ => Some(Err(invalid(&format!["Invalid Pauli string '{label}'"]))),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let length = label.chars().count();
+        match num_qubits {
+            Some(num_qubits) if num_qubits != length => {
+                return Err(invalid(&format![
+                    "Pauli string '{label}' has {length} qubits but earlier terms have {num_qubits}"
+                ]))
+            }
+            _ => num_qubits = Some(length),
+        }
+        terms.push(PauliTerm {
+            coefficient,
+            label: label.to_string(),
+            paulis,
+        });
+    }
+
+    let Some(num_qubits) = num_qubits else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The observable has no terms",
+        ));
+    };
+    Ok(Observable { num_qubits, terms })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::ket::Ket;
+    use bitvec::prelude::*;
+
+    /// Tests the expectation values of each Pauli in the eigenstates of Y.
+    #[test]
+    fn test_expectation() {
+        // (|0⟩ + i|1⟩) / √2 on qubit 1, with qubit 0 in |1⟩ and an unnormalised amplitude.
+        let state = State::from_ket_vec(&vec![
+            Ket::from_bit_vec(bitvec![1, 0], Complex::new(2.0, 0.0)),
+            Ket::from_bit_vec(bitvec![1, 1], Complex::new(0.0, 2.0)),
+        ])
+        .unwrap();
+        let observable = parse_observable("1 XI\n1 YI\n1 ZI\n2 YZ\n1 II\n").unwrap();
+        let expectation = observable.expectation(&state).unwrap();
+        let values = expectation.terms.iter().map(|(_, value)| value);
+        for (value, expected) in values.zip([0.0, 1.0, 0.0, -1.0, 1.0]) {
+            assert!((value - expected).abs() < 1e-12, "{value} != {expected}");
+        }
+        assert!((expectation.value - 0.0).abs() < 1e-12);
+
+        let error = parse_observable("1 ZZZ").unwrap().expectation(&state);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "The observable acts on 3 qubits but the state has 2"
+        );
+    }
+
+    /// Tests that sampled estimates are unbiased and that their standard errors match the
+    /// spread of repeated estimates.
+    #[test]
+    fn test_sample() {
+        let expectation = Expectation {
+            value: 0.0,
+            terms: vec![(parse_observable("2 Z").unwrap().terms[0].clone(), 0.0)],
+        };
+        let mut rng = Rng::new(3);
+        let estimates: Vec<SampledExpectation> = (0..200)
+            .map(|_| expectation.sample(100, &mut rng))
+            .collect();
+
+        // Each shot is a fair ±1, so the variance of the total is 4 per shot.
+        let means: Vec<f64> = estimates.iter().map(|sampled| sampled.value.mean).collect();
+        let average = means.iter().sum::<f64>() / means.len() as f64;
+        let spread = (means
+            .iter()
+            .map(|mean| (mean - average).powi(2))
+            .sum::<f64>()
+            / (means.len() - 1) as f64)
+            .sqrt();
+        assert!(average.abs() < 0.1, "{average}");
+        assert!((spread - 0.2).abs() < 0.03, "{spread}");
+        let variances = estimates.iter().map(|sampled| sampled.value.variance);
+        assert!((variances.sum::<f64>() / estimates.len() as f64 - 4.0).abs() < 0.05);
+        for sampled in &estimates {
+            let value = sampled.value;
+            assert!((value.standard_error - (value.variance / 100.0).sqrt()).abs() < 1e-12);
+        }
+    }
+
+    /// Tests that malformed terms are reported with their line.
+    #[test]
+    fn test_parse_errors() {
+        for (source, message) in [
+            ("ZZ", "Expected 'coefficient PauliString' on line 1"),
+            (
+                "\n1 ZZ extra",
+                "Expected 'coefficient PauliString' on line 2",
+            ),
+            ("half ZZ", "Invalid coefficient 'half' on line 1"),
+            ("1 ZA", "Invalid Pauli string 'ZA' on line 1"),
+            (
+                "1 ZZ\n1 Z",
+                "Pauli string 'Z' has 1 qubits but earlier terms have 2 on line 2",
+            ),
+            ("# nothing\n", "The observable has no terms"),
+        ] {
+            assert_eq!(parse_observable(source).unwrap_err().to_string(), message);
+        }
+    }
+}
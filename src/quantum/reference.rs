@@ -0,0 +1,287 @@
+use crate::quantum::state::State;
+use crate::quantum::tolerance::Tolerance;
+use num::complex::Complex;
+use std::io::{self, Read};
+
+/// The header of every NumPy `.npy` file.
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// The result of comparing a simulated state against a reference state vector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    /// The fidelity `|<reference|state>|^2` of the normalised states.
+    pub fidelity: f64,
+    /// The largest absolute difference between corresponding amplitudes.
+    pub max_deviation: f64,
+    /// The lowest basis index whose amplitudes differ by more than the tolerance.
+    pub first_difference: Option<usize>,
+}
+
+/// Reads a one dimensional state vector from a NumPy `.npy` file. Complex (`c16`,
+/// `c8`) and real (`f8`) little endian arrays are supported, which covers the output of
+/// `numpy.save(path, statevector)` for the common simulators.
+///
+/// # Examples
+/// ```
+/// use num::complex::Complex;
+/// use quantum_simulator::quantum::reference::read_npy;
+///
+/// let header = "{'descr': '<f8', 'fortran_order': False, 'shape': (2,), }";
+/// let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+/// bytes.extend((header.len() as u16).to_le_bytes());
+/// bytes.extend(header.as_bytes());
+/// bytes.extend(0.6_f64.to_le_bytes());
+/// bytes.extend(0.8_f64.to_le_bytes());
+///
+/// let amplitudes = read_npy(bytes.as_slice()).unwrap();
+/// assert_eq!(amplitudes, vec![Complex::new(0.6, 0.0), Complex::new(0.8, 0.0)]);
+/// ```
+pub fn read_npy<R: Read>(mut reader: R) -> io::Result<Vec<Complex<f64>>> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic[..6] != NPY_MAGIC {
+        return Err(invalid_npy("missing NumPy header"));
+    }
+
+    // Version 1 files use a two byte header length, later versions use four bytes.
+    let header_length = if magic[6] == 1 {
+        let mut length = [0; 2];
+        reader.read_exact(&mut length)?;
+        u16::from_le_bytes(length) as usize
+    } else {
+        let mut length = [0; 4];
+        reader.read_exact(&mut length)?;
+        u32::from_le_bytes(length) as usize
+    };
+    let mut header = vec![0; header_length];
+    reader.read_exact(&mut header)?;
+    let header = String::from_utf8_lossy(&header);
+
+    let descr = header_value(&header, "descr")
+        .and_then(|descr| descr.strip_prefix(['\'', '"']))
+        .and_then(|descr| descr.split(['\'', '"']).next())
+        .ok_or(invalid_npy("missing 'descr'"))?;
+    if header_value(&header, "fortran_order").is_some_and(|order| order.starts_with("True")) {
+        return Err(invalid_npy("Fortran ordered arrays are not supported"));
+    }
+    let length = parse_shape(&header)?;
+
+    let element_size = match descr {
+        "<c16" => 16,
+        "<c8" | "<f8" => 8,
+        descr => return Err(invalid_npy(&format!["unsupported dtype {descr}"])),
+    };
+
+    let mut data = vec![0; length * element_size];
+    reader.read_exact(&mut data)?;
+    Ok(data
+        .chunks_exact(element_size)
+        .map(|bytes| match descr {
+            "<c16" => Complex::new(read_f64(&bytes[..8]), read_f64(&bytes[8..])),
+            "<c8" => Complex::new(read_f32(&bytes[..4]) as f64, read_f32(&bytes[4..]) as f64),
+            _ => Complex::new(read_f64(bytes), 0.0),
+        })
+        .collect())
+}
+
+/// Compares a state against a reference state vector indexed by basis state, with qubit
+/// 0 as the least significant bit. Returns an error if the reference does not have one
+/// amplitude per basis state.
+///
+/// # Examples
+/// ```
+/// use num::complex::Complex;
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::reference::compare;
+/// use quantum_simulator::quantum::state::State;
+/// use quantum_simulator::quantum::tolerance::Tolerance;
+///
+/// let mut state = State::new(1);
+/// state.add_or_insert(Ket::new_zero_ket(1));
+/// let reference = [Complex::new(0.0, 0.0), Complex::new(1.0, 0.0)];
+///
+/// let comparison = compare(&state, &reference, Tolerance::DEFAULT).unwrap();
+/// assert_eq!(comparison.fidelity, 0.0);
+/// assert_eq!(comparison.first_difference, Some(0));
+/// ```
+pub fn compare(
+    state: &State,
+    reference: &[Complex<f64>],
+    tolerance: Tolerance,
+) -> io::Result<Comparison> {
+    let expected_length = u32::try_from(state.num_qubits())
+        .ok()
+        .and_then(|num_qubits| 1_usize.checked_shl(num_qubits));
+    if expected_length != Some(reference.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format![
+                "Reference has {} amplitudes but the state has {} qubits",
+                reference.len(),
+                state.num_qubits()
+            ],
+        ));
+    }
+
+    let mut amplitudes = vec![Complex::new(0.0, 0.0); reference.len()];
+    for ket in state.kets() {
+        amplitudes[ket.basis_index()] = ket.amplitude;
+    }
+
+    let mut overlap = Complex::new(0.0, 0.0);
+    let mut max_deviation: f64 = 0.0;
+    let mut first_difference = None;
+    for (index, (amplitude, expected)) in amplitudes.iter().zip(reference).enumerate() {
+        overlap += expected.conj() * amplitude;
+        let deviation = (amplitude - expected).norm();
+        max_deviation = max_deviation.max(deviation);
+        if first_difference.is_none() && !tolerance.approx_eq_complex(*amplitude, *expected) {
+            first_difference = Some(index);
+        }
+    }
+
+    let norms = norm_sqr(&amplitudes) * norm_sqr(reference);
+    let fidelity = if norms > 0.0 {
+        overlap.norm_sqr() / norms
+    } else {
+        0.0
+    };
+
+    Ok(Comparison {
+        fidelity,
+        max_deviation,
+        first_difference,
+    })
+}
+
+fn norm_sqr(amplitudes: &[Complex<f64>]) -> f64 {
+    amplitudes.iter().map(Complex::norm_sqr).sum()
+}
+
+/// Finds the raw text of a value in the Python dictionary literal of an `.npy` header.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!["'{key}':"])? + key.len() + 3;
+    Some(header[start..].trim_start())
+}
+
+/// Parses the number of elements in the one dimensional `shape` of an `.npy` header.
+fn parse_shape(header: &str) -> io::Result<usize> {
+    let shape = header_value(header, "shape").ok_or(invalid_npy("missing 'shape'"))?;
+    let end = shape.find(')').ok_or(invalid_npy("malformed 'shape'"))?;
+    let dimensions: Vec<&str> = shape[1..end]
+        .split(',')
+        .map(str::trim)
+        .filter(|dimension| !dimension.is_empty())
+        .collect();
+    match dimensions.as_slice() {
+        [length] => length.parse().map_err(|_| invalid_npy("malformed 'shape'")),
+        _ => Err(invalid_npy("only one dimensional arrays are supported")),
+    }
+}
+
+fn read_f64(bytes: &[u8]) -> f64 {
+    f64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn read_f32(bytes: &[u8]) -> f32 {
+    f32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn invalid_npy(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!["Invalid .npy file: {reason}"],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::ket::Ket;
+    use bitvec::prelude::*;
+
+    /// Helper function to build an `.npy` file with the given header and data.
+    fn npy(version: u8, header: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = NPY_MAGIC.to_vec();
+        bytes.extend([version, 0]);
+        if version == 1 {
+            bytes.extend((header.len() as u16).to_le_bytes());
+        } else {
+            bytes.extend((header.len() as u32).to_le_bytes());
+        }
+        bytes.extend(header.as_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    /// Tests reading complex arrays in both header versions.
+    #[test]
+    fn test_read_npy_complex() {
+        let data: Vec<u8> = [0.5_f64, -0.5, 0.0, 1.0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let header = "{'descr': '<c16', 'fortran_order': False, 'shape': (2,), }\n";
+        let expected = vec![Complex::new(0.5, -0.5), Complex::new(0.0, 1.0)];
+        assert_eq!(
+            read_npy(npy(1, header, &data).as_slice()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            read_npy(npy(2, header, &data).as_slice()).unwrap(),
+            expected
+        );
+    }
+
+    /// Tests that unsupported or truncated files are rejected.
+    #[test]
+    fn test_read_npy_errors() {
+        let data = [0; 32];
+        let headers = [
+            "{'descr': '<i8', 'fortran_order': False, 'shape': (2,), }",
+            "{'descr': '<c16', 'fortran_order': False, 'shape': (2, 2), }",
+            "{'descr': '<c16', 'fortran_order': False, 'shape': (4,), }",
+            "{'fortran_order': False, 'shape': (2,), }",
+        ];
+        for header in headers {
+            assert!(read_npy(npy(1, header, &data[..16]).as_slice()).is_err());
+        }
+        assert!(read_npy(&b"NUMPY"[..]).is_err());
+    }
+
+    /// Tests comparing a state against matching and differing references.
+    #[test]
+    fn test_compare() {
+        let amplitude = 1.0 / 2.0_f64.sqrt();
+        let state = State::from_ket_vec(&vec![
+            Ket::from_bit_vec(bitvec![0, 0], Complex::new(amplitude, 0.0)),
+            Ket::from_bit_vec(bitvec![1, 1], Complex::new(amplitude, 0.0)),
+        ])
+        .unwrap();
+
+        let zero = Complex::new(0.0, 0.0);
+        let bell = [
+            Complex::new(amplitude, 0.0),
+            zero,
+            zero,
+            Complex::new(amplitude, 0.0),
+        ];
+        let comparison = compare(&state, &bell, Tolerance::absolute(1e-9)).unwrap();
+        assert!((comparison.fidelity - 1.0).abs() < 1e-12);
+        assert_eq!(comparison.first_difference, None);
+
+        let other = [
+            Complex::new(amplitude, 0.0),
+            zero,
+            Complex::new(amplitude, 0.0),
+            zero,
+        ];
+        let comparison = compare(&state, &other, Tolerance::absolute(1e-9)).unwrap();
+        assert!((comparison.fidelity - 0.25).abs() < 1e-12);
+        assert!((comparison.max_deviation - amplitude).abs() < 1e-12);
+        assert_eq!(comparison.first_difference, Some(2));
+
+        assert!(compare(&state, &bell[..2], Tolerance::absolute(1e-9)).is_err());
+    }
+}
@@ -1,6 +1,56 @@
+use std::fmt;
+
 /// A register in a quantum circuit.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Register {
     pub name: String,
     pub size: usize,
 }
+
+/// A qubit of the quantum register, by its index.
+///
+/// Gates refer to their qubits with a `QubitRef` rather than a bare index, so that a
+/// qubit cannot be mixed up with a classical bit, a count or another index.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::register::QubitRef;
+///
+/// let qubit = QubitRef(3);
+/// assert_eq!(qubit.index(), 3);
+/// assert_eq!(qubit.to_string(), "q[3]");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QubitRef(pub usize);
+
+impl QubitRef {
+    /// Returns the index of this qubit in the register.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for QubitRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "q[{}]", self.0)
+    }
+}
+
+/// A classical bit, by its index among the bits of every classical register in the order
+/// they were declared.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::register::ClbitRef;
+///
+/// assert_eq!(ClbitRef(2).index(), 2);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClbitRef(pub usize);
+
+impl ClbitRef {
+    /// Returns the index of this classical bit.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
@@ -0,0 +1,305 @@
+use crate::quantum::state::CompensatedSum;
+use std::collections::BTreeMap;
+
+/// A small, fast pseudo-random number generator (SplitMix64) for sampling measurement
+/// outcomes.
+///
+/// It is seeded explicitly so that sampled results are reproducible, and is not suitable
+/// for anything that needs cryptographic randomness.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::sampling::Rng;
+///
+/// let mut rng = Rng::new(7);
+/// let sample = rng.next_f64();
+/// assert!((0.0..1.0).contains(&sample));
+/// assert_eq!(Rng::new(7).next_f64(), sample);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates a new `Rng` from a seed.
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Returns the next 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number uniformly distributed in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        // The top 53 bits fill the mantissa of a double exactly.
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Draws `shots` outcomes from a probability distribution and returns how many times
+/// each outcome was drawn.
+///
+/// # Examples
+/// ```
+/// use std::collections::BTreeMap;
+/// use quantum_simulator::quantum::sampling::{sample_counts, Rng};
+///
+/// let probabilities = BTreeMap::from([(0b00, 0.5), (0b11, 0.5)]);
+/// let counts = sample_counts(&probabilities, 100, &mut Rng::new(0));
+/// assert_eq!(counts.values().sum::<usize>(), 100);
+/// assert!(counts.keys().all(|outcome| probabilities.contains_key(outcome)));
+/// ```
+pub fn sample_counts(
+    probabilities: &BTreeMap<usize, f64>,
+    shots: usize,
+    rng: &mut Rng,
+) -> BTreeMap<usize, usize> {
+    let outcomes: Vec<usize> = probabilities.keys().copied().collect();
+    let table = AliasTable::new(probabilities.values().copied());
+
+    let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+    for _ in 0..shots {
+        *counts.entry(outcomes[table.sample(rng)]).or_default() += 1;
+    }
+    counts
+}
+
+/// The running totals of a list of probabilities, for drawing indices into the list in
+/// proportion to them.
+///
+/// The totals are summed with compensated summation, so that the rounding errors of
+/// millions of additions do not build up and shift the probabilities of the last
+/// outcomes. The probabilities need not sum to one.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::sampling::{CumulativeProbabilities, Rng};
+///
+/// let cumulative = CumulativeProbabilities::new([0.1; 10]);
+/// assert_eq!(cumulative.total(), 1.0);
+///
+/// let cumulative = CumulativeProbabilities::new([0.0, 2.0, 0.0]);
+/// assert_eq!(cumulative.sample(&mut Rng::new(0)), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct CumulativeProbabilities {
+    cumulative: Vec<f64>,
+}
+
+impl CumulativeProbabilities {
+    /// Sums the probabilities.
+    pub fn new(probabilities: impl IntoIterator<Item = f64>) -> Self {
+        let mut sum = CompensatedSum::default();
+        let cumulative = probabilities
+            .into_iter()
+            .map(|probability| {
+                sum.add(probability);
+                sum.value()
+            })
+            .collect();
+        Self { cumulative }
+    }
+
+    /// Returns the sum of all of the probabilities.
+    pub fn total(&self) -> f64 {
+        self.cumulative.last().copied().unwrap_or(0.0)
+    }
+
+    /// Draws an index in proportion to the probabilities. There must be at least one.
+    pub fn sample(&self, rng: &mut Rng) -> usize {
+        let target = rng.next_f64() * self.total();
+        // Rounding can leave the target just past the last cumulative probability.
+        self.cumulative
+            .partition_point(|probability| *probability <= target)
+            .min(self.cumulative.len() - 1)
+    }
+}
+
+/// A table for drawing indices in proportion to a list of probabilities in constant time
+/// per draw, with Vose's alias method.
+///
+/// Each entry of the table is drawn uniformly and then either kept or swapped for its
+/// alias, so building the table takes time in proportion to the number of
+/// probabilities once, after which any number of draws cost the same. The
+/// probabilities need not sum to one.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::sampling::{AliasTable, Rng};
+///
+/// let table = AliasTable::new([0.0, 3.0, 1.0]);
+/// let mut rng = Rng::new(0);
+/// let draws: Vec<usize> = (0..1000).map(|_| table.sample(&mut rng)).collect();
+/// assert!(draws.iter().all(|index| *index != 0));
+/// assert!(draws.iter().filter(|index| **index == 1).count() > 600);
+/// ```
+#[derive(Debug, Clone)]
+pub struct AliasTable {
+    /// The probability of keeping each entry rather than taking its alias.
+    keep: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    /// Builds the table for the probabilities.
+    pub fn new(probabilities: impl IntoIterator<Item = f64>) -> Self {
+        let probabilities: Vec<f64> = probabilities.into_iter().collect();
+        let mut total = CompensatedSum::default();
+        probabilities
+            .iter()
+            .for_each(|probability| total.add(*probability));
+        let scale = probabilities.len() as f64 / total.value();
+        let mut keep: Vec<f64> = probabilities
+            .iter()
+            .map(|probability| probability * scale)
+            .collect();
+        let mut alias: Vec<usize> = (0..keep.len()).collect();
+
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..keep.len()).partition(|index| keep[*index] < 1.0);
+        while let (Some(&under), Some(&over)) = (small.last(), large.last()) {
+            small.pop();
+            alias[under] = over;
+            // The excess of the large entry fills the rest of the small one.
+            keep[over] = (keep[over] + keep[under]) - 1.0;
+            if keep[over] < 1.0 {
+                large.pop();
+                small.push(over);
+            }
+        }
+        // Whatever is left is only short of or over one by rounding.
+        for index in small.into_iter().chain(large) {
+            keep[index] = 1.0;
+        }
+        Self { keep, alias }
+    }
+
+    /// Draws an index in proportion to the probabilities. At least one must be positive.
+    pub fn sample(&self, rng: &mut Rng) -> usize {
+        let index = ((rng.next_f64() * self.keep.len() as f64) as usize).min(self.keep.len() - 1);
+        if rng.next_f64() < self.keep[index] {
+            index
+        } else {
+            self.alias[index]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::iter;
+
+    /// Tests that samples are spread evenly over the unit interval.
+    #[test]
+    fn test_uniform() {
+        let mut rng = Rng::new(0);
+        let mut buckets = [0; 10];
+        let num_samples = 100_000;
+        for _ in 0..num_samples {
+            buckets[(rng.next_f64() * 10.0) as usize] += 1;
+        }
+        for count in buckets {
+            assert!((count as f64 / num_samples as f64 - 0.1).abs() < 0.005);
+        }
+    }
+
+    /// Tests that outcomes are drawn in proportion to their probabilities.
+    #[test]
+    fn test_sample_counts() {
+        let probabilities = BTreeMap::from([(1, 0.1), (2, 0.0), (5, 0.6), (6, 0.3)]);
+        let counts = sample_counts(&probabilities, 100_000, &mut Rng::new(2));
+        assert_eq!(counts.get(&2), None);
+        for (outcome, probability) in probabilities {
+            let fraction = counts.get(&outcome).copied().unwrap_or(0) as f64 / 100_000.0;
+            assert!(
+                (fraction - probability).abs() < 0.01,
+                "{outcome}: {fraction}"
+            );
+        }
+    }
+
+    /// Tests that the counts of many outcomes pass a chi-squared goodness of fit test.
+    #[test]
+    fn test_sample_counts_chi_squared() {
+        // Probabilities proportional to 1, 2, ..., 20.
+        let probabilities: BTreeMap<usize, f64> = (0..20)
+            .map(|outcome| (outcome, (outcome + 1) as f64 / 210.0))
+            .collect();
+        let shots = 200_000;
+        for seed in 0..5 {
+            let counts = sample_counts(&probabilities, shots, &mut Rng::new(seed));
+            let chi_squared: f64 = probabilities
+                .iter()
+                .map(|(outcome, probability)| {
+                    let expected = probability * shots as f64;
+                    let observed = counts.get(outcome).copied().unwrap_or(0) as f64;
+                    (observed - expected).powi(2) / expected
+                })
+                .sum();
+            // The 99.9th percentile of the chi-squared distribution with 19 degrees of
+            // freedom.
+            assert!(chi_squared < 43.82, "seed {seed}: {chi_squared}");
+        }
+    }
+
+    /// Tests that the running totals of many small probabilities after a large one do not
+    /// drift, which would change the probability of the last outcome.
+    #[test]
+    fn test_cumulative_probabilities_tail() {
+        let num_small = 1_000_000;
+        let small = 0.5 / num_small as f64;
+        let probabilities = iter::once(0.5).chain(iter::repeat_n(small, num_small));
+        let cumulative = CumulativeProbabilities::new(probabilities.clone());
+        assert!((cumulative.total() - 1.0).abs() < 1e-15);
+
+        let naive: f64 = probabilities.sum();
+        assert!((naive - 1.0).abs() > 1e-12, "{naive}");
+
+        // The last outcome is drawn when the target is in its interval.
+        let last = cumulative.cumulative[num_small] - cumulative.cumulative[num_small - 1];
+        assert!((last / small - 1.0).abs() < 1e-6, "{last}");
+    }
+
+    /// Tests that the alias table gives each index exactly its share of the probability,
+    /// including a long tail of small probabilities.
+    #[test]
+    fn test_alias_table() {
+        let probabilities: Vec<f64> = [0.5, 0.0, 0.25, 0.125]
+            .into_iter()
+            .chain(iter::repeat_n(0.125 / 1000.0, 1000))
+            .collect();
+        let table = AliasTable::new(probabilities.iter().copied());
+        let num_entries = probabilities.len() as f64;
+        let mut shares: Vec<f64> = table.keep.iter().map(|keep| keep / num_entries).collect();
+        for (index, alias) in table.alias.iter().enumerate() {
+            if *alias != index {
+                shares[*alias] += (1.0 - table.keep[index]) / num_entries;
+            }
+        }
+        for (index, (share, probability)) in shares.iter().zip(&probabilities).enumerate() {
+            assert!((share - probability).abs() < 1e-12, "{index}: {share}");
+        }
+        assert_eq!(shares[1], 0.0);
+
+        let mut rng = Rng::new(3);
+        let mut counts = vec![0; 4];
+        for _ in 0..100_000 {
+            counts[table.sample(&mut rng).min(3)] += 1;
+        }
+        for (count, probability) in counts.iter().zip([0.5, 0.0, 0.25, 0.25]) {
+            assert!(
+                (*count as f64 / 100_000.0 - probability).abs() < 0.01,
+                "{counts:?}"
+            );
+        }
+    }
+}
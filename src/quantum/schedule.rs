@@ -0,0 +1,354 @@
+use std::fmt;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// The width in pixels of the time axis of [`Schedule::to_svg`].
+const SVG_TIMELINE_WIDTH: f64 = 800.0;
+/// The height in pixels of each qubit's row of [`Schedule::to_svg`].
+const SVG_ROW_HEIGHT: usize = 30;
+/// The width in pixels of the qubit labels left of the timelines of [`Schedule::to_svg`].
+const SVG_LABEL_WIDTH: usize = 40;
+
+/// An operation placed on a qubit's timeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledOperation {
+    pub name: String,
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+impl ScheduledOperation {
+    /// Returns the time at which this operation finishes, which is at most the longest
+    /// [`Duration`].
+    pub fn end(&self) -> Duration {
+        self.start.saturating_add(self.duration)
+    }
+}
+
+/// A per-qubit timeline of the operations in a circuit.
+///
+/// Operations are scheduled as soon as possible: each one starts once every qubit it
+/// acts on has finished its previous operation.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use quantum_simulator::quantum::schedule::Schedule;
+///
+/// let mut schedule = Schedule::new(2);
+/// schedule.push("delay", &[0], Duration::from_nanos(100));
+/// let start = schedule.push("cx", &[0, 1], Duration::ZERO);
+/// assert_eq!(start, Duration::from_nanos(100));
+/// assert_eq!(schedule.timeline(1)[0].start, Duration::from_nanos(100));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    timelines: Vec<Vec<ScheduledOperation>>,
+}
+
+impl Schedule {
+    /// Creates an empty schedule for the given number of qubits.
+    pub fn new(num_qubits: usize) -> Self {
+        Self {
+            timelines: vec![Vec::new(); num_qubits],
+        }
+    }
+
+    /// Schedules an operation on the given qubits and returns its start time.
+    pub fn push(&mut self, name: &str, qubits: &[usize], duration: Duration) -> Duration {
+        let start = qubits
+            .iter()
+            .map(|qubit| self.qubit_end(*qubit))
+            .max()
+            .unwrap_or_default();
+        for qubit in qubits {
+            self.timelines[*qubit].push(ScheduledOperation {
+                name: name.to_string(),
+                start,
+                duration,
+            });
+        }
+        start
+    }
+
+    /// Returns the operations scheduled on a qubit in the order they start.
+    pub fn timeline(&self, qubit: usize) -> &[ScheduledOperation] {
+        &self.timelines[qubit]
+    }
+
+    /// Returns the time at which the last operation of the schedule finishes.
+    pub fn total_duration(&self) -> Duration {
+        (0..self.timelines.len())
+            .map(|qubit| self.qubit_end(qubit))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of qubits in the schedule.
+    pub fn num_qubits(&self) -> usize {
+        self.timelines.len()
+    }
+
+    /// Returns the start and end of each period in which a qubit has no operation, up to
+    /// the total duration of the schedule.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use quantum_simulator::quantum::schedule::Schedule;
+    ///
+    /// let mut schedule = Schedule::new(2);
+    /// schedule.push("delay", &[0], Duration::from_nanos(100));
+    /// schedule.push("h", &[1], Duration::ZERO);
+    /// let idle = schedule.idle_periods(1);
+    /// assert_eq!(idle, vec![(Duration::ZERO, Duration::from_nanos(100))]);
+    /// assert!(schedule.idle_periods(0).is_empty());
+    /// ```
+    pub fn idle_periods(&self, qubit: usize) -> Vec<(Duration, Duration)> {
+        let mut idle = Vec::new();
+        let mut free_from = Duration::ZERO;
+        for operation in &self.timelines[qubit] {
+            if operation.start > free_from {
+                idle.push((free_from, operation.start));
+            }
+            free_from = free_from.max(operation.end());
+        }
+        let total = self.total_duration();
+        if total > free_from {
+            idle.push((free_from, total));
+        }
+        idle
+    }
+
+    /// Returns the schedule as a JSON object with the total duration and, for each qubit,
+    /// its operations and idle periods. Times are in nanoseconds.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use quantum_simulator::json::parse_json;
+    /// use quantum_simulator::quantum::schedule::Schedule;
+    ///
+    /// let mut schedule = Schedule::new(1);
+    /// schedule.push("delay", &[0], Duration::from_nanos(50));
+    /// let json = parse_json(&schedule.to_json()).unwrap();
+    /// assert!(json.get("total_duration_ns").is_some());
+    /// ```
+    pub fn to_json(&self) -> String {
+        let period = |start: Duration, end: Duration| {
+            format![
+                "\"start_ns\":{},\"end_ns\":{}",
+                start.as_nanos(),
+                end.as_nanos()
+            ]
+        };
+        let qubits: Vec<String> = (0..self.num_qubits())
+            .map(|qubit| {
+                // Operation names are QASM identifiers, which need no escaping.
+                let operations: Vec<String> = self.timelines[qubit]
+                    .iter()
+                    .map(|operation| {
+                        format![
+                            "{{\"name\":\"{}\",{}}}",
+                            operation.name,
+                            period(operation.start, operation.end())
+                        ]
+                    })
+                    .collect();
+                let idle: Vec<String> = self
+                    .idle_periods(qubit)
+                    .into_iter()
+                    .map(|(start, end)| format!["{{{}}}", period(start, end)])
+                    .collect();
+                format![
+                    "{{\"qubit\":{qubit},\"operations\":[{}],\"idle\":[{}]}}",
+                    operations.join(","),
+                    idle.join(",")
+                ]
+            })
+            .collect();
+        format![
+            "{{\"total_duration_ns\":{},\"qubits\":[{}]}}",
+            self.total_duration().as_nanos(),
+            qubits.join(",")
+        ]
+    }
+
+    /// Returns the schedule as an SVG Gantt chart with one row per qubit.
+    ///
+    /// Operations that take time are drawn as blue bars and idle periods as gray ones.
+    /// Operations that take no time, such as gates, are drawn as thin marks, and each
+    /// mark and bar has the operation's name and times as its tooltip.
+    pub fn to_svg(&self) -> String {
+        let total = self.total_duration().as_nanos() as f64;
+        let x = |time: Duration| {
+            let fraction = match total > 0.0 {
+                true => time.as_nanos() as f64 / total,
+                false => 0.0,
+            };
+            SVG_LABEL_WIDTH as f64 + fraction * SVG_TIMELINE_WIDTH
+        };
+        let width = SVG_LABEL_WIDTH as f64 + SVG_TIMELINE_WIDTH + 10.0;
+        let height = SVG_ROW_HEIGHT * (self.num_qubits() + 1);
+        let mut svg = format![
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+             font-family=\"monospace\" font-size=\"12\">\n"
+        ];
+        for qubit in 0..self.num_qubits() {
+            let top = qubit * SVG_ROW_HEIGHT + 5;
+            let bar_height = SVG_ROW_HEIGHT - 10;
+            writeln!(
+                svg,
+                "<text x=\"0\" y=\"{}\">q{qubit}</text>",
+                top + bar_height - 5
+            )
+            .unwrap();
+            for (start, end) in self.idle_periods(qubit) {
+                writeln!(
+                    svg,
+                    "<rect x=\"{:.2}\" y=\"{top}\" width=\"{:.2}\" height=\"{bar_height}\" \
+                     fill=\"#ddd\"><title>idle {}-{}ns</title></rect>",
+                    x(start),
+                    x(end) - x(start),
+                    start.as_nanos(),
+                    end.as_nanos()
+                )
+                .unwrap();
+            }
+            for operation in &self.timelines[qubit] {
+                let (fill, bar_width, times) = match operation.duration.is_zero() {
+                    true => ("#333", 2.0, format!["{}ns", operation.start.as_nanos()]),
+                    false => (
+                        "#4a90d9",
+                        x(operation.end()) - x(operation.start),
+                        format![
+                            "{}-{}ns",
+                            operation.start.as_nanos(),
+                            operation.end().as_nanos()
+                        ],
+                    ),
+                };
+                writeln!(
+                    svg,
+                    "<rect x=\"{:.2}\" y=\"{top}\" width=\"{bar_width:.2}\" \
+                     height=\"{bar_height}\" fill=\"{fill}\"><title>{} {times}</title></rect>",
+                    x(operation.start),
+                    operation.name
+                )
+                .unwrap();
+            }
+        }
+        writeln!(
+            svg,
+            "<text x=\"{SVG_LABEL_WIDTH}\" y=\"{}\">Total duration: {}ns</text>\n</svg>",
+            height - 10,
+            self.total_duration().as_nanos()
+        )
+        .unwrap();
+        svg
+    }
+
+    fn qubit_end(&self, qubit: usize) -> Duration {
+        self.timelines[qubit]
+            .last()
+            .map(ScheduledOperation::end)
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (qubit, timeline) in self.timelines.iter().enumerate() {
+            write!(f, "{qubit}:")?;
+            for operation in timeline {
+                if operation.duration.is_zero() {
+                    write!(f, " {}@{}ns", operation.name, operation.start.as_nanos())?;
+                } else {
+                    write!(
+                        f,
+                        " {}@{}-{}ns",
+                        operation.name,
+                        operation.start.as_nanos(),
+                        operation.end().as_nanos()
+                    )?;
+                }
+            }
+            writeln!(f)?;
+        }
+        write!(f, "Total duration: {}ns", self.total_duration().as_nanos())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Tests that operations wait for every qubit they act on.
+    #[test]
+    fn test_schedule_as_soon_as_possible() {
+        let mut schedule = Schedule::new(3);
+        schedule.push("delay", &[0], Duration::from_nanos(100));
+        schedule.push("delay", &[1], Duration::from_nanos(50));
+        assert_eq!(
+            schedule.push("cx", &[1, 0], Duration::ZERO),
+            Duration::from_nanos(100)
+        );
+        assert_eq!(
+            schedule.push("delay", &[1], Duration::from_nanos(20)),
+            Duration::from_nanos(100)
+        );
+        assert_eq!(schedule.push("h", &[2], Duration::ZERO), Duration::ZERO);
+        assert_eq!(schedule.total_duration(), Duration::from_nanos(120));
+        assert_eq!(schedule.timeline(0).len(), 2);
+    }
+
+    /// Tests the per-qubit timeline report.
+    #[test]
+    fn test_schedule_display() {
+        let mut schedule = Schedule::new(2);
+        schedule.push("h", &[0], Duration::ZERO);
+        schedule.push("delay", &[1], Duration::from_nanos(100));
+        schedule.push("cx", &[0, 1], Duration::ZERO);
+        assert_eq!(
+            schedule.to_string(),
+            "0: h@0ns cx@100ns\n1: delay@0-100ns cx@100ns\nTotal duration: 100ns"
+        );
+    }
+
+    /// Tests the idle periods and the JSON timeline, including a qubit that waits for
+    /// another before its first operation.
+    #[test]
+    fn test_schedule_timeline() {
+        let mut schedule = Schedule::new(3);
+        schedule.push("delay", &[0], Duration::from_nanos(100));
+        schedule.push("cx", &[0, 1], Duration::ZERO);
+        schedule.push("delay", &[1], Duration::from_nanos(20));
+        assert_eq!(
+            schedule.idle_periods(0),
+            vec![(Duration::from_nanos(100), Duration::from_nanos(120))]
+        );
+        assert_eq!(
+            schedule.idle_periods(1),
+            vec![(Duration::ZERO, Duration::from_nanos(100))]
+        );
+        assert_eq!(
+            schedule.idle_periods(2),
+            vec![(Duration::ZERO, Duration::from_nanos(120))]
+        );
+        assert_eq!(
+            schedule.to_json(),
+            concat![
+                r#"{"total_duration_ns":120,"qubits":["#,
+                r#"{"qubit":0,"operations":[{"name":"delay","start_ns":0,"end_ns":100},"#,
+                r#"{"name":"cx","start_ns":100,"end_ns":100}],"idle":[{"start_ns":100,"end_ns":120}]},"#,
+                r#"{"qubit":1,"operations":[{"name":"cx","start_ns":100,"end_ns":100},"#,
+                r#"{"name":"delay","start_ns":100,"end_ns":120}],"idle":[{"start_ns":0,"end_ns":100}]},"#,
+                r#"{"qubit":2,"operations":[],"idle":[{"start_ns":0,"end_ns":120}]}]}"#,
+            ]
+        );
+        let svg = schedule.to_svg();
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<title>idle").count(), 3);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::gates::gate::apply_gate_to_state;
+use crate::quantum::observable::{Estimate, MeasurementGroup, Observable, Pauli, PauliTerm};
+use crate::quantum::sampling::{sample_counts, Rng};
+use crate::quantum::state::State;
+use std::collections::BTreeMap;
+use std::io;
+
+/// The expectation value of an observable and of each of its terms estimated from a
+/// classical shadow, alongside the exact values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowEstimate {
+    /// The number of shots, each measured in its own random setting.
+    pub shots: usize,
+    /// The number of distinct settings among the shots.
+    pub settings: usize,
+    /// The estimated weighted sum of the terms, and its exact value.
+    pub value: (Estimate, f64),
+    /// Each term with the estimated and exact expectation values of its Pauli string,
+    /// without the coefficient.
+    pub terms: Vec<(PauliTerm, Estimate, f64)>,
+}
+
+/// Estimates the expectation value of an observable from a classical shadow of `shots`
+/// shots (Huang, Kueng and Preskill, 2020).
+///
+/// Each shot measures every qubit the observable acts on in a basis drawn uniformly from
+/// X, Y and Z, which is what a random single qubit Clifford followed by a measurement in
+/// Z amounts to. A shot estimates a Pauli string of weight `w` as `3^w` times the parity
+/// of its outcomes if it measured each of its qubits in the matching basis, and as zero
+/// otherwise. These estimates are unbiased, so they are averaged over the shots.
+///
+/// Unlike [`Observable::sample`], the shots do not depend on the observable beyond the
+/// qubits it acts on, so the same shots estimate every term.
+///
+/// # Examples
+/// ```
+/// use bitvec::prelude::*;
+/// use num::complex::Complex;
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::observable::parse_observable;
+/// use quantum_simulator::quantum::sampling::Rng;
+/// use quantum_simulator::quantum::shadows::classical_shadow;
+/// use quantum_simulator::quantum::state::State;
+///
+/// let amplitude = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+/// let state = State::from_ket_vec(&vec![
+///     Ket::from_bit_vec(bitvec![0, 0], amplitude),
+///     Ket::from_bit_vec(bitvec![1, 1], amplitude),
+/// ]).unwrap();
+/// let observable = parse_observable("1 XX\n0.5 ZZ").unwrap();
+/// let shadow = classical_shadow(&state, &observable, 2000, &mut Rng::new(0)).unwrap();
+/// let (estimate, exact) = shadow.value;
+/// assert_eq!(exact, 1.5);
+/// assert!((estimate.mean - exact).abs() < 4.0 * estimate.standard_error);
+/// ```
+pub fn classical_shadow(
+    state: &State,
+    observable: &Observable,
+    shots: usize,
+    rng: &mut Rng,
+) -> io::Result<ShadowEstimate> {
+    // Checks the number of qubits.
+    let expectation = observable.expectation(state)?;
+    let qubits = observable.support();
+
+    // Shots in the same setting are sampled together.
+    let bases = [Pauli::X, Pauli::Y, Pauli::Z];
+    let mut settings: BTreeMap<Vec<Pauli>, usize> = BTreeMap::new();
+    for _ in 0..shots {
+        let setting = qubits
+            .iter()
+            .map(|_| bases[(rng.next_f64() * 3.0) as usize])
+            .collect();
+        *settings.entry(setting).or_default() += 1;
+    }
+
+    let num_terms = observable.terms.len();
+    let mut sums = vec![(0.0, 0.0); num_terms];
+    let mut value_sums = (0.0, 0.0);
+    for (setting, setting_shots) in &settings {
+        let group = MeasurementGroup {
+            basis: qubits
+                .iter()
+                .copied()
+                .zip(setting.iter().copied())
+                .collect(),
+            terms: Vec::new(),
+        };
+        let rotated = group
+            .basis_change()
+            .iter()
+            .fold(state.clone(), apply_gate_to_state);
+        let counts = sample_counts(
+            &rotated.marginal_probabilities(&qubits),
+            *setting_shots,
+            rng,
+        );
+
+        // The mask of the outcome bits of each term measured by this setting.
+        let masks: Vec<Option<usize>> = observable
+            .terms
+            .iter()
+            .map(|term| {
+                term.paulis.iter().try_fold(0, |mask, (qubit, pauli)| {
+                    let position = qubits.binary_search(qubit).unwrap();
+                    (setting[position] == *pauli).then_some(mask | 1 << position)
+                })
+            })
+            .collect();
+        for (outcome, count) in &counts {
+            let count = *count as f64;
+            let mut shot_value = 0.0;
+            for (index, term) in observable.terms.iter().enumerate() {
+                let Some(mask) = masks[index] else {
+                    continue;
+                };
+                let sign = match (outcome & mask).count_ones() % 2 {
+                    0 => 1.0,
+                    _ => -1.0,
+                };
+                let estimate = sign * 3.0_f64.powi(term.paulis.len() as i32);
+                sums[index].0 += count * estimate;
+                sums[index].1 += count * estimate * estimate;
+                shot_value += term.coefficient * estimate;
+            }
+            value_sums.0 += count * shot_value;
+            value_sums.1 += count * shot_value * shot_value;
+        }
+    }
+
+    let terms = expectation
+        .terms
+        .into_iter()
+        .zip(sums)
+        .map(|((term, exact), (sum, sum_of_squares))| {
+            (term, Estimate::from_sums(sum, sum_of_squares, shots), exact)
+        })
+        .collect();
+    Ok(ShadowEstimate {
+        shots,
+        settings: settings.len(),
+        value: (
+            Estimate::from_sums(value_sums.0, value_sums.1, shots),
+            expectation.value,
+        ),
+        terms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::gate::Gate;
+    use crate::quantum::ket::Ket;
+    use crate::quantum::observable::parse_observable;
+    use crate::quantum::register::QubitRef;
+
+    /// Tests that the shadow estimates of the terms of a GHZ state are within their error
+    /// bars, and that the weight three term is only measured by settings that match it.
+    #[test]
+    fn test_ghz_state() {
+        let mut state = State::new(3);
+        state.add_or_insert(Ket::new_zero_ket(3));
+        let gates = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::CX {
+                control: QubitRef(0),
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(2),
+            },
+        ];
+        let state = gates.iter().fold(state, apply_gate_to_state);
+        let observable = parse_observable("1 ZZI\n-0.5 IZZ\n2 XXX\n1 IIX").unwrap();
+        let shots = 20000;
+        let shadow = classical_shadow(&state, &observable, shots, &mut Rng::new(3)).unwrap();
+        assert_eq!(shadow.shots, shots);
+        assert_eq!(shadow.settings, 27);
+
+        let exact: Vec<f64> = shadow.terms.iter().map(|(_, _, exact)| *exact).collect();
+        assert_eq!(exact, [1.0, 1.0, 1.0, 0.0]);
+        for (term, estimate, exact) in &shadow.terms {
+            assert!(
+                (estimate.mean - exact).abs() < 4.0 * estimate.standard_error,
+                "{} {estimate:?}",
+                term.label
+            );
+        }
+        let (estimate, exact) = shadow.value;
+        assert_eq!(exact, 2.5);
+        assert!((estimate.mean - exact).abs() < 4.0 * estimate.standard_error);
+
+        // XXX is only estimated by the shots in the XXX setting, as +27 each.
+        let (_, xxx, _) = &shadow.terms[2];
+        assert!((xxx.variance - (27.0 * xxx.mean - xxx.mean.powi(2))).abs() < 0.1);
+
+        let error = classical_shadow(
+            &state,
+            &parse_observable("1 ZZ").unwrap(),
+            10,
+            &mut Rng::new(0),
+        );
+        assert!(error.is_err());
+    }
+}
@@ -1,11 +1,79 @@
+use crate::quantum::dense::MAX_DENSE_QUBITS;
 use crate::quantum::ket::Ket;
-use std::collections::HashSet;
+use crate::quantum::metrics::Metrics;
+use crate::quantum::observable::{Pauli, PauliTerm};
+use crate::quantum::tolerance::Tolerance;
+use bitvec::prelude::*;
+use num::complex::Complex;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
 use std::fmt;
+use std::io;
 
-#[derive(Debug)]
+/// How the amplitudes of kets that collide while applying a gate are summed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Accumulation {
+    /// Add each contribution to the ket as it is produced.
+    #[default]
+    Sequential,
+    /// Collect all contributions to a ket, sort them by magnitude and add them with
+    /// compensated (Neumaier) summation, which bounds the growth of rounding error.
+    Compensated,
+}
+
+/// Why a state could not be created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// No kets were given, so the number of qubits is not known. [`State::empty`] creates
+    /// a state with no kets on purpose.
+    NoKets,
+    /// A ket has a different number of qubits from the first one.
+    MismatchedQubits { expected: usize, found: usize },
+    /// The state has more qubits than the representation supports.
+    TooManyQubits { num_qubits: usize, max: usize },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NoKets => write!(f, "A state needs at least one ket"),
+            StateError::MismatchedQubits { expected, found } => {
+                write!(f, "A ket has {found} qubits but the state has {expected}")
+            }
+            StateError::TooManyQubits { num_qubits, max } => write!(
+                f,
+                "The state has {num_qubits} qubits but at most {max} are supported"
+            ),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+impl From<StateError> for io::Error {
+    fn from(error: StateError) -> Self {
+        let kind = match error {
+            StateError::TooManyQubits { .. } => io::ErrorKind::OutOfMemory,
+            _ => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// A sparse state, made of the kets with a non-zero amplitude.
+///
+/// The kets are only reachable through methods, such as [`State::kets`] and
+/// [`State::add_or_insert`], which keep the merging, pruning and metrics of the state
+/// consistent with them.
+#[derive(Debug, Clone)]
 pub struct State {
-    pub kets: HashSet<Ket>,
+    kets: HashSet<Ket>,
     num_qubits: usize,
+    canonical: bool,
+    accumulation: Accumulation,
+    tolerance: Tolerance,
+    pruned_probability: f64,
+    metrics: Metrics,
 }
 
 impl State {
@@ -18,21 +86,41 @@ impl State {
     ///
     /// let state = State::new(3);
     /// assert_eq!(state.num_qubits(), 3);
-    /// assert!(state.kets.is_empty());
+    /// assert!(state.is_empty());
     /// ```
     pub fn new(num_qubits: usize) -> Self {
-        return Self {
+        Self {
             kets: HashSet::new(),
             num_qubits,
-        };
+            canonical: false,
+            accumulation: Accumulation::default(),
+            tolerance: Tolerance::DEFAULT,
+            pruned_probability: 0.0,
+            metrics: Metrics::default(),
+        }
     }
 
-    /// Creates a new `State` from a vector of `Ket`s. Where all kets must have the same
-    /// number of qubits.
+    /// Creates a `State` with the given number of qubits and no kets, to be filled in with
+    /// [`State::add_or_insert`]. This is the same as [`State::new`].
     ///
     /// # Examples
     /// ```
     /// use quantum_simulator::quantum::state::State;
+    ///
+    /// let state = State::empty(2);
+    /// assert_eq!(state.num_qubits(), 2);
+    /// assert!(state.is_empty());
+    /// ```
+    pub fn empty(num_qubits: usize) -> Self {
+        Self::new(num_qubits)
+    }
+
+    /// Creates a new `State` from a vector of `Ket`s, which must all have the same number
+    /// of qubits. Returns an error if there are no kets or their numbers of qubits differ.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::{State, StateError};
     /// use quantum_simulator::quantum::ket::Ket;
     /// use num::complex::Complex;
     /// use bitvec::prelude::*;
@@ -40,26 +128,22 @@ impl State {
     /// let ket1 = Ket::from_bit_vec(bitvec![0, 0], Complex::new(1.0, 0.0));
     /// let ket2 = Ket::from_bit_vec(bitvec![0, 1], Complex::new(1.0, 0.0));
     /// let kets = vec![ket1, ket2];
-    /// let state = State::from_ket_vec(&kets);
+    /// let state = State::from_ket_vec(&kets).unwrap();
     /// assert_eq!(state.num_qubits(), 2);
     ///
-    /// assert!(state.kets.contains(&kets[0]));
-    /// assert!(state.kets.contains(&kets[1]));
+    /// assert!(state.contains(&kets[0]));
+    /// assert!(state.contains(&kets[1]));
+    ///
+    /// assert_eq!(State::from_ket_vec(&vec![]).unwrap_err(), StateError::NoKets);
     /// ```
-    pub fn from_ket_vec(kets: &Vec<Ket>) -> Self {
-        let num_qubits = kets[0].bit_vec().len();
-        for ket in kets {
-            if ket.bit_vec().len() != num_qubits {
-                panic!("All kets must have the same number of qubits.");
-            }
-        }
-
+    pub fn from_ket_vec(kets: &Vec<Ket>) -> Result<Self, StateError> {
+        let num_qubits = kets.first().ok_or(StateError::NoKets)?.bit_vec().len();
         let mut state = State::new(num_qubits);
         for ket in kets {
-            state.add_or_insert(ket.clone());
+            state.try_add_or_insert(ket.clone())?;
         }
 
-        state
+        Ok(state)
     }
 
     /// Returns the number of qubits in this state.
@@ -75,9 +159,469 @@ impl State {
         self.num_qubits
     }
 
+    /// Creates a new empty `State` with the same number of qubits and settings as this
+    /// one. The probability pruned so far and the [`Metrics`] are carried over, so that
+    /// they are kept for the whole run as gates replace one state with the next.
+    pub fn empty_like(&self) -> State {
+        let mut state = State::new(self.num_qubits);
+        state.canonical = self.canonical;
+        state.accumulation = self.accumulation;
+        state.tolerance = self.tolerance;
+        state.pruned_probability = self.pruned_probability;
+        state.metrics = self.metrics;
+        state
+    }
+
+    /// Returns the total probability of the amplitudes that have been dropped because
+    /// they summed to a negligible amplitude, see [`State::tolerance`]. This is how much
+    /// accuracy the sparse approximation has cost.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    /// use bitvec::prelude::*;
+    ///
+    /// let mut state = State::new(1);
+    /// state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(0.5, 0.0)));
+    /// state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(-0.5 + 1e-7, 0.0)));
+    /// assert!(state.is_empty());
+    /// assert!((state.pruned_probability() - 1e-14).abs() < 1e-20);
+    /// ```
+    pub fn pruned_probability(&self) -> f64 {
+        self.pruned_probability
+    }
+
+    /// Adds to the probability pruned so far, for backends that drop amplitudes while
+    /// converting to or combining states.
+    pub(crate) fn add_pruned_probability(&mut self, probability: f64) {
+        self.pruned_probability += probability;
+    }
+
+    /// Returns the counters of the work done applying gates to this state and the states
+    /// it replaced.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    /// Returns the counters, for gate application to record branches and for combining
+    /// states.
+    pub(crate) fn metrics_mut(&mut self) -> &mut Metrics {
+        &mut self.metrics
+    }
+
+    /// Returns whether kets are processed in canonical order, see
+    /// [`State::set_canonical`].
+    pub fn is_canonical(&self) -> bool {
+        self.canonical
+    }
+
+    /// Sets whether kets should be processed in canonical (basis index) order when gates
+    /// are applied. This makes the order of floating point accumulation, and therefore
+    /// the result, identical between runs at the cost of sorting the kets for each gate.
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    /// Returns how colliding amplitudes are summed when gates are applied.
+    pub fn accumulation(&self) -> Accumulation {
+        self.accumulation
+    }
+
+    /// Sets how colliding amplitudes are summed when gates are applied.
+    pub fn set_accumulation(&mut self, accumulation: Accumulation) {
+        self.accumulation = accumulation;
+    }
+
+    /// Returns the tolerance below which summed amplitudes are dropped from this state,
+    /// which is [`Tolerance::DEFAULT`] unless it has been set.
+    pub fn tolerance(&self) -> Tolerance {
+        self.tolerance
+    }
+
+    /// Sets the tolerance below which summed amplitudes are dropped from this state.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use quantum_simulator::quantum::tolerance::Tolerance;
+    /// use num::complex::Complex;
+    /// use bitvec::prelude::*;
+    ///
+    /// let mut state = State::new(1);
+    /// state.set_tolerance(Tolerance::EXACT);
+    /// state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(0.5, 0.0)));
+    /// state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(-0.5 + 1e-7, 0.0)));
+    /// assert_eq!(state.len(), 1);
+    /// ```
+    pub fn set_tolerance(&mut self, tolerance: Tolerance) {
+        self.tolerance = tolerance;
+    }
+
+    /// Returns the kets of this state sorted by basis index.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    /// use bitvec::prelude::*;
+    ///
+    /// let state = State::from_ket_vec(&vec![
+    ///     Ket::from_bit_vec(bitvec![0, 1], Complex::new(1.0, 0.0)),
+    ///     Ket::from_bit_vec(bitvec![1, 0], Complex::new(1.0, 0.0)),
+    /// ]).unwrap();
+    /// let indices: Vec<usize> = state.sorted_kets().iter().map(|ket| ket.basis_index()).collect();
+    /// assert_eq!(indices, vec![1, 2]);
+    /// ```
+    pub fn sorted_kets(&self) -> Vec<&Ket> {
+        let mut kets: Vec<&Ket> = self.kets.iter().collect();
+        kets.sort();
+        kets
+    }
+
+    /// Returns the kets of this state, in no particular order. [`State::sorted_kets`]
+    /// orders them by basis index.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    /// use bitvec::prelude::*;
+    ///
+    /// let state = State::from_ket_vec(&vec![Ket::from_bit_vec(bitvec![1], Complex::new(1.0, 0.0))]).unwrap();
+    /// let ket = state.kets().next().unwrap();
+    /// assert_eq!(ket.bit_vec(), &bitvec![1]);
+    /// ```
+    pub fn kets(&self) -> impl Iterator<Item = &Ket> {
+        self.kets.iter()
+    }
+
+    /// Consumes this state and returns its kets, in no particular order.
+    pub fn into_kets(self) -> impl Iterator<Item = Ket> {
+        self.kets.into_iter()
+    }
+
+    /// Returns the ket of this state with the same bits as `ket`, whatever its amplitude.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    /// use bitvec::prelude::*;
+    ///
+    /// let state = State::from_ket_vec(&vec![Ket::from_bit_vec(bitvec![1], Complex::new(0.6, 0.0))]).unwrap();
+    /// let found = state.get(&Ket::from_bit_vec(bitvec![1], Complex::new(1.0, 0.0))).unwrap();
+    /// assert_eq!(found.amplitude, Complex::new(0.6, 0.0));
+    /// assert!(!state.contains(&Ket::new_zero_ket(1)));
+    /// ```
+    pub fn get(&self, ket: &Ket) -> Option<&Ket> {
+        self.kets.get(ket)
+    }
+
+    /// Returns whether this state has a ket with the same bits as `ket`.
+    pub fn contains(&self, ket: &Ket) -> bool {
+        self.kets.contains(ket)
+    }
+
+    /// Reserves space for at least `additional` more kets.
+    pub fn reserve(&mut self, additional: usize) {
+        self.kets.reserve(additional);
+    }
+
+    /// Adds kets that are known not to be in this state already, without checking for
+    /// collisions. This is used to combine states built from disjoint sets of kets.
+    pub(crate) fn extend_disjoint(&mut self, kets: impl IntoIterator<Item = Ket>) {
+        let num_qubits = self.num_qubits;
+        self.kets.extend(kets.into_iter().inspect(|ket| {
+            debug_assert_eq!(
+                ket.bit_vec().len(),
+                num_qubits,
+                "A ket with the wrong number of qubits was added to a state"
+            )
+        }));
+    }
+
+    /// Returns the basis state and amplitude of each ket in this state, in no particular
+    /// order. [`State::sorted_kets`] orders them by basis index.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    /// use bitvec::prelude::*;
+    ///
+    /// let state = State::from_ket_vec(&vec![Ket::from_bit_vec(bitvec![0, 1], Complex::new(0.0, 1.0))]).unwrap();
+    /// let (bits, amplitude) = state.iter().next().unwrap();
+    /// assert_eq!(bits, bits![0, 1]);
+    /// assert_eq!(amplitude, Complex::new(0.0, 1.0));
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = (&BitSlice, Complex<f64>)> {
+        self.kets
+            .iter()
+            .map(|ket| (ket.bit_vec().as_bitslice(), ket.amplitude))
+    }
+
+    /// Returns the number of kets in this state, which are the basis states with a
+    /// non-zero amplitude.
+    pub fn len(&self) -> usize {
+        self.kets.len()
+    }
+
+    /// Returns whether this state has no kets.
+    pub fn is_empty(&self) -> bool {
+        self.kets.is_empty()
+    }
+
+    /// Returns the amplitude of every basis state, indexed with qubit 0 as the least
+    /// significant bit, or an error if the state has more than [`MAX_DENSE_QUBITS`]
+    /// qubits.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    ///
+    /// let mut state = State::new(2);
+    /// state.add_or_insert(Ket::from_basis_index(0b10, 2, Complex::new(1.0, 0.0)));
+    /// let amplitudes = state.to_dense_vec().unwrap();
+    /// assert_eq!(amplitudes.len(), 4);
+    /// assert_eq!(amplitudes[0b10], Complex::new(1.0, 0.0));
+    /// assert_eq!(amplitudes[0b01], Complex::new(0.0, 0.0));
+    /// ```
+    pub fn to_dense_vec(&self) -> Result<Vec<Complex<f64>>, StateError> {
+        if self.num_qubits > MAX_DENSE_QUBITS {
+            return Err(StateError::TooManyQubits {
+                num_qubits: self.num_qubits,
+                max: MAX_DENSE_QUBITS,
+            });
+        }
+        let mut amplitudes = vec![Complex::new(0.0, 0.0); 1 << self.num_qubits];
+        for ket in &self.kets {
+            amplitudes[ket.basis_index()] = ket.amplitude;
+        }
+        Ok(amplitudes)
+    }
+
+    /// Returns a ket whose amplitude is NaN or infinite, if there is one.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    /// use bitvec::prelude::*;
+    ///
+    /// let mut state = State::new(1);
+    /// state.add_or_insert(Ket::new_zero_ket(1));
+    /// assert!(state.non_finite_ket().is_none());
+    ///
+    /// state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(f64::NAN, 0.0)));
+    /// assert_eq!(state.non_finite_ket().unwrap().bit_vec(), &bitvec![1]);
+    /// ```
+    pub fn non_finite_ket(&self) -> Option<&Ket> {
+        self.kets.iter().find(|ket| !ket.is_finite())
+    }
+
+    /// Returns the probability of each outcome of measuring `qubits`, normalised by the
+    /// norm of the state. Bit `i` of an outcome is the value of `qubits[i]`, and outcomes
+    /// that cannot occur are left out.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    ///
+    /// let mut state = State::new(3);
+    /// state.add_or_insert(Ket::from_basis_index(0b001, 3, Complex::new(1.0, 0.0)));
+    /// state.add_or_insert(Ket::from_basis_index(0b111, 3, Complex::new(0.0, 1.0)));
+    /// let probabilities = state.marginal_probabilities(&[2]);
+    /// assert_eq!(probabilities.into_iter().collect::<Vec<_>>(), vec![(0, 0.5), (1, 0.5)]);
+    /// ```
+    pub fn marginal_probabilities(&self, qubits: &[usize]) -> BTreeMap<usize, f64> {
+        let mut probabilities: BTreeMap<usize, f64> = BTreeMap::new();
+        for ket in &self.kets {
+            let outcome = qubits
+                .iter()
+                .enumerate()
+                .filter(|(_, qubit)| ket.get(**qubit))
+                .map(|(bit, _)| 1 << bit)
+                .sum();
+            *probabilities.entry(outcome).or_default() += ket.amplitude.norm_sqr();
+        }
+        let norm_squared: f64 = probabilities.values().sum();
+        probabilities.retain(|_, probability| *probability > 0.0);
+        probabilities
+            .values_mut()
+            .for_each(|probability| *probability /= norm_squared);
+        probabilities
+    }
+
+    /// Returns the expectation value of Pauli Z on each of the given qubits in the
+    /// normalised state.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    ///
+    /// let mut state = State::new(2);
+    /// state.add_or_insert(Ket::from_basis_index(0b01, 2, Complex::new(0.6, 0.0)));
+    /// state.add_or_insert(Ket::from_basis_index(0b11, 2, Complex::new(0.0, 0.8)));
+    /// let expectations = state.z_expectations(&[0, 1]);
+    /// assert!((expectations[0] + 1.0).abs() < 1e-12);
+    /// assert!((expectations[1] - (0.36 - 0.64)).abs() < 1e-12);
+    /// ```
+    pub fn z_expectations(&self, qubits: &[usize]) -> Vec<f64> {
+        let mut totals = vec![0.0; qubits.len()];
+        let mut norm_squared = 0.0;
+        for ket in &self.kets {
+            let probability = ket.amplitude.norm_sqr();
+            norm_squared += probability;
+            for (total, qubit) in totals.iter_mut().zip(qubits) {
+                match ket.get(*qubit) {
+                    true => *total -= probability,
+                    false => *total += probability,
+                }
+            }
+        }
+        totals.iter().map(|total| total / norm_squared).collect()
+    }
+
+    /// Returns the probability of measuring the basis state with the given bits, which is
+    /// the squared norm of its amplitude in a normalised state, or zero if the state has
+    /// no such ket.
+    ///
+    /// # Examples
+    /// ```
+    /// use bitvec::prelude::*;
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    ///
+    /// let state = State::from_ket_vec(&vec![
+    ///     Ket::from_bit_vec(bitvec![0, 1], Complex::new(0.6, 0.0)),
+    ///     Ket::from_bit_vec(bitvec![1, 1], Complex::new(0.0, 0.8)),
+    /// ]).unwrap();
+    /// assert!((state.probability(bits![1, 1]) - 0.64).abs() < 1e-12);
+    /// assert_eq!(state.probability(bits![0, 0]), 0.0);
+    /// ```
+    pub fn probability(&self, bits: &BitSlice) -> f64 {
+        self.kets
+            .get(&Ket::from_bit_slice(bits, Complex::new(0.0, 0.0)))
+            .map_or(0.0, |ket| ket.amplitude.norm_sqr())
+    }
+
+    /// Returns the expectation values of Pauli strings, without their coefficients, in the
+    /// normalised state.
+    ///
+    /// A Pauli string maps each basis state onto the one with its X and Y qubits flipped,
+    /// with a phase. Strings that flip the same qubits share one pass over the kets and
+    /// one lookup of each image, and only their phases are worked out separately, so an
+    /// observable with many terms costs far fewer traversals than evaluating each term on
+    /// its own.
+    ///
+    /// # Examples
+    /// ```
+    /// use bitvec::prelude::*;
+    /// use num::complex::Complex;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use quantum_simulator::quantum::observable::parse_observable;
+    /// use quantum_simulator::quantum::state::State;
+    ///
+    /// // A Bell state, written with qubit 0 first.
+    /// let amplitude = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+    /// let state = State::from_ket_vec(&vec![
+    ///     Ket::from_bit_vec(bitvec![0, 0], amplitude),
+    ///     Ket::from_bit_vec(bitvec![1, 1], amplitude),
+    /// ]).unwrap();
+    /// let observable = parse_observable("1 XX\n1 YY\n1 ZZ\n1 ZI").unwrap();
+    /// let expectations = state.expectations(&observable.terms);
+    /// let expected = [1.0, -1.0, 1.0, 0.0];
+    /// for (expectation, expected) in expectations.iter().zip(expected) {
+    ///     assert!((expectation - expected).abs() < 1e-12);
+    /// }
+    /// ```
+    pub fn expectations(&self, terms: &[PauliTerm]) -> Vec<f64> {
+        // The indices of the terms that flip each set of qubits.
+        let mut groups: BTreeMap<Vec<usize>, Vec<usize>> = BTreeMap::new();
+        for (index, term) in terms.iter().enumerate() {
+            let flips = term
+                .paulis
+                .iter()
+                .filter(|(_, pauli)| *pauli != Pauli::Z)
+                .map(|(qubit, _)| *qubit)
+                .collect();
+            groups.entry(flips).or_default().push(index);
+        }
+
+        let norm_squared: f64 = self.kets.iter().map(|ket| ket.amplitude.norm_sqr()).sum();
+        let mut totals = vec![Complex::new(0.0, 0.0); terms.len()];
+        for (flips, indices) in &groups {
+            // Y|b⟩ = i (-1)^b |1 - b⟩ and Z|b⟩ = (-1)^b |b⟩, so each term contributes a
+            // power of i and the parity of the bits of its Y and Z qubits, which are held
+            // as a mask so that the parity is found a word at a time.
+            let phases: Vec<(BitVec, Complex<f64>)> = indices
+                .iter()
+                .map(|index| {
+                    let paulis = &terms[*index].paulis;
+                    let mut sign_mask = bitvec![0; self.num_qubits];
+                    paulis
+                        .iter()
+                        .filter(|(_, pauli)| *pauli != Pauli::X)
+                        .for_each(|(qubit, _)| sign_mask.set(*qubit, true));
+                    let num_y = paulis.iter().filter(|(_, pauli)| *pauli == Pauli::Y);
+                    (sign_mask, Complex::i().powi(num_y.count() as i32))
+                })
+                .collect();
+            for ket in &self.kets {
+                let image = match flips.is_empty() {
+                    true => ket,
+                    false => {
+                        let mut image = ket.clone();
+                        flips.iter().for_each(|qubit| image.flip(*qubit));
+                        match self.kets.get(&image) {
+                            Some(image) => image,
+                            None => continue,
+                        }
+                    }
+                };
+                let overlap = image.amplitude.conj() * ket.amplitude;
+                let words = ket.bit_vec().as_raw_slice();
+                for (index, (sign_mask, phase)) in indices.iter().zip(&phases) {
+                    let contribution = phase * overlap;
+                    let parity: u32 = words
+                        .iter()
+                        .zip(sign_mask.as_raw_slice())
+                        .map(|(word, mask)| (word & mask).count_ones())
+                        .sum();
+                    match parity % 2 {
+                        0 => totals[*index] += contribution,
+                        _ => totals[*index] -= contribution,
+                    }
+                }
+            }
+        }
+        totals.iter().map(|total| total.re / norm_squared).collect()
+    }
+
     /// Adds a new `Ket` to this state or adds to the amplitude if the ket
-    /// already exists.
+    /// already exists. The ket must have the number of qubits of this state, which is
+    /// only checked in debug builds; see [`State::try_add_or_insert`].
     pub fn add_or_insert(&mut self, ket: Ket) {
+        debug_assert_eq!(
+            ket.bit_vec().len(),
+            self.num_qubits,
+            "A ket with the wrong number of qubits was added to a state"
+        );
         // Ignore inserting a ket with zero amplitude.
         if ket.amplitude.norm() == 0.0 {
             return;
@@ -85,20 +629,122 @@ impl State {
 
         if let Some(mut found_ket) = self.kets.take(&ket) {
             found_ket.amplitude += ket.amplitude;
+            self.metrics.collisions += 1;
 
             // Only bother adding the ket back to the state if the amplitude is
             // non-zero.
-            if found_ket.amplitude.norm() > 1e-6 {
+            if !self.tolerance.is_negligible(found_ket.amplitude) {
                 self.kets.insert(found_ket);
+                self.metrics.kets_merged += 1;
+            } else {
+                self.pruned_probability += found_ket.amplitude.norm_sqr();
+                self.metrics.kets_pruned += 1;
             }
         } else {
             self.kets.insert(ket);
         }
     }
 
-    /// Removes a `Ket` from this state, if present.
-    pub fn remove(&mut self, ket: &Ket) {
-        self.kets.remove(ket);
+    /// Adds a new `Ket` to this state in the same way as [`State::add_or_insert`], or
+    /// returns an error without changing the state if the ket does not have the number
+    /// of qubits of this state.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::{State, StateError};
+    /// use quantum_simulator::quantum::ket::Ket;
+    ///
+    /// let mut state = State::new(2);
+    /// state.try_add_or_insert(Ket::new_zero_ket(2)).unwrap();
+    /// assert_eq!(
+    ///     state.try_add_or_insert(Ket::new_zero_ket(3)),
+    ///     Err(StateError::MismatchedQubits { expected: 2, found: 3 })
+    /// );
+    /// assert_eq!(state.len(), 1);
+    /// ```
+    pub fn try_add_or_insert(&mut self, ket: Ket) -> Result<(), StateError> {
+        if ket.bit_vec().len() != self.num_qubits {
+            return Err(StateError::MismatchedQubits {
+                expected: self.num_qubits,
+                found: ket.bit_vec().len(),
+            });
+        }
+        self.add_or_insert(ket);
+        Ok(())
+    }
+
+    /// Adds all of the given `Ket`s to this state, summing the amplitudes of kets with the
+    /// same bits using compensated summation. Kets whose amplitudes cancel out are
+    /// dropped, in the same way as [`State::add_or_insert`].
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use num::complex::Complex;
+    /// use bitvec::prelude::*;
+    ///
+    /// let mut state = State::new(1);
+    /// state.add_all_compensated(vec![
+    ///     Ket::from_bit_vec(bitvec![0], Complex::new(1.0, 0.0)),
+    ///     Ket::from_bit_vec(bitvec![1], Complex::new(1e100, 0.0)),
+    ///     Ket::from_bit_vec(bitvec![1], Complex::new(2.0, 0.0)),
+    ///     Ket::from_bit_vec(bitvec![1], Complex::new(-1e100, 0.0)),
+    /// ]);
+    /// let amplitudes: Vec<f64> = state.sorted_kets().iter().map(|ket| ket.amplitude.re).collect();
+    /// assert_eq!(amplitudes, vec![1.0, 2.0]);
+    /// ```
+    pub fn add_all_compensated(&mut self, mut kets: Vec<Ket>) {
+        debug_assert!(
+            kets.iter()
+                .all(|ket| ket.bit_vec().len() == self.num_qubits),
+            "A ket with the wrong number of qubits was added to a state"
+        );
+        // Group contributions to the same ket, smallest magnitude first.
+        kets.sort_by(|a, b| {
+            a.cmp(b)
+                .then_with(|| a.amplitude.norm().total_cmp(&b.amplitude.norm()))
+        });
+
+        let mut start = 0;
+        while start < kets.len() {
+            let end = start + kets[start..].partition_point(|ket| *ket == kets[start]);
+            let mut contributions: Vec<Complex<f64>> =
+                kets[start..end].iter().map(|ket| ket.amplitude).collect();
+            let collided = match self.kets.take(&kets[start]) {
+                Some(existing) => {
+                    contributions.push(existing.amplitude);
+                    true
+                }
+                None => end - start > 1,
+            };
+            self.metrics.collisions += contributions.len() as u64 - 1;
+
+            let mut ket = kets[start].clone();
+            ket.amplitude = Complex::new(
+                compensated_sum(contributions.iter().map(|amplitude| amplitude.re)),
+                compensated_sum(contributions.iter().map(|amplitude| amplitude.im)),
+            );
+            let tolerance = if collided {
+                self.tolerance
+            } else {
+                Tolerance::EXACT
+            };
+            if !tolerance.is_negligible(ket.amplitude) {
+                self.kets.insert(ket);
+                self.metrics.kets_merged += collided as u64;
+            } else {
+                self.pruned_probability += ket.amplitude.norm_sqr();
+                self.metrics.kets_pruned += collided as u64;
+            }
+            start = end;
+        }
+    }
+
+    /// Removes the ket with the same bits as `ket` from this state and returns it, if
+    /// present.
+    pub fn remove(&mut self, ket: &Ket) -> Option<Ket> {
+        self.kets.take(ket)
     }
 
     /// Removes all `Ket`s with zero amplitude from this state.
@@ -107,6 +753,40 @@ impl State {
     }
 }
 
+/// Sums values with Neumaier's variant of Kahan summation, which keeps track of the
+/// rounding error lost by each addition.
+fn compensated_sum(values: impl Iterator<Item = f64>) -> f64 {
+    let mut sum = CompensatedSum::default();
+    values.for_each(|value| sum.add(value));
+    sum.value()
+}
+
+/// A running sum kept with Neumaier's variant of Kahan summation, for when the partial
+/// sums are needed as well as the total.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct CompensatedSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl CompensatedSum {
+    /// Adds a value to the sum.
+    pub(crate) fn add(&mut self, value: f64) {
+        let total = self.sum + value;
+        if f64::abs(self.sum) >= f64::abs(value) {
+            self.compensation += (self.sum - total) + value;
+        } else {
+            self.compensation += (value - total) + self.sum;
+        }
+        self.sum = total;
+    }
+
+    /// Returns the sum of the values added so far.
+    pub(crate) fn value(&self) -> f64 {
+        self.sum + self.compensation
+    }
+}
+
 impl Eq for State {}
 
 impl PartialEq for State {
@@ -117,10 +797,8 @@ impl PartialEq for State {
 
 impl fmt::Display for State {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // Order the kets by the underlying bit vector.
-        let mut ket_vec: Vec<&Ket> = self.kets.iter().collect();
-        ket_vec.sort_by(|a, b| a.bit_vec().cmp(&b.bit_vec()));
-
+        // Order the kets by basis index so that output is identical between runs.
+        let ket_vec = self.sorted_kets();
         let mut ket_iter = ket_vec.iter();
         if let Some(first_ket) = ket_iter.next() {
             write!(f, "{}", first_ket)?;
@@ -136,14 +814,13 @@ impl fmt::Display for State {
 mod tests {
 
     use super::*;
-    use bitvec::prelude::*;
     use num::complex::Complex;
 
     #[test]
     /// Test that a new state with zero qubits creates an empty state.
     fn test_new_state_zero_qubits() {
         let state = State::new(0);
-        assert!(state.kets.is_empty());
+        assert!(state.is_empty());
         assert!(state.num_qubits == 0);
     }
 
@@ -152,11 +829,12 @@ mod tests {
     fn test_add_or_insert_basic() {
         let ket = Ket::from_bit_vec(bitvec![0], Complex::new(0.5, 0.0));
         let mut state = State::new(1);
+        state.add_or_insert(Ket::new_zero_ket(1));
         state.add_or_insert(ket);
 
         let expected_ket = &Ket::from_bit_vec(bitvec![0], Complex::new(1.5, 0.0));
-        assert!(state.kets.contains(&expected_ket));
-        if let Some(found_ket) = state.kets.take(expected_ket) {
+        assert!(state.contains(expected_ket));
+        if let Some(found_ket) = state.remove(expected_ket) {
             assert_eq!(found_ket.amplitude, expected_ket.amplitude);
         } else {
             panic!("Ket not found in state.");
@@ -166,13 +844,13 @@ mod tests {
     /// Tests that a zero amplitude Ket is not added to the state.
     #[test]
     fn test_add_or_insert_zero_amplitude() {
-        let bit_vec = bitvec![0, 1, 0];
-        let ket = Ket::from_bit_vec(bit_vec, Complex::new(0.0, 0.0));
+        let ket = Ket::from_bit_vec(bitvec![1], Complex::new(0.0, 0.0));
         let mut state = State::new(1);
+        state.add_or_insert(Ket::new_zero_ket(1));
         state.add_or_insert(ket);
 
         // Should only have the initial zero ket.
-        assert!(state.kets.len() == 1);
+        assert!(state.len() == 1);
     }
 
     /// Tests that a ket that creates a zero amplitude when added to
@@ -181,9 +859,99 @@ mod tests {
     fn test_add_or_insert_zero_amplitude_existing() {
         let ket = Ket::from_bit_vec(bitvec![1], Complex::new(-1.0, 0.0));
         let mut state = State::new(1);
+        state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(1.0, 0.0)));
         state.add_or_insert(ket);
 
-        assert!(state.kets.is_empty());
+        assert!(state.is_empty());
+    }
+
+    /// Tests that kets with different numbers of qubits are rejected with the counts.
+    #[test]
+    fn test_from_ket_vec_mismatched_qubits() {
+        let kets = vec![
+            Ket::from_bit_vec(bitvec![0, 1], Complex::new(1.0, 0.0)),
+            Ket::from_bit_vec(bitvec![1], Complex::new(1.0, 0.0)),
+        ];
+        let error = State::from_ket_vec(&kets).unwrap_err();
+        assert_eq!(
+            error,
+            StateError::MismatchedQubits {
+                expected: 2,
+                found: 1
+            }
+        );
+        assert_eq!(
+            io::Error::from(error).to_string(),
+            "A ket has 1 qubits but the state has 2"
+        );
+    }
+
+    /// Tests that compensated accumulation merges with existing kets and drops kets
+    /// that cancel out.
+    #[test]
+    fn test_add_all_compensated_existing() {
+        let mut state = State::new(1);
+        state.add_or_insert(Ket::from_bit_vec(bitvec![0], Complex::new(0.5, 0.0)));
+        state.add_or_insert(Ket::from_bit_vec(bitvec![1], Complex::new(0.5, 0.0)));
+        state.add_all_compensated(vec![
+            Ket::from_bit_vec(bitvec![0], Complex::new(0.25, 0.5)),
+            Ket::from_bit_vec(bitvec![1], Complex::new(-0.5, 0.0)),
+        ]);
+
+        assert_eq!(state.len(), 1);
+        let ket = state.kets().next().unwrap();
+        assert_eq!(ket.bit_vec(), &bitvec![0]);
+        assert_eq!(ket.amplitude, Complex::new(0.75, 0.5));
+    }
+
+    /// Tests that the probability of kets dropped by both ways of summing amplitudes is
+    /// kept, and carried over to the states that replace this one.
+    #[test]
+    fn test_pruned_probability() {
+        let mut state = State::new(2);
+        state.add_or_insert(Ket::from_bit_vec(bitvec![0, 0], Complex::new(0.6, 0.0)));
+        state.add_or_insert(Ket::from_bit_vec(bitvec![0, 0], Complex::new(-0.6, 1e-7)));
+        assert!(state.is_empty());
+        assert!((state.pruned_probability() - 1e-14).abs() < 1e-20);
+
+        state.add_all_compensated(vec![
+            Ket::from_bit_vec(bitvec![1, 0], Complex::new(0.5, 0.0)),
+            Ket::from_bit_vec(bitvec![1, 0], Complex::new(-0.5, -2e-7)),
+            // A ket that is only added once is kept, however small.
+            Ket::from_bit_vec(bitvec![0, 1], Complex::new(1e-9, 0.0)),
+        ]);
+        assert_eq!(state.len(), 1);
+        assert!((state.pruned_probability() - 5e-14).abs() < 1e-20);
+        assert_eq!(
+            state.empty_like().pruned_probability(),
+            state.pruned_probability()
+        );
+    }
+
+    /// Tests the collisions, merged kets and pruned kets counted by both ways of summing
+    /// amplitudes.
+    #[test]
+    fn test_metrics() {
+        let kets = vec![
+            Ket::from_bit_vec(bitvec![0], Complex::new(0.5, 0.0)),
+            Ket::from_bit_vec(bitvec![1], Complex::new(0.5, 0.0)),
+            Ket::from_bit_vec(bitvec![0], Complex::new(0.5, 0.0)),
+            Ket::from_bit_vec(bitvec![1], Complex::new(-0.5, 0.0)),
+            Ket::from_bit_vec(bitvec![0], Complex::new(0.25, 0.0)),
+        ];
+        let mut state = State::new(1);
+        kets.iter().for_each(|ket| state.add_or_insert(ket.clone()));
+        let mut compensated_state = State::new(1);
+        compensated_state.add_all_compensated(kets);
+
+        // Sequential summation merges the |0⟩ ket twice, compensated summation once.
+        assert_eq!(state.metrics().collisions, 3);
+        assert_eq!(state.metrics().kets_merged, 2);
+        assert_eq!(state.metrics().kets_pruned, 1);
+        assert_eq!(compensated_state.metrics().collisions, 3);
+        assert_eq!(compensated_state.metrics().kets_merged, 1);
+        assert_eq!(compensated_state.metrics().kets_pruned, 1);
+        assert_eq!(state.empty_like().metrics(), state.metrics());
     }
 
     #[test]
@@ -193,7 +961,7 @@ mod tests {
         state.add_or_insert(ket.clone());
 
         state.remove(&ket);
-        assert!(state.kets.is_empty());
+        assert!(state.is_empty());
     }
 
     #[test]
@@ -205,15 +973,68 @@ mod tests {
         state.add_or_insert(ket2);
 
         state.remove_zero_amplitude_kets();
-        assert!(state.kets.len() == 1);
+        assert!(state.len() == 1);
     }
 
     #[test]
     fn test_fmt_display() {
         let ket1 = Ket::from_bit_vec(bitvec![0], Complex::new(0.5, 0.0));
         let ket2 = Ket::from_bit_vec(bitvec![1], Complex::new(0.5, 0.5));
-        let state = State::from_ket_vec(&vec![ket1, ket2]);
+        let state = State::from_ket_vec(&vec![ket1, ket2]).unwrap();
 
         assert_eq!(format!("{}", state), "(0.5+0i)|0⟩ + (0.5+0.5i)|1⟩");
     }
+
+    /// Tests that the batched expectation values of many random Pauli strings on a random
+    /// state match measuring each string in its own basis.
+    #[test]
+    fn test_expectations() {
+        use crate::gates::gate::apply_gate_to_state;
+        use crate::gates::generators::mirror_circuit;
+        use crate::quantum::observable::{parse_observable, MeasurementGroup};
+        use crate::quantum::sampling::Rng;
+
+        let num_qubits = 5;
+        let mut rng = Rng::new(4);
+        // The first half of a mirror circuit is a random circuit.
+        let gates = mirror_circuit(num_qubits, 8, &mut rng);
+        let mut state = State::new(num_qubits);
+        state.add_or_insert(Ket::new_zero_ket(num_qubits));
+        let state = gates[..gates.len() / 2]
+            .iter()
+            .fold(state, apply_gate_to_state);
+
+        let source: Vec<String> = (0..200)
+            .map(|_| {
+                let label: String = (0..num_qubits)
+                    .map(|_| ['I', 'X', 'Y', 'Z'][(rng.next_f64() * 4.0) as usize])
+                    .collect();
+                format!["1 {label}"]
+            })
+            .collect();
+        let observable = parse_observable(&source.join("\n")).unwrap();
+        let expectations = state.expectations(&observable.terms);
+        assert_eq!(expectations.len(), observable.terms.len());
+        for (term, expectation) in observable.terms.iter().zip(expectations) {
+            let group = MeasurementGroup {
+                basis: term.paulis.iter().copied().collect(),
+                terms: Vec::new(),
+            };
+            let rotated = group
+                .basis_change()
+                .iter()
+                .fold(state.clone(), apply_gate_to_state);
+            let expected: f64 = rotated
+                .kets()
+                .map(
+                    |ket| match term.paulis.iter().filter(|(q, _)| ket.get(*q)).count() % 2 {
+                        0 => ket.amplitude.norm_sqr(),
+                        _ => -ket.amplitude.norm_sqr(),
+                    },
+                )
+                .sum();
+            assert!((expectation - expected).abs() < 1e-9, "{}", term.label);
+        }
+        assert!(state.expectations(&[]).is_empty());
+    }
 }
@@ -0,0 +1,75 @@
+use num::complex::Complex;
+
+/// How close two values must be to count as equal, and how small an amplitude must be to
+/// count as zero.
+///
+/// Two values are equal if they differ by at most `absolute`, or by at most `relative`
+/// times the larger of their magnitudes. An amplitude is negligible if its norm is at
+/// most `absolute`.
+///
+/// # Examples
+/// ```
+/// use num::complex::Complex;
+/// use quantum_simulator::quantum::tolerance::Tolerance;
+///
+/// let tolerance = Tolerance::new(1e-6, 1e-3);
+/// assert!(tolerance.approx_eq(1.0, 1.0 + 1e-7));
+/// assert!(tolerance.approx_eq(1000.0, 1000.5));
+/// assert!(!tolerance.approx_eq(1.0, 1.01));
+/// assert!(tolerance.is_negligible(Complex::new(1e-7, 0.0)));
+/// assert!(!Tolerance::EXACT.is_negligible(Complex::new(1e-300, 0.0)));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    /// The largest difference allowed whatever the size of the values.
+    pub absolute: f64,
+    /// The largest difference allowed as a fraction of the larger value.
+    pub relative: f64,
+}
+
+impl Tolerance {
+    /// The tolerance the simulator uses by default. Kets whose amplitudes sum to a norm at
+    /// or below `1e-6` are almost always the result of rounding errors when amplitudes
+    /// cancel out, so they are dropped.
+    pub const DEFAULT: Tolerance = Tolerance::absolute(1e-6);
+
+    /// A tolerance that only treats equal values as equal and zero as negligible.
+    pub const EXACT: Tolerance = Tolerance::absolute(0.0);
+
+    /// Creates a new `Tolerance` with the given absolute and relative epsilons.
+    pub const fn new(absolute: f64, relative: f64) -> Self {
+        Self { absolute, relative }
+    }
+
+    /// Creates a new `Tolerance` that only has an absolute epsilon.
+    pub const fn absolute(absolute: f64) -> Self {
+        Self::new(absolute, 0.0)
+    }
+
+    /// Returns the largest difference allowed between values of the given magnitude.
+    fn allowed(&self, magnitude: f64) -> f64 {
+        self.absolute.max(self.relative * magnitude)
+    }
+
+    /// Returns whether two real values are equal within this tolerance.
+    pub fn approx_eq(&self, a: f64, b: f64) -> bool {
+        (a - b).abs() <= self.allowed(a.abs().max(b.abs()))
+    }
+
+    /// Returns whether two complex values are equal within this tolerance, measuring
+    /// their difference by its norm.
+    pub fn approx_eq_complex(&self, a: Complex<f64>, b: Complex<f64>) -> bool {
+        (a - b).norm() <= self.allowed(a.norm().max(b.norm()))
+    }
+
+    /// Returns whether an amplitude is small enough to be treated as zero.
+    pub fn is_negligible(&self, amplitude: Complex<f64>) -> bool {
+        amplitude.norm() <= self.absolute
+    }
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance::DEFAULT
+    }
+}
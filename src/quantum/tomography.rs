@@ -0,0 +1,261 @@
+use crate::gates::gate::apply_gate_to_state;
+use crate::quantum::observable::{MeasurementGroup, Pauli, PauliTerm};
+use crate::quantum::sampling::{sample_counts, Rng};
+use crate::quantum::state::State;
+use std::collections::BTreeMap;
+use std::io;
+
+/// The largest number of qubits that can be reconstructed, since the number of
+/// measurement settings grows as `3^n` and the number of Pauli strings as `4^n`.
+pub const MAX_TOMOGRAPHY_QUBITS: usize = 6;
+
+/// The reconstruction of the reduced state of some qubits from simulated measurements in
+/// every product of the X, Y and Z bases, alongside the exact reduced state.
+///
+/// Both states are described by the expectation values of the `4^n` Pauli strings on the
+/// qubits, `rho = sum_P ⟨P⟩ P / 2^n`, which is the linear inversion estimate when the
+/// values are estimated from shots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tomography {
+    /// The qubits that were reconstructed, in increasing order.
+    pub qubits: Vec<usize>,
+    /// The number of measurement settings.
+    pub settings: usize,
+    /// The number of shots taken with each setting.
+    pub shots: usize,
+    /// Each Pauli string on the qubits, where the last character acts on the first qubit,
+    /// with its estimated and exact expectation values.
+    pub paulis: Vec<(String, f64, f64)>,
+}
+
+impl Tomography {
+    /// Returns `Tr(rho sigma)` for the estimated state `rho` and the exact state `sigma`,
+    /// which is the fidelity when the exact state is pure.
+    pub fn overlap(&self) -> f64 {
+        self.paulis
+            .iter()
+            .map(|(_, estimated, exact)| estimated * exact)
+            .sum::<f64>()
+            / self.dimension()
+    }
+
+    /// Returns the Hilbert-Schmidt distance `||rho - sigma||_2` between the estimated and
+    /// exact states.
+    pub fn distance(&self) -> f64 {
+        (self
+            .paulis
+            .iter()
+            .map(|(_, estimated, exact)| (estimated - exact).powi(2))
+            .sum::<f64>()
+            / self.dimension())
+        .sqrt()
+    }
+
+    /// Returns the purities `Tr(rho^2)` of the estimated and exact states.
+    pub fn purities(&self) -> (f64, f64) {
+        let purity = |value: fn(&(String, f64, f64)) -> f64| {
+            self.paulis
+                .iter()
+                .map(|pauli| value(pauli).powi(2))
+                .sum::<f64>()
+                / self.dimension()
+        };
+        (purity(|pauli| pauli.1), purity(|pauli| pauli.2))
+    }
+
+    fn dimension(&self) -> f64 {
+        (1 << self.qubits.len()) as f64
+    }
+}
+
+/// Simulates state tomography of `qubits`, taking `shots` shots in each of the `3^n`
+/// product bases and estimating every Pauli string from the settings that measure it.
+///
+/// Returns an error if there are no qubits, too many qubits or a qubit outside the
+/// state.
+///
+/// # Examples
+/// ```
+/// use bitvec::prelude::*;
+/// use num::complex::Complex;
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::sampling::Rng;
+/// use quantum_simulator::quantum::state::State;
+/// use quantum_simulator::quantum::tomography::tomography;
+///
+/// // |+⟩ on qubit 1, with qubit 0 left in |0⟩.
+/// let amplitude = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+/// let state = State::from_ket_vec(&vec![
+///     Ket::from_bit_vec(bitvec![0, 0], amplitude),
+///     Ket::from_bit_vec(bitvec![0, 1], amplitude),
+/// ]).unwrap();
+/// let result = tomography(&state, &[1], 1000, &mut Rng::new(0)).unwrap();
+/// assert_eq!(result.settings, 3);
+/// let (label, estimated, exact) = &result.paulis[1];
+/// assert_eq!(label, "X");
+/// assert!((estimated - 1.0).abs() < 1e-12 && (exact - 1.0).abs() < 1e-12);
+/// assert!(result.overlap() > 0.95);
+/// ```
+pub fn tomography(
+    state: &State,
+    qubits: &[usize],
+    shots: usize,
+    rng: &mut Rng,
+) -> io::Result<Tomography> {
+    let mut qubits = qubits.to_vec();
+    qubits.sort();
+    qubits.dedup();
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    if qubits.is_empty() || qubits.len() > MAX_TOMOGRAPHY_QUBITS {
+        return Err(invalid(format![
+            "Tomography needs between 1 and {MAX_TOMOGRAPHY_QUBITS} qubits"
+        ]));
+    }
+    if let Some(qubit) = qubits.iter().find(|qubit| **qubit >= state.num_qubits()) {
+        return Err(invalid(format!["Unknown qubit {qubit} for tomography"]));
+    }
+
+    // Each setting is a product of X, Y and Z, and gives the parity of any subset of
+    // its qubits.
+    let num_settings = 3usize.pow(qubits.len() as u32);
+    let mut sums: BTreeMap<Vec<Option<Pauli>>, (f64, usize)> = BTreeMap::new();
+    for setting in 0..num_settings {
+        let bases: Vec<Pauli> = (0..qubits.len())
+            .map(|position| {
+                [Pauli::X, Pauli::Y, Pauli::Z][setting / 3usize.pow(position as u32) % 3]
+            })
+            .collect();
+        let group = MeasurementGroup {
+            basis: qubits.iter().copied().zip(bases.iter().copied()).collect(),
+            terms: Vec::new(),
+        };
+        let rotated = group
+            .basis_change()
+            .iter()
+            .fold(state.clone(), apply_gate_to_state);
+        let counts = sample_counts(&rotated.marginal_probabilities(&qubits), shots, rng);
+
+        for subset in 0..1usize << qubits.len() {
+            let pauli: Vec<Option<Pauli>> = (0..qubits.len())
+                .map(|position| (subset >> position & 1 == 1).then_some(bases[position]))
+                .collect();
+            let sum: f64 = counts
+                .iter()
+                .map(
+                    |(outcome, count)| match (outcome & subset).count_ones() % 2 {
+                        0 => *count as f64,
+                        _ => -(*count as f64),
+                    },
+                )
+                .sum();
+            let entry = sums.entry(pauli).or_default();
+            entry.0 += sum;
+            entry.1 += shots;
+        }
+    }
+
+    let paulis = pauli_strings(qubits.len())
+        .into_iter()
+        .map(|pauli| {
+            let (sum, count) = sums[&pauli];
+            let term = PauliTerm {
+                coefficient: 1.0,
+                label: label(&pauli),
+                paulis: qubits
+                    .iter()
+                    .zip(&pauli)
+                    .filter_map(|(qubit, pauli)| pauli.map(|pauli| (*qubit, pauli)))
+                    .collect(),
+            };
+            let exact = term.expectation(state);
+            (term.label, sum / count as f64, exact)
+        })
+        .collect();
+    Ok(Tomography {
+        qubits,
+        settings: num_settings,
+        shots,
+        paulis,
+    })
+}
+
+/// Returns every Pauli string on `num_qubits` qubits, in the order of their labels with
+/// `I < X < Y < Z`.
+fn pauli_strings(num_qubits: usize) -> Vec<Vec<Option<Pauli>>> {
+    let choices = [None, Some(Pauli::X), Some(Pauli::Y), Some(Pauli::Z)];
+    // The last qubit is the most significant digit, since it is the first character.
+    (0..4usize.pow(num_qubits as u32))
+        .map(|index| {
+            (0..num_qubits)
+                .map(|position| choices[index / 4usize.pow(position as u32) % 4])
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the label of a Pauli string, with the first qubit as the last character.
+fn label(pauli: &[Option<Pauli>]) -> String {
+    pauli
+        .iter()
+        .rev()
+        .map(|pauli| match pauli {
+            None => 'I',
+            Some(Pauli::X) => 'X',
+            Some(Pauli::Y) => 'Y',
+            Some(Pauli::Z) => 'Z',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::ket::Ket;
+    use bitvec::prelude::*;
+    use num::complex::Complex;
+
+    /// Tests the reconstruction of a Bell state and of one half of it.
+    #[test]
+    fn test_bell_state() {
+        let amplitude = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+        let state = State::from_ket_vec(&vec![
+            Ket::from_bit_vec(bitvec![0, 0, 0], amplitude),
+            Ket::from_bit_vec(bitvec![1, 0, 1], amplitude),
+        ])
+        .unwrap();
+
+        let result = tomography(&state, &[2, 0], 2000, &mut Rng::new(5)).unwrap();
+        assert_eq!(result.qubits, vec![0, 2]);
+        assert_eq!(result.settings, 9);
+        let labels: Vec<&str> = result
+            .paulis
+            .iter()
+            .map(|(label, ..)| label.as_str())
+            .collect();
+        assert_eq!(labels[..6], ["II", "IX", "IY", "IZ", "XI", "XX"]);
+        let exact: Vec<f64> = result.paulis.iter().map(|(_, _, exact)| *exact).collect();
+        assert_eq!(exact.iter().filter(|value| value.abs() > 0.5).count(), 4);
+        assert!(result.overlap() > 0.97, "{}", result.overlap());
+        assert!(result.distance() < 0.1, "{}", result.distance());
+        assert!((result.purities().1 - 1.0).abs() < 1e-12);
+
+        let result = tomography(&state, &[0], 2000, &mut Rng::new(5)).unwrap();
+        assert!((result.purities().1 - 0.5).abs() < 1e-12);
+        assert!((result.overlap() - 0.5).abs() < 0.05);
+    }
+
+    /// Tests that invalid sets of qubits are rejected.
+    #[test]
+    fn test_invalid_qubits() {
+        let mut state = State::new(2);
+        state.add_or_insert(Ket::new_zero_ket(2));
+        for (qubits, message) in [
+            (vec![], "Tomography needs between 1 and 6 qubits"),
+            (vec![0, 7], "Unknown qubit 7 for tomography"),
+        ] {
+            let error = tomography(&state, &qubits, 10, &mut Rng::new(0)).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use crate::gates::gate::{apply_gate_to_ket_into, Gate};
+use crate::quantum::ket::Ket;
+use crate::quantum::state::State;
+use crate::quantum::tolerance::Tolerance;
+use bitvec::prelude::*;
+use num::Complex;
+
+/// Marks a missing child in the trie.
+const EMPTY: u32 = u32::MAX;
+
+/// A sparse state that stores its basis states in a binary trie with the amplitudes at
+/// the leaves, branching on the highest qubit first.
+///
+/// Basis states that share their high qubits share the nodes for them, so structured
+/// states such as the registers of arithmetic circuits take much less memory than a bit
+/// vector per ket. Nodes are held in a flat arena and refer to each other by index, so
+/// each node costs 8 bytes.
+///
+/// Applying a gate rebuilds the trie, and basis states whose amplitudes are negligible
+/// within its [`Tolerance`] are dropped, as the dense backend does when converting to
+/// kets.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::gates::gate::Gate;
+/// use quantum_simulator::quantum::register::QubitRef;
+/// use quantum_simulator::quantum::trie::TrieState;
+///
+/// let mut state = TrieState::new(3);
+/// state.apply_gate(&Gate::H { target: QubitRef(0) });
+/// state.apply_gate(&Gate::CX { control: QubitRef(0), target: QubitRef(2) });
+/// assert_eq!(state.num_kets(), 2);
+/// assert_eq!(state.to_state().to_string(), "(0.707+0i)|000⟩ + (0.707+0i)|101⟩");
+/// ```
+#[derive(Debug, Clone)]
+pub struct TrieState {
+    num_qubits: usize,
+    /// The children of each inner node, which are leaves below the last level.
+    nodes: Vec<[u32; 2]>,
+    leaves: Vec<Complex<f64>>,
+    /// The root node, or the only leaf when there are no qubits.
+    root: u32,
+    /// The probability of the basis states dropped so far, see
+    /// [`State::pruned_probability`].
+    pruned_probability: f64,
+    /// The tolerance below which amplitudes are dropped, see [`State::tolerance`].
+    tolerance: Tolerance,
+}
+
+impl TrieState {
+    /// Creates a new `TrieState` in the all zero basis state.
+    pub fn new(num_qubits: usize) -> Self {
+        let mut state = Self::empty(num_qubits);
+        state.add(&Ket::new_zero_ket(num_qubits));
+        state
+    }
+
+    /// Creates a new `TrieState` with the same amplitudes and tolerance as a sparse
+    /// state.
+    pub fn from_state(state: &State) -> Self {
+        let mut trie = Self::empty(state.num_qubits());
+        state.kets().for_each(|ket| trie.add(ket));
+        trie.pruned_probability = state.pruned_probability();
+        trie.tolerance = state.tolerance();
+        trie
+    }
+
+    fn empty(num_qubits: usize) -> Self {
+        Self {
+            num_qubits,
+            nodes: Vec::new(),
+            leaves: Vec::new(),
+            root: EMPTY,
+            pruned_probability: 0.0,
+            tolerance: Tolerance::DEFAULT,
+        }
+    }
+
+    /// Returns the number of qubits in this state.
+    pub fn num_qubits(&self) -> usize {
+        self.num_qubits
+    }
+
+    /// Returns the number of basis states held.
+    pub fn num_kets(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Returns the number of inner nodes of the trie.
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns the total probability of the basis states dropped because their
+    /// amplitudes were negligible, see [`TrieState::set_tolerance`].
+    pub fn pruned_probability(&self) -> f64 {
+        self.pruned_probability
+    }
+
+    /// Sets the tolerance below which amplitudes are dropped.
+    pub fn set_tolerance(&mut self, tolerance: Tolerance) {
+        self.tolerance = tolerance;
+    }
+
+    /// Applies a gate to this state.
+    pub fn apply_gate(&mut self, gate: &Gate) {
+        let mut new_state = Self::empty(self.num_qubits);
+        new_state.pruned_probability = self.pruned_probability;
+        new_state.tolerance = self.tolerance;
+        self.for_each_ket(|ket| {
+            if !self.tolerance.is_negligible(ket.amplitude) {
+                apply_gate_to_ket_into(gate, ket, &mut |new_ket| new_state.add(&new_ket));
+            } else {
+                new_state.pruned_probability += ket.amplitude.norm_sqr();
+            }
+        });
+        *self = new_state;
+    }
+
+    /// Returns a ket whose amplitude is NaN or infinite, if there is one.
+    pub fn non_finite_ket(&self) -> Option<Ket> {
+        let mut found = None;
+        self.for_each_ket(|ket| {
+            if found.is_none() && !ket.is_finite() {
+                found = Some(ket);
+            }
+        });
+        found
+    }
+
+    /// Converts this state into a sparse state, dropping basis states with a negligible
+    /// amplitude.
+    pub fn to_state(&self) -> State {
+        let mut state = State::new(self.num_qubits);
+        state.add_pruned_probability(self.pruned_probability);
+        state.set_tolerance(self.tolerance);
+        self.for_each_ket(|ket| {
+            if !self.tolerance.is_negligible(ket.amplitude) {
+                state.add_or_insert(ket);
+            } else {
+                state.add_pruned_probability(ket.amplitude.norm_sqr());
+            }
+        });
+        state
+    }
+
+    /// Adds the amplitude of a ket to its basis state.
+    fn add(&mut self, ket: &Ket) {
+        if ket.amplitude.norm() == 0.0 {
+            return;
+        }
+        // The inner node and branch leading to the current slot, or `None` for the root.
+        let mut parent: Option<(usize, usize)> = None;
+        for depth in 0..=self.num_qubits {
+            let slot = match parent {
+                None => self.root,
+                Some((node, bit)) => self.nodes[node][bit],
+            };
+            let slot = if slot != EMPTY {
+                slot
+            } else if depth == self.num_qubits {
+                self.leaves.push(Complex::new(0.0, 0.0));
+                self.leaves.len() as u32 - 1
+            } else {
+                self.nodes.push([EMPTY; 2]);
+                self.nodes.len() as u32 - 1
+            };
+            match parent {
+                None => self.root = slot,
+                Some((node, bit)) => self.nodes[node][bit] = slot,
+            }
+            if depth == self.num_qubits {
+                self.leaves[slot as usize] += ket.amplitude;
+            } else {
+                parent = Some((slot as usize, ket.get(self.num_qubits - 1 - depth) as usize));
+            }
+        }
+    }
+
+    /// Calls `f` with a ket for each basis state, ordered by their bits from the highest
+    /// qubit down.
+    fn for_each_ket(&self, mut f: impl FnMut(Ket)) {
+        if self.root == EMPTY {
+            return;
+        }
+        let mut bits = bitvec![0; self.num_qubits];
+        // Each entry is a node or leaf, its depth and the bit of the branch that led to
+        // it. Depth first order sets the bits of every level of a path before its leaf.
+        let mut stack = vec![(self.root, 0, false)];
+        while let Some((index, depth, bit)) = stack.pop() {
+            if depth > 0 {
+                bits.set(self.num_qubits - depth, bit);
+            }
+            if depth == self.num_qubits {
+                f(Ket::from_bit_slice(&bits, self.leaves[index as usize]));
+                continue;
+            }
+            let [zero, one] = self.nodes[index as usize];
+            // Push the one branch first so that the zero branch is visited first.
+            if one != EMPTY {
+                stack.push((one, depth + 1, true));
+            }
+            if zero != EMPTY {
+                stack.push((zero, depth + 1, false));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::gate::apply_gate_to_state;
+    use crate::quantum::register::QubitRef;
+
+    /// Tests that the trie gives the same states as the sparse backend, and shares the
+    /// nodes of common high qubits.
+    #[test]
+    fn test_matches_sparse_state() {
+        let gates = [
+            Gate::H {
+                target: QubitRef(0),
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::CX {
+                control: QubitRef(1),
+                target: QubitRef(3),
+            },
+            Gate::T {
+                target: QubitRef(3),
+            },
+            Gate::RZ {
+                target: QubitRef(0),
+                theta: 0.4,
+            },
+            Gate::H {
+                target: QubitRef(1),
+            },
+            Gate::X {
+                target: QubitRef(2),
+            },
+            Gate::TDgr {
+                target: QubitRef(2),
+            },
+        ];
+        let mut trie = TrieState::new(4);
+        let mut state = State::new(4);
+        state.add_or_insert(Ket::new_zero_ket(4));
+        for gate in &gates {
+            trie.apply_gate(gate);
+            state = apply_gate_to_state(state, gate);
+            assert_eq!(trie.to_state().to_string(), state.to_string());
+        }
+        assert!(trie.non_finite_ket().is_none());
+        let round_trip = TrieState::from_state(&state);
+        assert_eq!(round_trip.to_state().to_string(), state.to_string());
+
+        // After H on the two lowest qubits, the four kets share the nodes of qubits 3 and
+        // 2, so only the node for qubit 1 branches.
+        let mut trie = TrieState::new(4);
+        trie.apply_gate(&Gate::H {
+            target: QubitRef(0),
+        });
+        trie.apply_gate(&Gate::H {
+            target: QubitRef(1),
+        });
+        assert_eq!(trie.num_kets(), 4);
+        assert_eq!(trie.num_nodes(), 5);
+
+        let trie = TrieState::new(0);
+        assert_eq!(trie.to_state().to_string(), "(1+0i)|⟩");
+        assert_eq!(trie.num_nodes(), 0);
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A reading of the memory used by a simulation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemorySample {
+    /// The time since the watchdog was started.
+    pub elapsed: Duration,
+    /// The resident set size of the process in bytes, if the operating system reports it.
+    pub resident_bytes: Option<u64>,
+    /// The number of kets after the last gate that was applied, which is zero for the
+    /// dense backends.
+    pub kets: usize,
+}
+
+/// Returns the resident set size of this process in bytes, which is read from
+/// `/proc/self/status` and so is only known on Linux.
+pub fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kilobytes: u64 = value.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// A thread that samples the memory used at a fixed interval until it is dropped.
+///
+/// Memory is only checked by the simulator between gates, and a single gate can double
+/// the number of kets, so the watchdog reads the memory of the process while gates are
+/// being applied. The number of kets is read from a gauge the simulator updates after
+/// every gate, see [`Options::kets_gauge`](crate::qasm::simulator::Options::kets_gauge).
+///
+/// # Examples
+/// ```
+/// use std::sync::atomic::AtomicUsize;
+/// use std::sync::{mpsc, Arc};
+/// use std::time::Duration;
+/// use quantum_simulator::quantum::watchdog::Watchdog;
+///
+/// let kets = Arc::new(AtomicUsize::new(4));
+/// let (sender, receiver) = mpsc::channel();
+/// let watchdog = Watchdog::spawn(Duration::from_millis(1), kets, move |sample| {
+///     let _ = sender.send(*sample);
+/// });
+/// let sample = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+/// assert_eq!(sample.kets, 4);
+/// drop(watchdog);
+/// ```
+pub struct Watchdog {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Starts a thread that calls `on_sample` with a reading of the memory every
+    /// `interval`, reading the number of kets from `kets`.
+    pub fn spawn(
+        interval: Duration,
+        kets: Arc<AtomicUsize>,
+        mut on_sample: impl FnMut(&MemorySample) + Send + 'static,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let start = Instant::now();
+            let mut next = start + interval;
+            while !stopped.load(Ordering::Acquire) {
+                // Parking can end early, so the deadline is checked again.
+                let now = Instant::now();
+                if now < next {
+                    thread::park_timeout(next - now);
+                    continue;
+                }
+                next += interval;
+                on_sample(&MemorySample {
+                    elapsed: now - start,
+                    resident_bytes: resident_memory(),
+                    kets: kets.load(Ordering::Relaxed),
+                });
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            // A panic in the callback has already been reported by the thread.
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Tests that samples follow the gauge and stop once the watchdog is dropped.
+    #[test]
+    fn test_watchdog_samples() {
+        let kets = Arc::new(AtomicUsize::new(1));
+        let (sender, receiver) = mpsc::channel();
+        let watchdog =
+            Watchdog::spawn(Duration::from_millis(1), Arc::clone(&kets), move |sample| {
+                let _ = sender.send(*sample);
+            });
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(10)).unwrap().kets,
+            1
+        );
+        kets.store(8, Ordering::Relaxed);
+        let sample = receiver.iter().find(|sample| sample.kets == 8).unwrap();
+        if cfg!(target_os = "linux") {
+            assert!(sample.resident_bytes.unwrap() > 0);
+        }
+        drop(watchdog);
+        // The sender is dropped along with the thread, which ends the samples.
+        assert!(receiver.iter().all(|sample| sample.kets == 8));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::quantum::observable::Estimate;
+use crate::quantum::sampling::{AliasTable, Rng};
+use crate::quantum::state::State;
+use bitvec::prelude::*;
+use std::io;
+
+/// The linear cross-entropy benchmarking fidelity of samples from a device against the
+/// ideal distribution of the circuit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Xeb {
+    /// The number of samples.
+    pub samples: usize,
+    /// The estimated fidelity, `2^n ⟨p(x)⟩ - 1` over the samples `x`, with its error bars.
+    pub fidelity: Estimate,
+    /// The fidelity expected of noiseless samples, `2^n Σ p(x)^2 - 1`.
+    pub ideal: f64,
+}
+
+/// Estimates the linear XEB fidelity of `samples` against the ideal `state`.
+///
+/// A fully depolarised device gives a fidelity of zero, and a noiseless one gives
+/// [`Xeb::ideal`], which is close to one for random circuits. Returns an error if there
+/// are no samples or a sample does not have one bit per qubit.
+///
+/// # Examples
+/// ```
+/// use bitvec::prelude::*;
+/// use num::complex::Complex;
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::state::State;
+/// use quantum_simulator::quantum::xeb::linear_xeb;
+///
+/// // A Bell state, which only ever gives 00 or 11.
+/// let amplitude = Complex::new(1.0 / 2.0_f64.sqrt(), 0.0);
+/// let state = State::from_ket_vec(&vec![
+///     Ket::from_bit_vec(bitvec![0, 0], amplitude),
+///     Ket::from_bit_vec(bitvec![1, 1], amplitude),
+/// ]).unwrap();
+/// let xeb = linear_xeb(&state, &[bitvec![0, 0], bitvec![1, 1]]).unwrap();
+/// assert!((xeb.fidelity.mean - 1.0).abs() < 1e-12);
+/// assert!((xeb.ideal - 1.0).abs() < 1e-12);
+///
+/// // Uniformly random samples give a fidelity of zero on average.
+/// let noise = [bitvec![0, 0], bitvec![0, 1], bitvec![1, 0], bitvec![1, 1]];
+/// assert!(linear_xeb(&state, &noise).unwrap().fidelity.mean.abs() < 1e-12);
+/// ```
+pub fn linear_xeb(state: &State, samples: &[BitVec]) -> io::Result<Xeb> {
+    let num_qubits = state.num_qubits();
+    if samples.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "There are no samples to estimate the XEB fidelity from",
+        ));
+    }
+    if let Some(sample) = samples.iter().find(|sample| sample.len() != num_qubits) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format![
+                "A sample has {} bits but the circuit has {num_qubits} qubits",
+                sample.len()
+            ],
+        ));
+    }
+
+    let norm_squared: f64 = state
+        .iter()
+        .map(|(_, amplitude)| amplitude.norm_sqr())
+        .sum();
+    let dimension = 2.0_f64.powi(num_qubits as i32);
+    let (sum, sum_of_squares) = samples.iter().fold((0.0, 0.0), |(sum, squares), sample| {
+        let value = dimension * state.probability(sample) / norm_squared - 1.0;
+        (sum + value, squares + value * value)
+    });
+    let ideal = dimension
+        * state
+            .iter()
+            .map(|(_, amplitude)| (amplitude.norm_sqr() / norm_squared).powi(2))
+            .sum::<f64>()
+        - 1.0;
+    Ok(Xeb {
+        samples: samples.len(),
+        fidelity: Estimate::from_sums(sum, sum_of_squares, samples.len()),
+        ideal,
+    })
+}
+
+/// Draws `shots` basis states from the ideal distribution of a state, as a noiseless
+/// device would. Unlike [`crate::quantum::sampling::sample_counts`], this works for any
+/// number of qubits.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::quantum::ket::Ket;
+/// use quantum_simulator::quantum::sampling::Rng;
+/// use quantum_simulator::quantum::state::State;
+/// use quantum_simulator::quantum::xeb::sample_bitstrings;
+///
+/// let mut state = State::new(100);
+/// state.add_or_insert(Ket::new_zero_ket(100));
+/// let samples = sample_bitstrings(&state, 3, &mut Rng::new(0));
+/// assert_eq!(samples.len(), 3);
+/// assert!(samples.iter().all(|sample| sample.not_any()));
+/// ```
+pub fn sample_bitstrings(state: &State, shots: usize, rng: &mut Rng) -> Vec<BitVec> {
+    // Sorting the kets makes the samples depend only on the seed.
+    let kets = state.sorted_kets();
+    let table = AliasTable::new(kets.iter().map(|ket| ket.amplitude.norm_sqr()));
+    (0..shots)
+        .map(|_| kets[table.sample(rng)].bit_vec().clone())
+        .collect()
+}
+
+/// Parses a samples file, where each line is a bitstring with qubit 0 as the last
+/// character, as kets are printed, optionally followed by the number of times it was
+/// measured. Blank lines and `#` comments are ignored.
+///
+/// # Examples
+/// ```
+/// use bitvec::prelude::*;
+/// use quantum_simulator::quantum::xeb::parse_samples;
+///
+/// let samples = parse_samples("# Device run\n011\n100 2\n", 3).unwrap();
+/// assert_eq!(samples, vec![bitvec![1, 1, 0], bitvec![0, 0, 1], bitvec![0, 0, 1]]);
+/// ```
+pub fn parse_samples(source: &str, num_qubits: usize) -> io::Result<Vec<BitVec>> {
+    let mut samples = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!["{message} on line {line_number}"],
+            )
+        };
+
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (bits, count) = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [bits] => (bits, 1),
+            [bits, count] => match count.parse() {
+                Ok(count) => (bits, count),
+                Err(_) => return Err(invalid(format!["Invalid count '{count}'"])),
+            },
+            _ => return Err(invalid("Expected 'bitstring [count]'".to_string())),
+        };
+        if bits.len() != num_qubits || !bits.chars().all(|bit| bit == '0' || bit == '1') {
+            return Err(invalid(format![
+                "Expected a bitstring of {num_qubits} bits but found '{bits}'"
+            ]));
+        }
+        let sample: BitVec = bits.chars().rev().map(|bit| bit == '1').collect();
+        samples.extend(std::iter::repeat_n(sample, count));
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::gates::gate::{apply_gate_to_state, Gate};
+    use crate::gates::generators::mirror_circuit;
+    use crate::quantum::ket::Ket;
+    use crate::quantum::register::QubitRef;
+
+    /// Tests that samples of the ideal distribution of a random circuit give its ideal
+    /// fidelity, and that uniformly random samples give a fidelity of zero.
+    #[test]
+    fn test_random_circuit() {
+        let num_qubits = 6;
+        let mut rng = Rng::new(2);
+        // The first half of a mirror circuit is a random circuit.
+        let gates = mirror_circuit(num_qubits, 12, &mut rng);
+        let mut state = State::new(num_qubits);
+        state.add_or_insert(Ket::new_zero_ket(num_qubits));
+        let state = gates[..gates.len() / 2]
+            .iter()
+            .fold(state, apply_gate_to_state);
+
+        let samples = sample_bitstrings(&state, 20000, &mut rng);
+        let xeb = linear_xeb(&state, &samples).unwrap();
+        assert_eq!(xeb.samples, 20000);
+        assert!(xeb.ideal > 0.3, "{xeb:?}");
+        assert!((xeb.fidelity.mean - xeb.ideal).abs() < 4.0 * xeb.fidelity.standard_error);
+
+        let noise: Vec<BitVec> = (0..20000)
+            .map(|_| (0..num_qubits).map(|_| rng.next_f64() < 0.5).collect())
+            .collect();
+        let xeb = linear_xeb(&state, &noise).unwrap();
+        assert!(xeb.fidelity.mean.abs() < 4.0 * xeb.fidelity.standard_error);
+    }
+
+    /// Tests that samples of the wrong length and malformed sample files are rejected.
+    #[test]
+    fn test_invalid_samples() {
+        let mut state = State::new(2);
+        state.add_or_insert(Ket::new_zero_ket(2));
+        let state = apply_gate_to_state(
+            state,
+            &Gate::H {
+                target: QubitRef(0),
+            },
+        );
+        assert_eq!(
+            linear_xeb(&state, &[]).unwrap_err().to_string(),
+            "There are no samples to estimate the XEB fidelity from"
+        );
+        assert_eq!(
+            linear_xeb(&state, &[bitvec![0]]).unwrap_err().to_string(),
+            "A sample has 1 bits but the circuit has 2 qubits"
+        );
+        for (source, message) in [
+            (
+                "01\n012",
+                "Expected a bitstring of 2 bits but found '012' on line 2",
+            ),
+            (
+                "0a",
+                "Expected a bitstring of 2 bits but found '0a' on line 1",
+            ),
+            ("01 x", "Invalid count 'x' on line 1"),
+            ("01 1 1", "Expected 'bitstring [count]' on line 1"),
+        ] {
+            assert_eq!(parse_samples(source, 2).unwrap_err().to_string(), message);
+        }
+    }
+}
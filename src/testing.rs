@@ -0,0 +1,227 @@
+use crate::qasm::definitions::GateDefinitions;
+use crate::qasm::parser::Parser;
+use crate::qasm::simulator::{Options, Simulator};
+use crate::quantum::state::State;
+use crate::quantum::tolerance::Tolerance;
+use std::f64::consts::PI;
+use std::io;
+
+/// How far a probability may be from the expected one for a case to pass.
+pub const PROBABILITY_TOLERANCE: Tolerance = Tolerance::absolute(1e-9);
+
+/// The definitions of the controlled gates that are not built in, prepended to each case.
+/// CP is defined up to a global phase, which does not change any probability.
+const DEFINITIONS: &str = "\
+gate cz a, b { h b; cx a, b; h b; }
+gate cp(lambda) a, b { rz(lambda / 2) a; cx a, b; rz(-lambda / 2) b; cx a, b; rz(lambda / 2) b; }
+";
+
+/// An interference circuit along with the probability of each of its outcomes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterferenceCase {
+    /// A short name for the phases the case checks.
+    pub name: &'static str,
+    /// The OpenQASM 2.0 source of the circuit.
+    pub source: String,
+    /// The probability of each basis state, indexed with qubit 0 as the least
+    /// significant bit.
+    pub probabilities: Vec<f64>,
+}
+
+impl InterferenceCase {
+    fn new(name: &'static str, num_qubits: usize, body: &str, outcomes: &[(usize, f64)]) -> Self {
+        let mut probabilities = vec![0.0; 1 << num_qubits];
+        for (outcome, probability) in outcomes {
+            probabilities[*outcome] = *probability;
+        }
+        Self {
+            name,
+            source: format![
+                "OPENQASM 2.0;\ninclude \"qelib1.inc\";\n{DEFINITIONS}qreg q[{num_qubits}];\n{body}"
+            ],
+            probabilities,
+        }
+    }
+
+    /// Checks the final state of this case against the expected probabilities.
+    ///
+    /// # Examples
+    /// ```
+    /// use quantum_simulator::quantum::state::State;
+    /// use quantum_simulator::quantum::ket::Ket;
+    /// use quantum_simulator::testing::interference_cases;
+    ///
+    /// let case = &interference_cases()[0];
+    /// let state = State::from_ket_vec(&vec![Ket::new_zero_ket(1)]).unwrap();
+    /// assert!(case.check(&state).is_err());
+    /// ```
+    pub fn check(&self, state: &State) -> io::Result<()> {
+        let qubits: Vec<usize> = (0..state.num_qubits()).collect();
+        let probabilities = state.marginal_probabilities(&qubits);
+        if 1 << qubits.len() != self.probabilities.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format![
+                    "Case '{}' expects {} basis states but the state has {} qubits",
+                    self.name,
+                    self.probabilities.len(),
+                    qubits.len()
+                ],
+            ));
+        }
+        for (outcome, expected) in self.probabilities.iter().enumerate() {
+            let probability = probabilities.get(&outcome).copied().unwrap_or(0.0);
+            if !PROBABILITY_TOLERANCE.approx_eq(probability, *expected) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format![
+                        "Case '{}' gives probability {probability} for |{outcome:0width$b}⟩ instead of {expected}",
+                        self.name,
+                        width = qubits.len()
+                    ],
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns interference circuits with analytically known outcome probabilities, for
+/// checking that a backend gets the relative phases of T, Tdg, CZ and CP right.
+///
+/// Each circuit puts a qubit into superposition, applies phases and interferes the
+/// branches again with a Hadamard gate, so that a phase with the wrong sign or on the
+/// wrong basis state changes the probabilities. The cases check T and Tdg against each
+/// other and RZ, phase kickback through CZ and CP, and phases spread over an entangled
+/// state.
+pub fn interference_cases() -> Vec<InterferenceCase> {
+    let t_ramsey = (1.0 + (PI / 4.0).cos()) / 2.0;
+    let ghz_ramsey = (1.0 + (3.0 * PI / 4.0).cos()) / 2.0;
+    vec![
+        InterferenceCase::new(
+            "t_ramsey",
+            1,
+            "h q[0];\nt q[0];\nh q[0];",
+            &[(0, t_ramsey), (1, 1.0 - t_ramsey)],
+        ),
+        InterferenceCase::new(
+            "t_tdg_cancel",
+            1,
+            "h q[0];\nt q[0];\ntdg q[0];\nh q[0];",
+            &[(0, 1.0)],
+        ),
+        // Two T gates are S, which rz(-pi/2) undoes up to a global phase. A T gate with
+        // the wrong sign leaves Z and so gives |1⟩.
+        InterferenceCase::new(
+            "t_against_rz",
+            1,
+            "h q[0];\nt q[0];\nt q[0];\nrz(-pi/2) q[0];\nh q[0];",
+            &[(0, 1.0)],
+        ),
+        InterferenceCase::new(
+            "tdg_against_rz",
+            1,
+            "h q[0];\ntdg q[0];\ntdg q[0];\nrz(pi/2) q[0];\nh q[0];",
+            &[(0, 1.0)],
+        ),
+        InterferenceCase::new(
+            "cz_kickback",
+            2,
+            "x q[1];\nh q[0];\ncz q[0], q[1];\nh q[0];",
+            &[(0b11, 1.0)],
+        ),
+        InterferenceCase::new(
+            "cz_without_target",
+            2,
+            "h q[0];\ncz q[0], q[1];\nh q[0];",
+            &[(0b00, 1.0)],
+        ),
+        InterferenceCase::new(
+            "cp_ramsey",
+            2,
+            "x q[1];\nh q[0];\ncp(pi/3) q[0], q[1];\nh q[0];",
+            &[(0b10, 0.75), (0b11, 0.25)],
+        ),
+        // A controlled T on a target of |1⟩ kicks a T back onto the control, which the
+        // Tdg undoes.
+        InterferenceCase::new(
+            "cp_against_tdg",
+            2,
+            "x q[1];\nh q[0];\ncp(pi/4) q[0], q[1];\ntdg q[0];\nh q[0];",
+            &[(0b10, 1.0)],
+        ),
+        // A T on each qubit of a GHZ state gives |111⟩ a phase of 3π/4, which is moved
+        // back onto qubit 0 by undoing the entangling gates.
+        InterferenceCase::new(
+            "ghz_phases",
+            3,
+            "h q[0];\ncx q[0], q[1];\ncx q[1], q[2];\nt q[0];\nt q[1];\nt q[2];\n\
+             cx q[1], q[2];\ncx q[0], q[1];\nh q[0];",
+            &[(0b000, ghz_ramsey), (0b001, 1.0 - ghz_ramsey)],
+        ),
+    ]
+}
+
+/// Simulates every interference case with `options` and checks its final state,
+/// returning the first case that fails.
+///
+/// # Examples
+/// ```
+/// use quantum_simulator::qasm::simulator::Options;
+/// use quantum_simulator::quantum::backend::Backend;
+/// use quantum_simulator::testing::check_interference;
+///
+/// let options = Options { backend: Backend::Dense, ..Options::default() };
+/// check_interference(&options).unwrap();
+/// ```
+pub fn check_interference(options: &Options) -> io::Result<()> {
+    for case in interference_cases() {
+        let simulator = Simulator::new(GateDefinitions::new(), options.clone());
+        let result = simulator.run(Parser::new(case.source.as_bytes()))?;
+        case.check(&result.final_state)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::quantum::backend::Backend;
+
+    /// Tests that every backend passes the interference cases, with and without fusion.
+    #[test]
+    fn test_check_interference() {
+        for backend in [
+            Backend::Sparse,
+            Backend::Dense,
+            Backend::File,
+            Backend::Trie,
+        ] {
+            for fuse in [false, true] {
+                let options = Options {
+                    backend,
+                    fuse,
+                    ..Options::default()
+                };
+                check_interference(&options).unwrap();
+            }
+        }
+    }
+
+    /// Tests that a phase with the wrong sign fails its case.
+    #[test]
+    fn test_check_wrong_phase() {
+        let case = interference_cases()
+            .into_iter()
+            .find(|case| case.name == "t_against_rz")
+            .unwrap();
+        let wrong = case.source.replace("rz(-pi/2)", "rz(pi/2)");
+        let simulator = Simulator::new(GateDefinitions::new(), Options::default());
+        let result = simulator.run(Parser::new(wrong.as_bytes())).unwrap();
+        assert_eq!(
+            case.check(&result.final_state).unwrap_err().to_string(),
+            "Case 't_against_rz' gives probability 0 for |0⟩ instead of 1"
+        );
+    }
+}
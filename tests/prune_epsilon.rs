@@ -0,0 +1,40 @@
+use std::{env, fs, process};
+
+/// Runs the simulator on a circuit whose `|1⟩` amplitude is left with a norm of about
+/// 0.383 after the kets collide, and returns its JSON report.
+fn run_with_config(config: &str) -> String {
+    let dir = env::temp_dir().join(format!["qasm-simulator-prune-{}", process::id()]);
+    fs::create_dir_all(&dir).unwrap();
+    let circuit = dir.join("hth.qasm");
+    let config_path = dir.join("qasm-simulator.toml");
+    fs::write(
+        &circuit,
+        "OPENQASM 2.0;\nqreg q[1];\nh q[0];\nt q[0];\nh q[0];\n",
+    )
+    .unwrap();
+    fs::write(&config_path, config).unwrap();
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_quantum_simulator"))
+        .args(["--config", config_path.to_str().unwrap(), "--json", "-q"])
+        .arg(&circuit)
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Tests that `prune-epsilon` in a config file sets the tolerance the state prunes kets
+/// with.
+#[test]
+fn test_prune_epsilon_from_config() {
+    let default = run_with_config("");
+    assert!(default.contains(r#""kets_pruned":0"#));
+    assert!(default.contains(r#""pruned_probability":0"#));
+
+    let pruned = run_with_config("prune-epsilon = 0.5\n");
+    assert!(pruned.contains(r#""kets_pruned":1"#));
+    assert!(pruned.contains(r#""pruned_probability":0.146"#));
+    assert!(pruned.contains(r#""final_state":[{"basis":"0""#));
+}